@@ -1,4 +1,6 @@
 use seed::{self, prelude::*, *};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::convert::TryInto;
 #[allow(unused_imports)]
 use web_sys::console;
 
@@ -13,11 +15,133 @@ pub struct Model {
     pub current_page: Page,
     pub old_password: String,
     pub password_confirmation: String,
+    pub sessions: Vec<shared::SessionInfo>,
+    pub totp_token: String,
+    pub totp_code: String,
+    pub totp_enabled: bool,
+    pub totp_enrollment: Option<(String, String)>,
+    pub recovery_codes: Vec<String>,
+    pub tokens: Vec<shared::ApiTokenInfo>,
+    pub new_token: Option<String>,
+    pub token_name: String,
+    pub token_scopes: Vec<shared::TokenScope>,
+    pub token_expiry: String,
+    pub sso: bool,
+    // private keys made in the browser or typed in, by public key. They are
+    // never sent to the server and gone when the page is closed.
+    pub local_keys: HashMap<String, String>,
+    pub own_public_key: String,
+    pub audit_entries: Vec<shared::AuditEntry>,
+    pub audit_actor: String,
+    pub audit_action: String,
+    pub audit_target: String,
+    pub webhook_deliveries: Vec<shared::WebhookDelivery>,
+    pub traffic_range: shared::TrafficRange,
+    pub traffic: Option<Traffic>,
+    pub events: Option<EventStream>,
+    pub peer_status: HashMap<String, shared::PeerStatus>,
+    // links made here, by public key with their token and expiry
+    pub share_links: HashMap<String, (String, u64)>,
+    // the token of `/share/<token>` and what it showed
+    pub share_token: String,
+    pub shared_config: Option<SharedConfig>,
+    pub invites: Vec<shared::InviteInfo>,
+    pub new_invite: Option<String>,
+    pub invite_routes: String,
+    pub invite_quota: String,
+    pub invite_uses: String,
+    pub invite_hours: String,
+    pub invite_interface: String,
+    pub invite_peer_hours: String,
+    pub enroll_code: String,
+    pub enroll_name: String,
+    // only peers with this tag are listed
+    pub tag_filter: Option<String>,
+    // tags of the peers added next, comma separated
+    pub new_peer_tags: String,
+    // public keys of the peers the bulk actions are for
+    pub selected: HashSet<String>,
+    pub bulk_routes: String,
+    pub bulk_tag: String,
+}
+
+pub struct SharedConfig {
+    pub name: String,
+    pub config: String,
+    pub qr_code: String,
+}
+
+impl Model {
+    // the page of a share link, it works without a session
+    pub fn share(token: String) -> Model {
+        Model {
+            share_token: token,
+            current_page: Page::Share,
+            loaded: true,
+            ..Default::default()
+        }
+    }
+
+    // enrolling with an invite code, without a session as well
+    pub fn enroll(code: String) -> Model {
+        Model {
+            enroll_code: code,
+            current_page: Page::Enroll,
+            loaded: true,
+            ..Default::default()
+        }
+    }
+}
+
+// The open `/api/events` stream, closed when dropped.
+pub struct EventStream {
+    source: web_sys::EventSource,
+    // opened before, so the browser connected again
+    opened: bool,
+    _on_message: Closure<dyn FnMut(web_sys::MessageEvent)>,
+    _on_open: Closure<dyn FnMut(web_sys::Event)>,
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.source.close();
+    }
+}
+
+// the buckets of all peers for the selected range
+pub struct Traffic {
+    pub start: u64,
+    pub step: u64,
+    pub buckets: usize,
+    pub series: HashMap<String, Vec<shared::TrafficPoint>>,
+}
+
+impl Traffic {
+    // received and sent bytes of a peer over the whole range
+    fn total(&self, public_key: &str) -> (u64, u64) {
+        self.series
+            .get(public_key)
+            .map(|points| {
+                points
+                    .iter()
+                    .fold((0, 0), |(rx, tx), p| (rx + p.rx, tx + p.tx))
+            })
+            .unwrap_or_default()
+    }
 }
 
 pub enum Page {
+    Audit,
+    Enroll,
+    Invites,
+    Webhooks,
     EditUser,
     Login,
+    Sessions,
+    Share,
+    Tokens,
+    Totp,
+    TotpLogin,
     WGCong,
 }
 
@@ -34,8 +158,26 @@ pub enum Msg {
     LogoutRequest,
 
     NewPeer,
-    UpdatePeerName(usize, String),
-    RemovePeer(usize),
+    NewPeerLocalKey,
+    NewPeerWithKey,
+    OwnPublicKeyChanged(String),
+    LocalKeyChanged(String, String),
+    // the peers by public key, then the new value
+    UpdatePeerName(String, String),
+    SetPeerQuota(String, String),
+    SetPeerTags(String, String),
+    SendPeerConfig(String, String),
+    SharePeer(String, u64),
+    OpenShare,
+    RemovePeer(String),
+
+    FilterTag(Option<String>),
+    NewPeerTagsChanged(String),
+    SelectPeer(String, bool),
+    SelectShown(bool),
+    BulkRoutesChanged(String),
+    BulkTagChanged(String),
+    Bulk(shared::BulkAction),
 
     ShowPage(Page),
 
@@ -46,6 +188,51 @@ pub enum Msg {
 
     UpdateUser,
 
+    ShowSessions,
+    RevokeSession(String),
+
+    TotpCodeChanged(String),
+    LoginTotpRequest,
+    ShowTotp,
+    TotpEnroll,
+    TotpConfirm,
+    TotpDisable,
+
+    ShowTokens,
+    TokenNameChanged(String),
+    TokenExpiryChanged(String),
+    ToggleTokenScope(shared::TokenScope),
+    CreateToken,
+    RevokeToken(String),
+
+    ShowAudit,
+    AuditActorChanged(String),
+    AuditActionChanged(String),
+    AuditTargetChanged(String),
+
+    ShowTraffic(shared::TrafficRange),
+
+    ShowWebhooks,
+
+    ShowInvites,
+    InviteRoutesChanged(String),
+    InviteQuotaChanged(String),
+    InviteUsesChanged(String),
+    InviteHoursChanged(String),
+    InviteInterfaceChanged(String),
+    InvitePeerHoursChanged(String),
+    CreateInvite,
+    RevokeInvite(String),
+
+    EnrollCodeChanged(String),
+    EnrollNameChanged(String),
+    Enroll,
+
+    EventsOpened,
+    EventReceived(String),
+    // the config loaded again in the background, the page stays
+    ConfigRefreshed(fetch::Result<shared::Response>),
+
     Fetched(fetch::Result<shared::Response>),
 }
 
@@ -55,15 +242,95 @@ pub fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
 
         Msg::ShowPage(page) => model.current_page = page,
 
-        Msg::UpdatePeerName(i, name) => {
-            model.wireguard_config.peers[i].name = name.clone();
-            orders.perform_cmd(async move { Msg::Fetched(update_peer_name(i, name).await) });
+        Msg::UpdatePeerName(public_key, name) => {
+            if let Some(peer) = model
+                .wireguard_config
+                .peers
+                .iter_mut()
+                .find(|p| p.public_key == public_key)
+            {
+                peer.name = name.clone();
+            }
+            orders
+                .perform_cmd(async move { Msg::Fetched(update_peer_name(public_key, name).await) });
+        }
+
+        Msg::SetPeerQuota(public_key, quota) => {
+            // in GiB, empty removes the quota
+            let quota = quota.trim();
+            let limit = if quota.is_empty() {
+                None
+            } else {
+                match quota.parse::<f64>() {
+                    Ok(gib) if gib >= 0.0 => Some((gib * GIB as f64) as u64),
+                    _ => {
+                        model.last_response = Some(shared::Response::Failure);
+                        return;
+                    }
+                }
+            };
+            orders.perform_cmd(async move {
+                Msg::Fetched(set_peer_quota_request(public_key, limit).await)
+            });
+        }
+
+        Msg::SetPeerTags(public_key, tags) => {
+            let tags = split_tags(&tags);
+            orders.perform_cmd(async move {
+                Msg::Fetched(set_peer_tags_request(public_key, tags).await)
+            });
+        }
+
+        Msg::SendPeerConfig(public_key, email) => {
+            if email.trim().is_empty() {
+                return;
+            }
+            model.loaded = false;
+            orders.perform_cmd(async move {
+                Msg::Fetched(send_peer_config_request(public_key, email).await)
+            });
+        }
+
+        Msg::SharePeer(public_key, hours) => {
+            orders.perform_cmd(
+                async move { Msg::Fetched(share_peer_request(public_key, hours).await) },
+            );
+        }
+
+        Msg::OpenShare => {
+            model.loaded = false;
+            let token = model.share_token.clone();
+            orders.perform_cmd(async move { Msg::Fetched(open_share_request(token).await) });
         }
 
         Msg::UsernameChanged(s) => model.username = s,
         Msg::PasswordChanged(s) => model.password = s,
         Msg::OldPasswordChanged(s) => model.old_password = s,
         Msg::ConfirmationChanged(s) => model.password_confirmation = s,
+        Msg::TotpCodeChanged(s) => model.totp_code = s,
+        Msg::TokenNameChanged(s) => model.token_name = s,
+        Msg::TokenExpiryChanged(s) => model.token_expiry = s,
+        Msg::OwnPublicKeyChanged(s) => model.own_public_key = s,
+        Msg::AuditActorChanged(s) => model.audit_actor = s,
+        Msg::AuditActionChanged(s) => model.audit_action = s,
+        Msg::AuditTargetChanged(s) => model.audit_target = s,
+        Msg::LocalKeyChanged(public_key, private_key) => {
+            // only keep keys that belong to the peer
+            if public_key_of(private_key.trim()).as_deref() == Some(public_key.as_str()) {
+                model
+                    .local_keys
+                    .insert(public_key, private_key.trim().to_string());
+            } else {
+                model.local_keys.remove(&public_key);
+            }
+        }
+        Msg::ToggleTokenScope(scope) => {
+            if model.token_scopes.contains(&scope) {
+                model.token_scopes.retain(|s| *s != scope);
+            } else {
+                model.token_scopes.push(scope);
+            }
+        }
 
         Msg::LoginRequest => {
             model.loaded = false;
@@ -81,15 +348,73 @@ pub fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
         }
 
         Msg::NewPeer => {
+            let tags = split_tags(&model.new_peer_tags);
             orders
                 .skip()
-                .perform_cmd(async { Msg::Fetched(new_peer_request().await) });
+                .perform_cmd(async { Msg::Fetched(new_peer_request(tags).await) });
+        }
+
+        Msg::NewPeerLocalKey => {
+            let (private_key, public_key) = generate_key_pair();
+            model.local_keys.insert(public_key.clone(), private_key);
+            let tags = split_tags(&model.new_peer_tags);
+            orders.skip().perform_cmd(async {
+                Msg::Fetched(new_peer_with_key_request(public_key, tags).await)
+            });
+        }
+
+        Msg::NewPeerWithKey => {
+            let public_key = model.own_public_key.trim().to_string();
+            model.own_public_key.clear();
+            let tags = split_tags(&model.new_peer_tags);
+            orders.skip().perform_cmd(async {
+                Msg::Fetched(new_peer_with_key_request(public_key, tags).await)
+            });
         }
 
-        Msg::RemovePeer(index) => {
+        Msg::RemovePeer(public_key) => {
             orders
                 .skip()
-                .perform_cmd(async move { Msg::Fetched(remove_peer_request(index).await) });
+                .perform_cmd(async move { Msg::Fetched(remove_peer_request(public_key).await) });
+        }
+
+        // new peers go into the group that is looked at
+        Msg::FilterTag(tag) => {
+            model.selected.clear();
+            model.new_peer_tags = tag.clone().unwrap_or_default();
+            model.tag_filter = tag;
+        }
+        Msg::NewPeerTagsChanged(tags) => model.new_peer_tags = tags,
+        Msg::SelectPeer(public_key, selected) => {
+            if selected {
+                model.selected.insert(public_key);
+            } else {
+                model.selected.remove(&public_key);
+            }
+        }
+        Msg::SelectShown(selected) => {
+            model.selected = if selected {
+                shown_peers(model)
+                    .map(|(_, p)| p.public_key.clone())
+                    .collect()
+            } else {
+                HashSet::new()
+            };
+        }
+        Msg::BulkRoutesChanged(routes) => model.bulk_routes = routes,
+        Msg::BulkTagChanged(tag) => model.bulk_tag = tag,
+        Msg::Bulk(action) => {
+            let public_keys = model.selected.iter().cloned().collect::<Vec<_>>();
+            if public_keys.is_empty() {
+                return;
+            }
+            if let shared::BulkAction::Remove = action {
+                model.selected.clear();
+            }
+            model.loaded = false;
+            orders.perform_cmd(async move {
+                Msg::Fetched(bulk_peers_request(public_keys, action).await)
+            });
         }
 
         Msg::UpdateUser => {
@@ -114,23 +439,343 @@ pub fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
             model.password_confirmation.clear();
         }
 
+        Msg::ShowSessions => {
+            model.loaded = false;
+            orders.perform_cmd(async { Msg::Fetched(sessions_request().await) });
+        }
+
+        Msg::RevokeSession(id) => {
+            orders
+                .skip()
+                .perform_cmd(async move { Msg::Fetched(revoke_session_request(id).await) });
+        }
+
+        Msg::LoginTotpRequest => {
+            model.loaded = false;
+            let token = model.totp_token.clone();
+            let code = model.totp_code.clone();
+            orders.perform_cmd(async { Msg::Fetched(login_totp_request(token, code).await) });
+
+            model.totp_code.clear();
+        }
+
+        Msg::ShowTotp => {
+            model.loaded = false;
+            orders.perform_cmd(async { Msg::Fetched(totp_status_request().await) });
+        }
+
+        Msg::TotpEnroll => {
+            model.loaded = false;
+            orders.perform_cmd(async { Msg::Fetched(totp_enroll_request().await) });
+        }
+
+        Msg::TotpConfirm => {
+            model.loaded = false;
+            let code = model.totp_code.clone();
+            orders.perform_cmd(async { Msg::Fetched(totp_confirm_request(code).await) });
+
+            model.totp_code.clear();
+        }
+
+        Msg::TotpDisable => {
+            model.loaded = false;
+            let password = model.password.clone();
+            orders.perform_cmd(async { Msg::Fetched(totp_disable_request(password).await) });
+
+            model.password.clear();
+        }
+
+        Msg::ShowTokens => {
+            model.loaded = false;
+            model.new_token = None;
+            orders.perform_cmd(async { Msg::Fetched(tokens_request().await) });
+        }
+
+        Msg::ShowAudit => {
+            model.loaded = false;
+            let query = audit_query(model);
+            orders.perform_cmd(async move { Msg::Fetched(audit_request(query).await) });
+        }
+
+        Msg::ShowWebhooks => {
+            model.loaded = false;
+            orders.perform_cmd(async { Msg::Fetched(webhook_deliveries_request().await) });
+        }
+
+        Msg::ShowInvites => {
+            model.loaded = false;
+            model.new_invite = None;
+            orders.perform_cmd(async { Msg::Fetched(invites_request().await) });
+        }
+        Msg::InviteRoutesChanged(s) => model.invite_routes = s,
+        Msg::InviteQuotaChanged(s) => model.invite_quota = s,
+        Msg::InviteUsesChanged(s) => model.invite_uses = s,
+        Msg::InviteHoursChanged(s) => model.invite_hours = s,
+        Msg::InviteInterfaceChanged(s) => model.invite_interface = s,
+        Msg::InvitePeerHoursChanged(s) => model.invite_peer_hours = s,
+        Msg::CreateInvite => {
+            // empty fields take the defaults: the server's interface and address, no quota,
+            // one use, a day and peers that live until they are removed
+            let number = |value: &str, default: u64| {
+                let value = value.trim();
+                if value.is_empty() {
+                    Some(default)
+                } else {
+                    value.parse::<u64>().ok()
+                }
+            };
+            let quota = model.invite_quota.trim();
+            let quota = if quota.is_empty() {
+                Some(None)
+            } else {
+                match quota.parse::<f64>() {
+                    Ok(gib) if gib >= 0.0 => Some(Some((gib * GIB as f64) as u64)),
+                    _ => None,
+                }
+            };
+            let (quota, uses, hours) = match (
+                quota,
+                number(&model.invite_uses, 1),
+                number(&model.invite_hours, 24),
+            ) {
+                (Some(quota), Some(uses), Some(hours)) => (quota, uses as u32, hours),
+                _ => {
+                    model.last_response = Some(shared::Response::Failure);
+                    return;
+                }
+            };
+            let peer_hours = model.invite_peer_hours.trim();
+            let peer_hours = if peer_hours.is_empty() {
+                None
+            } else {
+                match peer_hours.parse::<u64>() {
+                    Ok(hours) => Some(hours),
+                    Err(_) => {
+                        model.last_response = Some(shared::Response::Failure);
+                        return;
+                    }
+                }
+            };
+            let request = shared::Request::CreateInvite {
+                interface: Some(model.invite_interface.trim().to_string())
+                    .filter(|i| !i.is_empty()),
+                routes: Some(model.invite_routes.clone()).filter(|r| !r.trim().is_empty()),
+                quota,
+                uses,
+                hours,
+                peer_hours,
+            };
+            model.loaded = false;
+            orders.perform_cmd(async move { Msg::Fetched(create_invite_request(request).await) });
+        }
+        Msg::RevokeInvite(id) => {
+            orders
+                .skip()
+                .perform_cmd(async move { Msg::Fetched(revoke_invite_request(id).await) });
+        }
+
+        Msg::EnrollCodeChanged(s) => model.enroll_code = s,
+        Msg::EnrollNameChanged(s) => model.enroll_name = s,
+        Msg::Enroll => {
+            let code = model.enroll_code.clone();
+            let name = model.enroll_name.clone();
+            model.last_response = None;
+            model.loaded = false;
+            orders.perform_cmd(async move { Msg::Fetched(enroll_request(code, name).await) });
+        }
+
+        Msg::ShowTraffic(range) => {
+            model.traffic_range = range;
+            orders
+                .skip()
+                .perform_cmd(async move { Msg::Fetched(traffic_request(range).await) });
+        }
+
+        Msg::CreateToken => {
+            // empty means the token never expires
+            let expiry = model.token_expiry.trim();
+            let expires_in_days = if expiry.is_empty() {
+                None
+            } else {
+                match expiry.parse::<u64>() {
+                    Ok(days) => Some(days),
+                    Err(_) => {
+                        model.last_response = Some(shared::Response::Failure);
+                        return;
+                    }
+                }
+            };
+            let name = model.token_name.clone();
+            let scopes = model.token_scopes.clone();
+            model.loaded = false;
+            orders.perform_cmd(async move {
+                Msg::Fetched(create_token_request(name, scopes, expires_in_days).await)
+            });
+        }
+
+        Msg::RevokeToken(id) => {
+            orders
+                .skip()
+                .perform_cmd(async move { Msg::Fetched(revoke_token_request(id).await) });
+        }
+
+        Msg::EventsOpened => {
+            // events sent while it was gone are missing
+            if let Some(events) = &mut model.events {
+                if events.opened {
+                    orders.perform_cmd(async { Msg::ConfigRefreshed(config_request().await) });
+                }
+                events.opened = true;
+            }
+            orders.skip();
+        }
+
+        Msg::EventReceived(data) => match serde_json::from_str(&data) {
+            Ok(event) => apply_event(model, event, orders),
+            Err(e) => log!("invalid event:", e),
+        },
+
+        Msg::ConfigRefreshed(Ok(shared::Response::WireGuardConf { config })) => {
+            model.wireguard_config = config;
+        }
+        Msg::ConfigRefreshed(_) => {
+            orders.skip();
+        }
+
         Msg::Fetched(Ok(response_data)) => match response_data {
             shared::Response::LoginSuccess { session } => {
                 model.last_response = Some(shared::Response::Success);
                 model.loaded = true;
                 model.session = session;
+                model.totp_token.clear();
                 orders.perform_cmd(async { Msg::Fetched(config_request().await) });
             }
             shared::Response::LoginFailure => {
                 model.last_response = Some(shared::Response::Failure);
                 model.loaded = true;
             }
+            shared::Response::LoginOptions { sso } => {
+                model.sso = sso;
+                orders.skip();
+            }
+            shared::Response::TooManyAttempts { retry_after } => {
+                model.last_response = Some(shared::Response::TooManyAttempts { retry_after });
+                model.loaded = true;
+            }
+            shared::Response::TotpRequired { token } => {
+                model.totp_token = token;
+                model.current_page = Page::TotpLogin;
+                model.loaded = true;
+            }
+            shared::Response::ApiTokens { tokens } => {
+                model.tokens = tokens;
+                model.current_page = Page::Tokens;
+                model.loaded = true;
+            }
+            shared::Response::ApiTokenCreated { token, tokens } => {
+                model.new_token = Some(token);
+                model.tokens = tokens;
+                model.token_name.clear();
+                model.token_scopes.clear();
+                model.token_expiry.clear();
+                model.loaded = true;
+            }
+            shared::Response::TotpStatus { enabled } => {
+                model.totp_enabled = enabled;
+                model.totp_enrollment = None;
+                model.recovery_codes.clear();
+                model.current_page = Page::Totp;
+                model.loaded = true;
+            }
+            shared::Response::TotpEnrollment { secret, qr_code } => {
+                model.totp_enrollment = Some((secret, qr_code));
+                model.loaded = true;
+            }
+            shared::Response::RecoveryCodes { codes } => {
+                model.recovery_codes = codes;
+                model.totp_enabled = true;
+                model.totp_enrollment = None;
+                model.loaded = true;
+            }
             shared::Response::WireGuardConf { config } => {
                 model.wireguard_config = config;
                 model.current_page = Page::WGCong;
                 model.loaded = true;
+                let range = model.traffic_range;
+                orders.perform_cmd(async move { Msg::Fetched(traffic_request(range).await) });
+                if model.events.is_none() {
+                    model.events = open_events(orders);
+                }
+            }
+            shared::Response::Traffic {
+                range,
+                start,
+                step,
+                series,
+            } => {
+                model.traffic = Some(Traffic {
+                    start,
+                    step,
+                    buckets: range.secs().div_ceil(step) as usize,
+                    series: series
+                        .into_iter()
+                        .map(|s| (s.public_key, s.points))
+                        .collect(),
+                });
+            }
+            shared::Response::Invites { invites } => {
+                model.invites = invites;
+                model.current_page = Page::Invites;
+                model.loaded = true;
+            }
+            shared::Response::InviteCreated { code, invites } => {
+                model.new_invite = Some(code);
+                model.invites = invites;
+                model.invite_routes.clear();
+                model.invite_quota.clear();
+                model.invite_uses.clear();
+                model.invite_hours.clear();
+                model.invite_interface.clear();
+                model.invite_peer_hours.clear();
+                model.loaded = true;
+            }
+            shared::Response::ShareLink {
+                public_key,
+                token,
+                expires,
+            } => {
+                model.share_links.insert(public_key, (token, expires));
+            }
+            shared::Response::SharedConfig {
+                name,
+                config,
+                qr_code,
+            } => {
+                model.shared_config = Some(SharedConfig {
+                    name,
+                    config,
+                    qr_code,
+                });
+                model.loaded = true;
+            }
+            shared::Response::WebhookDeliveries { deliveries } => {
+                model.webhook_deliveries = deliveries;
+                model.current_page = Page::Webhooks;
+                model.loaded = true;
+            }
+            shared::Response::AuditLog { entries } => {
+                model.audit_entries = entries;
+                model.current_page = Page::Audit;
+                model.loaded = true;
+            }
+            shared::Response::Sessions { sessions } => {
+                model.sessions = sessions;
+                model.current_page = Page::Sessions;
+                model.loaded = true;
             }
             shared::Response::Logout => {
+                model.events = None;
+                model.peer_status.clear();
                 model.loaded = true;
                 model.session.clear();
                 model.current_page = Page::Login;
@@ -153,9 +798,87 @@ pub fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
     }
 }
 
+fn apply_event(model: &mut Model, event: shared::Event, orders: &mut impl Orders<Msg>) {
+    let peers = &mut model.wireguard_config.peers;
+    match event {
+        shared::Event::PeerAdded { index, peer } => {
+            if peers.iter().all(|p| p.public_key != peer.public_key) {
+                peers.insert(index.min(peers.len()), peer);
+            }
+        }
+        shared::Event::PeerRenamed { public_key, name } => {
+            if let Some(peer) = peers.iter_mut().find(|p| p.public_key == public_key) {
+                peer.name = name;
+            }
+        }
+        shared::Event::PeerRemoved { public_key } => {
+            peers.retain(|p| p.public_key != public_key);
+        }
+        shared::Event::ConfigChanged => {
+            orders.perform_cmd(async { Msg::ConfigRefreshed(config_request().await) });
+        }
+        shared::Event::Status { peers } => {
+            model.peer_status = peers
+                .into_iter()
+                .map(|status| (status.public_key.clone(), status))
+                .collect();
+        }
+    }
+}
+
+// messages of the stream go to `update`, the browser connects again by itself
+fn open_events(orders: &mut impl Orders<Msg>) -> Option<EventStream> {
+    let source = web_sys::EventSource::new("/api/events").ok()?;
+
+    let sender = orders.msg_sender();
+    let on_message = Closure::wrap(Box::new(move |ev: web_sys::MessageEvent| {
+        if let Some(data) = ev.data().as_string() {
+            sender(Some(Msg::EventReceived(data)));
+        }
+    }) as Box<dyn FnMut(web_sys::MessageEvent)>);
+    source.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+    let sender = orders.msg_sender();
+    let on_open = Closure::wrap(
+        Box::new(move |_: web_sys::Event| sender(Some(Msg::EventsOpened)))
+            as Box<dyn FnMut(web_sys::Event)>,
+    );
+    source.set_onopen(Some(on_open.as_ref().unchecked_ref()));
+
+    Some(EventStream {
+        source,
+        opened: false,
+        _on_message: on_message,
+        _on_open: on_open,
+    })
+}
+
+// the server rejects changes without the token from its csrf cookie in this header
+fn csrf_request<'a>(
+    url: impl Into<std::borrow::Cow<'a, str>>,
+    method: fetch::Method,
+) -> Request<'a> {
+    Request::new(url)
+        .method(method)
+        .header(fetch::Header::custom("X-CSRF-Token", csrf_token()))
+}
+
+fn csrf_token() -> String {
+    web_sys::window()
+        .and_then(|window| window.document())
+        .and_then(|document| document.dyn_into::<web_sys::HtmlDocument>().ok())
+        .and_then(|document| document.cookie().ok())
+        .and_then(|cookies| {
+            cookies
+                .split("; ")
+                .find_map(|cookie| cookie.strip_prefix("csrf-token="))
+                .map(|token| token.to_string())
+        })
+        .unwrap_or_default()
+}
+
 async fn login_request(username: String, password: String) -> fetch::Result<shared::Response> {
-    Request::new("/api/login")
-        .method(fetch::Method::Post)
+    csrf_request("/api/login", fetch::Method::Post)
         .json(&shared::Request::Login { username, password })?
         .fetch()
         .await?
@@ -164,9 +887,18 @@ async fn login_request(username: String, password: String) -> fetch::Result<shar
         .await
 }
 
+async fn login_totp_request(token: String, code: String) -> fetch::Result<shared::Response> {
+    csrf_request("/api/login_totp", fetch::Method::Post)
+        .json(&shared::Request::LoginTotp { token, code })?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
 async fn logout_request() -> fetch::Result<shared::Response> {
-    fetch::Request::new("/api/logout")
-        .method(fetch::Method::Post)
+    csrf_request("/api/logout", fetch::Method::Post)
         .fetch()
         .await?
         .check_status()?
@@ -180,8 +912,7 @@ async fn update_user_request(
     new_password: String,
     password_confirmation: String,
 ) -> fetch::Result<shared::Response> {
-    fetch::Request::new("/api/update_user")
-        .method(fetch::Method::Post)
+    csrf_request("/api/update_user", fetch::Method::Post)
         .json(&shared::Request::UpdateUser {
             name,
             old_password,
@@ -205,6 +936,16 @@ pub async fn session_request() -> fetch::Result<shared::Response> {
         .await
 }
 
+pub async fn login_options_request() -> fetch::Result<shared::Response> {
+    fetch::Request::new("/api/login_options")
+        .method(fetch::Method::Get)
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
 async fn config_request() -> fetch::Result<shared::Response> {
     fetch::Request::new("/api/config")
         .method(fetch::Method::Get)
@@ -215,110 +956,1772 @@ async fn config_request() -> fetch::Result<shared::Response> {
         .await
 }
 
-async fn new_peer_request() -> fetch::Result<shared::Response> {
-    fetch::Request::new("/api/new_peer")
-        .method(fetch::Method::Get)
-        .fetch()
-        .await?
-        .check_status()?
-        .json()
-        .await
+async fn new_peer_request(tags: Vec<String>) -> fetch::Result<shared::Response> {
+    csrf_request("/api/new_peer", fetch::Method::Post)
+        .json(&shared::Request::NewPeer { tags })?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn new_peer_with_key_request(
+    public_key: String,
+    tags: Vec<String>,
+) -> fetch::Result<shared::Response> {
+    csrf_request("/api/new_peer_with_key", fetch::Method::Post)
+        .json(&shared::Request::NewPeerWithKey { public_key, tags })?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn remove_peer_request(public_key: String) -> fetch::Result<shared::Response> {
+    let url = format!("/api/remove_peer?public_key={}", escape_key(&public_key));
+    csrf_request(url, fetch::Method::Delete)
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn set_peer_tags_request(
+    public_key: String,
+    tags: Vec<String>,
+) -> fetch::Result<shared::Response> {
+    csrf_request("/api/set_peer_tags", fetch::Method::Post)
+        .json(&shared::Request::SetPeerTags { public_key, tags })?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn bulk_peers_request(
+    public_keys: Vec<String>,
+    action: shared::BulkAction,
+) -> fetch::Result<shared::Response> {
+    csrf_request("/api/bulk_peers", fetch::Method::Post)
+        .json(&shared::Request::BulkPeers {
+            public_keys,
+            action,
+        })?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+// the filters as query string, for the list and the export
+fn audit_query(model: &Model) -> String {
+    [
+        ("actor", &model.audit_actor),
+        ("action", &model.audit_action),
+        ("target", &model.audit_target),
+    ]
+    .iter()
+    .filter(|(_, value)| !value.trim().is_empty())
+    .map(|(key, value)| {
+        format!(
+            "{}={}",
+            key,
+            String::from(js_sys::encode_uri_component(value.trim()))
+        )
+    })
+    .collect::<Vec<_>>()
+    .join("&")
+}
+
+// only admins may read the log, the others get a failure instead of an error
+async fn audit_request(query: String) -> fetch::Result<shared::Response> {
+    let response = fetch::Request::new(format!("/api/audit?{}", query))
+        .method(fetch::Method::Get)
+        .fetch()
+        .await?;
+    if response.status().code == 403 {
+        return Ok(shared::Response::Failure);
+    }
+    response.check_status()?.json().await
+}
+
+// only admins may read it, like the audit log
+async fn webhook_deliveries_request() -> fetch::Result<shared::Response> {
+    let response = fetch::Request::new("/api/webhooks/deliveries")
+        .method(fetch::Method::Get)
+        .fetch()
+        .await?;
+    if response.status().code == 403 {
+        return Ok(shared::Response::Failure);
+    }
+    response.check_status()?.json().await
+}
+
+async fn traffic_request(range: shared::TrafficRange) -> fetch::Result<shared::Response> {
+    fetch::Request::new(format!("/api/traffic/{}", range.path()))
+        .method(fetch::Method::Get)
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn sessions_request() -> fetch::Result<shared::Response> {
+    fetch::Request::new("/api/sessions")
+        .method(fetch::Method::Get)
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn revoke_session_request(id: String) -> fetch::Result<shared::Response> {
+    csrf_request("/api/revoke_session", fetch::Method::Post)
+        .json(&shared::Request::RevokeSession { id })?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn totp_status_request() -> fetch::Result<shared::Response> {
+    fetch::Request::new("/api/totp")
+        .method(fetch::Method::Get)
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn totp_enroll_request() -> fetch::Result<shared::Response> {
+    csrf_request("/api/totp/enroll", fetch::Method::Post)
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn totp_confirm_request(code: String) -> fetch::Result<shared::Response> {
+    csrf_request("/api/totp/confirm", fetch::Method::Post)
+        .json(&shared::Request::TotpConfirm { code })?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn totp_disable_request(password: String) -> fetch::Result<shared::Response> {
+    csrf_request("/api/totp/disable", fetch::Method::Post)
+        .json(&shared::Request::TotpDisable { password })?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn tokens_request() -> fetch::Result<shared::Response> {
+    fetch::Request::new("/api/tokens")
+        .method(fetch::Method::Get)
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn create_token_request(
+    name: String,
+    scopes: Vec<shared::TokenScope>,
+    expires_in_days: Option<u64>,
+) -> fetch::Result<shared::Response> {
+    csrf_request("/api/tokens", fetch::Method::Post)
+        .json(&shared::Request::CreateApiToken {
+            name,
+            scopes,
+            expires_in_days,
+        })?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn revoke_token_request(id: String) -> fetch::Result<shared::Response> {
+    csrf_request("/api/revoke_token", fetch::Method::Post)
+        .json(&shared::Request::RevokeApiToken { id })?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn set_peer_quota_request(
+    public_key: String,
+    limit: Option<u64>,
+) -> fetch::Result<shared::Response> {
+    csrf_request("/api/set_peer_quota", fetch::Method::Post)
+        .json(&shared::Request::SetPeerQuota { public_key, limit })?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+// the server answers 404 when it has no smtp relay set up
+async fn send_peer_config_request(
+    public_key: String,
+    email: String,
+) -> fetch::Result<shared::Response> {
+    let response = csrf_request("/api/send_peer_config", fetch::Method::Post)
+        .json(&shared::Request::SendPeerConfig { public_key, email })?
+        .fetch()
+        .await?;
+    if response.status().code == 404 {
+        return Ok(shared::Response::Failure);
+    }
+    response.check_status()?.json().await
+}
+
+// only admins may manage invites, like the audit log
+async fn invites_request() -> fetch::Result<shared::Response> {
+    let response = fetch::Request::new("/api/invites")
+        .method(fetch::Method::Get)
+        .fetch()
+        .await?;
+    if response.status().code == 403 {
+        return Ok(shared::Response::Failure);
+    }
+    response.check_status()?.json().await
+}
+
+// `request` is a `CreateInvite`
+async fn create_invite_request(request: shared::Request) -> fetch::Result<shared::Response> {
+    csrf_request("/api/create_invite", fetch::Method::Post)
+        .json(&request)?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn revoke_invite_request(id: String) -> fetch::Result<shared::Response> {
+    csrf_request("/api/revoke_invite", fetch::Method::Post)
+        .json(&shared::Request::RevokeInvite { id })?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn enroll_request(code: String, name: String) -> fetch::Result<shared::Response> {
+    csrf_request("/api/enroll", fetch::Method::Post)
+        .json(&shared::Request::Enroll { code, name })?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn share_peer_request(public_key: String, hours: u64) -> fetch::Result<shared::Response> {
+    csrf_request("/api/share_peer", fetch::Method::Post)
+        .json(&shared::Request::SharePeer { public_key, hours })?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn open_share_request(token: String) -> fetch::Result<shared::Response> {
+    csrf_request(format!("/api/share/{}", token), fetch::Method::Post)
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+async fn update_peer_name(public_key: String, name: String) -> fetch::Result<shared::Response> {
+    csrf_request("/api/update_peer_name", fetch::Method::Post)
+        .json(&shared::Request::UpdatePeerName { public_key, name })?
+        .fetch()
+        .await?
+        .check_status()?
+        .json()
+        .await
+}
+
+// a key pair like `wg genkey` and `wg pubkey` make it, as base64
+fn generate_key_pair() -> (String, String) {
+    let mut private_key = [0u8; 32];
+    web_sys::window()
+        .unwrap()
+        .crypto()
+        .unwrap()
+        .get_random_values_with_u8_array(&mut private_key)
+        .unwrap();
+    // clamped as curve25519 wants it
+    private_key[0] &= 248;
+    private_key[31] &= 127;
+    private_key[31] |= 64;
+    let private_key = base64::encode(private_key);
+    let public_key = public_key_of(&private_key).unwrap();
+    (private_key, public_key)
+}
+
+fn public_key_of(private_key: &str) -> Option<String> {
+    let bytes: [u8; 32] = base64::decode(private_key).ok()?.try_into().ok()?;
+    let secret = x25519_dalek::StaticSecret::from(bytes);
+    Some(base64::encode(
+        x25519_dalek::PublicKey::from(&secret).as_bytes(),
+    ))
+}
+
+// the config with the private key from the browser, as a link target
+fn local_config_url(
+    wg_config: &shared::wg_conf::WireGuardConf,
+    peer: &shared::wg_conf::Peer,
+    private_key: &str,
+) -> String {
+    let peer = shared::wg_conf::Peer {
+        private_key: private_key.to_string(),
+        ..peer.clone()
+    };
+    config_url(&wg_config.peer_config(&peer))
+}
+
+fn config_url(config: &str) -> String {
+    format!(
+        "data:text/plain;charset=utf-8,{}",
+        String::from(js_sys::encode_uri_component(config))
+    )
+}
+
+// One-time links for people without a login, the link of this page stays
+// until it is reloaded.
+fn display_share(public_key: &str, share_link: Option<&(String, u64)>) -> Vec<Node<Msg>> {
+    let link = match share_link {
+        Some((token, expires)) => {
+            let origin = web_sys::window()
+                .and_then(|w| w.location().origin().ok())
+                .unwrap_or_default();
+            nodes![
+                input![attrs! {
+                    At::Class => "form-control form-control-sm text-monospace",
+                    At::ReadOnly => AtValue::None,
+                    At::Value => format!("{}/share/{}", origin, token)
+                }],
+                small![format!(
+                    "Works once until {}, copy it now, it isn't shown again",
+                    format_time(*expires)
+                )],
+            ]
+        }
+        None => nodes![],
+    };
+    nodes![
+        div![
+            attrs! {At::Class => "input-group input-group-sm mt-1 mb-1"},
+            div![
+                attrs! {At::Class => "input-group-prepend"},
+                span![attrs! {At::Class => "input-group-text"}, "Share link for"],
+            ],
+            div![
+                attrs! {At::Class => "input-group-append"},
+                [(1, "1h"), (24, "24h"), (7 * 24, "7d")]
+                    .iter()
+                    .map(|(hours, label)| {
+                        let hours = *hours;
+                        let public_key = public_key.to_string();
+                        button![
+                            attrs! {At::Class => "btn btn-outline-secondary"},
+                            ev(Ev::Click, move |_| Msg::SharePeer(public_key, hours)),
+                            *label
+                        ]
+                    }),
+            ],
+        ],
+        link,
+    ]
+}
+
+// a config shown to someone without a login, it can't be fetched again
+fn display_shared_config(shared: &SharedConfig, note: &str) -> Vec<Node<Msg>> {
+    nodes![
+        strong![format!("WireGuard config {}", shared.name)],
+        div![note],
+        div![
+            attrs! {At::Class => "text-center"},
+            img![attrs! {At::Src => shared.qr_code, At::Alt => "QR code"}],
+        ],
+        pre![attrs! {At::Class => "border p-2"}, &shared.config],
+        a![
+            attrs! {At::Class => "btn btn-secondary",
+            At::Href => config_url(&shared.config),
+            At::Download => "wg.conf"},
+            "Download"
+        ],
+    ]
+}
+
+// what a share link shows, once
+fn share_page(model: &Model) -> Vec<Node<Msg>> {
+    let content = match &model.shared_config {
+        Some(shared) => display_shared_config(
+            shared,
+            "The link is used up now. Download the config or scan the code with the WireGuard \
+             app before you leave this page.",
+        ),
+        None if matches!(model.last_response, Some(shared::Response::Failure)) => nodes![div![
+            attrs! {At::Class => "alert alert-warning mb-0"},
+            "This link was already used or has expired, ask for a new one."
+        ]],
+        None => nodes![
+            div![
+                "A WireGuard config was shared with you. The link works once, it stops \
+                  working as soon as the config is shown."
+            ],
+            button![
+                attrs! {At::Class => "btn btn-primary mt-1"},
+                ev(Ev::Click, |_| Msg::OpenShare),
+                "Show config"
+            ],
+        ],
+    };
+    nodes![div![
+        attrs! {At::Class => "list-group-item rounded-0"},
+        content
+    ]]
+}
+
+// redeeming an invite code for a new peer
+fn enroll_page(model: &Model) -> Vec<Node<Msg>> {
+    let content = match &model.shared_config {
+        Some(shared) => display_shared_config(
+            shared,
+            "Your device was added. Download the config or scan the code with the WireGuard app \
+             before you leave this page, it isn't shown again.",
+        ),
+        None => nodes![
+            div!["Enter the invite code you got and a name for your device."],
+            if matches!(model.last_response, Some(shared::Response::Failure)) {
+                nodes![div![
+                    attrs! {At::Class => "alert alert-warning mt-1 mb-1"},
+                    "The code is unknown, used up or expired, or the name is missing."
+                ]]
+            } else {
+                nodes![]
+            },
+            div![
+                attrs! {At::Class => "input-group mt-1"},
+                div![
+                    attrs! {At::Class => "input-group-prepend w-25"},
+                    div![
+                        attrs! {At::Class => "input-group-text rounded-0 w-100"},
+                        "Invite code"
+                    ],
+                ],
+                input![
+                    input_ev(Ev::Input, Msg::EnrollCodeChanged),
+                    attrs! {
+                        At::Value => model.enroll_code,
+                        At::Type => "text",
+                        At::Class => "form-control rounded-0 text-monospace",
+                        At::AutoComplete => "off",
+                    },
+                ],
+            ],
+            div![
+                attrs! {At::Class => "input-group"},
+                div![
+                    attrs! {At::Class => "input-group-prepend w-25"},
+                    div![
+                        attrs! {At::Class => "input-group-text rounded-0 w-100"},
+                        "Device name"
+                    ],
+                ],
+                input![
+                    input_ev(Ev::Input, Msg::EnrollNameChanged),
+                    attrs! {
+                        At::Value => model.enroll_name,
+                        At::Type => "text",
+                        At::Class => "form-control rounded-0",
+                        At::Placeholder => "Laptop",
+                    },
+                ],
+            ],
+            button![
+                attrs! {At::Class => "btn btn-primary mt-1"},
+                ev(Ev::Click, |_| Msg::Enroll),
+                "Get config"
+            ],
+        ],
+    };
+    nodes![div![
+        attrs! {At::Class => "list-group-item rounded-0"},
+        content
+    ]]
+}
+
+fn display_interface(interface: &shared::wg_conf::Interface) -> Vec<Node<Msg>> {
+    nodes![li![
+        attrs! {At::Class => "list-group-item rounded-0"},
+        div![format!("Interface: {}", interface.address.to_string())],
+        div![format!("Private Key: {}", interface.private_key)],
+        div![format!("Public Key: {}", interface.public_key)],
+    ]]
+}
+
+fn display_peer(index: usize, peer: &shared::wg_conf::Peer, model: &Model) -> Vec<Node<Msg>> {
+    let wg_config = &model.wireguard_config;
+    let local_key = model.local_keys.get(&peer.public_key);
+    let status = model.peer_status.get(&peer.public_key);
+    let share_link = model.share_links.get(&peer.public_key);
+    let selected = model.selected.contains(&peer.public_key);
+    // making lots of copies for all the closures
+    let name = peer.name.clone();
+    let public_key = peer.public_key.clone();
+    let rename_key = public_key.clone();
+    let remove_key = public_key.clone();
+    let div_id1 = format!("peer{}", index);
+    let div_id2 = div_id1.clone();
+    let div_id3 = div_id1.clone();
+    let input_id1 = format!("peer{}i", index);
+    let input_id2 = input_id1.clone();
+    let input_id3 = input_id1.clone();
+    nodes![li![
+        attrs! {At::Class => "list-group-item"},
+        input![
+            attrs! {
+                At::Type => "checkbox",
+                At::Class => "float-right",
+                At::Title => "Select for the actions on several peers",
+                At::Checked => selected.as_at_value()
+            },
+            ev(Ev::Click, move |_| Msg::SelectPeer(public_key, !selected)),
+        ],
+        div![
+            attrs! {At::Id => div_id1},
+            ev(Ev::Click, move |_ev| {
+                hide_element(&div_id1);
+                show_element(&input_id1);
+                find_element_by_id(&input_id1)
+                    .dyn_into::<web_sys::HtmlInputElement>()
+                    .unwrap()
+                    .set_value(&name); // prefill the text input with the old name
+
+                focus_element(&input_id1);
+                Msg::NoAction
+            }),
+            format!("Name: {}", peer.name)
+        ],
+        input![
+            attrs! {At::Id => input_id3, At::Style => "display: none"},
+            ev(Ev::Blur, move |_ev: web_sys::Event| {
+                hide_element(&input_id3);
+                show_element(&div_id3);
+                Msg::NoAction
+            }),
+            ev(Ev::KeyDown, move |ev: web_sys::Event| {
+                let ev = ev.dyn_into::<web_sys::KeyboardEvent>().unwrap();
+                let mut action = Msg::NoAction;
+
+                if ev.key() == "Enter" {
+                    let value = ev
+                        .target()
+                        .unwrap()
+                        .dyn_into::<web_sys::HtmlInputElement>()
+                        .unwrap()
+                        .value();
+
+                    action = Msg::UpdatePeerName(rename_key.clone(), value);
+                }
+
+                if ev.key() == "Enter" || ev.key() == "Escape" {
+                    hide_element(&input_id2);
+                    show_element(&div_id2);
+                }
+
+                action
+            })
+        ],
+        div![format!("Peer: {}", peer.allowed_ips.to_string())],
+        div![format!("Public Key: {}", peer.public_key)],
+        peer.expires
+            .map(|expires| div![format!("Removed on {}", format_time(expires))])
+            .into_iter()
+            .collect::<Vec<_>>(),
+        display_tags(peer),
+        status.map(display_status).unwrap_or_default(),
+        model
+            .traffic
+            .as_ref()
+            .map(|traffic| traffic_chart(traffic, &peer.public_key))
+            .unwrap_or_default(),
+        display_quota(peer),
+        display_download(index, peer, wg_config, local_key),
+        if peer.private_key.is_empty() {
+            nodes![]
+        } else {
+            display_share(&peer.public_key, share_link)
+        },
+        button![
+            attrs! {At::Class => "btn btn-danger float-right"},
+            ev(Ev::Click, move |_| {
+                if web_sys::window()
+                    .unwrap()
+                    .confirm_with_message("Sure?")
+                    .unwrap()
+                {
+                    Msg::RemovePeer(remove_key.clone())
+                } else {
+                    Msg::NoAction
+                }
+            }),
+            "Remove"
+        ],
+    ]]
+}
+
+// Peers without a private key on the server get their config put together
+// here, with the key from this browser.
+fn display_download(
+    index: usize,
+    peer: &shared::wg_conf::Peer,
+    wg_config: &shared::wg_conf::WireGuardConf,
+    local_key: Option<&String>,
+) -> Vec<Node<Msg>> {
+    if !peer.private_key.is_empty() {
+        let email_id = format!("peer{}m", index);
+        let button_email_id = email_id.clone();
+        let public_key = peer.public_key.clone();
+        let button_public_key = public_key.clone();
+        return nodes![
+            div![
+                attrs! {At::Class => "input-group input-group-sm mt-1 mb-1"},
+                div![
+                    attrs! {At::Class => "input-group-prepend"},
+                    span![attrs! {At::Class => "input-group-text"}, "Send to"],
+                ],
+                input![
+                    attrs! {
+                        At::Id => email_id,
+                        At::Class => "form-control",
+                        At::Type => "email",
+                        At::Placeholder => "user@example.com"
+                    },
+                    keyboard_ev(Ev::KeyDown, move |ev| {
+                        if ev.key() != "Enter" {
+                            return Msg::NoAction;
+                        }
+                        let value = ev
+                            .target()
+                            .unwrap()
+                            .dyn_into::<web_sys::HtmlInputElement>()
+                            .unwrap()
+                            .value();
+                        Msg::SendPeerConfig(public_key.clone(), value)
+                    })
+                ],
+                div![
+                    attrs! {At::Class => "input-group-append"},
+                    button![
+                        attrs! {At::Class => "btn btn-outline-secondary"},
+                        ev(Ev::Click, move |_| {
+                            let value = find_element_by_id(&button_email_id)
+                                .dyn_into::<web_sys::HtmlInputElement>()
+                                .unwrap()
+                                .value();
+                            Msg::SendPeerConfig(button_public_key.clone(), value)
+                        }),
+                        "Send"
+                    ],
+                ],
+            ],
+            a![
+                attrs! {At::Class => "btn btn-secondary",
+                At::Href => format!(
+                    "api/download_peer?public_key={}",
+                    escape_key(&peer.public_key)
+                ),
+                At::Target => "_blank", At::Download => ""},
+                "Download"
+            ]
+        ];
+    }
+
+    let public_key = peer.public_key.clone();
+    nodes![
+        div![
+            attrs! {At::Class => "form-group mt-1"},
+            label![
+                attrs! {At::For => format!("peer{}k", index)},
+                "Private Key (not stored, download the config before leaving the page)"
+            ],
+            input![
+                attrs! {
+                    At::Id => format!("peer{}k", index),
+                    At::Class => "form-control",
+                    At::Type => "password",
+                    At::Value => local_key.cloned().unwrap_or_default(),
+                    At::AutoComplete => "off"
+                },
+                input_ev(Ev::Input, move |key| Msg::LocalKeyChanged(public_key, key))
+            ],
+        ],
+        match local_key {
+            Some(private_key) => a![
+                attrs! {At::Class => "btn btn-secondary",
+                At::Href => local_config_url(wg_config, peer, private_key),
+                At::Download => "wg.conf"},
+                "Download"
+            ],
+            None => a![
+                attrs! {At::Class => "btn btn-secondary disabled"},
+                "Download"
+            ],
+        },
+    ]
+}
+
+const GIB: u64 = 1024 * 1024 * 1024;
+// peers renew their session every two minutes while they are connected
+const ONLINE_SECS: u64 = 3 * 60;
+
+fn display_status(status: &shared::PeerStatus) -> Vec<Node<Msg>> {
+    let now = (js_sys::Date::now() / 1000.0) as u64;
+    let connection = if status.latest_handshake == 0 {
+        span!["Never connected"]
+    } else if now.saturating_sub(status.latest_handshake) < ONLINE_SECS {
+        span![attrs! {At::Class => "badge badge-success"}, "Online"]
+    } else {
+        span![format!(
+            "Last handshake {}",
+            format_time(status.latest_handshake)
+        )]
+    };
+    nodes![div![
+        connection,
+        format!(
+            " · upload {}, download {}",
+            format_bytes(status.rx_bytes),
+            format_bytes(status.tx_bytes)
+        ),
+    ]]
+}
+
+fn display_tags(peer: &shared::wg_conf::Peer) -> Vec<Node<Msg>> {
+    let public_key = peer.public_key.clone();
+    nodes![div![
+        attrs! {At::Class => "input-group input-group-sm mt-1"},
+        div![
+            attrs! {At::Class => "input-group-prepend"},
+            span![attrs! {At::Class => "input-group-text"}, "Tags"],
+        ],
+        input![
+            attrs! {
+                At::Class => "form-control",
+                At::Placeholder => "none, e.g. office, laptops",
+                At::Value => peer.tags.join(", ")
+            },
+            keyboard_ev(Ev::KeyDown, move |ev| {
+                if ev.key() != "Enter" {
+                    return Msg::NoAction;
+                }
+                let value = ev
+                    .target()
+                    .unwrap()
+                    .dyn_into::<web_sys::HtmlInputElement>()
+                    .unwrap()
+                    .value();
+                Msg::SetPeerTags(public_key.clone(), value)
+            })
+        ],
+    ]]
+}
+
+// comma or space separated, the server checks and sorts them
+fn split_tags(tags: &str) -> Vec<String> {
+    tags.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_lowercase())
+        .collect()
+}
+
+// the peers that pass the tag filter, with their index in the config
+fn shown_peers(model: &Model) -> impl Iterator<Item = (usize, &shared::wg_conf::Peer)> {
+    model
+        .wireguard_config
+        .peers
+        .iter()
+        .enumerate()
+        .filter(move |(_, peer)| match &model.tag_filter {
+            Some(tag) => peer.tags.contains(tag),
+            None => true,
+        })
+}
+
+// the keys are base64, `+`, `/` and `=` have to be escaped in the query
+fn escape_key(public_key: &str) -> String {
+    public_key
+        .replace('+', "%2B")
+        .replace('/', "%2F")
+        .replace('=', "%3D")
+}
+
+fn download_peers_url(public_keys: &HashSet<String>) -> String {
+    let peers = public_keys
+        .iter()
+        .map(|key| escape_key(key))
+        .collect::<Vec<_>>()
+        .join(",");
+    format!("/api/download_peers?peers={}", peers)
+}
+
+fn tag_filter(model: &Model) -> Vec<Node<Msg>> {
+    let tags = model
+        .wireguard_config
+        .peers
+        .iter()
+        .flat_map(|p| p.tags.iter().cloned())
+        .chain(model.tag_filter.clone())
+        .collect::<BTreeSet<_>>();
+    if tags.is_empty() {
+        return nodes![];
+    }
+    let button = |label: String, tag: Option<String>| {
+        let class = if model.tag_filter == tag {
+            "btn btn-sm btn-secondary mr-1 mb-1"
+        } else {
+            "btn btn-sm btn-outline-secondary mr-1 mb-1"
+        };
+        button![
+            attrs! {At::Class => class},
+            ev(Ev::Click, move |_| Msg::FilterTag(tag)),
+            label
+        ]
+    };
+    nodes![div![
+        attrs! {At::Class => "mt-1"},
+        button("All".to_string(), None),
+        tags.into_iter()
+            .map(|tag| button(tag.clone(), Some(tag)))
+            .collect::<Vec<_>>(),
+    ]]
+}
+
+// what can be done with the selected peers at once
+fn bulk_actions(model: &Model) -> Vec<Node<Msg>> {
+    let all_shown = shown_peers(model).all(|(_, p)| model.selected.contains(&p.public_key));
+    let selection = nodes![label![
+        attrs! {At::Class => "mr-2 mb-1"},
+        input![
+            attrs! {
+                At::Type => "checkbox",
+                At::Class => "mr-1",
+                At::Checked => (all_shown && !model.selected.is_empty()).as_at_value()
+            },
+            ev(Ev::Click, move |_| Msg::SelectShown(!all_shown)),
+        ],
+        format!("{} selected", model.selected.len()),
+    ]];
+    if model.selected.is_empty() {
+        return nodes![div![attrs! {At::Class => "mt-1"}, selection]];
+    }
+    let count = model.selected.len();
+    nodes![div![
+        attrs! {At::Class => "border p-1 mt-1"},
+        selection,
+        button![
+            attrs! {At::Class => "btn btn-sm btn-outline-secondary mr-1 mb-1"},
+            ev(Ev::Click, |_| Msg::Bulk(shared::BulkAction::Disable)),
+            "Disable"
+        ],
+        button![
+            attrs! {At::Class => "btn btn-sm btn-outline-secondary mr-1 mb-1"},
+            ev(Ev::Click, |_| Msg::Bulk(shared::BulkAction::Enable)),
+            "Enable"
+        ],
+        if model.selected.len() > shared::MAX_DOWNLOAD_PEERS {
+            button![
+                attrs! {
+                    At::Class => "btn btn-sm btn-outline-secondary mr-1 mb-1",
+                    At::Disabled => true.as_at_value(),
+                    At::Title => format!("At most {} peers at a time", shared::MAX_DOWNLOAD_PEERS)
+                },
+                "Download zip"
+            ]
+        } else {
+            a![
+                attrs! {
+                    At::Class => "btn btn-sm btn-outline-secondary mr-1 mb-1",
+                    At::Href => download_peers_url(&model.selected),
+                    At::Download => "wg-configs.zip"
+                },
+                "Download zip"
+            ]
+        },
+        button![
+            attrs! {At::Class => "btn btn-sm btn-danger mr-1 mb-1"},
+            ev(Ev::Click, move |_| {
+                if web_sys::window()
+                    .unwrap()
+                    .confirm_with_message(&format!("Remove {} peers?", count))
+                    .unwrap()
+                {
+                    Msg::Bulk(shared::BulkAction::Remove)
+                } else {
+                    Msg::NoAction
+                }
+            }),
+            "Remove"
+        ],
+        div![
+            attrs! {At::Class => "input-group input-group-sm mb-1"},
+            input![
+                attrs! {
+                    At::Class => "form-control",
+                    At::Placeholder => "AllowedIPs, e.g. {subnet} or 0.0.0.0/0, empty for the server",
+                    At::Value => model.bulk_routes
+                },
+                input_ev(Ev::Input, Msg::BulkRoutesChanged)
+            ],
+            div![
+                attrs! {At::Class => "input-group-append"},
+                button![
+                    attrs! {At::Class => "btn btn-outline-secondary"},
+                    {
+                        let routes =
+                            Some(model.bulk_routes.clone()).filter(|r| !r.trim().is_empty());
+                        ev(Ev::Click, move |_| {
+                            Msg::Bulk(shared::BulkAction::SetRoutes(routes))
+                        })
+                    },
+                    "Set AllowedIPs"
+                ],
+            ],
+        ],
+        div![
+            attrs! {At::Class => "input-group input-group-sm"},
+            input![
+                attrs! {
+                    At::Class => "form-control",
+                    At::Placeholder => "Tag",
+                    At::Value => model.bulk_tag
+                },
+                input_ev(Ev::Input, Msg::BulkTagChanged)
+            ],
+            div![
+                attrs! {At::Class => "input-group-append"},
+                button![
+                    attrs! {At::Class => "btn btn-outline-secondary"},
+                    {
+                        let tag = model.bulk_tag.clone();
+                        ev(Ev::Click, move |_| {
+                            Msg::Bulk(shared::BulkAction::AddTag(tag))
+                        })
+                    },
+                    "Add tag"
+                ],
+                button![
+                    attrs! {At::Class => "btn btn-outline-secondary"},
+                    {
+                        let tag = model.bulk_tag.clone();
+                        ev(Ev::Click, move |_| {
+                            Msg::Bulk(shared::BulkAction::RemoveTag(tag))
+                        })
+                    },
+                    "Remove tag"
+                ],
+            ],
+        ],
+    ]]
+}
+
+fn display_quota(peer: &shared::wg_conf::Peer) -> Vec<Node<Msg>> {
+    let usage = match &peer.quota {
+        Some(quota) => {
+            let share = quota.used as f64 * 100.0 / quota.limit.max(1) as f64;
+            let color = if quota.used >= quota.limit {
+                "bg-danger"
+            } else if share >= quota.warn_percent as f64 {
+                "bg-warning"
+            } else {
+                "bg-success"
+            };
+            nodes![
+                div![
+                    attrs! {At::Class => "progress mt-1"},
+                    div![
+                        attrs! {At::Class => format!("progress-bar {}", color)},
+                        style! {St::Width => percent(share.min(100.0))}
+                    ],
+                ],
+                small![format!(
+                    "{} of {} used this month, starts over {}",
+                    format_bytes(quota.used),
+                    format_bytes(quota.limit),
+                    format_time(quota.resets)
+                )],
+            ]
+        }
+        None => nodes![],
+    };
+    let limit = peer
+        .quota
+        .as_ref()
+        .map(|quota| {
+            let gib = quota.limit as f64 / GIB as f64;
+            format!("{}", (gib * 100.0).round() / 100.0)
+        })
+        .unwrap_or_default();
+
+    let used_up = peer
+        .quota
+        .as_ref()
+        .map(|q| q.used >= q.limit)
+        .unwrap_or(false);
+    let public_key = peer.public_key.clone();
+    nodes![
+        if peer.disabled {
+            nodes![div![
+                attrs! {At::Class => "alert alert-danger p-1 mt-1 mb-0"},
+                if used_up {
+                    "Disabled, the quota is used up"
+                } else {
+                    "Disabled"
+                }
+            ]]
+        } else {
+            nodes![]
+        },
+        usage,
+        div![
+            attrs! {At::Class => "input-group input-group-sm mt-1 mb-1"},
+            div![
+                attrs! {At::Class => "input-group-prepend"},
+                span![
+                    attrs! {At::Class => "input-group-text"},
+                    "Monthly quota (GiB)"
+                ],
+            ],
+            input![
+                attrs! {
+                    At::Class => "form-control",
+                    At::Placeholder => "none",
+                    At::Value => limit
+                },
+                keyboard_ev(Ev::KeyDown, move |ev| {
+                    if ev.key() != "Enter" {
+                        return Msg::NoAction;
+                    }
+                    let value = ev
+                        .target()
+                        .unwrap()
+                        .dyn_into::<web_sys::HtmlInputElement>()
+                        .unwrap()
+                        .value();
+                    Msg::SetPeerQuota(public_key.clone(), value)
+                })
+            ],
+        ],
+    ]
+}
+
+fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < units.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+// One bar per bucket, what the peer sent below what it received. Bars are
+// scaled to the busiest bucket of the peer.
+fn traffic_chart(traffic: &Traffic, public_key: &str) -> Vec<Node<Msg>> {
+    let mut buckets = vec![(0, 0); traffic.buckets];
+    for point in traffic.series.get(public_key).into_iter().flatten() {
+        let index = ((point.time.saturating_sub(traffic.start)) / traffic.step) as usize;
+        if let Some(bucket) = buckets.get_mut(index) {
+            *bucket = (point.rx, point.tx);
+        }
+    }
+    let max = buckets
+        .iter()
+        .map(|(rx, tx)| rx + tx)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let (rx, tx) = traffic.total(public_key);
+
+    nodes![
+        div![
+            attrs! {At::Class => "d-flex align-items-end mt-1 border-bottom"},
+            style! {St::Height => px(48)},
+            buckets.iter().enumerate().map(|(i, (rx, tx))| {
+                let time = traffic.start + i as u64 * traffic.step;
+                div![
+                    attrs! {
+                        At::Class => "d-flex flex-column-reverse flex-fill",
+                        At::Title => format!(
+                            "{}: upload {}, download {}",
+                            format_time(time),
+                            format_bytes(*rx),
+                            format_bytes(*tx)
+                        )
+                    },
+                    style! {St::Height => "100%"},
+                    div![
+                        attrs! {At::Class => "bg-primary"},
+                        style! {St::Height => percent(*rx as f64 * 100.0 / max as f64)}
+                    ],
+                    div![
+                        attrs! {At::Class => "bg-info"},
+                        style! {St::Height => percent(*tx as f64 * 100.0 / max as f64)}
+                    ],
+                ]
+            })
+        ],
+        small![
+            span![attrs! {At::Class => "text-primary"}, "■ "],
+            format!("Upload {} ", format_bytes(rx)),
+            span![attrs! {At::Class => "text-info"}, "■ "],
+            format!("Download {}", format_bytes(tx)),
+        ],
+    ]
+}
+
+// the peers that moved the most data in the selected range
+fn top_talkers(model: &Model) -> Vec<Node<Msg>> {
+    let traffic = match &model.traffic {
+        Some(traffic) => traffic,
+        None => return nodes![],
+    };
+    let mut peers = model
+        .wireguard_config
+        .peers
+        .iter()
+        .map(|peer| {
+            let (rx, tx) = traffic.total(&peer.public_key);
+            (peer.name.as_str(), rx + tx)
+        })
+        .filter(|(_, total)| *total > 0)
+        .collect::<Vec<_>>();
+    peers.sort_by_key(|(_, total)| std::cmp::Reverse(*total));
+    let busiest = peers.first().map(|(_, total)| *total).unwrap_or(1);
+
+    nodes![div![
+        attrs! {At::Class => "card mt-1 mb-1"},
+        div![
+            attrs! {At::Class => "card-body p-2"},
+            div![
+                attrs! {At::Class => "d-flex justify-content-between align-items-center mb-1"},
+                strong!["Top Talkers"],
+                div![
+                    attrs! {At::Class => "btn-group btn-group-sm"},
+                    shared::TrafficRange::ALL.iter().map(|range| {
+                        let range = *range;
+                        button![
+                            attrs! {At::Class => if range == model.traffic_range {
+                                "btn btn-secondary active"
+                            } else {
+                                "btn btn-secondary"
+                            }},
+                            ev(Ev::Click, move |_| Msg::ShowTraffic(range)),
+                            range.label()
+                        ]
+                    })
+                ],
+            ],
+            if peers.is_empty() {
+                nodes![small!["No traffic in this time range"]]
+            } else {
+                peers
+                    .iter()
+                    .take(5)
+                    .map(|(name, total)| {
+                        div![
+                            attrs! {At::Class => "d-flex align-items-center"},
+                            div![attrs! {At::Class => "w-25 text-truncate"}, name],
+                            div![
+                                attrs! {At::Class => "progress flex-fill mx-2"},
+                                div![
+                                    attrs! {At::Class => "progress-bar"},
+                                    style! {St::Width => percent(*total as f64 * 100.0 / busiest as f64)}
+                                ],
+                            ],
+                            small![format_bytes(*total)],
+                        ]
+                    })
+                    .collect()
+            },
+        ],
+    ]]
+}
+
+fn wg_conf_page(model: &Model) -> Vec<Node<Msg>> {
+    let wg_config = &model.wireguard_config;
+    nodes![
+        top_talkers(model),
+        tag_filter(model),
+        bulk_actions(model),
+        ul![
+            attrs! {At::Class => "list-group", At::Style => "margin-top: -1px !important"},
+            display_interface(&wg_config.interface),
+            shown_peers(model).map(|(i, peer)| display_peer(i, peer, model))
+        ],
+        div![
+            attrs! {At::Class => "input-group input-group-sm mt-1"},
+            div![
+                attrs! {At::Class => "input-group-prepend"},
+                span![
+                    attrs! {At::Class => "input-group-text"},
+                    "Tags of new peers"
+                ],
+            ],
+            input![
+                attrs! {
+                    At::Class => "form-control",
+                    At::Placeholder => "none",
+                    At::Value => model.new_peer_tags
+                },
+                input_ev(Ev::Input, Msg::NewPeerTagsChanged)
+            ],
+        ],
+        button![
+            attrs! {At::Class => "btn btn-secondary mt-1"},
+            ev(Ev::Click, |_| Msg::NewPeer),
+            "Add New Peer"
+        ],
+        button![
+            attrs! {At::Class => "btn btn-secondary mt-1 ml-1"},
+            ev(Ev::Click, |_| Msg::NewPeerLocalKey),
+            "Add New Peer (key made in this browser)"
+        ],
+        div![
+            attrs! {At::Class => "input-group mt-1"},
+            input![
+                attrs! {
+                    At::Class => "form-control",
+                    At::Placeholder => "Public key of your own key pair",
+                    At::Value => model.own_public_key
+                },
+                input_ev(Ev::Input, Msg::OwnPublicKeyChanged)
+            ],
+            div![
+                attrs! {At::Class => "input-group-append"},
+                button![
+                    attrs! {At::Class => "btn btn-secondary"},
+                    ev(Ev::Click, |_| Msg::NewPeerWithKey),
+                    "Add Peer With This Key"
+                ],
+            ],
+        ],
+    ]
+}
+
+fn edit_user_page(model: &Model) -> Vec<Node<Msg>> {
+    nodes![
+        div![
+            attrs! {At::Class => "span12 mt-0", At::Style => "margin-top: -1px !important"},
+            div![
+                attrs! {At::Class => "input-group"},
+                div![
+                    attrs! {At::Class => "input-group-prepend w-25"},
+                    div![
+                        attrs! {At::Class => "input-group-text rounded-0 w-100"},
+                        "Username"
+                    ],
+                ],
+                input![
+                    input_ev(Ev::Input, Msg::UsernameChanged),
+                    attrs! {
+                        At::Value => model.username,
+                        At::Type => "text",
+                        At::Class => "form-control rounded-0",
+                    },
+                    id!("user"),
+                ],
+            ],
+            div![
+                attrs! {At::Class => "input-group"},
+                div![
+                    attrs! {At::Class => "input-group-prepend w-25"},
+                    div![
+                        attrs! {At::Class => "input-group-text rounded-0 w-100"},
+                        "Old Password"
+                    ],
+                ],
+                input![
+                    input_ev(Ev::Input, Msg::OldPasswordChanged),
+                    attrs! {
+                        At::Value => model.old_password,
+                        At::AutoFocus => AtValue::None,
+                        At::Type => "password",
+                        At::Class => "form-control rounded-0",
+                    },
+                    id!("old_password"),
+                ],
+            ],
+            div![
+                attrs! {At::Class => "input-group"},
+                div![
+                    attrs! {At::Class => "input-group-prepend w-25"},
+                    div![
+                        attrs! {At::Class => "input-group-text rounded-0 w-100"},
+                        "New Password"
+                    ],
+                ],
+                input![
+                    input_ev(Ev::Input, Msg::PasswordChanged),
+                    attrs! {
+                        At::Value => model.password,
+                        At::Type => "password",
+                        At::Class => "form-control rounded-0",
+                    },
+                    id!("password"),
+                ],
+            ],
+            div![
+                attrs! {At::Class => "input-group mt-0", At::Style => "margin-top: -1px !important"},
+                div![
+                    attrs! {At::Class => "input-group-prepend w-25"},
+                    div![
+                        attrs! {At::Class => "input-group-text rounded-0 w-100", At::Style => "border-bottom-left-radius: .25rem !important"},
+                        "Confirmation"
+                    ],
+                ],
+                input![
+                    input_ev(Ev::Input, Msg::ConfirmationChanged),
+                    attrs! {
+                        At::Value => model.password_confirmation,
+                        At::Type => "password",
+                        At::Class => "form-control rounded-0",
+                        At::Style => "border-bottom-right-radius: .25rem !important"
+                    },
+                    ev(Ev::KeyDown, |ev| {
+                        let ev = ev.dyn_into::<web_sys::KeyboardEvent>().unwrap();
+                        if ev.key() == "Enter" {
+                            Msg::UpdateUser
+                        } else {
+                            Msg::NoAction
+                        }
+                    }),
+                    id!("password_confirmation"),
+                ],
+            ],
+        ],
+        button![
+            attrs! {At::Class => "btn btn-secondary mt-1"},
+            ev(Ev::Click, |_| Msg::ShowPage(Page::WGCong)),
+            "Back"
+        ],
+        button![
+            attrs! {At::Class => "btn btn-outline-secondary mt-1 ml-1"},
+            ev(Ev::Click, |_| Msg::ShowTotp),
+            "Two-Factor Authentication"
+        ],
+        button![
+            attrs! {At::Class => "btn btn-outline-secondary mt-1 ml-1"},
+            ev(Ev::Click, |_| Msg::ShowTokens),
+            "API Tokens"
+        ],
+        button![
+            attrs! {At::Class => "btn btn-primary mt-1 float-right"},
+            ev(Ev::Click, |_| Msg::UpdateUser),
+            "Update"
+        ],
+    ]
+}
+
+// single text input with a label, submits `msg` on enter
+fn totp_input(label: &str, model: &Model, msg: fn() -> Msg) -> Vec<Node<Msg>> {
+    nodes![div![
+        attrs! {At::Class => "input-group"},
+        div![
+            attrs! {At::Class => "input-group-prepend w-25"},
+            div![
+                attrs! {At::Class => "input-group-text rounded-0 w-100"},
+                label
+            ],
+        ],
+        input![
+            input_ev(Ev::Input, Msg::TotpCodeChanged),
+            attrs! {
+                At::Value => model.totp_code,
+                At::AutoFocus => AtValue::None,
+                At::Type => "text",
+                At::Class => "form-control rounded-0",
+                At::AutoComplete => "one-time-code",
+            },
+            ev(Ev::KeyDown, move |ev| {
+                let ev = ev.dyn_into::<web_sys::KeyboardEvent>().unwrap();
+                if ev.key() == "Enter" {
+                    msg()
+                } else {
+                    Msg::NoAction
+                }
+            }),
+            id!("totp_code"),
+        ],
+    ]]
+}
+
+fn totp_login_view(model: &Model) -> Vec<Node<Msg>> {
+    nodes![
+        div![
+            attrs! {At::Class => "span12 mt-0", At::Style => "margin-top: -1px !important"},
+            totp_input("Code", model, || Msg::LoginTotpRequest),
+        ],
+        small![
+            attrs! {At::Class => "form-text text-muted"},
+            "Enter the code from your authenticator app or one of your recovery codes."
+        ],
+        button![
+            attrs! {At::Class => "btn btn-secondary mt-1"},
+            ev(Ev::Click, |_| Msg::ShowPage(Page::Login)),
+            "Back"
+        ],
+        button![
+            attrs! {At::Class => "btn btn-primary mt-1 float-right"},
+            ev(Ev::Click, |_| Msg::LoginTotpRequest),
+            "Verify"
+        ],
+    ]
+}
+
+fn totp_page(model: &Model) -> Vec<Node<Msg>> {
+    let content = if !model.recovery_codes.is_empty() {
+        nodes![
+            div![
+                attrs! {At::Class => "alert alert-warning rounded-0 mb-0"},
+                "Two-factor authentication is enabled. Store these recovery codes somewhere safe, \
+                 each of them can be used once in place of a code and they won't be shown again."
+            ],
+            ul![
+                attrs! {At::Class => "list-group"},
+                model.recovery_codes.iter().map(|code| li![
+                    attrs! {At::Class => "list-group-item rounded-0 text-monospace"},
+                    code
+                ])
+            ],
+        ]
+    } else if let Some((secret, qr_code)) = &model.totp_enrollment {
+        nodes![
+            div![
+                attrs! {At::Class => "list-group-item rounded-0 text-center"},
+                div!["Scan the code with your authenticator app, then enter the code it shows."],
+                img![attrs! {At::Src => qr_code, At::Alt => "QR code"}],
+                div![
+                    attrs! {At::Class => "text-monospace text-break"},
+                    format!("Secret: {}", secret)
+                ],
+            ],
+            totp_input("Code", model, || Msg::TotpConfirm),
+        ]
+    } else if model.totp_enabled {
+        nodes![
+            div![
+                attrs! {At::Class => "list-group-item rounded-0"},
+                "Two-factor authentication is enabled."
+            ],
+            div![
+                attrs! {At::Class => "input-group"},
+                div![
+                    attrs! {At::Class => "input-group-prepend w-25"},
+                    div![
+                        attrs! {At::Class => "input-group-text rounded-0 w-100"},
+                        "Password"
+                    ],
+                ],
+                input![
+                    input_ev(Ev::Input, Msg::PasswordChanged),
+                    attrs! {
+                        At::Value => model.password,
+                        At::Type => "password",
+                        At::Class => "form-control rounded-0",
+                    },
+                    id!("password"),
+                ],
+            ],
+        ]
+    } else {
+        nodes![div![
+            attrs! {At::Class => "list-group-item rounded-0"},
+            "Two-factor authentication is disabled."
+        ]]
+    };
+
+    let action = if !model.recovery_codes.is_empty() {
+        nodes![]
+    } else if model.totp_enrollment.is_some() {
+        nodes![button![
+            attrs! {At::Class => "btn btn-primary mt-1 float-right"},
+            ev(Ev::Click, |_| Msg::TotpConfirm),
+            "Confirm"
+        ]]
+    } else if model.totp_enabled {
+        nodes![button![
+            attrs! {At::Class => "btn btn-danger mt-1 float-right"},
+            ev(Ev::Click, |_| Msg::TotpDisable),
+            "Disable"
+        ]]
+    } else {
+        nodes![button![
+            attrs! {At::Class => "btn btn-primary mt-1 float-right"},
+            ev(Ev::Click, |_| Msg::TotpEnroll),
+            "Enable"
+        ]]
+    };
+
+    nodes![
+        div![
+            attrs! {At::Class => "span12 mt-0", At::Style => "margin-top: -1px !important"},
+            content
+        ],
+        button![
+            attrs! {At::Class => "btn btn-secondary mt-1"},
+            ev(Ev::Click, |_| Msg::ShowPage(Page::EditUser)),
+            "Back"
+        ],
+        action,
+    ]
+}
+
+fn format_time(secs: u64) -> String {
+    let date = js_sys::Date::new(&JsValue::from_f64(secs as f64 * 1000.0));
+    String::from(date.to_locale_string("default", &JsValue::UNDEFINED))
 }
 
-async fn remove_peer_request(index: usize) -> fetch::Result<shared::Response> {
-    fetch::Request::new(format!("/api/remove_peer/{}", index))
-        .method(fetch::Method::Get)
-        .fetch()
-        .await?
-        .check_status()?
-        .json()
-        .await
+fn display_session(session: &shared::SessionInfo) -> Vec<Node<Msg>> {
+    let id = session.id.clone();
+    nodes![li![
+        attrs! {At::Class => "list-group-item"},
+        div![format!("IP: {}", session.ip)],
+        div![format!("Browser: {}", session.user_agent)],
+        div![format!("Logged in: {}", format_time(session.created))],
+        div![format!("Last seen: {}", format_time(session.last_seen))],
+        if session.current {
+            nodes![span![
+                attrs! {At::Class => "badge badge-secondary"},
+                "This browser"
+            ]]
+        } else {
+            nodes![button![
+                attrs! {At::Class => "btn btn-danger float-right"},
+                ev(Ev::Click, move |_| Msg::RevokeSession(id)),
+                "Revoke"
+            ]]
+        },
+    ]]
 }
 
-async fn update_peer_name(index: usize, name: String) -> fetch::Result<shared::Response> {
-    fetch::Request::new("/api/update_peer_name")
-        .method(fetch::Method::Post)
-        .json(&shared::Request::UpdatePeerName { index, name })?
-        .fetch()
-        .await?
-        .check_status()?
-        .json()
-        .await
+fn sessions_page(sessions: &[shared::SessionInfo]) -> Vec<Node<Msg>> {
+    nodes![
+        ul![
+            attrs! {At::Class => "list-group", At::Style => "margin-top: -1px !important"},
+            sessions.iter().map(display_session)
+        ],
+        button![
+            attrs! {At::Class => "btn btn-secondary mt-1"},
+            ev(Ev::Click, |_| Msg::ShowPage(Page::WGCong)),
+            "Back"
+        ],
+    ]
 }
 
-fn display_interface(interface: &shared::wg_conf::Interface) -> Vec<Node<Msg>> {
-    nodes![li![
-        attrs! {At::Class => "list-group-item rounded-0"},
-        div![format!("Interface: {}", interface.address.to_string())],
-        div![format!("Private Key: {}", interface.private_key)],
-        div![format!("Public Key: {}", interface.public_key)],
-    ]]
+fn display_audit_entry(entry: &shared::AuditEntry) -> Node<Msg> {
+    let change = match (&entry.before, &entry.after) {
+        (Some(before), Some(after)) => format!("{} → {}", before, after),
+        (Some(before), None) => format!("{} →", before),
+        (None, Some(after)) => format!("→ {}", after),
+        (None, None) => String::new(),
+    };
+    tr![
+        td![format_time(entry.time)],
+        td![entry.actor.clone()],
+        td![entry.action.clone()],
+        td![attrs! {At::Class => "text-break"}, entry.target.clone()],
+        td![change],
+        td![entry.ip.clone()],
+    ]
 }
 
-fn display_peer(index: usize, peer: &shared::wg_conf::Peer) -> Vec<Node<Msg>> {
-    // making lots of copies for all the closures
-    let name = peer.name.clone();
-    let div_id1 = format!("peer{}", index);
-    let div_id2 = div_id1.clone();
-    let div_id3 = div_id1.clone();
-    let input_id1 = format!("peer{}i", index);
-    let input_id2 = input_id1.clone();
-    let input_id3 = input_id1.clone();
-    nodes![li![
-        attrs! {At::Class => "list-group-item"},
-        div![
-            attrs! {At::Id => div_id1},
-            ev(Ev::Click, move |_ev| {
-                hide_element(&div_id1);
-                show_element(&input_id1);
-                find_element_by_id(&input_id1)
-                    .dyn_into::<web_sys::HtmlInputElement>()
-                    .unwrap()
-                    .set_value(&name); // prefill the text input with the old name
-
-                focus_element(&input_id1);
-                Msg::NoAction
-            }),
-            format!("Name: {}", peer.name)
-        ],
+fn audit_page(model: &Model) -> Vec<Node<Msg>> {
+    let filter = |placeholder: &str, value: &str, msg: fn(String) -> Msg| {
         input![
-            attrs! {At::Id => input_id3, At::Style => "display: none"},
-            ev(Ev::Blur, move |_ev: web_sys::Event| {
-                hide_element(&input_id3);
-                show_element(&div_id3);
-                Msg::NoAction
-            }),
-            ev(Ev::KeyDown, move |ev: web_sys::Event| {
-                let ev = ev.dyn_into::<web_sys::KeyboardEvent>().unwrap();
-                let mut action = Msg::NoAction;
-
+            attrs! {
+                At::Class => "form-control",
+                At::Placeholder => placeholder,
+                At::Value => value
+            },
+            input_ev(Ev::Input, msg),
+            keyboard_ev(Ev::KeyDown, |ev| {
                 if ev.key() == "Enter" {
-                    let value = ev
-                        .target()
-                        .unwrap()
-                        .dyn_into::<web_sys::HtmlInputElement>()
-                        .unwrap()
-                        .value();
-
-                    action = Msg::UpdatePeerName(index, value);
+                    Msg::ShowAudit
+                } else {
+                    Msg::NoAction
                 }
+            })
+        ]
+    };
+    nodes![
+        div![
+            attrs! {At::Class => "input-group", At::Style => "margin-top: -1px !important"},
+            filter("User", &model.audit_actor, Msg::AuditActorChanged),
+            filter(
+                "Action, e.g. peer or peer.add",
+                &model.audit_action,
+                Msg::AuditActionChanged
+            ),
+            filter("Target", &model.audit_target, Msg::AuditTargetChanged),
+            div![
+                attrs! {At::Class => "input-group-append"},
+                button![
+                    attrs! {At::Class => "btn btn-secondary"},
+                    ev(Ev::Click, |_| Msg::ShowAudit),
+                    "Filter"
+                ],
+                a![
+                    attrs! {At::Class => "btn btn-outline-secondary",
+                    At::Href => format!("api/audit/export?{}", audit_query(model)),
+                    At::Download => "audit.jsonl"},
+                    "Export"
+                ],
+            ],
+        ],
+        table![
+            attrs! {At::Class => "table table-sm table-bordered bg-white mb-0"},
+            thead![tr![
+                th!["Time"],
+                th!["User"],
+                th!["Action"],
+                th!["Target"],
+                th!["Change"],
+                th!["IP"],
+            ]],
+            tbody![model.audit_entries.iter().map(display_audit_entry)],
+        ],
+        button![
+            attrs! {At::Class => "btn btn-secondary mt-1"},
+            ev(Ev::Click, |_| Msg::ShowPage(Page::WGCong)),
+            "Back"
+        ],
+    ]
+}
 
-                if ev.key() == "Enter" || ev.key() == "Escape" {
-                    hide_element(&input_id2);
-                    show_element(&div_id2);
-                }
+fn display_delivery(delivery: &shared::WebhookDelivery) -> Node<Msg> {
+    let state = match (delivery.delivered, delivery.next_attempt) {
+        (Some(delivered), _) => format!("delivered {}", format_time(delivered)),
+        (None, Some(next_attempt)) if delivery.attempts == 0 => {
+            format!("queued for {}", format_time(next_attempt))
+        }
+        (None, Some(next_attempt)) => format!("retry at {}", format_time(next_attempt)),
+        (None, None) => "given up".to_string(),
+    };
+    let answer = match (&delivery.last_status, &delivery.last_error) {
+        (_, Some(error)) => error.clone(),
+        (Some(status), None) => status.to_string(),
+        (None, None) => String::new(),
+    };
+    tr![
+        attrs! {At::Class => if delivery.delivered.is_none() && delivery.next_attempt.is_none() {
+            "table-danger"
+        } else {
+            ""
+        }},
+        td![format_time(delivery.created)],
+        td![delivery.event.clone()],
+        td![attrs! {At::Class => "text-break"}, delivery.url.clone()],
+        td![delivery.attempts.to_string()],
+        td![state],
+        td![attrs! {At::Class => "text-break"}, answer],
+    ]
+}
 
-                action
-            })
+fn webhooks_page(model: &Model) -> Vec<Node<Msg>> {
+    nodes![
+        div![
+            attrs! {At::Class => "d-flex justify-content-between align-items-center mt-1 mb-1"},
+            strong!["Webhook Deliveries"],
+            button![
+                attrs! {At::Class => "btn btn-sm btn-secondary"},
+                ev(Ev::Click, |_| Msg::ShowWebhooks),
+                "Refresh"
+            ],
         ],
-        div![format!("Peer: {}", peer.allowed_ips.to_string())],
-        div![format!("Public Key: {}", peer.public_key)],
-        a![
-            attrs! {At::Class => "btn btn-secondary",
-            At::Href => format!("api/download_peer/{}", index),
-            At::Target => "_blank", At::Download => ""},
-            "Download"
+        table![
+            attrs! {At::Class => "table table-sm table-bordered bg-white mb-0"},
+            thead![tr![
+                th!["Time"],
+                th!["Event"],
+                th!["Url"],
+                th!["Attempts"],
+                th!["State"],
+                th!["Last answer"],
+            ]],
+            tbody![model.webhook_deliveries.iter().map(display_delivery)],
+        ],
+        button![
+            attrs! {At::Class => "btn btn-secondary mt-1"},
+            ev(Ev::Click, |_| Msg::ShowPage(Page::WGCong)),
+            "Back"
         ],
+    ]
+}
+
+fn display_token(token: &shared::ApiTokenInfo) -> Vec<Node<Msg>> {
+    let id = token.id.clone();
+    let scopes = token
+        .scopes
+        .iter()
+        .map(|s| s.label())
+        .collect::<Vec<_>>()
+        .join(", ");
+    nodes![li![
+        attrs! {At::Class => "list-group-item"},
+        div![strong![&token.name]],
+        div![format!("Scopes: {}", scopes)],
+        div![format!("Created: {}", format_time(token.created))],
+        div![format!(
+            "Expires: {}",
+            token
+                .expires
+                .map(format_time)
+                .unwrap_or_else(|| "never".to_string())
+        )],
+        div![format!(
+            "Last used: {}",
+            token
+                .last_used
+                .map(format_time)
+                .unwrap_or_else(|| "never".to_string())
+        )],
         button![
             attrs! {At::Class => "btn btn-danger float-right"},
             ev(Ev::Click, move |_| {
@@ -327,57 +2730,50 @@ fn display_peer(index: usize, peer: &shared::wg_conf::Peer) -> Vec<Node<Msg>> {
                     .confirm_with_message("Sure?")
                     .unwrap()
                 {
-                    Msg::RemovePeer(index)
+                    Msg::RevokeToken(id)
                 } else {
                     Msg::NoAction
                 }
             }),
-            "Remove"
+            "Revoke"
         ],
     ]]
 }
 
-fn wg_conf_page(wg_config: &shared::wg_conf::WireGuardConf) -> Vec<Node<Msg>> {
+fn tokens_page(model: &Model) -> Vec<Node<Msg>> {
     nodes![
+        if let Some(token) = &model.new_token {
+            nodes![div![
+                attrs! {At::Class => "alert alert-warning rounded-0 mb-0"},
+                div!["Copy the new token now, it won't be shown again:"],
+                div![attrs! {At::Class => "text-monospace text-break"}, token],
+            ]]
+        } else {
+            nodes![]
+        },
         ul![
             attrs! {At::Class => "list-group", At::Style => "margin-top: -1px !important"},
-            display_interface(&wg_config.interface),
-            wg_config
-                .peers
-                .clone()
-                .into_iter()
-                .enumerate()
-                .map(|(i, peer)| { display_peer(i, &peer) })
-        ],
-        button![
-            attrs! {At::Class => "btn btn-secondary mt-1"},
-            ev(Ev::Click, |_| Msg::NewPeer),
-            "Add New Peer"
+            model.tokens.iter().map(display_token)
         ],
-    ]
-}
-
-fn edit_user_page(model: &Model) -> Vec<Node<Msg>> {
-    nodes![
         div![
-            attrs! {At::Class => "span12 mt-0", At::Style => "margin-top: -1px !important"},
+            attrs! {At::Class => "span12 mt-1"},
             div![
                 attrs! {At::Class => "input-group"},
                 div![
                     attrs! {At::Class => "input-group-prepend w-25"},
                     div![
                         attrs! {At::Class => "input-group-text rounded-0 w-100"},
-                        "Username"
+                        "Name"
                     ],
                 ],
                 input![
-                    input_ev(Ev::Input, Msg::UsernameChanged),
+                    input_ev(Ev::Input, Msg::TokenNameChanged),
                     attrs! {
-                        At::Value => model.username,
+                        At::Value => model.token_name,
                         At::Type => "text",
                         At::Class => "form-control rounded-0",
                     },
-                    id!("user"),
+                    id!("token_name"),
                 ],
             ],
             div![
@@ -386,67 +2782,185 @@ fn edit_user_page(model: &Model) -> Vec<Node<Msg>> {
                     attrs! {At::Class => "input-group-prepend w-25"},
                     div![
                         attrs! {At::Class => "input-group-text rounded-0 w-100"},
-                        "Old Password"
+                        "Expires in days"
                     ],
                 ],
                 input![
-                    input_ev(Ev::Input, Msg::OldPasswordChanged),
+                    input_ev(Ev::Input, Msg::TokenExpiryChanged),
                     attrs! {
-                        At::Value => model.old_password,
-                        At::AutoFocus => AtValue::None,
-                        At::Type => "password",
+                        At::Value => model.token_expiry,
+                        At::Type => "number",
+                        At::Min => "1",
+                        At::Placeholder => "never",
                         At::Class => "form-control rounded-0",
                     },
-                    id!("old_password"),
+                    id!("token_expiry"),
                 ],
             ],
             div![
-                attrs! {At::Class => "input-group"},
-                div![
-                    attrs! {At::Class => "input-group-prepend w-25"},
+                attrs! {At::Class => "list-group-item rounded-0"},
+                shared::TokenScope::ALL.iter().map(|scope| {
+                    let scope = *scope;
+                    let id = format!("scope_{:?}", scope);
                     div![
-                        attrs! {At::Class => "input-group-text rounded-0 w-100"},
-                        "New Password"
-                    ],
-                ],
-                input![
-                    input_ev(Ev::Input, Msg::PasswordChanged),
-                    attrs! {
-                        At::Value => model.password,
-                        At::Type => "password",
-                        At::Class => "form-control rounded-0",
-                    },
-                    id!("password"),
-                ],
+                        attrs! {At::Class => "form-check form-check-inline"},
+                        input![
+                            attrs! {
+                                At::Type => "checkbox",
+                                At::Class => "form-check-input",
+                                At::Id => id,
+                                At::Checked => model.token_scopes.contains(&scope).as_at_value(),
+                            },
+                            ev(Ev::Change, move |_| Msg::ToggleTokenScope(scope)),
+                        ],
+                        label![
+                            attrs! {At::Class => "form-check-label", At::For => id},
+                            scope.label()
+                        ],
+                    ]
+                })
             ],
+        ],
+        button![
+            attrs! {At::Class => "btn btn-secondary mt-1"},
+            ev(Ev::Click, |_| Msg::ShowPage(Page::EditUser)),
+            "Back"
+        ],
+        button![
+            attrs! {At::Class => "btn btn-primary mt-1 float-right"},
+            ev(Ev::Click, |_| Msg::CreateToken),
+            "Create Token"
+        ],
+    ]
+}
+
+fn display_invite(invite: &shared::InviteInfo) -> Vec<Node<Msg>> {
+    let id = invite.id.clone();
+    nodes![li![
+        attrs! {At::Class => "list-group-item"},
+        div![strong![format!("Invite {}", invite.id)]],
+        div![format!(
+            "Interface: {}, AllowedIPs: {}",
+            invite.interface,
+            invite.routes.as_deref().unwrap_or("the server")
+        )],
+        div![format!(
+            "Quota: {}",
+            invite
+                .quota
+                .map(|q| format!("{} per month", format_bytes(q)))
+                .unwrap_or_else(|| "none".to_string())
+        )],
+        div![format!(
+            "Devices are removed: {}",
+            invite
+                .peer_hours
+                .map(|h| format!("after {} hours", h))
+                .unwrap_or_else(|| "never".to_string())
+        )],
+        div![format!(
+            "Uses left: {}, expires: {}",
+            invite.uses_left,
+            format_time(invite.expires)
+        )],
+        div![format!(
+            "Created by {} on {}",
+            invite.created_by,
+            format_time(invite.created)
+        )],
+        button![
+            attrs! {At::Class => "btn btn-danger float-right"},
+            ev(Ev::Click, move |_| {
+                if web_sys::window()
+                    .unwrap()
+                    .confirm_with_message("Sure?")
+                    .unwrap()
+                {
+                    Msg::RevokeInvite(id)
+                } else {
+                    Msg::NoAction
+                }
+            }),
+            "Revoke"
+        ],
+    ]]
+}
+
+fn invites_page(model: &Model) -> Vec<Node<Msg>> {
+    let field = |label: &str, value: &str, placeholder: &str, msg: fn(String) -> Msg| {
+        div![
+            attrs! {At::Class => "input-group"},
             div![
-                attrs! {At::Class => "input-group mt-0", At::Style => "margin-top: -1px !important"},
+                attrs! {At::Class => "input-group-prepend w-25"},
                 div![
-                    attrs! {At::Class => "input-group-prepend w-25"},
-                    div![
-                        attrs! {At::Class => "input-group-text rounded-0 w-100", At::Style => "border-bottom-left-radius: .25rem !important"},
-                        "Confirmation"
-                    ],
-                ],
-                input![
-                    input_ev(Ev::Input, Msg::ConfirmationChanged),
-                    attrs! {
-                        At::Value => model.password_confirmation,
-                        At::Type => "password",
-                        At::Class => "form-control rounded-0",
-                        At::Style => "border-bottom-right-radius: .25rem !important"
-                    },
-                    ev(Ev::KeyDown, |ev| {
-                        let ev = ev.dyn_into::<web_sys::KeyboardEvent>().unwrap();
-                        if ev.key() == "Enter" {
-                            Msg::UpdateUser
-                        } else {
-                            Msg::NoAction
-                        }
-                    }),
-                    id!("password_confirmation"),
+                    attrs! {At::Class => "input-group-text rounded-0 w-100"},
+                    label
                 ],
             ],
+            input![
+                input_ev(Ev::Input, msg),
+                attrs! {
+                    At::Value => value,
+                    At::Type => "text",
+                    At::Placeholder => placeholder,
+                    At::Class => "form-control rounded-0",
+                },
+            ],
+        ]
+    };
+    let origin = web_sys::window()
+        .and_then(|w| w.location().origin().ok())
+        .unwrap_or_default();
+    nodes![
+        if let Some(code) = &model.new_invite {
+            nodes![div![
+                attrs! {At::Class => "alert alert-warning rounded-0 mb-0"},
+                div!["Copy the code now, it won't be shown again. It is redeemed on"],
+                div![
+                    attrs! {At::Class => "text-monospace text-break"},
+                    format!("{}/enroll?code={}", origin, code)
+                ],
+            ]]
+        } else {
+            nodes![]
+        },
+        ul![
+            attrs! {At::Class => "list-group", At::Style => "margin-top: -1px !important"},
+            model.invites.iter().map(display_invite)
+        ],
+        div![
+            attrs! {At::Class => "span12 mt-1"},
+            field(
+                "Interface",
+                &model.invite_interface,
+                "the server's",
+                Msg::InviteInterfaceChanged
+            ),
+            field(
+                "AllowedIPs",
+                &model.invite_routes,
+                "the server, or e.g. {subnet} or 0.0.0.0/0",
+                Msg::InviteRoutesChanged
+            ),
+            field(
+                "Quota (GiB)",
+                &model.invite_quota,
+                "none",
+                Msg::InviteQuotaChanged
+            ),
+            field("Devices", &model.invite_uses, "1", Msg::InviteUsesChanged),
+            field(
+                "Valid for hours",
+                &model.invite_hours,
+                "24",
+                Msg::InviteHoursChanged
+            ),
+            field(
+                "Devices live for hours",
+                &model.invite_peer_hours,
+                "until removed",
+                Msg::InvitePeerHoursChanged
+            ),
         ],
         button![
             attrs! {At::Class => "btn btn-secondary mt-1"},
@@ -455,8 +2969,8 @@ fn edit_user_page(model: &Model) -> Vec<Node<Msg>> {
         ],
         button![
             attrs! {At::Class => "btn btn-primary mt-1 float-right"},
-            ev(Ev::Click, |_| Msg::UpdateUser),
-            "Update"
+            ev(Ev::Click, |_| Msg::CreateInvite),
+            "Create Invite"
         ],
     ]
 }
@@ -469,8 +2983,17 @@ pub fn view(model: &Model) -> Vec<Node<Msg>> {
         } else {
             match model.current_page {
                 Page::Login => login_view(model),
-                Page::WGCong => wg_conf_page(&model.wireguard_config),
+                Page::WGCong => wg_conf_page(model),
                 Page::EditUser => edit_user_page(&model),
+                Page::Sessions => sessions_page(&model.sessions),
+                Page::Audit => audit_page(model),
+                Page::Webhooks => webhooks_page(model),
+                Page::Share => share_page(model),
+                Page::Enroll => enroll_page(model),
+                Page::Invites => invites_page(model),
+                Page::Tokens => tokens_page(model),
+                Page::Totp => totp_page(model),
+                Page::TotpLogin => totp_login_view(model),
             }
         }
     ]
@@ -517,6 +3040,11 @@ fn display_alert(model: &Model) -> Vec<Node<Msg>> {
             attrs! {At::Class => "alert alert-danger float-left p-2 m-0",
             At::Style => "animation: fadeOut 2s forwards;animation-delay: 3s;"}
         ]],
+        Some(shared::Response::TooManyAttempts { retry_after }) => nodes![div![
+            format!("Too many attempts, try again in {} seconds", retry_after),
+            attrs! {At::Class => "alert alert-warning float-left p-2 m-0",
+            At::Style => "animation: fadeOut 2s forwards;animation-delay: 3s;"}
+        ]],
         _ => nodes![],
     }
 }
@@ -537,6 +3065,26 @@ fn nav_bar(model: &Model) -> Vec<Node<Msg>> {
                         At::Style => "text-transform: capitalize"},
                         ev(Ev::Click, |_| Msg::ShowPage(Page::EditUser))
                     ],
+                    button![
+                        attrs! {At::Class => "btn btn-outline-secondary mr-2"},
+                        ev(Ev::Click, |_| Msg::ShowSessions),
+                        "Sessions"
+                    ],
+                    button![
+                        attrs! {At::Class => "btn btn-outline-secondary mr-2"},
+                        ev(Ev::Click, |_| Msg::ShowAudit),
+                        "Audit Log"
+                    ],
+                    button![
+                        attrs! {At::Class => "btn btn-outline-secondary mr-2"},
+                        ev(Ev::Click, |_| Msg::ShowWebhooks),
+                        "Webhooks"
+                    ],
+                    button![
+                        attrs! {At::Class => "btn btn-outline-secondary mr-2"},
+                        ev(Ev::Click, |_| Msg::ShowInvites),
+                        "Invites"
+                    ],
                     button![
                         attrs! {At::Class => "btn btn-secondary"},
                         ev(Ev::Click, |_| Msg::LogoutRequest),
@@ -640,5 +3188,14 @@ fn login_view(model: &Model) -> Vec<Node<Msg>> {
             ev(Ev::Click, |_| Msg::LoginRequest),
             "Login"
         ],
+        if model.sso {
+            nodes![a![
+                attrs! {At::Class => "btn btn-outline-secondary mt-1 float-right",
+                At::Href => "/api/oidc/login"},
+                "Login with SSO"
+            ]]
+        } else {
+            nodes![]
+        },
     ]
 }
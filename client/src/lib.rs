@@ -6,11 +6,30 @@ mod client_list;
 //     Init
 // ------ ------
 
-fn init(_: Url, orders: &mut impl Orders<Msg>) -> Model {
+fn init(url: Url, orders: &mut impl Orders<Msg>) -> Model {
+    // share links and enrollments are opened without a session
+    match url.path() {
+        [first, token] if first == "share" => {
+            return Model {
+                client_list: client_list::Model::share(token.clone()),
+            };
+        }
+        [first] if first == "enroll" => {
+            let code = url.search().get("code").and_then(|c| c.first()).cloned();
+            return Model {
+                client_list: client_list::Model::enroll(code.unwrap_or_default()),
+            };
+        }
+        _ => {}
+    }
+
     let model = Model::default();
     orders
         .proxy(Msg::ClientList)
-        .perform_cmd(async { client_list::Msg::Fetched(client_list::session_request().await) });
+        .perform_cmd(async { client_list::Msg::Fetched(client_list::session_request().await) })
+        .perform_cmd(async {
+            client_list::Msg::Fetched(client_list::login_options_request().await)
+        });
     model
 }
 
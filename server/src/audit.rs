@@ -0,0 +1,273 @@
+use crate::db::Db;
+use crate::session::now;
+use crate::tokens::authorized_user;
+use crate::AppData;
+use actix_identity::Identity;
+use actix_web::{get, http::header, web, HttpRequest, HttpResponse, Responder};
+use rusqlite::{params, Connection, Row};
+use serde::Deserialize;
+use shared::{AuditEntry, TokenScope};
+use tracing::error;
+
+// entries shown in the ui, the export has all of them
+const LIST_LIMIT: i64 = 500;
+
+// Append only log in the `audit` table, triggers refuse to change or delete
+// entries.
+pub struct AuditLog {
+    db: Db,
+}
+
+// Filters of the log, empty values match everything.
+#[derive(Deserialize, Default, Debug)]
+pub struct AuditQuery {
+    actor: Option<String>,
+    // matches the action and everything below it, `peer` matches `peer.add`
+    action: Option<String>,
+    // part of the target
+    target: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+impl AuditLog {
+    pub fn new(db: Db) -> Self {
+        Self { db }
+    }
+
+    pub fn record(&self, actor: &str, action: &str, target: &str, ip: &str) {
+        self.change(actor, action, target, None, None, ip);
+    }
+
+    // an action that changed a value from `before` to `after`
+    pub fn change(
+        &self,
+        actor: &str,
+        action: &str,
+        target: &str,
+        before: Option<&str>,
+        after: Option<&str>,
+        ip: &str,
+    ) {
+        let entry = AuditEntry {
+            time: now(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            target: target.to_string(),
+            before: before.map(str::to_string),
+            after: after.map(str::to_string),
+            ip: ip.to_string(),
+        };
+        if let Err(e) = insert(&self.db.conn(), &entry) {
+            error!("Could not write audit log: {} {:?}", e, entry);
+        }
+    }
+
+    // newest first, at most `limit` entries, all of them if it is negative
+    pub fn entries(&self, query: &AuditQuery, limit: i64) -> rusqlite::Result<Vec<AuditEntry>> {
+        let filter = |value: &Option<String>| value.clone().filter(|v| !v.trim().is_empty());
+        let action = filter(&query.action);
+        let below_action = action.as_deref().map(|a| format!("{}.%", escape_like(a)));
+        let conn = self.db.conn();
+        let mut statement = conn.prepare(
+            "SELECT time, actor, action, target, old_value, new_value, ip FROM audit
+             WHERE (?1 IS NULL OR actor = ?1)
+               AND (?2 IS NULL OR action = ?2 OR action LIKE ?7 ESCAPE '\\')
+               AND (?3 IS NULL OR instr(target, ?3) > 0)
+               AND (?4 IS NULL OR time >= ?4)
+               AND (?5 IS NULL OR time < ?5)
+             ORDER BY id DESC LIMIT ?6",
+        )?;
+        let entries = statement
+            .query_map(
+                params![
+                    filter(&query.actor),
+                    action,
+                    filter(&query.target),
+                    query.since,
+                    query.until,
+                    limit,
+                    below_action
+                ],
+                entry_from_row,
+            )?
+            .collect::<Result<_, _>>();
+        entries
+    }
+}
+
+pub fn insert(conn: &Connection, entry: &AuditEntry) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO audit (time, actor, action, target, old_value, new_value, ip)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            entry.time,
+            entry.actor,
+            entry.action,
+            entry.target,
+            entry.before,
+            entry.after,
+            entry.ip
+        ],
+    )?;
+    Ok(())
+}
+
+// so `%` and `_` in a filter only match themselves
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+fn entry_from_row(row: &Row) -> rusqlite::Result<AuditEntry> {
+    Ok(AuditEntry {
+        time: row.get(0)?,
+        actor: row.get(1)?,
+        action: row.get(2)?,
+        target: row.get(3)?,
+        before: row.get(4)?,
+        after: row.get(5)?,
+        ip: row.get(6)?,
+    })
+}
+
+// ---- Apis ----
+
+#[get("/audit")]
+async fn list_audit(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    query: web::Query<AuditQuery>,
+) -> impl Responder {
+    if authorized_user(&req, &id, &data, TokenScope::ReadAudit).is_none() {
+        return HttpResponse::Forbidden().body("");
+    }
+    match data.audit.entries(&query, LIST_LIMIT) {
+        Ok(entries) => HttpResponse::Ok().json(shared::Response::AuditLog { entries }),
+        Err(e) => {
+            error!("Could not read audit log: {}", e);
+            HttpResponse::Ok().json(shared::Response::Failure)
+        }
+    }
+}
+
+// one json object per line, oldest first
+#[get("/audit/export")]
+async fn export_audit(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    query: web::Query<AuditQuery>,
+) -> HttpResponse {
+    if authorized_user(&req, &id, &data, TokenScope::ReadAudit).is_none() {
+        return HttpResponse::Forbidden().body("");
+    }
+    let entries = match data.audit.entries(&query, -1) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Could not read audit log: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let mut lines = String::new();
+    for entry in entries.iter().rev() {
+        lines.push_str(&serde_json::to_string(entry).unwrap());
+        lines.push('\n');
+    }
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            "attachment; filename=\"audit.jsonl\"",
+        ))
+        .body(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn actions(audit: &AuditLog, action: &str) -> Vec<String> {
+        let query = AuditQuery {
+            action: Some(action.to_string()),
+            ..Default::default()
+        };
+        let mut actions = audit
+            .entries(&query, -1)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.action)
+            .collect::<Vec<_>>();
+        actions.sort();
+        actions
+    }
+
+    #[test]
+    fn filters_by_action_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit = AuditLog::new(Db::open(dir.path(), None).unwrap());
+        for action in [
+            "peer",
+            "peer.add",
+            "peer.tag.add",
+            "peers.add",
+            "pe%.add",
+            "pe_r",
+        ] {
+            audit.record("admin", action, "target", "");
+        }
+
+        assert_eq!(
+            actions(&audit, "peer"),
+            ["peer", "peer.add", "peer.tag.add"]
+        );
+        assert_eq!(actions(&audit, "peer.tag"), ["peer.tag.add"]);
+        // wildcards only match themselves
+        assert_eq!(actions(&audit, "pe%"), ["pe%.add"]);
+        assert_eq!(actions(&audit, "pe_r"), ["pe_r"]);
+        assert!(actions(&audit, "%").is_empty());
+    }
+
+    #[test]
+    fn filters_by_actor_target_and_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit = AuditLog::new(Db::open(dir.path(), None).unwrap());
+        audit.change(
+            "alice",
+            "peer.rename",
+            "key-1",
+            Some("a"),
+            Some("b"),
+            "10.0.0.1",
+        );
+        audit.record("bob", "login", "bob", "10.0.0.2");
+
+        let query = AuditQuery {
+            actor: Some("alice".to_string()),
+            ..Default::default()
+        };
+        let entries = audit.entries(&query, -1).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].before.as_deref(), Some("a"));
+        assert_eq!(entries[0].after.as_deref(), Some("b"));
+
+        let query = AuditQuery {
+            target: Some("ey-".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(audit.entries(&query, -1).unwrap()[0].actor, "alice");
+
+        let query = AuditQuery {
+            until: Some(now() - 60),
+            ..Default::default()
+        };
+        assert!(audit.entries(&query, -1).unwrap().is_empty());
+        // newest first
+        let all = audit.entries(&AuditQuery::default(), 1).unwrap();
+        assert_eq!(all[0].actor, "bob");
+    }
+}
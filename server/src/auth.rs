@@ -0,0 +1,72 @@
+use crate::config::Config;
+use crate::ldap::Ldap;
+use crate::{get_user_by_name, AppData, User};
+use actix_web::web;
+use bcrypt::verify;
+
+pub enum Outcome {
+    // the user is not handled by this provider, the next one is asked
+    Unknown,
+    Failure,
+    Success(User),
+}
+
+// A way to check a username and password. Providers are asked in order until
+// one of them knows the user, a failure is final. Checks may block, they are
+// run outside of the async executor.
+pub trait AuthProvider: Send + Sync {
+    fn authenticate(&self, data: &web::Data<AppData>, username: &str, password: &str) -> Outcome;
+}
+
+// Users with a password hash in the local store.
+pub struct Local;
+
+impl AuthProvider for Local {
+    fn authenticate(&self, data: &web::Data<AppData>, username: &str, password: &str) -> Outcome {
+        let user = match get_user_by_name(username.to_string(), data) {
+            Some(user) if user.ldap_dn.is_none() => user,
+            _ => return Outcome::Unknown,
+        };
+        // single sign-on users have no password
+        if user.oidc_subject.is_some() {
+            return Outcome::Failure;
+        }
+        if verify(password, &user.hashed_pass).unwrap_or(false) {
+            Outcome::Success(user)
+        } else {
+            Outcome::Failure
+        }
+    }
+}
+
+pub fn providers(config: &Config) -> Vec<Box<dyn AuthProvider>> {
+    let mut providers: Vec<Box<dyn AuthProvider>> = vec![Box::new(Local)];
+    if let Some(ldap) = &config.ldap {
+        providers.push(Box::new(Ldap::new(ldap.clone())));
+    }
+    providers
+}
+
+// the user the password belongs to
+pub async fn authenticate(
+    data: &web::Data<AppData>,
+    username: &str,
+    password: &str,
+) -> Option<User> {
+    let data = data.clone();
+    let username = username.to_string();
+    let password = password.to_string();
+    web::block(move || {
+        for provider in data.auth.iter() {
+            match provider.authenticate(&data, &username, &password) {
+                Outcome::Unknown => continue,
+                Outcome::Failure => return None,
+                Outcome::Success(user) => return Some(user),
+            }
+        }
+        None
+    })
+    .await
+    .ok()
+    .flatten()
+}
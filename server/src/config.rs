@@ -0,0 +1,1309 @@
+use crate::{groups, invites, webhooks};
+use actix_web::cookie::SameSite;
+use ipnet::Ipv4Net;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+const ENV_PREFIX: &str = "WG_WEB_";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Env(String, String),
+    Invalid(&'static str, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "could not parse {}: {}", path.display(), e),
+            ConfigError::Env(var, msg) => write!(f, "environment variable {}: {}", var, msg),
+            ConfigError::Invalid(field, msg) => write!(f, "{}: {}", field, msg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix socket path is empty".to_string());
+            }
+            return Ok(ListenAddr::Unix(PathBuf::from(path)));
+        }
+        s.parse()
+            .map(ListenAddr::Tcp)
+            .map_err(|_| format!("'{}' is neither ip:port, [ipv6]:port nor unix:/path", s))
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// setuid `wg_wrapper` binary, the server runs unprivileged
+    Wrapper,
+    /// call `wg` directly, the server needs CAP_NET_ADMIN
+    Wg,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrapper" => Ok(Backend::Wrapper),
+            "wg" => Ok(Backend::Wg),
+            _ => Err(format!("unknown backend '{}', expected wrapper or wg", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: Vec<ListenAddr>,
+    pub data_dir: PathBuf,
+    pub static_dir: PathBuf,
+    pub wireguard: WireGuardConfig,
+    pub cookie: CookieConfig,
+    pub session: SessionConfig,
+    pub login: LoginConfig,
+    pub tls: Option<TlsConfig>,
+    pub encryption: EncryptionConfig,
+    pub log: LogConfig,
+    pub metrics: MetricsConfig,
+    pub traffic: TrafficConfig,
+    pub quota: QuotaConfig,
+    pub webhooks: Vec<WebhookConfig>,
+    pub groups: BTreeMap<String, GroupConfig>,
+    pub smtp: Option<SmtpConfig>,
+    pub oidc: Option<OidcConfig>,
+    pub ldap: Option<LdapConfig>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// one human readable line per event
+    Text,
+    /// one json object per event, for log collectors
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}', expected text or json", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WireGuardConfig {
+    pub backend: Backend,
+    pub wrapper: PathBuf,
+    pub wg: PathBuf,
+    /// taken from `wg show` when not set
+    pub interface: Option<String>,
+    /// hostname peers use to reach the server, defaults to the ip of the default route
+    pub endpoint: Option<String>,
+    pub subnet: Ipv4Net,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CookieConfig {
+    pub name: String,
+    pub same_site: String,
+    pub secure: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// a session ends this long after login, no matter the activity
+    pub lifetime_secs: u64,
+    /// a session ends after this much inactivity
+    pub idle_timeout_secs: u64,
+    /// how long cookies signed with a rotated out key stay valid
+    pub key_grace_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoginConfig {
+    /// failed attempts for one username before it is locked
+    pub max_failures: u32,
+    /// failed attempts from one address before it is locked
+    pub max_failures_per_ip: u32,
+    pub lockout_secs: u64,
+    /// wait after a failed attempt, doubled with every further one
+    pub backoff_secs: u64,
+    pub max_backoff_secs: u64,
+}
+
+/// key for the private keys of the peers, they are stored in plain text without one
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// file with the base64 encoded key, see `server --rotate-encryption-key`
+    pub key_file: Option<PathBuf>,
+    /// the key itself, meant to be passed in the environment
+    pub key: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// error, warn, info, debug or trace, or filter directives like `info,server::wg=debug`
+    pub level: String,
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrafficConfig {
+    /// how often the transfer counters of the peers are read, 0 turns it off
+    pub interval_secs: u64,
+    /// how long the samples are kept at that interval
+    pub raw_retention_hours: u64,
+    /// how long the hourly sums are kept
+    pub hourly_retention_days: u64,
+    /// how long the daily sums are kept
+    pub daily_retention_days: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    /// day of the month the usage starts over, at midnight UTC, the last day
+    /// for shorter months
+    pub reset_day: u32,
+    /// share of the quota at which a warning is logged, in percent
+    pub warn_percent: u64,
+}
+
+/// `/metrics` is only served when one of these is set
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// bearer token the scraper has to send
+    pub token: Option<String>,
+    /// separate plain http listener only serving `/metrics`, the token is
+    /// still required there if set
+    pub listen: Option<SocketAddr>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// plain http listener that redirects everything to https
+    pub redirect: Option<SocketAddr>,
+    /// host the redirects point to, `wireguard.endpoint` if not set
+    pub redirect_host: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn redirect_host<'a>(&'a self, wireguard: &'a WireGuardConfig) -> Option<&'a str> {
+        self.redirect_host
+            .as_deref()
+            .or(wireguard.endpoint.as_deref())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// key of the HMAC-SHA256 signature of the payloads
+    pub secret: String,
+    /// events sent to the url, all of them when empty
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+/// defaults of new peers with the tag, one `[groups.<tag>]` section each
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GroupConfig {
+    /// AllowedIPs of their configs, `{subnet}` and `{server}` are filled in
+    pub routes: Option<String>,
+    /// monthly quota in GiB
+    pub quota_gib: Option<u64>,
+}
+
+impl GroupConfig {
+    pub fn quota(&self) -> Option<u64> {
+        self.quota_gib.map(|gib| gib.saturating_mul(1 << 30))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    /// tls from the start, port 465
+    Tls,
+    /// plain connection upgraded with STARTTLS, port 587
+    Starttls,
+    /// no encryption, only for a relay on this machine
+    None,
+}
+
+impl FromStr for SmtpSecurity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tls" => Ok(SmtpSecurity::Tls),
+            "starttls" => Ok(SmtpSecurity::Starttls),
+            "none" => Ok(SmtpSecurity::None),
+            _ => Err(format!(
+                "unknown smtp security '{}', expected tls, starttls or none",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpConfig {
+    pub host: String,
+    /// 465 for tls, 587 for starttls and 25 for none when not set
+    pub port: Option<u16>,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// `Name <address>` or just the address
+    pub from: String,
+    /// `{name}`, `{address}` and `{interface}` are replaced by those of the peer
+    pub subject: String,
+    pub body: String,
+}
+
+impl SmtpConfig {
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(match self.security {
+            SmtpSecurity::Tls => 465,
+            SmtpSecurity::Starttls => 587,
+            SmtpSecurity::None => 25,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    /// not needed for public clients, pkce is always used
+    pub client_secret: Option<String>,
+    /// where the identity provider sends the browser back to, `https://<host>/api/oidc/callback`
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    /// claim used as the user name
+    pub username_claim: String,
+    /// claim holding the groups or roles that are mapped by `roles`
+    pub role_claim: String,
+    pub roles: RoleMapping,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LdapConfig {
+    /// `ldaps://host` or `ldap://host` with `starttls`
+    pub url: String,
+    pub starttls: bool,
+    /// account used to search for users, the search is anonymous when not set
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    pub base_dn: String,
+    /// `{username}` is replaced by the escaped login name
+    pub user_filter: String,
+    /// attribute used as the user name, so the spelling of the login doesn't matter
+    pub username_attribute: String,
+    /// attribute of the user listing the dns of their groups, mapped by `roles`
+    pub group_attribute: String,
+    pub roles: RoleMapping,
+}
+
+/// claim values (or group dns) that grant a role, everybody is a viewer if both are empty
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoleMapping {
+    pub admin: Vec<String>,
+    pub viewer: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![ListenAddr::Tcp(([127, 0, 0, 1], 8000).into())],
+            data_dir: PathBuf::from("."),
+            static_dir: PathBuf::from("./client"),
+            wireguard: WireGuardConfig::default(),
+            cookie: CookieConfig::default(),
+            session: SessionConfig::default(),
+            login: LoginConfig::default(),
+            tls: None,
+            encryption: EncryptionConfig::default(),
+            log: LogConfig::default(),
+            metrics: MetricsConfig::default(),
+            traffic: TrafficConfig::default(),
+            quota: QuotaConfig::default(),
+            webhooks: vec![],
+            groups: BTreeMap::new(),
+            smtp: None,
+            oidc: None,
+            ldap: None,
+        }
+    }
+}
+
+impl Default for WireGuardConfig {
+    fn default() -> Self {
+        Self {
+            backend: Backend::Wrapper,
+            wrapper: PathBuf::from("./wg_wrapper.bin"),
+            wg: PathBuf::from("/usr/bin/wg"),
+            interface: None,
+            endpoint: None,
+            subnet: "10.200.100.0/24".parse().unwrap(),
+        }
+    }
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        Self {
+            name: "auth-cookie".to_string(),
+            same_site: "strict".to_string(),
+            secure: false,
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            lifetime_secs: 12 * 60 * 60,
+            idle_timeout_secs: 60 * 60,
+            key_grace_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl Default for TrafficConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 5 * 60,
+            raw_retention_hours: 48,
+            hourly_retention_days: 35,
+            daily_retention_days: 400,
+        }
+    }
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        Self {
+            reset_day: 1,
+            warn_percent: 80,
+        }
+    }
+}
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: None,
+            security: SmtpSecurity::Starttls,
+            username: None,
+            password: None,
+            from: String::new(),
+            subject: "WireGuard config for {name}".to_string(),
+            body: "Hello,\n\n\
+                   attached is your WireGuard config for {interface}, your address is {address}.\n\
+                   Import wg.conf into the WireGuard app or scan wg.png with it on your phone.\n\n\
+                   Keep the config to yourself, it contains your private key.\n"
+                .to_string(),
+        }
+    }
+}
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        Self {
+            issuer: String::new(),
+            client_id: String::new(),
+            client_secret: None,
+            redirect_url: String::new(),
+            scopes: vec![
+                "openid".to_string(),
+                "profile".to_string(),
+                "email".to_string(),
+            ],
+            username_claim: "preferred_username".to_string(),
+            role_claim: "groups".to_string(),
+            roles: RoleMapping::default(),
+        }
+    }
+}
+
+impl Default for LdapConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            starttls: false,
+            bind_dn: None,
+            bind_password: None,
+            base_dn: String::new(),
+            user_filter: "(uid={username})".to_string(),
+            username_attribute: "uid".to_string(),
+            group_attribute: "memberOf".to_string(),
+            roles: RoleMapping::default(),
+        }
+    }
+}
+
+impl Default for LoginConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            max_failures_per_ip: 20,
+            lockout_secs: 15 * 60,
+            backoff_secs: 1,
+            max_backoff_secs: 60,
+        }
+    }
+}
+
+impl CookieConfig {
+    pub fn same_site(&self) -> SameSite {
+        match self.same_site.as_str() {
+            "lax" => SameSite::Lax,
+            "none" => SameSite::None,
+            _ => SameSite::Strict,
+        }
+    }
+}
+
+impl Config {
+    // cookies are always secure when we terminate tls ourselves
+    pub fn secure_cookies(&self) -> bool {
+        self.cookie.secure || self.tls.is_some()
+    }
+
+    /// Loads the config file given with `--config <path>` or `WG_WEB_CONFIG`,
+    /// falls back to `./config.toml` if present, then applies the `WG_WEB_*`
+    /// environment overrides and validates the result.
+    pub fn load() -> Result<Config, ConfigError> {
+        let explicit =
+            config_path_arg().or_else(|| std::env::var_os("WG_WEB_CONFIG").map(PathBuf::from));
+
+        let mut config = match explicit {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        toml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        if let Some(value) = env("LISTEN") {
+            self.listen = value
+                .split(',')
+                .map(|addr| parse_env("LISTEN", addr.trim()))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(value) = env("DATA_DIR") {
+            self.data_dir = PathBuf::from(value);
+        }
+        if let Some(value) = env("STATIC_DIR") {
+            self.static_dir = PathBuf::from(value);
+        }
+        if let Some(value) = env("BACKEND") {
+            self.wireguard.backend = parse_env("BACKEND", &value)?;
+        }
+        if let Some(value) = env("WRAPPER") {
+            self.wireguard.wrapper = PathBuf::from(value);
+        }
+        if let Some(value) = env("WG") {
+            self.wireguard.wg = PathBuf::from(value);
+        }
+        if let Some(value) = env("INTERFACE") {
+            self.wireguard.interface = Some(value);
+        }
+        if let Some(value) = env("ENDPOINT") {
+            self.wireguard.endpoint = Some(value);
+        }
+        if let Some(value) = env("SUBNET") {
+            self.wireguard.subnet = parse_env("SUBNET", &value)?;
+        }
+        if let Some(value) = env("COOKIE_NAME") {
+            self.cookie.name = value;
+        }
+        if let Some(value) = env("COOKIE_SAME_SITE") {
+            self.cookie.same_site = value;
+        }
+        if let Some(value) = env("COOKIE_SECURE") {
+            self.cookie.secure = parse_env("COOKIE_SECURE", &value)?;
+        }
+        if let Some(value) = env("SESSION_LIFETIME_SECS") {
+            self.session.lifetime_secs = parse_env("SESSION_LIFETIME_SECS", &value)?;
+        }
+        if let Some(value) = env("SESSION_IDLE_TIMEOUT_SECS") {
+            self.session.idle_timeout_secs = parse_env("SESSION_IDLE_TIMEOUT_SECS", &value)?;
+        }
+        if let Some(value) = env("SESSION_KEY_GRACE_SECS") {
+            self.session.key_grace_secs = parse_env("SESSION_KEY_GRACE_SECS", &value)?;
+        }
+        if let Some(value) = env("LOGIN_MAX_FAILURES") {
+            self.login.max_failures = parse_env("LOGIN_MAX_FAILURES", &value)?;
+        }
+        if let Some(value) = env("LOGIN_MAX_FAILURES_PER_IP") {
+            self.login.max_failures_per_ip = parse_env("LOGIN_MAX_FAILURES_PER_IP", &value)?;
+        }
+        if let Some(value) = env("LOGIN_LOCKOUT_SECS") {
+            self.login.lockout_secs = parse_env("LOGIN_LOCKOUT_SECS", &value)?;
+        }
+        if let Some(value) = env("LOGIN_BACKOFF_SECS") {
+            self.login.backoff_secs = parse_env("LOGIN_BACKOFF_SECS", &value)?;
+        }
+        if let Some(value) = env("LOGIN_MAX_BACKOFF_SECS") {
+            self.login.max_backoff_secs = parse_env("LOGIN_MAX_BACKOFF_SECS", &value)?;
+        }
+        if let Some(value) = env("TLS_CERT") {
+            self.tls.get_or_insert_with(Default::default).cert = PathBuf::from(value);
+        }
+        if let Some(value) = env("TLS_KEY") {
+            self.tls.get_or_insert_with(Default::default).key = PathBuf::from(value);
+        }
+        if let Some(value) = env("TLS_REDIRECT") {
+            self.tls.get_or_insert_with(Default::default).redirect =
+                Some(parse_env("TLS_REDIRECT", &value)?);
+        }
+        if let Some(value) = env("TLS_REDIRECT_HOST") {
+            self.tls.get_or_insert_with(Default::default).redirect_host = Some(value);
+        }
+        if let Some(value) = env("ENCRYPTION_KEY_FILE") {
+            self.encryption.key_file = Some(PathBuf::from(value));
+        }
+        if let Some(value) = env("ENCRYPTION_KEY") {
+            self.encryption.key = Some(value);
+        }
+        if let Some(value) = env("LOG_LEVEL") {
+            self.log.level = value;
+        }
+        if let Some(value) = env("LOG_FORMAT") {
+            self.log.format = parse_env("LOG_FORMAT", &value)?;
+        }
+        if let Some(value) = env("METRICS_TOKEN") {
+            self.metrics.token = Some(value);
+        }
+        if let Some(value) = env("METRICS_LISTEN") {
+            self.metrics.listen = Some(parse_env("METRICS_LISTEN", &value)?);
+        }
+        if let Some(value) = env("TRAFFIC_INTERVAL_SECS") {
+            self.traffic.interval_secs = parse_env("TRAFFIC_INTERVAL_SECS", &value)?;
+        }
+        if let Some(value) = env("SMTP_HOST") {
+            self.smtp.get_or_insert_with(Default::default).host = value;
+        }
+        if let Some(value) = env("SMTP_PORT") {
+            self.smtp.get_or_insert_with(Default::default).port =
+                Some(parse_env("SMTP_PORT", &value)?);
+        }
+        if let Some(value) = env("SMTP_SECURITY") {
+            self.smtp.get_or_insert_with(Default::default).security =
+                parse_env("SMTP_SECURITY", &value)?;
+        }
+        if let Some(value) = env("SMTP_USERNAME") {
+            self.smtp.get_or_insert_with(Default::default).username = Some(value);
+        }
+        if let Some(value) = env("SMTP_PASSWORD") {
+            self.smtp.get_or_insert_with(Default::default).password = Some(value);
+        }
+        if let Some(value) = env("SMTP_FROM") {
+            self.smtp.get_or_insert_with(Default::default).from = value;
+        }
+        if let Some(value) = env("OIDC_ISSUER") {
+            self.oidc.get_or_insert_with(Default::default).issuer = value;
+        }
+        if let Some(value) = env("OIDC_CLIENT_ID") {
+            self.oidc.get_or_insert_with(Default::default).client_id = value;
+        }
+        if let Some(value) = env("OIDC_CLIENT_SECRET") {
+            self.oidc.get_or_insert_with(Default::default).client_secret = Some(value);
+        }
+        if let Some(value) = env("OIDC_REDIRECT_URL") {
+            self.oidc.get_or_insert_with(Default::default).redirect_url = value;
+        }
+        if let Some(value) = env("LDAP_URL") {
+            self.ldap.get_or_insert_with(Default::default).url = value;
+        }
+        if let Some(value) = env("LDAP_BIND_DN") {
+            self.ldap.get_or_insert_with(Default::default).bind_dn = Some(value);
+        }
+        if let Some(value) = env("LDAP_BIND_PASSWORD") {
+            self.ldap.get_or_insert_with(Default::default).bind_password = Some(value);
+        }
+        if let Some(value) = env("LDAP_BASE_DN") {
+            self.ldap.get_or_insert_with(Default::default).base_dn = value;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.listen.is_empty() {
+            return Err(invalid("listen", "at least one address is required"));
+        }
+
+        if !self.data_dir.is_dir() {
+            return Err(invalid(
+                "data_dir",
+                format!("{} is not a directory", self.data_dir.display()),
+            ));
+        }
+
+        let index = self.static_dir.join("index.html");
+        if !index.is_file() {
+            return Err(invalid(
+                "static_dir",
+                format!("{} does not exist", index.display()),
+            ));
+        }
+
+        let binary = match self.wireguard.backend {
+            Backend::Wrapper => ("wireguard.wrapper", &self.wireguard.wrapper),
+            Backend::Wg => ("wireguard.wg", &self.wireguard.wg),
+        };
+        if !binary.1.is_file() {
+            return Err(invalid(
+                binary.0,
+                format!("{} does not exist", binary.1.display()),
+            ));
+        }
+
+        if let Some(interface) = &self.wireguard.interface {
+            if interface.is_empty() || interface.len() > 15 {
+                return Err(invalid(
+                    "wireguard.interface",
+                    format!("'{}' is not a valid interface name", interface),
+                ));
+            }
+        }
+
+        if let Some(endpoint) = &self.wireguard.endpoint {
+            if !valid_host(endpoint) {
+                return Err(invalid(
+                    "wireguard.endpoint",
+                    format!(
+                        "'{}' is not a valid hostname or ip address (the port is taken from the interface)",
+                        endpoint
+                    ),
+                ));
+            }
+        }
+
+        if self.wireguard.subnet.prefix_len() > 30 {
+            return Err(invalid(
+                "wireguard.subnet",
+                format!("{} leaves no room for peers", self.wireguard.subnet),
+            ));
+        }
+
+        if self.cookie.name.is_empty() {
+            return Err(invalid("cookie.name", "must not be empty"));
+        }
+
+        match self.cookie.same_site.as_str() {
+            "strict" | "lax" => {}
+            "none" if self.secure_cookies() => {}
+            "none" => {
+                return Err(invalid(
+                    "cookie.same_site",
+                    "\"none\" is only accepted by browsers together with secure = true or tls",
+                ))
+            }
+            other => {
+                return Err(invalid(
+                    "cookie.same_site",
+                    format!("'{}' is not one of strict, lax or none", other),
+                ))
+            }
+        }
+
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log.level) {
+            return Err(invalid(
+                "log.level",
+                format!("'{}' is not a valid level: {}", self.log.level, e),
+            ));
+        }
+
+        if let Some(token) = &self.metrics.token {
+            if token.trim().len() < 16 {
+                return Err(invalid("metrics.token", "must have at least 16 characters"));
+            }
+        }
+
+        // the charts for a day are drawn from the samples
+        let traffic = &self.traffic;
+        if traffic.interval_secs != 0 && !(10..=3600).contains(&traffic.interval_secs) {
+            return Err(invalid(
+                "traffic.interval_secs",
+                "must be 0 or between 10 and 3600",
+            ));
+        }
+        if traffic.raw_retention_hours < 24
+            || traffic.hourly_retention_days < 7
+            || traffic.daily_retention_days < 31
+        {
+            return Err(invalid(
+                "traffic",
+                "samples have to be kept for 24 hours, hourly sums for 7 days and daily ones for 31",
+            ));
+        }
+        // the usage of a month is summed up from the daily traffic
+        if !(1..=31).contains(&self.quota.reset_day) {
+            return Err(invalid("quota.reset_day", "must be between 1 and 31"));
+        }
+        if !(1..=99).contains(&self.quota.warn_percent) {
+            return Err(invalid("quota.warn_percent", "must be between 1 and 99"));
+        }
+
+        for (i, webhook) in self.webhooks.iter().enumerate() {
+            match url::Url::parse(&webhook.url) {
+                Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {}
+                _ => {
+                    return Err(invalid(
+                        "webhooks.url",
+                        format!("'{}' is not a http(s) url", webhook.url),
+                    ))
+                }
+            }
+            // deliveries are matched to their target by the url
+            if self.webhooks[..i].iter().any(|w| w.url == webhook.url) {
+                return Err(invalid(
+                    "webhooks.url",
+                    format!("'{}' is there twice", webhook.url),
+                ));
+            }
+            if webhook.secret.len() < 16 {
+                return Err(invalid(
+                    "webhooks.secret",
+                    "must have at least 16 characters",
+                ));
+            }
+            if let Some(event) = webhook
+                .events
+                .iter()
+                .find(|e| !webhooks::EVENTS.contains(&e.as_str()))
+            {
+                return Err(invalid(
+                    "webhooks.events",
+                    format!(
+                        "unknown event '{}', expected one of {}",
+                        event,
+                        webhooks::EVENTS.join(", ")
+                    ),
+                ));
+            }
+        }
+
+        for (tag, group) in &self.groups {
+            if !groups::valid_tag(tag) {
+                return Err(invalid(
+                    "groups",
+                    format!(
+                        "'{}' is not a valid tag, it takes up to {} of a-z, 0-9, - and _",
+                        tag,
+                        groups::MAX_TAG_LEN
+                    ),
+                ));
+            }
+            // the server's address isn't known yet, any one of the subnet will do
+            let server = self
+                .wireguard
+                .subnet
+                .hosts()
+                .next()
+                .unwrap_or(self.wireguard.subnet.addr());
+            if let Some(routes) = &group.routes {
+                if invites::fill_routes(routes, self.wireguard.subnet, server).is_none() {
+                    return Err(invalid(
+                        "groups.routes",
+                        format!("'{}' of group {} is not a list of networks", routes, tag),
+                    ));
+                }
+            }
+            if group.quota_gib == Some(0) {
+                return Err(invalid("groups.quota_gib", "must be greater than 0"));
+            }
+            if group.quota_gib.is_some() && self.traffic.interval_secs == 0 {
+                return Err(invalid(
+                    "groups.quota_gib",
+                    "needs the traffic sampling, traffic.interval_secs is 0",
+                ));
+            }
+        }
+
+        // the deadlines of the identity cookie are i64 seconds
+        let session_range = format!("must be between 1 and {}", i64::MAX);
+        if self.session.lifetime_secs == 0 || self.session.lifetime_secs > i64::MAX as u64 {
+            return Err(invalid("session.lifetime_secs", session_range));
+        }
+        if self.session.idle_timeout_secs == 0 || self.session.idle_timeout_secs > i64::MAX as u64 {
+            return Err(invalid("session.idle_timeout_secs", session_range));
+        }
+
+        if let Some(smtp) = &self.smtp {
+            if smtp.host.is_empty() {
+                return Err(invalid("smtp.host", "must not be empty"));
+            }
+            // the mails carry private keys, they are only sent in the clear on this machine
+            let local = matches!(smtp.host.as_str(), "localhost" | "127.0.0.1" | "::1");
+            if smtp.security == SmtpSecurity::None && !local {
+                return Err(invalid(
+                    "smtp.security",
+                    "must be tls or starttls, none is only allowed for localhost",
+                ));
+            }
+            if smtp.username.is_some() != smtp.password.is_some() {
+                return Err(invalid(
+                    "smtp.username",
+                    "needs smtp.password and the other way around",
+                ));
+            }
+            if let Err(e) = smtp.from.parse::<lettre::message::Mailbox>() {
+                return Err(invalid(
+                    "smtp.from",
+                    format!("'{}' is not a mail address: {}", smtp.from, e),
+                ));
+            }
+        }
+
+        if let Some(oidc) = &self.oidc {
+            // the id token is trusted because it comes straight from the issuer over tls,
+            // plain http is only accepted for a mock identity provider on this machine
+            let issuer = url::Url::parse(&oidc.issuer)
+                .map_err(|e| invalid("oidc.issuer", format!("'{}': {}", oidc.issuer, e)))?;
+            let local = matches!(
+                issuer.host_str(),
+                Some("localhost") | Some("127.0.0.1") | Some("[::1]")
+            );
+            if issuer.scheme() != "https" && !(issuer.scheme() == "http" && local) {
+                return Err(invalid(
+                    "oidc.issuer",
+                    "must be https, plain http is only allowed for localhost",
+                ));
+            }
+            if oidc.client_id.is_empty() {
+                return Err(invalid("oidc.client_id", "must not be empty"));
+            }
+            if let Err(e) = url::Url::parse(&oidc.redirect_url) {
+                return Err(invalid(
+                    "oidc.redirect_url",
+                    format!("'{}': {}", oidc.redirect_url, e),
+                ));
+            }
+            if !oidc.scopes.iter().any(|s| s == "openid") {
+                return Err(invalid("oidc.scopes", "must contain \"openid\""));
+            }
+            if oidc.username_claim.is_empty() {
+                return Err(invalid("oidc.username_claim", "must not be empty"));
+            }
+        }
+
+        if let Some(ldap) = &self.ldap {
+            // passwords are sent with the bind, plain ldap is only accepted on this machine
+            let url = url::Url::parse(&ldap.url)
+                .map_err(|e| invalid("ldap.url", format!("'{}': {}", ldap.url, e)))?;
+            let local = matches!(
+                url.host_str(),
+                Some("localhost") | Some("127.0.0.1") | Some("[::1]")
+            );
+            match url.scheme() {
+                "ldaps" if !ldap.starttls => {}
+                "ldaps" => {
+                    return Err(invalid(
+                        "ldap.starttls",
+                        "can't be used with ldaps, the connection is already encrypted",
+                    ))
+                }
+                "ldap" if ldap.starttls || local => {}
+                _ => return Err(invalid(
+                    "ldap.url",
+                    "must be ldaps or ldap with starttls, plain ldap is only allowed for localhost",
+                )),
+            }
+            if ldap.bind_password.is_some() && ldap.bind_dn.is_none() {
+                return Err(invalid("ldap.bind_password", "needs ldap.bind_dn"));
+            }
+            if ldap.base_dn.is_empty() {
+                return Err(invalid("ldap.base_dn", "must not be empty"));
+            }
+            if !ldap.user_filter.contains("{username}") {
+                return Err(invalid("ldap.user_filter", "must contain {username}"));
+            }
+            if ldap.username_attribute.is_empty() {
+                return Err(invalid("ldap.username_attribute", "must not be empty"));
+            }
+        }
+
+        if self.login.max_failures == 0 {
+            return Err(invalid("login.max_failures", "must be greater than 0"));
+        }
+        if self.login.max_failures_per_ip == 0 {
+            return Err(invalid(
+                "login.max_failures_per_ip",
+                "must be greater than 0",
+            ));
+        }
+        if self.login.max_backoff_secs < self.login.backoff_secs {
+            return Err(invalid(
+                "login.max_backoff_secs",
+                "must not be smaller than login.backoff_secs",
+            ));
+        }
+
+        if self.encryption.key.is_some() && self.encryption.key_file.is_some() {
+            return Err(invalid(
+                "encryption.key",
+                "can't be used together with encryption.key_file",
+            ));
+        }
+        if let Some(key_file) = &self.encryption.key_file {
+            if !key_file.is_file() {
+                return Err(invalid(
+                    "encryption.key_file",
+                    format!("'{}' does not exist", key_file.display()),
+                ));
+            }
+        }
+
+        if let Some(tls) = &self.tls {
+            if !tls.cert.is_file() {
+                return Err(invalid(
+                    "tls.cert",
+                    format!("'{}' does not exist", tls.cert.display()),
+                ));
+            }
+            if !tls.key.is_file() {
+                return Err(invalid(
+                    "tls.key",
+                    format!("'{}' does not exist", tls.key.display()),
+                ));
+            }
+            if !self.listen.iter().any(|l| matches!(l, ListenAddr::Tcp(_))) {
+                return Err(invalid(
+                    "listen",
+                    "tls needs at least one ip:port address, unix sockets stay plain http",
+                ));
+            }
+            if let Some(redirect) = tls.redirect {
+                if self.listen.contains(&ListenAddr::Tcp(redirect)) {
+                    return Err(invalid(
+                        "tls.redirect",
+                        format!("{} is already used in listen", redirect),
+                    ));
+                }
+                // the host header of the request can't be trusted
+                match tls.redirect_host(&self.wireguard) {
+                    Some(host) if valid_host(host) => {}
+                    Some(host) => {
+                        return Err(invalid(
+                            "tls.redirect_host",
+                            format!("'{}' is not a valid hostname or ip address", host),
+                        ))
+                    }
+                    None => {
+                        return Err(invalid(
+                            "tls.redirect_host",
+                            "is needed for tls.redirect when wireguard.endpoint is not set",
+                        ))
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn config_path_arg() -> Option<PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" || arg == "-c" {
+            return args.next().map(PathBuf::from);
+        }
+    }
+    None
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(format!("{}{}", ENV_PREFIX, name)).ok()
+}
+
+fn parse_env<T: FromStr>(name: &str, value: &str) -> Result<T, ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e: T::Err| ConfigError::Env(format!("{}{}", ENV_PREFIX, name), e.to_string()))
+}
+
+// a hostname or an ip address, without a port
+fn valid_host(host: &str) -> bool {
+    host.parse::<IpAddr>().is_ok()
+        || (!host.is_empty()
+            && host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-'))
+}
+
+// the host as written in urls and endpoints, ipv6 addresses in brackets
+pub fn url_host(host: &str) -> String {
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+        _ => host.to_string(),
+    }
+}
+
+fn invalid(field: &'static str, msg: impl Into<String>) -> ConfigError {
+    ConfigError::Invalid(field, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a config that passes, with the paths in `dir`
+    fn valid(dir: &Path) -> Config {
+        let static_dir = dir.join("static");
+        std::fs::create_dir_all(&static_dir).unwrap();
+        std::fs::write(static_dir.join("index.html"), "").unwrap();
+        std::fs::write(dir.join("wg"), "").unwrap();
+        let mut config = Config {
+            data_dir: dir.to_path_buf(),
+            static_dir,
+            ..Default::default()
+        };
+        config.wireguard.backend = Backend::Wg;
+        config.wireguard.wg = dir.join("wg");
+        config
+    }
+
+    fn field(result: Result<(), ConfigError>) -> Option<&'static str> {
+        match result {
+            Err(ConfigError::Invalid(field, _)) => Some(field),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(()) => None,
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(field(valid(dir.path()).validate()), None);
+    }
+
+    #[test]
+    fn parses_the_example() {
+        let example = include_str!("../../config.example.toml");
+        toml::from_str::<Config>(example).unwrap();
+    }
+
+    #[test]
+    fn rejects_missing_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = valid(dir.path());
+        config.data_dir = dir.path().join("missing");
+        assert_eq!(field(config.validate()), Some("data_dir"));
+
+        let mut config = valid(dir.path());
+        config.static_dir = dir.path().to_path_buf();
+        assert_eq!(field(config.validate()), Some("static_dir"));
+
+        let mut config = valid(dir.path());
+        config.wireguard.wg = dir.path().join("missing");
+        assert_eq!(field(config.validate()), Some("wireguard.wg"));
+    }
+
+    #[test]
+    fn rejects_bad_wireguard_settings() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = valid(dir.path());
+        config.wireguard.interface = Some("a-much-too-long-name".to_string());
+        assert_eq!(field(config.validate()), Some("wireguard.interface"));
+
+        let mut config = valid(dir.path());
+        config.wireguard.endpoint = Some("vpn.example.com:51820".to_string());
+        assert_eq!(field(config.validate()), Some("wireguard.endpoint"));
+        for endpoint in ["vpn.example.com", "192.0.2.1", "2001:db8::1"] {
+            config.wireguard.endpoint = Some(endpoint.to_string());
+            assert_eq!(field(config.validate()), None, "{}", endpoint);
+        }
+        config.wireguard.endpoint = Some("[2001:db8::1]:51820".to_string());
+        assert_eq!(field(config.validate()), Some("wireguard.endpoint"));
+
+        let mut config = valid(dir.path());
+        config.wireguard.subnet = "10.0.0.0/31".parse().unwrap();
+        assert_eq!(field(config.validate()), Some("wireguard.subnet"));
+    }
+
+    #[test]
+    fn same_site_none_needs_secure_cookies() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = valid(dir.path());
+        config.cookie.same_site = "none".to_string();
+        assert_eq!(field(config.validate()), Some("cookie.same_site"));
+        config.cookie.secure = true;
+        assert_eq!(field(config.validate()), None);
+    }
+
+    #[test]
+    fn tls_redirect_needs_a_host() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("cert.pem"), "").unwrap();
+        std::fs::write(dir.path().join("key.pem"), "").unwrap();
+        let mut config = valid(dir.path());
+        config.tls = Some(TlsConfig {
+            cert: dir.path().join("cert.pem"),
+            key: dir.path().join("key.pem"),
+            redirect: Some(([127, 0, 0, 1], 8080).into()),
+            redirect_host: None,
+        });
+        assert_eq!(field(config.validate()), Some("tls.redirect_host"));
+
+        config.wireguard.endpoint = Some("vpn.example.com".to_string());
+        assert_eq!(field(config.validate()), None);
+
+        config.tls.as_mut().unwrap().redirect_host = Some("evil.example.org/x".to_string());
+        assert_eq!(field(config.validate()), Some("tls.redirect_host"));
+    }
+
+    #[test]
+    fn rejects_empty_listen() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = valid(dir.path());
+        config.listen.clear();
+        assert_eq!(field(config.validate()), Some("listen"));
+    }
+
+    #[test]
+    fn quotas_need_the_traffic_sampling() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = valid(dir.path());
+        config.groups.insert(
+            "office".to_string(),
+            GroupConfig {
+                routes: None,
+                quota_gib: Some(50),
+            },
+        );
+        assert_eq!(field(config.validate()), None);
+        config.traffic.interval_secs = 0;
+        assert_eq!(field(config.validate()), Some("groups.quota_gib"));
+        config.groups.clear();
+        assert_eq!(field(config.validate()), None);
+    }
+
+    #[test]
+    fn checks_the_reset_day() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = valid(dir.path());
+        for (day, valid) in [(0, false), (1, true), (31, true), (32, false)] {
+            config.quota.reset_day = day;
+            let expected = if valid { None } else { Some("quota.reset_day") };
+            assert_eq!(field(config.validate()), expected, "day {}", day);
+        }
+    }
+
+    #[test]
+    fn checks_the_session_durations() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = valid(dir.path());
+        config.session.lifetime_secs = u64::MAX;
+        match config.validate() {
+            Err(ConfigError::Invalid("session.lifetime_secs", msg)) => {
+                assert_eq!(msg, "must be between 1 and 9223372036854775807")
+            }
+            other => panic!("unexpected result: {:?}", other),
+        }
+        config.session.lifetime_secs = i64::MAX as u64;
+        assert_eq!(field(config.validate()), None);
+        config.session.idle_timeout_secs = 0;
+        assert_eq!(field(config.validate()), Some("session.idle_timeout_secs"));
+    }
+
+    #[test]
+    fn brackets_ipv6_hosts() {
+        assert_eq!(url_host("vpn.example.com"), "vpn.example.com");
+        assert_eq!(url_host("192.0.2.1"), "192.0.2.1");
+        assert_eq!(url_host("2001:db8::1"), "[2001:db8::1]");
+    }
+}
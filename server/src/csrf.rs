@@ -0,0 +1,248 @@
+use crate::config::Config;
+use crate::session::random_token;
+use crate::tokens::bearer_token;
+use crate::AppData;
+use actix_web::body::EitherBody;
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::{http::Method, web, Error, HttpResponse};
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+
+pub const COOKIE_NAME: &str = "csrf-token";
+pub const HEADER_NAME: &str = "X-CSRF-Token";
+
+// Double submit protection: every response hands out a random token in a
+// cookie readable by the client, which has to echo it in a header on every
+// request that changes something. Other sites can make the browser send the
+// cookie, but can't read it to set the header.
+pub struct Csrf {
+    secure: bool,
+    same_site: SameSite,
+}
+
+impl Csrf {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            secure: config.secure_cookies(),
+            same_site: config.cookie.same_site(),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for Csrf
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = CsrfMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CsrfMiddleware {
+            service,
+            secure: self.secure,
+            same_site: self.same_site,
+        }))
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: S,
+    secure: bool,
+    same_site: SameSite,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let token = req.cookie(COOKIE_NAME).map(|c| c.value().to_string());
+
+        // browsers never add a bearer token on their own, requests authenticated
+        // by one can't be forged. Any other header gets no exemption.
+        if !is_safe(req.method()) && !token_authenticated(&req) {
+            let header = req.headers().get(HEADER_NAME).and_then(|h| h.to_str().ok());
+            let valid = match (&token, header) {
+                (Some(token), Some(header)) => !token.is_empty() && equal(token, header),
+                _ => false,
+            };
+            if !valid {
+                let response = HttpResponse::Forbidden().body("missing or invalid csrf token");
+                return Box::pin(ready(Ok(req.into_response(response).map_into_right_body())));
+            }
+        }
+
+        let secure = self.secure;
+        let same_site = self.same_site;
+        let future = self.service.call(req);
+        Box::pin(async move {
+            let mut response = future.await?;
+            if token.is_none() {
+                let cookie = Cookie::build(COOKIE_NAME, random_token(32))
+                    .path("/")
+                    .secure(secure)
+                    .same_site(same_site)
+                    .finish();
+                let _ = response.response_mut().add_cookie(&cookie);
+            }
+            Ok(response.map_into_left_body())
+        })
+    }
+}
+
+fn token_authenticated(req: &ServiceRequest) -> bool {
+    match (
+        bearer_token(req.headers()),
+        req.app_data::<web::Data<AppData>>(),
+    ) {
+        (Some(secret), Some(data)) => data.tokens.valid(secret),
+        _ => false,
+    }
+}
+
+fn is_safe(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+// compares in constant time so the token can't be guessed byte by byte
+pub fn equal(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_data, User};
+    use actix_web::body::MessageBody;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+    use shared::TokenScope;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    async fn call(data: web::Data<AppData>, req: TestRequest) -> ServiceResponse<impl MessageBody> {
+        let app = test::init_service(
+            App::new()
+                .app_data(data)
+                .wrap(Csrf {
+                    secure: false,
+                    same_site: SameSite::Strict,
+                })
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        test::call_service(&app, req.uri("/").to_request()).await
+    }
+
+    async fn status(req: TestRequest) -> StatusCode {
+        let dir = tempfile::tempdir().unwrap();
+        call(test_data(dir.path()), req).await.status()
+    }
+
+    fn cookie(value: &str) -> Cookie<'static> {
+        Cookie::new(COOKIE_NAME, value.to_string())
+    }
+
+    #[actix_rt::test]
+    async fn changes_need_the_matching_header() {
+        assert_eq!(status(TestRequest::post()).await, StatusCode::FORBIDDEN);
+        assert_eq!(
+            status(TestRequest::post().cookie(cookie(TOKEN))).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(TestRequest::post().insert_header((HEADER_NAME, TOKEN))).await,
+            StatusCode::FORBIDDEN
+        );
+        let mismatched = TestRequest::post()
+            .cookie(cookie(TOKEN))
+            .insert_header((HEADER_NAME, "0123456789abcdeF"));
+        assert_eq!(status(mismatched).await, StatusCode::FORBIDDEN);
+        let matching = TestRequest::post()
+            .cookie(cookie(TOKEN))
+            .insert_header((HEADER_NAME, TOKEN));
+        assert_eq!(status(matching).await, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn empty_tokens_are_refused() {
+        let req = TestRequest::post()
+            .cookie(cookie(""))
+            .insert_header((HEADER_NAME, ""));
+        assert_eq!(status(req).await, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_rt::test]
+    async fn safe_methods_hand_out_a_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = test_data(dir.path());
+        let response = call(data.clone(), TestRequest::get()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let cookie = response
+            .response()
+            .cookies()
+            .find(|c| c.name() == COOKIE_NAME)
+            .unwrap();
+        assert!(!cookie.value().is_empty());
+        assert_eq!(cookie.path(), Some("/"));
+
+        // a client that has one keeps it
+        let response = call(data, TestRequest::get().cookie(cookie)).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.response().cookies().count(), 0);
+    }
+
+    #[actix_rt::test]
+    async fn only_valid_api_tokens_are_exempt() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = test_data(dir.path());
+        let user = User {
+            name: "alice".to_string(),
+            ..Default::default()
+        };
+        data.db.save_user("alice", &user).unwrap();
+        let secret = data
+            .tokens
+            .create(
+                "alice",
+                "ci".to_string(),
+                vec![TokenScope::ReadConfig],
+                None,
+            )
+            .unwrap();
+
+        let valid = TestRequest::post()
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", secret)));
+        assert_eq!(call(data.clone(), valid).await.status(), StatusCode::OK);
+        let guessed = TestRequest::post()
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}x", secret)));
+        assert_eq!(call(data, guessed).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn compares_whole_tokens() {
+        assert!(equal("abc", "abc"));
+        assert!(!equal("abc", "abd"));
+        assert!(!equal("abc", "abcd"));
+    }
+}
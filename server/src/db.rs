@@ -0,0 +1,1077 @@
+use crate::kek::{self, Kek};
+use crate::{PubPrivKey, Role, User};
+use ipnet::Ipv4Net;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::{params, Connection, OptionalExtension, Row, Transaction};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+const DB_FILE: &str = "data.db";
+
+// Every entry moves the schema one version up, the version the database is
+// at is kept in `PRAGMA user_version`. Released entries are never changed,
+// later changes go into a new one.
+const MIGRATIONS: &[&str] = &[
+    r#"
+CREATE TABLE interfaces (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE peers (
+    id INTEGER PRIMARY KEY,
+    interface_id INTEGER NOT NULL REFERENCES interfaces (id) ON DELETE CASCADE,
+    public_key TEXT NOT NULL UNIQUE,
+    private_key TEXT NOT NULL,
+    allowed_ips TEXT NOT NULL,
+    name TEXT NOT NULL
+);
+
+CREATE TABLE users (
+    id INTEGER PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    hashed_pass TEXT NOT NULL,
+    role TEXT NOT NULL,
+    oidc_subject TEXT UNIQUE,
+    ldap_dn TEXT UNIQUE COLLATE NOCASE,
+    -- json, see totp::Totp
+    totp TEXT
+);
+
+CREATE TABLE sessions (
+    id TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    created INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    handle TEXT NOT NULL UNIQUE,
+    ip TEXT NOT NULL,
+    user_agent TEXT NOT NULL
+);
+
+-- tokens are stored by the sha256 of the secret
+CREATE TABLE api_tokens (
+    hash TEXT PRIMARY KEY,
+    id TEXT NOT NULL UNIQUE,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    -- json list of shared::TokenScope
+    scopes TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    expires INTEGER,
+    last_used INTEGER
+);
+
+-- the actor is kept by name, entries have to outlive renamed users
+CREATE TABLE audit (
+    id INTEGER PRIMARY KEY,
+    time INTEGER NOT NULL,
+    actor TEXT NOT NULL,
+    action TEXT NOT NULL,
+    target TEXT NOT NULL,
+    ip TEXT NOT NULL
+);
+CREATE INDEX audit_time ON audit (time);
+"#,
+    r#"
+ALTER TABLE audit ADD COLUMN old_value TEXT;
+ALTER TABLE audit ADD COLUMN new_value TEXT;
+CREATE TRIGGER audit_no_update BEFORE UPDATE ON audit
+BEGIN SELECT RAISE(ABORT, 'the audit log is append only'); END;
+CREATE TRIGGER audit_no_delete BEFORE DELETE ON audit
+BEGIN SELECT RAISE(ABORT, 'the audit log is append only'); END;
+"#,
+    r#"
+CREATE TABLE health (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    checked INTEGER NOT NULL
+);
+"#,
+    r#"
+CREATE TABLE traffic_counters (
+    public_key TEXT PRIMARY KEY,
+    rx INTEGER NOT NULL,
+    tx INTEGER NOT NULL
+);
+CREATE TABLE traffic (
+    public_key TEXT NOT NULL,
+    resolution INTEGER NOT NULL,
+    time INTEGER NOT NULL,
+    rx INTEGER NOT NULL,
+    tx INTEGER NOT NULL,
+    PRIMARY KEY (public_key, resolution, time)
+);
+CREATE INDEX traffic_time ON traffic (resolution, time);
+"#,
+    r#"
+ALTER TABLE peers ADD COLUMN quota_bytes INTEGER;
+-- start of the month the peer was last warned about its quota
+ALTER TABLE peers ADD COLUMN quota_warned INTEGER;
+ALTER TABLE peers ADD COLUMN quota_disabled INTEGER NOT NULL DEFAULT 0;
+"#,
+    r#"
+CREATE TABLE webhook_deliveries (
+    id TEXT PRIMARY KEY,
+    url TEXT NOT NULL,
+    event TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    -- NULL once it was delivered or given up on
+    next_attempt INTEGER,
+    delivered INTEGER,
+    last_status INTEGER,
+    last_error TEXT
+);
+CREATE INDEX webhook_deliveries_due ON webhook_deliveries (next_attempt);
+"#,
+    r#"
+-- one-time links to a peer's config, by the sha256 of the token
+CREATE TABLE share_links (
+    hash TEXT PRIMARY KEY,
+    public_key TEXT NOT NULL,
+    created_by TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    expires INTEGER NOT NULL
+);
+CREATE INDEX share_links_public_key ON share_links (public_key);
+"#,
+    r#"
+-- invite codes for enrolling peers without an admin, by the sha256 of the code
+CREATE TABLE invites (
+    id TEXT PRIMARY KEY,
+    hash TEXT NOT NULL UNIQUE,
+    interface TEXT NOT NULL,
+    routes TEXT,
+    quota_bytes INTEGER,
+    uses_left INTEGER NOT NULL,
+    created_by TEXT NOT NULL,
+    created INTEGER NOT NULL,
+    expires INTEGER NOT NULL
+);
+-- AllowedIPs of the peer's own config, NULL for the address of the server
+ALTER TABLE peers ADD COLUMN routes TEXT;
+"#,
+    r#"
+-- comma separated, like "office,laptops"
+ALTER TABLE peers ADD COLUMN tags TEXT NOT NULL DEFAULT '';
+-- taken off the interface by a user, quota_disabled is set by the quota
+ALTER TABLE peers ADD COLUMN user_disabled INTEGER NOT NULL DEFAULT 0;
+"#,
+    r#"
+-- when the peer is removed, NULL for never
+ALTER TABLE peers ADD COLUMN expires INTEGER;
+-- hours the peers enrolled with the invite live, NULL for no limit
+ALTER TABLE invites ADD COLUMN peer_hours INTEGER;
+"#,
+    r#"
+CREATE UNIQUE INDEX peers_address ON peers (interface_id, allowed_ips);
+"#,
+];
+
+#[derive(Debug)]
+pub enum DbError {
+    Sqlite(rusqlite::Error),
+    Io(std::io::Error),
+    // the database was written by a newer version of the server
+    TooNew(i64),
+    Import(PathBuf, String),
+    // private keys that can't be decrypted with the configured key
+    Key(String),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Sqlite(e) => write!(f, "{}", e),
+            DbError::Io(e) => write!(f, "{}", e),
+            DbError::TooNew(version) => write!(
+                f,
+                "the database is at version {}, this server only knows up to {}",
+                version,
+                MIGRATIONS.len()
+            ),
+            DbError::Import(path, e) => write!(f, "could not import {}: {}", path.display(), e),
+            DbError::Key(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        DbError::Sqlite(e)
+    }
+}
+
+impl From<std::io::Error> for DbError {
+    fn from(e: std::io::Error) -> Self {
+        DbError::Io(e)
+    }
+}
+
+// One connection shared by everything, sqlite serializes writes anyway.
+// Private keys of peers are encrypted when a key encryption key is set.
+#[derive(Clone)]
+pub struct Db {
+    conn: Arc<Mutex<Connection>>,
+    kek: Option<Kek>,
+}
+
+impl Db {
+    // Opens (or creates) the database in the data dir and brings the schema up
+    // to date. Fails if stored private keys need another key than `kek`.
+    pub fn open(data_dir: &Path, kek: Option<Kek>) -> Result<Self, DbError> {
+        let path = data_dir.join(DB_FILE);
+        // holds password hashes and private keys
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(&path)?;
+
+        let mut conn = Connection::open(&path)?;
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute_batch("PRAGMA foreign_keys = ON; PRAGMA journal_mode = WAL;")?;
+        migrate(&mut conn)?;
+        check_keys(&mut conn, kek.as_ref())?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            kek,
+        })
+    }
+
+    pub fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    pub fn add_interface(&self, interface: &str) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "INSERT OR IGNORE INTO interfaces (name) VALUES (?1)",
+            [interface],
+        )?;
+        Ok(())
+    }
+
+    // reads and writes a row, for the readiness check
+    pub fn check(&self) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        conn.query_row("SELECT count(*) FROM peers", [], |row| row.get::<_, i64>(0))?;
+        conn.execute(
+            "INSERT INTO health (id, checked) VALUES (1, ?1)
+             ON CONFLICT (id) DO UPDATE SET checked = excluded.checked",
+            [crate::session::now()],
+        )?;
+        Ok(())
+    }
+
+    // imports the json stores of older versions, see `import_json`
+    pub fn import_json(&self, data_dir: &Path, interface: &str) -> Result<(), DbError> {
+        import_json(&mut self.conn(), data_dir, interface, self.kek.as_ref())
+    }
+
+    // ---- Users ----
+
+    pub fn users(&self) -> rusqlite::Result<Vec<User>> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(&format!("SELECT {} FROM users", USER_COLUMNS))?;
+        let users = statement
+            .query_map([], user_from_row)?
+            .collect::<Result<_, _>>();
+        users
+    }
+
+    pub fn user_by_name(&self, name: &str) -> rusqlite::Result<Option<User>> {
+        self.conn()
+            .query_row(
+                &format!("SELECT {} FROM users WHERE name = ?1", USER_COLUMNS),
+                [name],
+                user_from_row,
+            )
+            .optional()
+    }
+
+    // updates the user called `old_name`, or adds it if there is none
+    pub fn save_user(&self, old_name: &str, user: &User) -> rusqlite::Result<()> {
+        let conn = self.conn();
+        let totp = user
+            .totp
+            .as_ref()
+            .map(|t| serde_json::to_string(t).unwrap());
+        let updated = conn.execute(
+            "UPDATE users SET name = ?1, hashed_pass = ?2, role = ?3, oidc_subject = ?4,
+             ldap_dn = ?5, totp = ?6 WHERE name = ?7",
+            params![
+                user.name,
+                user.hashed_pass,
+                user.role,
+                user.oidc_subject,
+                user.ldap_dn,
+                totp,
+                old_name
+            ],
+        )?;
+        if updated == 0 {
+            insert_user(&conn, user)?;
+        }
+        Ok(())
+    }
+
+    // Moves the last used step of the user's authenticator forward, false if
+    // the step (or a later one) was already used.
+    pub fn consume_totp_step(&self, name: &str, step: u64) -> rusqlite::Result<bool> {
+        let updated = self.conn().execute(
+            "UPDATE users SET totp = json_set(totp, '$.last_step', ?1)
+             WHERE name = ?2 AND IFNULL(json_extract(totp, '$.last_step'), 0) < ?1",
+            params![step, name],
+        )?;
+        Ok(updated == 1)
+    }
+
+    // Removes a hashed recovery code of the user, false if it is not (or no
+    // longer) there.
+    pub fn consume_recovery_code(&self, name: &str, hashed: &str) -> rusqlite::Result<bool> {
+        let updated = self.conn().execute(
+            "UPDATE users SET totp = json_remove(totp, (
+                 SELECT '$.recovery_codes[' || key || ']'
+                 FROM json_each(totp, '$.recovery_codes') WHERE value = ?1 LIMIT 1))
+             WHERE name = ?2 AND EXISTS (
+                 SELECT 1 FROM json_each(totp, '$.recovery_codes') WHERE value = ?1)",
+            params![hashed, name],
+        )?;
+        Ok(updated == 1)
+    }
+
+    // ---- Peers ----
+
+    // A private key that can't be decrypted is left empty, so nothing but the
+    // ciphertext could leak from it.
+    pub fn peers(&self, interface: &str) -> rusqlite::Result<Vec<PubPrivKey>> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(
+            "SELECT p.private_key, p.public_key, p.name FROM peers p
+             JOIN interfaces i ON i.id = p.interface_id WHERE i.name = ?1",
+        )?;
+        let peers = statement
+            .query_map([interface], |row| {
+                let stored: String = row.get(0)?;
+                let public_key: String = row.get(1)?;
+                let private_key = match decrypt(self.kek.as_ref(), &stored, &public_key) {
+                    Ok(private_key) => private_key,
+                    Err(e) => {
+                        error!("Could not decrypt the private key of {}: {}", public_key, e);
+                        String::new()
+                    }
+                };
+                Ok(PubPrivKey {
+                    private_key,
+                    public_key,
+                    name: row.get(2)?,
+                })
+            })?
+            .collect::<Result<_, _>>();
+        peers
+    }
+
+    // adds the peer or updates the one with the same public key
+    pub fn save_peer(
+        &self,
+        interface: &str,
+        allowed_ips: &str,
+        key: &PubPrivKey,
+    ) -> rusqlite::Result<()> {
+        save_peer(&self.conn(), interface, allowed_ips, key, self.kek.as_ref())
+    }
+
+    pub fn set_peer_routes(&self, public_key: &str, routes: Option<&str>) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "UPDATE peers SET routes = ?2 WHERE public_key = ?1",
+            params![public_key, routes],
+        )?;
+        Ok(())
+    }
+
+    // the routes of the peers that have them, by public key
+    pub fn peer_routes(&self, interface: &str) -> rusqlite::Result<HashMap<String, String>> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(
+            "SELECT p.public_key, p.routes FROM peers p
+             JOIN interfaces i ON i.id = p.interface_id
+             WHERE i.name = ?1 AND p.routes IS NOT NULL",
+        )?;
+        let routes = statement
+            .query_map([interface], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>();
+        routes
+    }
+
+    pub fn set_peer_tags(&self, public_key: &str, tags: &[String]) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "UPDATE peers SET tags = ?2 WHERE public_key = ?1",
+            params![public_key, tags.join(",")],
+        )?;
+        Ok(())
+    }
+
+    // the tags of the peers that have some, by public key
+    pub fn peer_tags(&self, interface: &str) -> rusqlite::Result<HashMap<String, Vec<String>>> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(
+            "SELECT p.public_key, p.tags FROM peers p
+             JOIN interfaces i ON i.id = p.interface_id
+             WHERE i.name = ?1 AND p.tags != ''",
+        )?;
+        let tags = statement
+            .query_map([interface], |row| {
+                let tags: String = row.get(1)?;
+                Ok((row.get(0)?, tags.split(',').map(String::from).collect()))
+            })?
+            .collect::<Result<_, _>>();
+        tags
+    }
+
+    pub fn set_peer_expiry(&self, public_key: &str, expires: Option<u64>) -> rusqlite::Result<()> {
+        self.conn().execute(
+            "UPDATE peers SET expires = ?2 WHERE public_key = ?1",
+            params![public_key, expires],
+        )?;
+        Ok(())
+    }
+
+    // when the peers that expire are removed, by public key
+    pub fn peer_expiries(&self, interface: &str) -> rusqlite::Result<HashMap<String, u64>> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(
+            "SELECT p.public_key, p.expires FROM peers p
+             JOIN interfaces i ON i.id = p.interface_id
+             WHERE i.name = ?1 AND p.expires IS NOT NULL",
+        )?;
+        let expiries = statement
+            .query_map([interface], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>();
+        expiries
+    }
+
+    // Stores a new peer with the first of `addresses` no other peer of the
+    // interface has, and runs `apply` with it to add the peer to the kernel.
+    // The peer is only kept if that worked. None if every address is taken.
+    pub fn add_peer(
+        &self,
+        interface: &str,
+        addresses: impl IntoIterator<Item = Ipv4Net>,
+        key: &PubPrivKey,
+        apply: impl FnOnce(Ipv4Net) -> Result<(), std::io::Error>,
+    ) -> Result<Option<Ipv4Net>, DbError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let taken = {
+            let mut statement = tx.prepare(
+                "SELECT p.allowed_ips FROM peers p
+                 JOIN interfaces i ON i.id = p.interface_id WHERE i.name = ?1",
+            )?;
+            let taken = statement
+                .query_map([interface], |row| row.get(0))?
+                .collect::<Result<HashSet<String>, _>>()?;
+            taken
+        };
+        let address = match addresses
+            .into_iter()
+            .find(|a| !taken.contains(&a.to_string()))
+        {
+            Some(address) => address,
+            None => return Ok(None),
+        };
+        save_peer(&tx, interface, &address.to_string(), key, self.kek.as_ref())?;
+        apply(address)?;
+        tx.commit()?;
+        Ok(Some(address))
+    }
+
+    // Deletes the peer and runs `apply` to remove it from the kernel. The peer
+    // is kept if that failed.
+    pub fn remove_peer(
+        &self,
+        public_key: &str,
+        apply: impl FnOnce() -> Result<(), std::io::Error>,
+    ) -> Result<(), DbError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM peers WHERE public_key = ?1", [public_key])?;
+        tx.execute("DELETE FROM traffic WHERE public_key = ?1", [public_key])?;
+        tx.execute(
+            "DELETE FROM traffic_counters WHERE public_key = ?1",
+            [public_key],
+        )?;
+        tx.execute(
+            "DELETE FROM share_links WHERE public_key = ?1",
+            [public_key],
+        )?;
+        apply()?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Re-encrypts every private key with `new`, returns how many there are.
+    // The server has to be restarted with the new key afterwards.
+    pub fn rotate_kek(&self, new: &Kek) -> Result<usize, DbError> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        let keys = stored_keys(&tx)?;
+        for (public_key, stored) in &keys {
+            let private_key =
+                decrypt(self.kek.as_ref(), stored, public_key).map_err(DbError::Key)?;
+            tx.execute(
+                "UPDATE peers SET private_key = ?1 WHERE public_key = ?2",
+                params![new.encrypt(&private_key, public_key), public_key],
+            )?;
+        }
+        tx.commit()?;
+        Ok(keys.len())
+    }
+}
+
+// public and stored private key of every peer that has one
+fn stored_keys(conn: &Connection) -> rusqlite::Result<Vec<(String, String)>> {
+    let mut statement =
+        conn.prepare("SELECT public_key, private_key FROM peers WHERE private_key != ''")?;
+    let keys = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<_, _>>();
+    keys
+}
+
+fn decrypt(kek: Option<&Kek>, stored: &str, public_key: &str) -> Result<String, String> {
+    match (kek, kek::key_id(stored)) {
+        (Some(kek), _) => kek.decrypt(stored, public_key),
+        (None, Some(id)) => Err(format!(
+            "encrypted with key {}, but no key is configured",
+            id
+        )),
+        (None, None) => Ok(stored.to_string()),
+    }
+}
+
+// Refuses to start with private keys the configured key can't decrypt, and
+// encrypts the ones still stored in plain text.
+fn check_keys(conn: &mut Connection, kek: Option<&Kek>) -> Result<(), DbError> {
+    let tx = conn.transaction()?;
+    let mut plain = 0;
+    for (public_key, stored) in stored_keys(&tx)? {
+        match (kek, kek::key_id(&stored)) {
+            (Some(kek), Some(id)) if id == kek.id => {}
+            (_, Some(id)) => {
+                return Err(DbError::Key(format!(
+                    "the private keys of the peers are encrypted with key {}, but {}",
+                    id,
+                    match kek {
+                        Some(kek) => format!("the configured key is {}", kek.id),
+                        None => "no encryption key is configured".to_string(),
+                    }
+                )))
+            }
+            (Some(kek), None) => {
+                tx.execute(
+                    "UPDATE peers SET private_key = ?1 WHERE public_key = ?2",
+                    params![kek.encrypt(&stored, &public_key), public_key],
+                )?;
+                plain += 1;
+            }
+            (None, None) => plain += 1,
+        }
+    }
+    tx.commit()?;
+
+    match kek {
+        Some(kek) if plain > 0 => info!("Encrypted {} private keys with key {}", plain, kek.id),
+        None if plain > 0 => warn!(
+            "{} private keys are stored in plain text, set encryption.key_file",
+            plain
+        ),
+        _ => {}
+    }
+    Ok(())
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), DbError> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version > MIGRATIONS.len() as i64 {
+        return Err(DbError::TooNew(version));
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i as i64 + 1)?;
+        tx.commit()?;
+        info!("Migrated the database to version {}", i + 1);
+    }
+    Ok(())
+}
+
+const USER_COLUMNS: &str = "name, hashed_pass, role, oidc_subject, ldap_dn, totp";
+
+fn user_from_row(row: &Row) -> rusqlite::Result<User> {
+    let totp = match row.get::<_, Option<String>>(5)? {
+        Some(totp) => Some(serde_json::from_str(&totp).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(5, rusqlite::types::Type::Text, Box::new(e))
+        })?),
+        None => None,
+    };
+    Ok(User {
+        name: row.get(0)?,
+        hashed_pass: row.get(1)?,
+        role: row.get(2)?,
+        oidc_subject: row.get(3)?,
+        ldap_dn: row.get(4)?,
+        totp,
+    })
+}
+
+fn insert_user(conn: &Connection, user: &User) -> rusqlite::Result<()> {
+    let totp = user
+        .totp
+        .as_ref()
+        .map(|t| serde_json::to_string(t).unwrap());
+    conn.execute(
+        &format!(
+            "INSERT INTO users ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            USER_COLUMNS
+        ),
+        params![
+            user.name,
+            user.hashed_pass,
+            user.role,
+            user.oidc_subject,
+            user.ldap_dn,
+            totp
+        ],
+    )?;
+    Ok(())
+}
+
+fn save_peer(
+    conn: &Connection,
+    interface: &str,
+    allowed_ips: &str,
+    key: &PubPrivKey,
+    kek: Option<&Kek>,
+) -> rusqlite::Result<()> {
+    // peers with a key pair made by the client have no private key
+    let private_key = match kek {
+        Some(kek) if !key.private_key.is_empty() => kek.encrypt(&key.private_key, &key.public_key),
+        _ => key.private_key.clone(),
+    };
+    conn.execute(
+        "INSERT INTO peers (interface_id, public_key, private_key, allowed_ips, name)
+         SELECT id, ?2, ?3, ?4, ?5 FROM interfaces WHERE name = ?1
+         ON CONFLICT (public_key) DO UPDATE SET
+             private_key = excluded.private_key,
+             allowed_ips = excluded.allowed_ips,
+             name = excluded.name",
+        params![
+            interface,
+            key.public_key,
+            private_key,
+            allowed_ips,
+            key.name
+        ],
+    )?;
+    Ok(())
+}
+
+impl ToSql for Role {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            Role::Admin => "admin",
+            Role::Viewer => "viewer",
+        }
+        .into())
+    }
+}
+
+impl FromSql for Role {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "admin" => Ok(Role::Admin),
+            "viewer" => Ok(Role::Viewer),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+// ---- Import of the json stores used before the database ----
+
+// `jfs` with `single: true` keeps all records of a store in one object, keyed by their id
+const JSON_DATA: &str = "data.json";
+const JSON_SESSIONS: &str = "sessions.json";
+const JSON_TOKENS: &str = "tokens.json";
+const AUDIT_LOG: &str = "audit.log";
+
+#[derive(Deserialize)]
+struct JsonSession {
+    user: String,
+    created: u64,
+    #[serde(default)]
+    last_seen: u64,
+    #[serde(default)]
+    handle: String,
+    #[serde(default)]
+    ip: String,
+    #[serde(default)]
+    user_agent: String,
+}
+
+#[derive(Deserialize)]
+struct JsonToken {
+    id: String,
+    user: String,
+    name: String,
+    scopes: Vec<shared::TokenScope>,
+    created: u64,
+    expires: Option<u64>,
+    #[serde(default)]
+    last_used: Option<u64>,
+}
+
+// Moves everything from the json files into the database in one transaction.
+// Imported files are renamed to `*.imported`, a failed import leaves them
+// untouched and is tried again on the next start.
+fn import_json(
+    conn: &mut Connection,
+    data_dir: &Path,
+    interface: &str,
+    kek: Option<&Kek>,
+) -> Result<(), DbError> {
+    let files = [JSON_DATA, JSON_SESSIONS, JSON_TOKENS, AUDIT_LOG]
+        .iter()
+        .map(|file| data_dir.join(file))
+        .filter(|path| path.exists())
+        .collect::<Vec<_>>();
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    let tx = conn.transaction()?;
+    for path in &files {
+        let content = std::fs::read_to_string(path)?;
+        let error = |e: String| DbError::Import(path.clone(), e);
+        match path.file_name().and_then(|f| f.to_str()) {
+            Some(JSON_DATA) => import_data(&tx, &content, interface, kek).map_err(error)?,
+            Some(JSON_SESSIONS) => import_sessions(&tx, &content).map_err(error)?,
+            Some(JSON_TOKENS) => import_tokens(&tx, &content).map_err(error)?,
+            _ => import_audit(&tx, &content).map_err(error)?,
+        }
+    }
+    tx.commit()?;
+
+    for path in files {
+        info!("Imported {} into the database", path.display());
+        let mut imported = path.clone().into_os_string();
+        imported.push(".imported");
+        std::fs::rename(&path, imported)?;
+    }
+    Ok(())
+}
+
+// users and peers, the peers were keyed by their allowed ips
+fn import_data(
+    tx: &Transaction,
+    content: &str,
+    interface: &str,
+    kek: Option<&Kek>,
+) -> Result<(), String> {
+    let records: HashMap<String, serde_json::Value> =
+        serde_json::from_str(content).map_err(|e| e.to_string())?;
+    for (key, record) in records {
+        if record.get("hashed_pass").is_some() {
+            let user: User = serde_json::from_value(record).map_err(|e| e.to_string())?;
+            insert_user(tx, &user).map_err(|e| format!("user {}: {}", user.name, e))?;
+        } else if record.get("public_key").is_some() {
+            let peer: PubPrivKey = serde_json::from_value(record).map_err(|e| e.to_string())?;
+            save_peer(tx, interface, &key, &peer, kek)
+                .map_err(|e| format!("peer {}: {}", key, e))?;
+        } else {
+            return Err(format!("unknown record {}", key));
+        }
+    }
+    Ok(())
+}
+
+// sessions and tokens of users that are gone are dropped
+fn import_sessions(tx: &Transaction, content: &str) -> Result<(), String> {
+    let sessions: HashMap<String, JsonSession> =
+        serde_json::from_str(content).map_err(|e| e.to_string())?;
+    for (session_id, session) in sessions {
+        // sessions from before handles existed get one now
+        let handle = if session.handle.is_empty() {
+            crate::session::random_token(12)
+        } else {
+            session.handle
+        };
+        tx.execute(
+            "INSERT INTO sessions (id, user_id, created, last_seen, handle, ip, user_agent)
+             SELECT ?1, id, ?2, ?3, ?4, ?5, ?6 FROM users WHERE name = ?7",
+            params![
+                session_id,
+                session.created,
+                session.last_seen,
+                handle,
+                session.ip,
+                session.user_agent,
+                session.user
+            ],
+        )
+        .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn import_tokens(tx: &Transaction, content: &str) -> Result<(), String> {
+    let tokens: HashMap<String, JsonToken> =
+        serde_json::from_str(content).map_err(|e| e.to_string())?;
+    for (hash, token) in tokens {
+        tx.execute(
+            "INSERT INTO api_tokens (hash, id, user_id, name, scopes, created, expires, last_used)
+             SELECT ?1, ?2, id, ?3, ?4, ?5, ?6, ?7 FROM users WHERE name = ?8",
+            params![
+                hash,
+                token.id,
+                token.name,
+                serde_json::to_string(&token.scopes).unwrap(),
+                token.created,
+                token.expires,
+                token.last_used,
+                token.user
+            ],
+        )
+        .map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+fn import_audit(tx: &Transaction, content: &str) -> Result<(), String> {
+    for (number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: shared::AuditEntry =
+            serde_json::from_str(line).map_err(|e| format!("line {}: {}", number + 1, e))?;
+        crate::audit::insert(tx, &entry).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC_KEY: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+
+    fn version(conn: &Connection) -> i64 {
+        conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn migrates_a_new_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len() as i64);
+        // nothing left to do the second time
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len() as i64);
+    }
+
+    #[test]
+    fn keeps_data_of_older_versions() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.pragma_update(None, "user_version", 1).unwrap();
+        conn.execute_batch(&format!(
+            "INSERT INTO interfaces (name) VALUES ('wg0');
+             INSERT INTO peers (interface_id, public_key, private_key, allowed_ips, name)
+             VALUES (1, '{}', '', '10.0.0.2/32', 'laptop');",
+            PUBLIC_KEY
+        ))
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+        assert_eq!(version(&conn), MIGRATIONS.len() as i64);
+        let (name, tags, disabled, expires): (String, String, bool, Option<u64>) = conn
+            .query_row(
+                "SELECT name, tags, user_disabled, expires FROM peers WHERE public_key = ?1",
+                [PUBLIC_KEY],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            (name.as_str(), tags.as_str(), disabled, expires),
+            ("laptop", "", false, None)
+        );
+    }
+
+    #[test]
+    fn refuses_newer_databases() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() as i64 + 1)
+            .unwrap();
+        assert!(matches!(migrate(&mut conn), Err(DbError::TooNew(_))));
+    }
+
+    #[test]
+    fn audit_log_is_append_only() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO audit (time, actor, action, target, ip) VALUES (1, 'a', 'login', 'a', '')",
+            [],
+        )
+        .unwrap();
+        assert!(conn.execute("UPDATE audit SET actor = 'b'", []).is_err());
+        assert!(conn.execute("DELETE FROM audit", []).is_err());
+    }
+
+    #[test]
+    fn imports_the_json_stores() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |file: &str, content: String| {
+            std::fs::write(dir.path().join(file), content).unwrap();
+        };
+        write(
+            JSON_DATA,
+            serde_json::json!({
+                "alice": { "name": "alice", "hashed_pass": "hash", "role": "viewer" },
+                "10.0.0.2/32": { "public_key": PUBLIC_KEY, "private_key": "", "name": "laptop" },
+            })
+            .to_string(),
+        );
+        write(
+            JSON_SESSIONS,
+            serde_json::json!({
+                "s1": { "user": "alice", "created": 1 },
+                "s2": { "user": "gone", "created": 1 },
+            })
+            .to_string(),
+        );
+        write(
+            AUDIT_LOG,
+            "{\"time\":1,\"actor\":\"alice\",\"action\":\"login\",\"target\":\"alice\",\"ip\":\"\"}\n"
+                .to_string(),
+        );
+
+        let db = Db::open(dir.path(), None).unwrap();
+        db.add_interface("wg0").unwrap();
+        db.import_json(dir.path(), "wg0").unwrap();
+
+        let user = db.user_by_name("alice").unwrap().unwrap();
+        assert_eq!(user.role, Role::Viewer);
+        let peers = db.peers("wg0").unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].name, "laptop");
+        let count = |table: &str| -> i64 {
+            db.conn()
+                .query_row(&format!("SELECT count(*) FROM {}", table), [], |row| {
+                    row.get(0)
+                })
+                .unwrap()
+        };
+        // the session of the missing user is dropped
+        assert_eq!(count("sessions"), 1);
+        assert_eq!(count("audit"), 1);
+        assert!(dir.path().join("data.json.imported").exists());
+        assert!(!dir.path().join(JSON_DATA).exists());
+    }
+
+    #[test]
+    fn encrypts_and_rotates_private_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let private_key = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+        let stored = |db: &Db| -> String {
+            db.conn()
+                .query_row("SELECT private_key FROM peers", [], |row| row.get(0))
+                .unwrap()
+        };
+        let key = |byte: u8| Kek::from_base64(&base64::encode([byte; 32])).unwrap();
+
+        let db = Db::open(dir.path(), None).unwrap();
+        db.add_interface("wg0").unwrap();
+        let peer = PubPrivKey {
+            private_key: private_key.to_string(),
+            public_key: PUBLIC_KEY.to_string(),
+            name: "laptop".to_string(),
+        };
+        save_peer(&db.conn(), "wg0", "10.0.0.2/32", &peer, None).unwrap();
+        assert_eq!(stored(&db), private_key);
+        drop(db);
+
+        // plain keys are encrypted on the first start with a key
+        let db = Db::open(dir.path(), Some(key(1))).unwrap();
+        assert_eq!(kek::key_id(&stored(&db)), Some(key(1).id.as_str()));
+        assert_eq!(db.peers("wg0").unwrap()[0].private_key, private_key);
+        assert_eq!(db.rotate_kek(&key(2)).unwrap(), 1);
+        drop(db);
+
+        assert!(matches!(Db::open(dir.path(), None), Err(DbError::Key(_))));
+        assert!(matches!(
+            Db::open(dir.path(), Some(key(1))),
+            Err(DbError::Key(_))
+        ));
+        let db = Db::open(dir.path(), Some(key(2))).unwrap();
+        assert_eq!(db.peers("wg0").unwrap()[0].private_key, private_key);
+    }
+
+    #[test]
+    fn concurrent_peers_get_their_own_address() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), None).unwrap();
+        db.add_interface("wg0").unwrap();
+        let addresses = || (2..12).map(|i| format!("10.0.0.{}/32", i).parse::<Ipv4Net>().unwrap());
+
+        let key = |i: u32| PubPrivKey {
+            private_key: String::new(),
+            public_key: format!("key {}", i),
+            name: format!("peer {}", i),
+        };
+
+        let threads = (0..8)
+            .map(|i| {
+                let db = db.clone();
+                std::thread::spawn(move || {
+                    db.add_peer("wg0", addresses(), &key(i), |_| {
+                        std::thread::sleep(Duration::from_millis(10));
+                        // the kernel refused one, its address is free again
+                        match i {
+                            3 => Err(std::io::Error::other("refused")),
+                            _ => Ok(()),
+                        }
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut added = threads
+            .into_iter()
+            .filter_map(|t| t.join().unwrap().ok())
+            .map(|a| a.unwrap().to_string())
+            .collect::<Vec<_>>();
+        added.sort();
+        added.dedup();
+        assert_eq!(added.len(), 7);
+        assert_eq!(db.peers("wg0").unwrap().len(), 7);
+
+        // every address was given out once
+        assert!(save_peer(&db.conn(), "wg0", &added[0], &key(8), None).is_err());
+        let key = key(9);
+        let left = db.add_peer("wg0", addresses(), &key, |_| Ok(())).unwrap();
+        let left = left.unwrap().to_string();
+        assert!(!added.contains(&left));
+        let full = db.add_peer("wg0", addresses().take(8), &key, |_| Ok(()));
+        assert!(matches!(full, Ok(None)));
+    }
+}
@@ -0,0 +1,159 @@
+use crate::tokens::authorized_user;
+use crate::AppData;
+use actix_identity::Identity;
+use actix_web::{get, http::header, web, HttpRequest, HttpResponse};
+use futures_util::stream;
+use shared::{Event, PeerStatus, TokenScope};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::error;
+
+// events a slow page may fall behind before it is disconnected
+const BACKLOG: usize = 64;
+const STATUS_INTERVAL: Duration = Duration::from_secs(10);
+// Streams are closed after a while and the browser connects again, which
+// checks the session again. Revoked sessions stop getting events this way.
+const STREAM_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+// Changes of the peers, sent to the open pages as server-sent events.
+pub struct Events {
+    sender: broadcast::Sender<String>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(BACKLOG);
+        Self { sender }
+    }
+}
+
+impl Events {
+    pub fn send(&self, event: &Event) {
+        // fails when no page listens, which is fine
+        let _ = self.sender.send(serde_json::to_string(event).unwrap());
+    }
+}
+
+// Sends the handshakes and transfer of the peers while pages listen.
+pub fn start_status(data: web::Data<AppData>) {
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(STATUS_INTERVAL);
+        loop {
+            interval.tick().await;
+            if data.events.sender.receiver_count() == 0 {
+                continue;
+            }
+            let dump_data = data.clone();
+            match web::block(move || dump_data.wg.dump()).await {
+                Ok(stats) => data.events.send(&Event::Status {
+                    peers: stats
+                        .into_iter()
+                        .map(|s| PeerStatus {
+                            public_key: s.public_key,
+                            latest_handshake: s.latest_handshake,
+                            rx_bytes: s.rx_bytes,
+                            tx_bytes: s.tx_bytes,
+                        })
+                        .collect(),
+                }),
+                Err(e) => error!("Could not read the status of the peers: {}", e),
+            }
+        }
+    });
+}
+
+// ---- Apis ----
+
+// A page that fell behind is disconnected, the browser connects again on its
+// own and the page loads the config again when it does.
+#[get("/events")]
+async fn events(req: HttpRequest, id: Identity, data: web::Data<AppData>) -> HttpResponse {
+    if authorized_user(&req, &id, &data, TokenScope::ReadConfig).is_none() {
+        return HttpResponse::Forbidden().body("");
+    }
+
+    let receiver = data.events.sender.subscribe();
+    let deadline = Instant::now() + STREAM_LIFETIME;
+    let events = stream::unfold(receiver, move |mut receiver| async move {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match actix_rt::time::timeout(left, receiver.recv()).await {
+            Ok(Ok(event)) => Some((
+                Ok::<_, actix_web::Error>(web::Bytes::from(format!("data: {}\n\n", event))),
+                receiver,
+            )),
+            Ok(Err(RecvError::Lagged(_))) | Ok(Err(RecvError::Closed)) | Err(_) => None,
+        }
+    });
+    // a comment, so the browser sees the stream is open right away
+    let opened = stream::once(async { Ok(web::Bytes::from_static(b": open\n\n")) });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(futures_util::StreamExt::chain(opened, events))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_data, User};
+    use actix_web::body::MessageBody;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+    use std::pin::Pin;
+
+    async fn next_chunk(body: &mut (impl MessageBody + Unpin)) -> web::Bytes {
+        futures_util::future::poll_fn(|cx| Pin::new(&mut *body).poll_next(cx))
+            .await
+            .unwrap()
+            .ok()
+            .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn streams_the_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = test_data(dir.path());
+        let app = test::init_service(App::new().app_data(data.clone()).service(events)).await;
+
+        // without a session or token
+        let req = TestRequest::get().uri("/events").to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let reader = User {
+            name: "reader".to_string(),
+            ..Default::default()
+        };
+        data.db.save_user("reader", &reader).unwrap();
+        let secret = data
+            .tokens
+            .create(
+                "reader",
+                "test".to_string(),
+                vec![TokenScope::ReadConfig],
+                None,
+            )
+            .unwrap();
+        let req = TestRequest::get()
+            .uri("/events")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", secret)))
+            .to_request();
+        let response = test::call_service(&app, req).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/event-stream"
+        );
+
+        let mut body = response.into_body();
+        assert_eq!(next_chunk(&mut body).await, ": open\n\n");
+        data.events.send(&Event::ConfigChanged);
+        let expected = format!(
+            "data: {}\n\n",
+            serde_json::to_string(&Event::ConfigChanged).unwrap()
+        );
+        assert_eq!(next_chunk(&mut body).await, expected.as_bytes());
+    }
+}
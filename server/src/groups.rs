@@ -0,0 +1,509 @@
+use crate::config::GroupConfig;
+use crate::invites::fill_routes;
+use crate::session::client_ip;
+use crate::tokens::authorized_user;
+use crate::webhooks::PeerInfo;
+use crate::{current_wg_config, delete_peer, logging, AppData};
+use actix_identity::Identity;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use serde::Deserialize;
+use shared::{BulkAction, TokenScope};
+use std::collections::HashSet;
+use tracing::{error, info};
+
+pub const MAX_TAG_LEN: usize = 32;
+const MAX_TAGS: usize = 8;
+// the wireguard apps take the file name as the name of the tunnel, they
+// allow 15 characters
+const MAX_FILE_NAME_LEN: usize = 15;
+
+// lowercase, so "Office" and "office" are one group
+pub fn valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.len() <= MAX_TAG_LEN
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+// trimmed, lowercased, sorted and without doubles, None if one isn't valid
+pub fn clean_tags(tags: Vec<String>) -> Option<Vec<String>> {
+    let mut tags = tags
+        .into_iter()
+        .map(|t| t.trim().to_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<_>>();
+    tags.sort();
+    tags.dedup();
+    if tags.len() > MAX_TAGS || !tags.iter().all(|t| valid_tag(t)) {
+        return None;
+    }
+    Some(tags)
+}
+
+// The routes of the first group of the peer that has some, if the peer has
+// none yet. Returns the quota of the first group with one.
+pub fn apply_defaults(data: &AppData, peer: &mut shared::wg_conf::Peer) -> Option<u64> {
+    let groups = peer
+        .tags
+        .iter()
+        .filter_map(|tag| data.config.groups.get(tag))
+        .collect::<Vec<&GroupConfig>>();
+    if peer.routes.is_none() {
+        peer.routes = groups
+            .iter()
+            .find_map(|g| g.routes.as_deref())
+            .and_then(|r| fill_routes(r, data.config.wireguard.subnet, data.interface_address));
+    }
+    groups.iter().find_map(|g| g.quota())
+}
+
+// A zip archive without compression, the configs are tiny. One `<name>.conf`
+// per peer.
+fn zip(files: &[(String, String)]) -> Vec<u8> {
+    let mut archive = vec![];
+    let mut directory = vec![];
+    for (name, content) in files {
+        let offset = archive.len() as u32;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(content.as_bytes());
+        let crc = hasher.finalize();
+        let size = content.len() as u32;
+        // version, flags, no compression, dos time and date (1980-01-01)
+        let common = |out: &mut Vec<u8>| {
+            out.extend_from_slice(&20u16.to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+            out.extend_from_slice(&0x21u16.to_le_bytes());
+            out.extend_from_slice(&crc.to_le_bytes());
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&size.to_le_bytes());
+            out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&0u16.to_le_bytes());
+        };
+
+        archive.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        common(&mut archive);
+        archive.extend_from_slice(name.as_bytes());
+        archive.extend_from_slice(content.as_bytes());
+
+        directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        directory.extend_from_slice(&20u16.to_le_bytes());
+        common(&mut directory);
+        // comment length, disk, internal and external attributes
+        directory.extend_from_slice(&[0; 10]);
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+    }
+
+    let directory_offset = archive.len() as u32;
+    let count = files.len() as u16;
+    archive.extend_from_slice(&directory);
+    archive.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    archive.extend_from_slice(&[0; 4]);
+    archive.extend_from_slice(&count.to_le_bytes());
+    archive.extend_from_slice(&count.to_le_bytes());
+    archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    archive.extend_from_slice(&directory_offset.to_le_bytes());
+    archive.extend_from_slice(&0u16.to_le_bytes());
+    archive
+}
+
+// what the wireguard apps take as a tunnel name, made unique with a number
+fn file_name(name: &str, taken: &mut HashSet<String>) -> String {
+    let base = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "_=+.-".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(MAX_FILE_NAME_LEN)
+        .collect::<String>();
+    let base = if base.is_empty() {
+        "peer".to_string()
+    } else {
+        base
+    };
+    let mut file = base.clone();
+    let mut number = 2;
+    while !taken.insert(file.clone()) {
+        let suffix = number.to_string();
+        let keep = base.len().min(MAX_FILE_NAME_LEN - suffix.len());
+        file = format!("{}{}", &base[..keep], suffix);
+        number += 1;
+    }
+    file
+}
+
+// ---- Apis ----
+
+#[post("/set_peer_tags")]
+async fn set_peer_tags(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    request_data: web::Json<shared::Request>,
+) -> impl Responder {
+    let username = match authorized_user(&req, &id, &data, TokenScope::ManagePeers) {
+        Some(username) => username,
+        None => return HttpResponse::Forbidden().body(""),
+    };
+    let (public_key, tags) = match request_data.0 {
+        shared::Request::SetPeerTags { public_key, tags } => (public_key, clean_tags(tags)),
+        _ => return HttpResponse::Ok().json(shared::Response::Failure),
+    };
+    let wg_config = current_wg_config(&data);
+    let (peer, tags) = match (wg_config.peer(&public_key), tags) {
+        (Some(peer), Some(tags)) => (peer, tags),
+        _ => return HttpResponse::Ok().json(shared::Response::Failure),
+    };
+
+    if let Err(e) = data.db.set_peer_tags(&peer.public_key, &tags) {
+        error!("Could not save the tags of peer {}: {}", peer.name, e);
+        return HttpResponse::Ok().json(shared::Response::Failure);
+    }
+    data.audit.change(
+        &username,
+        "peer.tags",
+        &peer.public_key,
+        Some(&peer.tags.join(",")),
+        Some(&tags.join(",")),
+        &client_ip(&req),
+    );
+    data.events.send(&shared::Event::ConfigChanged);
+    HttpResponse::Ok().json(shared::Response::WireGuardConf {
+        config: current_wg_config(&data),
+    })
+}
+
+// Runs the action on every peer of the selection, a peer that fails is
+// logged and the others still get it.
+#[post("/bulk_peers")]
+async fn bulk_peers(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    request_data: web::Json<shared::Request>,
+) -> impl Responder {
+    let username = match authorized_user(&req, &id, &data, TokenScope::ManagePeers) {
+        Some(username) => username,
+        None => return HttpResponse::Forbidden().body(""),
+    };
+    let (public_keys, action) = match request_data.0 {
+        shared::Request::BulkPeers {
+            public_keys,
+            action,
+        } => (public_keys, action),
+        _ => return HttpResponse::Ok().json(shared::Response::Failure),
+    };
+    // checked before anything is changed
+    let action = match action {
+        BulkAction::SetRoutes(Some(template)) if !template.trim().is_empty() => {
+            let template = template.trim();
+            match fill_routes(
+                template,
+                data.config.wireguard.subnet,
+                data.interface_address,
+            ) {
+                Some(routes) => BulkAction::SetRoutes(Some(routes)),
+                None => return HttpResponse::Ok().json(shared::Response::Failure),
+            }
+        }
+        BulkAction::SetRoutes(_) => BulkAction::SetRoutes(None),
+        BulkAction::AddTag(tag) | BulkAction::RemoveTag(tag)
+            if !valid_tag(&tag.trim().to_lowercase()) =>
+        {
+            return HttpResponse::Ok().json(shared::Response::Failure)
+        }
+        BulkAction::AddTag(tag) => BulkAction::AddTag(tag.trim().to_lowercase()),
+        BulkAction::RemoveTag(tag) => BulkAction::RemoveTag(tag.trim().to_lowercase()),
+        action => action,
+    };
+
+    let wg_config = current_wg_config(&data);
+    let peers = wg_config
+        .peers
+        .iter()
+        .filter(|p| public_keys.contains(&p.public_key))
+        .collect::<Vec<_>>();
+    let ip = client_ip(&req);
+    let mut failed = 0;
+    for peer in &peers {
+        let done = match &action {
+            BulkAction::Disable | BulkAction::Enable => {
+                let disabled = matches!(action, BulkAction::Disable);
+                let block_data = data.clone();
+                let changed = (*peer).clone();
+                let result = logging::block(move || {
+                    block_data
+                        .quotas
+                        .set_user_disabled(&block_data.wg, &changed, disabled)
+                })
+                .await
+                .unwrap_or_else(|e| Err(e.to_string()));
+                match result {
+                    Ok(None) => true,
+                    Ok(Some(changed_interface)) => {
+                        let (action, event) = if disabled {
+                            ("peer.disable", "peer.disabled")
+                        } else {
+                            ("peer.enable", "peer.enabled")
+                        };
+                        data.audit
+                            .change(&username, action, &peer.public_key, None, None, &ip);
+                        if changed_interface {
+                            data.webhooks.notify(
+                                event,
+                                &data.wg.interface,
+                                PeerInfo {
+                                    public_key: &peer.public_key,
+                                    name: &peer.name,
+                                    allowed_ips: &peer.allowed_ips.to_string(),
+                                },
+                            );
+                        }
+                        true
+                    }
+                    Err(e) => {
+                        error!("Could not update peer {}: {}", peer.name, e);
+                        false
+                    }
+                }
+            }
+            BulkAction::Remove => delete_peer(&data, &req, &username, peer).await,
+            BulkAction::SetRoutes(routes) => {
+                match data.db.set_peer_routes(&peer.public_key, routes.as_deref()) {
+                    Ok(()) => {
+                        data.audit.change(
+                            &username,
+                            "peer.routes",
+                            &peer.public_key,
+                            peer.routes.as_deref(),
+                            routes.as_deref(),
+                            &ip,
+                        );
+                        true
+                    }
+                    Err(e) => {
+                        error!("Could not save the routes of peer {}: {}", peer.name, e);
+                        false
+                    }
+                }
+            }
+            BulkAction::AddTag(tag) | BulkAction::RemoveTag(tag) => {
+                let mut tags = peer.tags.clone();
+                tags.retain(|t| t != tag);
+                if matches!(action, BulkAction::AddTag(_)) {
+                    tags.push(tag.clone());
+                }
+                match clean_tags(tags) {
+                    Some(tags) if tags == peer.tags => true,
+                    Some(tags) => match data.db.set_peer_tags(&peer.public_key, &tags) {
+                        Ok(()) => {
+                            data.audit.change(
+                                &username,
+                                "peer.tags",
+                                &peer.public_key,
+                                Some(&peer.tags.join(",")),
+                                Some(&tags.join(",")),
+                                &ip,
+                            );
+                            true
+                        }
+                        Err(e) => {
+                            error!("Could not save the tags of peer {}: {}", peer.name, e);
+                            false
+                        }
+                    },
+                    // too many tags
+                    None => false,
+                }
+            }
+        };
+        if !done {
+            failed += 1;
+        }
+    }
+    info!("{:?} on {} peers, {} failed", action, peers.len(), failed);
+    data.events.send(&shared::Event::ConfigChanged);
+
+    if failed > 0 {
+        return HttpResponse::Ok().json(shared::Response::Failure);
+    }
+    HttpResponse::Ok().json(shared::Response::WireGuardConf {
+        config: current_wg_config(&data),
+    })
+}
+
+#[derive(Deserialize)]
+struct Selection {
+    // comma separated public keys
+    peers: String,
+}
+
+// A get, so the browser can download it with a plain link, which limits how
+// many keys fit. Peers whose private key stays in the browser are left out.
+#[get("/download_peers")]
+async fn download_peers(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    selection: web::Query<Selection>,
+) -> impl Responder {
+    let username = match authorized_user(&req, &id, &data, TokenScope::DownloadConfigs) {
+        Some(username) => username,
+        None => return HttpResponse::Forbidden().body(""),
+    };
+    let public_keys = selection.peers.split(',').collect::<HashSet<_>>();
+    if public_keys.len() > shared::MAX_DOWNLOAD_PEERS {
+        return HttpResponse::BadRequest().body(format!(
+            "at most {} peers at a time",
+            shared::MAX_DOWNLOAD_PEERS
+        ));
+    }
+    let wg_config = current_wg_config(&data);
+    let peers = wg_config
+        .peers
+        .iter()
+        .filter(|p| public_keys.contains(p.public_key.as_str()) && !p.private_key.is_empty())
+        .collect::<Vec<_>>();
+    if peers.is_empty() {
+        return HttpResponse::NotFound().finish();
+    }
+    // the configs carry private keys, so every one is audited
+    let ip = client_ip(&req);
+    let mut taken = HashSet::new();
+    let files = peers
+        .into_iter()
+        .map(|p| {
+            data.audit
+                .record(&username, "peer.download", &p.public_key, &ip);
+            (
+                format!("{}.conf", file_name(&p.name, &mut taken)),
+                wg_config.peer_config(p),
+            )
+        })
+        .collect::<Vec<_>>();
+    HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header((
+            "Content-Disposition",
+            "attachment; filename=\"wg-configs.zip\"",
+        ))
+        .body(zip(&files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    }
+
+    // Reads the files back through the central directory, like unzip does,
+    // and checks them against their local headers.
+    fn unzip(archive: &[u8]) -> Vec<(String, String)> {
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(archive, end), 0x0605_4b50);
+        let count = u16_at(archive, end + 10) as usize;
+        assert_eq!(u16_at(archive, end + 8) as usize, count);
+        let directory_len = u32_at(archive, end + 12) as usize;
+        let mut entry = u32_at(archive, end + 16) as usize;
+        assert_eq!(entry + directory_len, end);
+
+        let mut files = vec![];
+        for _ in 0..count {
+            assert_eq!(u32_at(archive, entry), 0x0201_4b50);
+            // stored, not compressed
+            assert_eq!(u16_at(archive, entry + 10), 0);
+            let crc = u32_at(archive, entry + 16);
+            let size = u32_at(archive, entry + 20) as usize;
+            assert_eq!(u32_at(archive, entry + 24) as usize, size);
+            let name_len = u16_at(archive, entry + 28) as usize;
+            let extra_len = u16_at(archive, entry + 30) as usize;
+            let comment_len = u16_at(archive, entry + 32) as usize;
+            let offset = u32_at(archive, entry + 42) as usize;
+            let name = &archive[entry + 46..entry + 46 + name_len];
+
+            assert_eq!(u32_at(archive, offset), 0x0403_4b50);
+            assert_eq!(u16_at(archive, offset + 8), 0);
+            assert_eq!(u32_at(archive, offset + 14), crc);
+            assert_eq!(u32_at(archive, offset + 18) as usize, size);
+            assert_eq!(u16_at(archive, offset + 26) as usize, name_len);
+            let data = offset + 30 + name_len + u16_at(archive, offset + 28) as usize;
+            assert_eq!(&archive[offset + 30..offset + 30 + name_len], name);
+            let content = &archive[data..data + size];
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(content);
+            assert_eq!(hasher.finalize(), crc);
+
+            files.push((
+                String::from_utf8(name.to_vec()).unwrap(),
+                String::from_utf8(content.to_vec()).unwrap(),
+            ));
+            entry += 46 + name_len + extra_len + comment_len;
+        }
+        assert_eq!(entry, end);
+        files
+    }
+
+    #[test]
+    fn zips_can_be_read_back() {
+        let files = vec![
+            (
+                "laptop.conf".to_string(),
+                "[Interface]\nPrivateKey = a\n".to_string(),
+            ),
+            ("phone.conf".to_string(), "[Interface]\n".repeat(100)),
+            ("empty.conf".to_string(), String::new()),
+        ];
+        assert_eq!(unzip(&zip(&files)), files);
+        assert_eq!(unzip(&zip(&[])), vec![]);
+    }
+
+    #[test]
+    fn file_names_are_safe_tunnel_names() {
+        let mut taken = HashSet::new();
+        assert_eq!(file_name("Laptop", &mut taken), "Laptop");
+        assert_eq!(file_name("Anna's phone", &mut taken), "Anna_s_phone");
+        assert_eq!(file_name("../../etc/passwd", &mut taken), ".._.._etc_passw");
+        assert_eq!(file_name("Büro-PC", &mut taken), "B_ro-PC");
+        assert_eq!(file_name("", &mut taken), "peer");
+        assert_eq!(
+            file_name("a very long name indeed", &mut taken),
+            "a_very_long_nam"
+        );
+    }
+
+    #[test]
+    fn file_names_are_numbered_when_taken() {
+        let mut taken = HashSet::new();
+        assert_eq!(file_name("Peer", &mut taken), "Peer");
+        assert_eq!(file_name("Peer", &mut taken), "Peer2");
+        assert_eq!(file_name("Peer?", &mut taken), "Peer_");
+        assert_eq!(file_name("Peer", &mut taken), "Peer3");
+        // the number replaces the end of long names
+        assert_eq!(file_name("abcdefghijklmno", &mut taken), "abcdefghijklmno");
+        assert_eq!(file_name("abcdefghijklmnop", &mut taken), "abcdefghijklmn2");
+        for number in 3..10 {
+            assert_eq!(
+                file_name("abcdefghijklmno", &mut taken),
+                format!("abcdefghijklmn{}", number)
+            );
+        }
+        assert_eq!(file_name("abcdefghijklmno", &mut taken), "abcdefghijklm10");
+        // and skips names that are taken by other peers
+        assert_eq!(file_name("Office2", &mut taken), "Office2");
+        assert_eq!(file_name("Office", &mut taken), "Office");
+        assert_eq!(file_name("Office", &mut taken), "Office3");
+    }
+}
@@ -0,0 +1,180 @@
+use crate::config::Backend;
+use crate::db::Db;
+use crate::logging;
+use crate::wg::WireGuard;
+use crate::AppData;
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Serialize)]
+struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl From<Result<(), String>> for Check {
+    fn from(result: Result<(), String>) -> Self {
+        Check {
+            ok: result.is_ok(),
+            error: result.err(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+fn readiness(db: &Db, wg: &WireGuard, backend: Backend) -> Readiness {
+    let mut checks: BTreeMap<_, Check> = BTreeMap::new();
+    checks.insert("store", db.check().map_err(|e| e.to_string()).into());
+    checks.insert("interface", wg.check_interface().into());
+    let binary = match backend {
+        Backend::Wrapper => "wrapper",
+        Backend::Wg => "wg",
+    };
+    checks.insert(binary, wg.check_binary().into());
+
+    Readiness {
+        ready: checks.values().all(|c| c.ok),
+        checks,
+    }
+}
+
+fn response(readiness: Readiness) -> HttpResponse {
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+// ---- Apis ----
+
+// the process is up and answers
+#[get("/healthz")]
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": "ok" }))
+}
+
+// Everything needed to serve requests works, 503 with the failed checks if not.
+#[get("/readyz")]
+async fn readyz(data: web::Data<AppData>) -> HttpResponse {
+    let check_data = data.clone();
+    let checks = move || {
+        let backend = check_data.config.wireguard.backend;
+        readiness(&check_data.db, &check_data.wg, backend)
+    };
+    match logging::block(checks).await {
+        Ok(readiness) => response(readiness),
+        Err(e) => HttpResponse::ServiceUnavailable().json(serde_json::json!({
+            "ready": false,
+            "error": e.to_string(),
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WireGuardConfig;
+    use actix_web::body::to_bytes;
+    use actix_web::http::StatusCode;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    // a `wg` that prints a line for every command
+    fn fake_wg(dir: &Path, mode: u32) -> WireGuard {
+        let binary = dir.join("wg");
+        std::fs::write(&binary, "#!/bin/sh\necho \"$@\"\n").unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(mode)).unwrap();
+        let config = WireGuardConfig {
+            backend: Backend::Wg,
+            wg: binary,
+            interface: Some("wg0".to_string()),
+            ..Default::default()
+        };
+        WireGuard::new(&config).unwrap()
+    }
+
+    async fn body(readiness: Readiness) -> (StatusCode, serde_json::Value) {
+        let response = response(readiness);
+        let status = response.status();
+        let body = to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[actix_rt::test]
+    async fn ready_when_every_check_passes() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), None).unwrap();
+        let wg = fake_wg(dir.path(), 0o755);
+
+        let (status, body) = body(readiness(&db, &wg, Backend::Wg)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "ready": true,
+                "checks": {
+                    "interface": { "ok": true },
+                    "store": { "ok": true },
+                    "wg": { "ok": true },
+                },
+            })
+        );
+    }
+
+    #[actix_rt::test]
+    async fn lists_the_failed_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), None).unwrap();
+        db.conn().execute_batch("DROP TABLE health").unwrap();
+        // can't be run, so the interface can't be listed either
+        let wg = fake_wg(dir.path(), 0o644);
+
+        let (status, body) = body(readiness(&db, &wg, Backend::Wg)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["ready"], false);
+        let checks = body["checks"].as_object().unwrap();
+        assert_eq!(
+            checks.keys().collect::<Vec<_>>(),
+            ["interface", "store", "wg"]
+        );
+        for check in checks.values() {
+            assert_eq!(check["ok"], false);
+            assert!(!check["error"].as_str().unwrap().is_empty());
+        }
+        assert!(checks["wg"]["error"]
+            .as_str()
+            .unwrap()
+            .ends_with("is not executable"));
+        assert!(checks["store"]["error"]
+            .as_str()
+            .unwrap()
+            .contains("health"));
+    }
+
+    #[actix_rt::test]
+    async fn the_binary_check_is_named_after_the_backend() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), None).unwrap();
+        let wg = WireGuard::new(&WireGuardConfig {
+            wrapper: dir.path().join("missing"),
+            interface: Some("wg0".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let (status, body) = body(readiness(&db, &wg, Backend::Wrapper)).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["checks"]["store"]["ok"], true);
+        assert_eq!(body["checks"]["interface"]["ok"], false);
+        assert_eq!(body["checks"]["wrapper"]["ok"], false);
+        assert!(body["checks"].get("wg").is_none());
+    }
+}
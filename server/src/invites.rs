@@ -0,0 +1,624 @@
+use crate::db::Db;
+use crate::session::{client_ip, now, random_token};
+use crate::tokens::{authorized_user, hash};
+use crate::totp::qr_code;
+use crate::webhooks::PeerInfo;
+use crate::{create_peer, delete_peer, generated_peer, peer_summary, AppData};
+use actix_identity::Identity;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use ipnet::{IpNet, Ipv4Net};
+use rusqlite::{params, Row};
+use shared::{InviteInfo, TokenScope};
+use std::net::Ipv4Addr;
+use tracing::{error, info};
+
+// invites can be used for 30 days at most, their peers can live for a year
+const MAX_HOURS: u64 = 30 * 24;
+const MAX_PEER_HOURS: u64 = 365 * 24;
+const MAX_USES: u32 = 100;
+const MAX_NAME_LEN: usize = 64;
+// who removes the peers that expired, in the audit log
+const ACTOR: &str = "expiry";
+
+// what the peers made with an invite get
+struct Profile {
+    interface: String,
+    routes: Option<String>,
+    quota: Option<u64>,
+    peer_hours: Option<u64>,
+}
+
+// Codes that let people add a peer for their device themselves. Each one
+// carries the profile of the peers made with it and can be used a number of
+// times until it expires. Only the sha256 of the code is stored.
+pub struct Invites {
+    db: Db,
+}
+
+impl Invites {
+    pub fn new(db: Db) -> Self {
+        Self { db }
+    }
+
+    // returns the id and the code, expired and used up invites are removed on the way
+    fn create(
+        &self,
+        user: &str,
+        profile: &Profile,
+        uses: u32,
+        expires: u64,
+    ) -> rusqlite::Result<(String, String)> {
+        let id = random_token(6);
+        let bytes: Vec<u8> = (0..10).map(|_| rand::random::<u8>()).collect();
+        let code = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes);
+        let code = format!(
+            "{}-{}-{}-{}",
+            &code[..4],
+            &code[4..8],
+            &code[8..12],
+            &code[12..]
+        );
+        let conn = self.db.conn();
+        conn.execute(
+            "DELETE FROM invites WHERE expires <= ?1 OR uses_left = 0",
+            [now()],
+        )?;
+        conn.execute(
+            "INSERT INTO invites (id, hash, interface, routes, quota_bytes, peer_hours, uses_left,
+                                  created_by, created, expires)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                id,
+                hash(&normalize(&code)),
+                profile.interface,
+                profile.routes,
+                profile.quota,
+                profile.peer_hours,
+                uses,
+                user,
+                now(),
+                expires
+            ],
+        )?;
+        Ok((id, code))
+    }
+
+    fn list(&self) -> rusqlite::Result<Vec<InviteInfo>> {
+        let conn = self.db.conn();
+        let mut statement = conn.prepare(
+            "SELECT id, interface, routes, quota_bytes, peer_hours, uses_left, created_by, created,
+                    expires
+             FROM invites WHERE expires > ?1 AND uses_left > 0 ORDER BY created",
+        )?;
+        let invites = statement
+            .query_map([now()], invite_from_row)?
+            .collect::<Result<_, _>>();
+        invites
+    }
+
+    fn revoke(&self, id: &str) -> rusqlite::Result<bool> {
+        let removed = self
+            .db
+            .conn()
+            .execute("DELETE FROM invites WHERE id = ?1", [id])?;
+        Ok(removed > 0)
+    }
+
+    // Takes one use of the invite, None if the code is unknown, expired or used
+    // up. Returns the id of the invite and its profile.
+    fn redeem(&self, code: &str) -> rusqlite::Result<Option<(String, Profile)>> {
+        let key = hash(&normalize(code));
+        let conn = self.db.conn();
+        let taken = conn.execute(
+            "UPDATE invites SET uses_left = uses_left - 1
+             WHERE hash = ?1 AND expires > ?2 AND uses_left > 0",
+            params![key, now()],
+        )?;
+        if taken == 0 {
+            return Ok(None);
+        }
+        conn.query_row(
+            "SELECT id, interface, routes, quota_bytes, peer_hours FROM invites WHERE hash = ?1",
+            [key],
+            |row| {
+                let profile = Profile {
+                    interface: row.get(1)?,
+                    routes: row.get(2)?,
+                    quota: row.get(3)?,
+                    peer_hours: row.get(4)?,
+                };
+                Ok((row.get(0)?, profile))
+            },
+        )
+        .map(Some)
+    }
+
+    // gives back the use of an enrollment that failed
+    fn restore(&self, id: &str) {
+        if let Err(e) = self.db.conn().execute(
+            "UPDATE invites SET uses_left = uses_left + 1 WHERE id = ?1",
+            [id],
+        ) {
+            error!("Could not restore invite {}: {}", id, e);
+        }
+    }
+
+    // the peers of `interface` that live until `time` at most
+    fn expired_peers(
+        &self,
+        interface: &str,
+        time: u64,
+    ) -> rusqlite::Result<Vec<shared::wg_conf::Peer>> {
+        let conn = self.db.conn();
+        let mut statement = conn.prepare(
+            "SELECT p.public_key, p.name, p.allowed_ips, p.expires FROM peers p
+             JOIN interfaces i ON i.id = p.interface_id
+             WHERE i.name = ?1 AND p.expires <= ?2",
+        )?;
+        let peers = statement
+            .query_map(params![interface, time], |row| {
+                let mut peer = shared::wg_conf::Peer {
+                    public_key: row.get(0)?,
+                    name: row.get(1)?,
+                    expires: row.get(3)?,
+                    ..Default::default()
+                };
+                if let Ok(allowed_ips) = row.get::<_, String>(2)?.parse() {
+                    peer.allowed_ips = allowed_ips;
+                }
+                Ok(peer)
+            })?
+            .collect::<Result<_, _>>();
+        peers
+    }
+}
+
+// Removes the peers whose lifetime from their invite is over, run by the
+// traffic sampler.
+pub fn remove_expired_peers(data: &AppData) {
+    let interface = &data.wg.interface;
+    let peers = match data.invites.expired_peers(interface, now()) {
+        Ok(peers) => peers,
+        Err(e) => {
+            error!("Could not read the expired peers: {}", e);
+            return;
+        }
+    };
+    for peer in peers {
+        if let Err(e) = data
+            .db
+            .remove_peer(&peer.public_key, || data.wg.remove_peer(&peer))
+        {
+            error!("Could not remove expired peer {}: {}", peer.name, e);
+            continue;
+        }
+        info!("Removed peer {}, its lifetime is over", peer.name);
+        data.audit.change(
+            ACTOR,
+            "peer.expire",
+            &peer.public_key,
+            Some(&peer_summary(&peer)),
+            None,
+            "",
+        );
+        data.events.send(&shared::Event::PeerRemoved {
+            public_key: peer.public_key.clone(),
+        });
+        data.webhooks.notify(
+            "peer.expired",
+            interface,
+            PeerInfo {
+                public_key: &peer.public_key,
+                name: &peer.name,
+                allowed_ips: &peer.allowed_ips.to_string(),
+            },
+        );
+    }
+}
+
+fn invite_from_row(row: &Row) -> rusqlite::Result<InviteInfo> {
+    Ok(InviteInfo {
+        id: row.get(0)?,
+        interface: row.get(1)?,
+        routes: row.get(2)?,
+        quota: row.get(3)?,
+        peer_hours: row.get(4)?,
+        uses_left: row.get(5)?,
+        created_by: row.get(6)?,
+        created: row.get(7)?,
+        expires: row.get(8)?,
+    })
+}
+
+// codes are typed in, case and dashes don't matter
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+// `{subnet}` is the tunnel subnet and `{server}` the address of the server,
+// the result has to be a list of networks
+pub fn fill_routes(template: &str, subnet: Ipv4Net, server: Ipv4Addr) -> Option<String> {
+    let routes = template
+        .replace("{subnet}", &subnet.to_string())
+        .replace("{server}", &format!("{}/32", server));
+    let routes = routes
+        .split(',')
+        .map(|route| route.trim().parse::<IpNet>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    if routes.is_empty() {
+        return None;
+    }
+    Some(
+        routes
+            .iter()
+            .map(|route| route.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+}
+
+// ---- Apis ----
+
+// Invites make peers, so they need the rights to manage them.
+#[get("/invites")]
+async fn list_invites(req: HttpRequest, id: Identity, data: web::Data<AppData>) -> impl Responder {
+    if authorized_user(&req, &id, &data, TokenScope::ManagePeers).is_none() {
+        return HttpResponse::Forbidden().body("");
+    }
+    match data.invites.list() {
+        Ok(invites) => HttpResponse::Ok().json(shared::Response::Invites { invites }),
+        Err(e) => {
+            error!("Could not read the invites: {}", e);
+            HttpResponse::Ok().json(shared::Response::Failure)
+        }
+    }
+}
+
+#[post("/create_invite")]
+async fn create_invite(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    request_data: web::Json<shared::Request>,
+) -> impl Responder {
+    let username = match authorized_user(&req, &id, &data, TokenScope::ManagePeers) {
+        Some(username) => username,
+        None => return HttpResponse::Forbidden().body(""),
+    };
+    let (interface, routes, quota, uses, hours, peer_hours) = match request_data.0 {
+        shared::Request::CreateInvite {
+            interface,
+            routes,
+            quota,
+            uses,
+            hours,
+            peer_hours,
+        } => (interface, routes, quota, uses, hours, peer_hours),
+        _ => return HttpResponse::Ok().json(shared::Response::Failure),
+    };
+    // the server manages one interface, the peers can only go there
+    let interface = interface
+        .map(|i| i.trim().to_string())
+        .filter(|i| !i.is_empty())
+        .unwrap_or_else(|| data.wg.interface.clone());
+    if interface != data.wg.interface {
+        return HttpResponse::Ok().json(shared::Response::Failure);
+    }
+    let routes = routes
+        .map(|r| r.trim().to_string())
+        .filter(|r| !r.is_empty());
+    // checked now, so the invite can't fail later
+    let valid_routes = routes
+        .as_deref()
+        .map(|r| fill_routes(r, data.config.wireguard.subnet, data.interface_address).is_some())
+        .unwrap_or(true);
+    let valid_peer_hours = peer_hours
+        .map(|h| (1..=MAX_PEER_HOURS).contains(&h))
+        .unwrap_or(true);
+    if !valid_routes
+        || !valid_peer_hours
+        || !(1..=MAX_USES).contains(&uses)
+        || !(1..=MAX_HOURS).contains(&hours)
+    {
+        return HttpResponse::Ok().json(shared::Response::Failure);
+    }
+    // the usage is counted and the expired peers are removed by the traffic sampler
+    if (quota.is_some() || peer_hours.is_some()) && data.config.traffic.interval_secs == 0 {
+        return HttpResponse::Ok().json(shared::Response::Failure);
+    }
+
+    let profile = Profile {
+        interface,
+        routes,
+        quota,
+        peer_hours,
+    };
+    let expires = now() + hours * 60 * 60;
+    let created = data.invites.create(&username, &profile, uses, expires);
+    match created.and_then(|(id, code)| Ok((id, code, data.invites.list()?))) {
+        Ok((invite_id, code, invites)) => {
+            data.audit.change(
+                &username,
+                "invite.create",
+                &invite_id,
+                None,
+                Some(&format!(
+                    "{} uses for {} hours, interface {}, routes {}, quota {}, peers live {}",
+                    uses,
+                    hours,
+                    profile.interface,
+                    profile.routes.as_deref().unwrap_or("default"),
+                    profile
+                        .quota
+                        .map(|q| format!("{} bytes", q))
+                        .as_deref()
+                        .unwrap_or("none"),
+                    profile
+                        .peer_hours
+                        .map(|h| format!("{} hours", h))
+                        .as_deref()
+                        .unwrap_or("forever")
+                )),
+                &client_ip(&req),
+            );
+            HttpResponse::Ok().json(shared::Response::InviteCreated { code, invites })
+        }
+        Err(e) => {
+            error!("Could not create an invite: {}", e);
+            HttpResponse::Ok().json(shared::Response::Failure)
+        }
+    }
+}
+
+#[post("/revoke_invite")]
+async fn revoke_invite(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    request_data: web::Json<shared::Request>,
+) -> impl Responder {
+    let username = match authorized_user(&req, &id, &data, TokenScope::ManagePeers) {
+        Some(username) => username,
+        None => return HttpResponse::Forbidden().body(""),
+    };
+    let invite_id = match request_data.0 {
+        shared::Request::RevokeInvite { id } => id,
+        _ => return HttpResponse::Ok().json(shared::Response::Failure),
+    };
+    match data.invites.revoke(&invite_id) {
+        Ok(true) => {
+            data.audit
+                .record(&username, "invite.revoke", &invite_id, &client_ip(&req));
+        }
+        Ok(false) => {}
+        Err(e) => {
+            error!("Could not revoke invite {}: {}", invite_id, e);
+            return HttpResponse::Ok().json(shared::Response::Failure);
+        }
+    }
+    match data.invites.list() {
+        Ok(invites) => HttpResponse::Ok().json(shared::Response::Invites { invites }),
+        Err(e) => {
+            error!("Could not read the invites: {}", e);
+            HttpResponse::Ok().json(shared::Response::Failure)
+        }
+    }
+}
+
+// Public, the code is the permission. The new peer is made like with
+// `new_peer` and gets the profile of the invite. Wrong codes are throttled
+// per client address like failed logins, but counted apart from them.
+#[post("/enroll")]
+async fn enroll(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    request_data: web::Json<shared::Request>,
+) -> impl Responder {
+    let (code, name) = match request_data.0 {
+        shared::Request::Enroll { code, name } => (code, name.trim().to_string()),
+        _ => return HttpResponse::Ok().json(shared::Response::Failure),
+    };
+    if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+        return HttpResponse::Ok().json(shared::Response::Failure);
+    }
+    let ip = client_ip(&req);
+    // not a user name, so every address has its own
+    let throttle_key = format!("enroll@{}", ip);
+    if let Err(retry_after) = data
+        .enroll_throttle
+        .attempt(&ip, &throttle_key, &data.audit)
+    {
+        return HttpResponse::Ok().json(shared::Response::TooManyAttempts { retry_after });
+    }
+    let (invite_id, profile) = match data.invites.redeem(&code) {
+        Ok(Some(invite)) => invite,
+        Ok(None) => {
+            info!(
+                "Refused an unknown, expired or used up invite code from {}",
+                ip
+            );
+            return HttpResponse::Ok().json(shared::Response::Failure);
+        }
+        Err(e) => {
+            error!("Could not redeem an invite: {}", e);
+            return HttpResponse::Ok().json(shared::Response::Failure);
+        }
+    };
+    data.enroll_throttle.success(&ip, &throttle_key);
+    // the server was moved to another interface since
+    if profile.interface != data.wg.interface {
+        info!(
+            "Refused invite {}, it is for interface {}",
+            invite_id, profile.interface
+        );
+        data.invites.restore(&invite_id);
+        return HttpResponse::Ok().json(shared::Response::Failure);
+    }
+
+    let mut peer = match generated_peer(&data) {
+        Some(peer) => peer,
+        None => {
+            data.invites.restore(&invite_id);
+            return HttpResponse::Ok().json(shared::Response::Failure);
+        }
+    };
+    peer.name = name;
+    peer.routes = profile
+        .routes
+        .as_deref()
+        .and_then(|r| fill_routes(r, data.config.wireguard.subnet, data.interface_address));
+    let actor = format!("invite:{}", invite_id);
+    let (wg_config, peer) = match create_peer(&data, &req, &actor, peer).await {
+        Some(created) => created,
+        None => {
+            data.invites.restore(&invite_id);
+            return HttpResponse::Ok().json(shared::Response::Failure);
+        }
+    };
+    let config = wg_config.peer_config(&peer);
+    let complete = || {
+        if let Some(quota) = profile.quota {
+            if let Err(e) = data.quotas.set_limit(&peer.public_key, Some(quota)) {
+                error!("Could not set the quota of peer {}: {}", peer.name, e);
+                return None;
+            }
+        }
+        if let Some(hours) = profile.peer_hours {
+            if let Err(e) = data
+                .db
+                .set_peer_expiry(&peer.public_key, Some(now() + hours * 60 * 60))
+            {
+                error!("Could not set the expiry of peer {}: {}", peer.name, e);
+                return None;
+            }
+        }
+        let qr_code = qr_code(&config);
+        if qr_code.is_none() {
+            error!("Could not make the qr code of peer {}", peer.name);
+        }
+        qr_code
+    };
+    let qr_code = match complete() {
+        Some(qr_code) => qr_code,
+        None => {
+            // the invite is only used up once the peer is complete, else it
+            // would live without its quota or expiry
+            delete_peer(&data, &req, &actor, &peer).await;
+            data.invites.restore(&invite_id);
+            return HttpResponse::Ok().json(shared::Response::Failure);
+        }
+    };
+    info!("Peer {} enrolled with invite {}", peer.name, invite_id);
+    HttpResponse::Ok().json(shared::Response::SharedConfig {
+        name: peer.name,
+        config,
+        qr_code,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PubPrivKey;
+
+    fn setup() -> (Invites, Db, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), None).unwrap();
+        db.add_interface("wg0").unwrap();
+        (Invites::new(db.clone()), db, dir)
+    }
+
+    fn profile() -> Profile {
+        Profile {
+            interface: "wg0".to_string(),
+            routes: Some("{subnet}".to_string()),
+            quota: Some(1 << 30),
+            peer_hours: Some(24),
+        }
+    }
+
+    fn add_peer(db: &Db, public_key: &str, allowed_ips: &str) {
+        let key = PubPrivKey {
+            private_key: String::new(),
+            public_key: public_key.to_string(),
+            name: format!("peer {}", public_key),
+        };
+        db.save_peer("wg0", allowed_ips, &key).unwrap();
+    }
+
+    #[test]
+    fn redeems_the_profile_until_used_up() {
+        let (invites, _db, _dir) = setup();
+        let (id, code) = invites
+            .create("admin", &profile(), 2, now() + 3600)
+            .unwrap();
+
+        // typed in by hand
+        let typed = code.to_lowercase().replace('-', " ");
+        let (redeemed, profile) = invites.redeem(&typed).unwrap().unwrap();
+        assert_eq!(redeemed, id);
+        assert_eq!(profile.interface, "wg0");
+        assert_eq!(profile.routes.as_deref(), Some("{subnet}"));
+        assert_eq!(profile.quota, Some(1 << 30));
+        assert_eq!(profile.peer_hours, Some(24));
+        assert_eq!(invites.list().unwrap()[0].peer_hours, Some(24));
+
+        assert!(invites.redeem(&code).unwrap().is_some());
+        assert!(invites.redeem(&code).unwrap().is_none());
+        // a failed enrollment gives the use back
+        invites.restore(&id);
+        assert!(invites.redeem(&code).unwrap().is_some());
+        assert!(invites.redeem("AAAA-BBBB-CCCC-DDDD").unwrap().is_none());
+    }
+
+    #[test]
+    fn expired_invites_are_refused() {
+        let (invites, _db, _dir) = setup();
+        let (_, code) = invites.create("admin", &profile(), 1, now()).unwrap();
+        assert!(invites.redeem(&code).unwrap().is_none());
+        assert!(invites.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn finds_the_expired_peers() {
+        let (invites, db, _dir) = setup();
+        add_peer(&db, "forever", "10.0.0.2/32");
+        add_peer(&db, "soon", "10.0.0.3/32");
+        add_peer(&db, "later", "10.0.0.4/32");
+        db.set_peer_expiry("soon", Some(1000)).unwrap();
+        db.set_peer_expiry("later", Some(2000)).unwrap();
+
+        assert!(invites.expired_peers("wg0", 999).unwrap().is_empty());
+        let expired = invites.expired_peers("wg0", 1000).unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].public_key, "soon");
+        assert_eq!(expired[0].name, "peer soon");
+        assert_eq!(expired[0].allowed_ips.to_string(), "10.0.0.3/32");
+        assert_eq!(expired[0].expires, Some(1000));
+        assert_eq!(invites.expired_peers("wg0", 5000).unwrap().len(), 2);
+        assert!(invites.expired_peers("wg1", 5000).unwrap().is_empty());
+
+        let expiries = db.peer_expiries("wg0").unwrap();
+        assert_eq!(expiries.len(), 2);
+        assert_eq!(expiries["later"], 2000);
+    }
+
+    #[test]
+    fn fills_route_templates() {
+        let subnet = "10.0.0.0/24".parse().unwrap();
+        let server = Ipv4Addr::new(10, 0, 0, 1);
+        assert_eq!(
+            fill_routes("{subnet}, {server}", subnet, server).as_deref(),
+            Some("10.0.0.0/24, 10.0.0.1/32")
+        );
+        assert_eq!(
+            fill_routes("0.0.0.0/0,::/0", subnet, server).as_deref(),
+            Some("0.0.0.0/0, ::/0")
+        );
+        assert_eq!(fill_routes("{subnet}, nonsense", subnet, server), None);
+        assert_eq!(fill_routes("", subnet, server), None);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::config::EncryptionConfig;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use tracing::info;
+
+// stored values look like `enc:v1:<key id>:<base64 of nonce and ciphertext>`
+const PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+
+// Key encryption key for the private keys of the peers. The public key of
+// the peer is bound to the ciphertext, so values can't be swapped between
+// peers in the database.
+#[derive(Clone)]
+pub struct Kek {
+    cipher: Aes256Gcm,
+    // short hash of the key, stored with every value to tell which key it needs
+    pub id: String,
+}
+
+impl Kek {
+    // base64 of 32 random bytes, surrounding whitespace is ignored
+    pub fn from_base64(key: &str) -> Result<Self, String> {
+        let key = base64::decode(key.trim()).map_err(|e| format!("invalid base64: {}", e))?;
+        if key.len() != 32 {
+            return Err(format!("the key has {} bytes instead of 32", key.len()));
+        }
+        let id = Sha256::digest(&key)[..4]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        Ok(Self {
+            cipher: Aes256Gcm::new_from_slice(&key).map_err(|e| e.to_string())?,
+            id,
+        })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let key =
+            std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Self::from_base64(&key).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // reads the key file, or creates it with a new key if there is none
+    pub fn load_or_generate(path: &Path) -> Result<Self, String> {
+        if path.exists() {
+            return Self::from_file(path);
+        }
+
+        let mut key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        let key = base64::encode(key);
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", key))
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+        info!("Generated new encryption key in {}", path.display());
+        Self::from_base64(&key)
+    }
+
+    pub fn encrypt(&self, private_key: &str, public_key: &str) -> String {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let payload = Payload {
+            msg: private_key.as_bytes(),
+            aad: public_key.as_bytes(),
+        };
+        let mut sealed = nonce.to_vec();
+        sealed.extend(
+            self.cipher
+                .encrypt(Nonce::from_slice(&nonce), payload)
+                .expect("encrypting to a vec can't fail"),
+        );
+        format!("{}{}:{}", PREFIX, self.id, base64::encode(sealed))
+    }
+
+    // stored values from before encryption was configured are passed through
+    pub fn decrypt(&self, stored: &str, public_key: &str) -> Result<String, String> {
+        let (id, sealed) = match split(stored) {
+            Some(parts) => parts,
+            None => return Ok(stored.to_string()),
+        };
+        if id != self.id {
+            return Err(format!(
+                "encrypted with key {}, the configured key is {}",
+                id, self.id
+            ));
+        }
+
+        let sealed = base64::decode(sealed).map_err(|e| e.to_string())?;
+        if sealed.len() < NONCE_LEN {
+            return Err("the encrypted value is too short".to_string());
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: public_key.as_bytes(),
+        };
+        let plain = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), payload)
+            .map_err(|_| "the encrypted value does not match the key".to_string())?;
+        String::from_utf8(plain).map_err(|e| e.to_string())
+    }
+}
+
+// the configured key, if any
+pub fn load(config: &EncryptionConfig) -> Result<Option<Kek>, String> {
+    match (&config.key, &config.key_file) {
+        (Some(key), _) => Kek::from_base64(key).map(Some),
+        (None, Some(path)) => Kek::from_file(path).map(Some),
+        (None, None) => Ok(None),
+    }
+}
+
+// id of the key a stored value is encrypted with, None for plain text
+pub fn key_id(stored: &str) -> Option<&str> {
+    split(stored).map(|(id, _)| id)
+}
+
+fn split(stored: &str) -> Option<(&str, &str)> {
+    stored.strip_prefix(PREFIX)?.split_once(':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC_KEY: &str = "xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=";
+    const PRIVATE_KEY: &str = "yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=";
+
+    fn kek(byte: u8) -> Kek {
+        Kek::from_base64(&base64::encode([byte; 32])).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let kek = kek(1);
+        let stored = kek.encrypt(PRIVATE_KEY, PUBLIC_KEY);
+        assert!(stored.starts_with(&format!("{}{}:", PREFIX, kek.id)));
+        assert!(!stored.contains(PRIVATE_KEY));
+        assert_eq!(key_id(&stored), Some(kek.id.as_str()));
+        assert_eq!(kek.decrypt(&stored, PUBLIC_KEY).unwrap(), PRIVATE_KEY);
+        // a new nonce every time
+        assert_ne!(stored, kek.encrypt(PRIVATE_KEY, PUBLIC_KEY));
+    }
+
+    #[test]
+    fn plain_text_passes_through() {
+        assert_eq!(key_id(PRIVATE_KEY), None);
+        assert_eq!(
+            kek(1).decrypt(PRIVATE_KEY, PUBLIC_KEY).unwrap(),
+            PRIVATE_KEY
+        );
+    }
+
+    #[test]
+    fn rejects_other_keys_peers_and_changes() {
+        let stored = kek(1).encrypt(PRIVATE_KEY, PUBLIC_KEY);
+        assert!(kek(2).decrypt(&stored, PUBLIC_KEY).is_err());
+        // bound to the peer it belongs to
+        assert!(kek(1).decrypt(&stored, PRIVATE_KEY).is_err());
+
+        let (prefix, sealed) = stored.rsplit_once(':').unwrap();
+        let mut sealed = base64::decode(sealed).unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        let tampered = format!("{}:{}", prefix, base64::encode(&sealed));
+        assert!(kek(1).decrypt(&tampered, PUBLIC_KEY).is_err());
+        let short = format!("{}:{}", prefix, base64::encode([0u8; 4]));
+        assert!(kek(1).decrypt(&short, PUBLIC_KEY).is_err());
+    }
+
+    #[test]
+    fn checks_the_key_length() {
+        assert!(Kek::from_base64(&base64::encode([0u8; 16])).is_err());
+        assert!(Kek::from_base64("not base64!").is_err());
+        // whitespace of the key file is ignored
+        let key = format!(" {}\n", base64::encode([1u8; 32]));
+        assert_eq!(Kek::from_base64(&key).unwrap().id, kek(1).id);
+    }
+
+    #[test]
+    fn generates_the_key_file_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        let generated = Kek::load_or_generate(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions();
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&mode) & 0o777,
+            0o600
+        );
+        assert_eq!(Kek::load_or_generate(&path).unwrap().id, generated.id);
+        assert_eq!(Kek::from_file(&path).unwrap().id, generated.id);
+    }
+}
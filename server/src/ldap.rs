@@ -0,0 +1,312 @@
+use crate::auth::{AuthProvider, Outcome};
+use crate::config::LdapConfig;
+use crate::{get_user_by_name, save_user, AppData, Role, User};
+use actix_web::web;
+use ldap3::{ldap_escape, LdapConn, LdapConnSettings, LdapError, Scope, SearchEntry};
+use std::time::Duration;
+use tracing::{info, warn};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// What the directory told us about the user.
+struct DirectoryUser {
+    dn: String,
+    name: String,
+    groups: Vec<String>,
+}
+
+// Searches the user in the directory and binds with their dn and password.
+// The users are stored locally without a password, only to keep their role
+// and second factor.
+pub struct Ldap {
+    config: LdapConfig,
+}
+
+impl Ldap {
+    pub fn new(config: LdapConfig) -> Self {
+        Self { config }
+    }
+
+    // None if the user is not in the directory or the password is wrong
+    fn bind(&self, username: &str, password: &str) -> Result<Option<DirectoryUser>, LdapError> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(TIMEOUT)
+            .set_starttls(self.config.starttls);
+        let mut conn = LdapConn::with_settings(settings, &self.config.url)?;
+
+        if let Some(bind_dn) = &self.config.bind_dn {
+            let bind_password = self.config.bind_password.as_deref().unwrap_or("");
+            conn.with_timeout(TIMEOUT)
+                .simple_bind(bind_dn, bind_password)?
+                .success()?;
+        }
+
+        let filter = self.user_filter(username);
+        let attributes = vec![
+            self.config.username_attribute.as_str(),
+            self.config.group_attribute.as_str(),
+        ];
+        let (entries, _) = conn
+            .with_timeout(TIMEOUT)
+            .search(&self.config.base_dn, Scope::Subtree, &filter, attributes)?
+            .success()?;
+        // a filter matching several entries must not log in as the first of them
+        if entries.len() != 1 {
+            let _ = conn.unbind();
+            return Ok(None);
+        }
+        let entry = SearchEntry::construct(entries.into_iter().next().unwrap());
+
+        let result = conn
+            .with_timeout(TIMEOUT)
+            .simple_bind(&entry.dn, password)?;
+        let _ = conn.unbind();
+        if result.rc != 0 {
+            return Ok(None);
+        }
+
+        let name = attribute(&entry, &self.config.username_attribute)
+            .into_iter()
+            .next()
+            .unwrap_or_else(|| username.to_string());
+        Ok(Some(DirectoryUser {
+            groups: attribute(&entry, &self.config.group_attribute),
+            dn: entry.dn,
+            name,
+        }))
+    }
+
+    fn user_filter(&self, username: &str) -> String {
+        self.config
+            .user_filter
+            .replace("{username}", &ldap_escape(username))
+    }
+
+    // group dns are compared without case, like the directory does
+    fn role(&self, groups: &[String]) -> Option<Role> {
+        let roles = &self.config.roles;
+        if roles.admin.is_empty() && roles.viewer.is_empty() {
+            return Some(Role::Viewer);
+        }
+
+        let member_of = |mapped: &[String]| {
+            groups
+                .iter()
+                .any(|g| mapped.iter().any(|m| m.eq_ignore_ascii_case(g)))
+        };
+        if member_of(&roles.admin) {
+            Some(Role::Admin)
+        } else if member_of(&roles.viewer) {
+            Some(Role::Viewer)
+        } else {
+            None
+        }
+    }
+}
+
+impl AuthProvider for Ldap {
+    fn authenticate(&self, data: &web::Data<AppData>, username: &str, password: &str) -> Outcome {
+        // a bind without password is an anonymous bind, which most servers accept
+        if password.is_empty() {
+            return Outcome::Failure;
+        }
+
+        let directory_user = match self.bind(username, password) {
+            Ok(Some(directory_user)) => directory_user,
+            Ok(None) => return Outcome::Failure,
+            Err(e) => {
+                warn!("Ldap login of {} failed: {}", username, e);
+                return Outcome::Failure;
+            }
+        };
+        let role = match self.role(&directory_user.groups) {
+            Some(role) => role,
+            None => {
+                warn!(
+                    "Ldap login of {} refused, not in any of the configured groups",
+                    directory_user.name
+                );
+                return Outcome::Failure;
+            }
+        };
+
+        match directory_user_record(data, directory_user, role) {
+            Ok(user) => Outcome::Success(user),
+            Err(e) => {
+                warn!("Ldap login failed: {}", e);
+                Outcome::Failure
+            }
+        }
+    }
+}
+
+// Finds the user stored by an earlier login or creates it. A user with the
+// same name from somewhere else is never taken over.
+fn directory_user_record(
+    data: &web::Data<AppData>,
+    directory_user: DirectoryUser,
+    role: Role,
+) -> Result<User, String> {
+    let existing = data.db.users().unwrap_or_default().into_iter().find(|u| {
+        u.ldap_dn
+            .as_deref()
+            .map(|dn| dn.eq_ignore_ascii_case(&directory_user.dn))
+            .unwrap_or(false)
+    });
+
+    let user = match existing {
+        Some(user) => User { role, ..user },
+        None => {
+            if get_user_by_name(directory_user.name.clone(), data).is_some() {
+                return Err(format!(
+                    "the name {} is taken by another user",
+                    directory_user.name
+                ));
+            }
+            info!("Creating user {} on first ldap login", directory_user.name);
+            User {
+                name: directory_user.name,
+                role,
+                ldap_dn: Some(directory_user.dn),
+                ..Default::default()
+            }
+        }
+    };
+
+    save_user(data, &user.name, &user).map_err(|e| format!("could not save user: {}", e))?;
+    Ok(user)
+}
+
+// attribute names are case insensitive
+fn attribute(entry: &SearchEntry, name: &str) -> Vec<String> {
+    entry
+        .attrs
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, values)| values.clone())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RoleMapping;
+    use crate::test_data;
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+
+    const ADMINS: &str = "cn=admins,ou=groups,dc=example,dc=org";
+    const STAFF: &str = "cn=staff,ou=groups,dc=example,dc=org";
+
+    fn ldap(roles: RoleMapping) -> Ldap {
+        Ldap::new(LdapConfig {
+            roles,
+            ..Default::default()
+        })
+    }
+
+    fn groups(groups: &[&str]) -> Vec<String> {
+        groups.iter().map(|g| g.to_string()).collect()
+    }
+
+    #[test]
+    fn maps_groups_to_roles() {
+        let mapped = ldap(RoleMapping {
+            admin: vec![ADMINS.to_string()],
+            viewer: vec![STAFF.to_string()],
+        });
+        assert_eq!(mapped.role(&groups(&[STAFF, ADMINS])), Some(Role::Admin));
+        assert_eq!(mapped.role(&groups(&[STAFF])), Some(Role::Viewer));
+        assert_eq!(
+            mapped.role(&groups(&["CN=Admins,OU=Groups,DC=example,DC=org"])),
+            Some(Role::Admin)
+        );
+        assert_eq!(mapped.role(&groups(&["cn=other,dc=example,dc=org"])), None);
+        assert_eq!(mapped.role(&[]), None);
+
+        // without a mapping everybody is a viewer
+        let unmapped = ldap(RoleMapping::default());
+        assert_eq!(unmapped.role(&[]), Some(Role::Viewer));
+        assert_eq!(unmapped.role(&groups(&[ADMINS])), Some(Role::Viewer));
+    }
+
+    #[test]
+    fn attributes_are_found_without_case() {
+        let entry = SearchEntry {
+            dn: "uid=alice,dc=example,dc=org".to_string(),
+            attrs: HashMap::from([
+                ("uid".to_string(), vec!["alice".to_string()]),
+                ("memberOf".to_string(), groups(&[ADMINS, STAFF])),
+            ]),
+            bin_attrs: HashMap::new(),
+        };
+        assert_eq!(attribute(&entry, "UID"), ["alice"]);
+        assert_eq!(attribute(&entry, "memberof"), [ADMINS, STAFF]);
+        assert!(attribute(&entry, "mail").is_empty());
+    }
+
+    #[test]
+    fn escapes_the_username_in_the_filter() {
+        let ldap = Ldap::new(LdapConfig {
+            user_filter: "(&(objectClass=person)(uid={username}))".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(
+            ldap.user_filter("alice"),
+            "(&(objectClass=person)(uid=alice))"
+        );
+        assert_eq!(
+            ldap.user_filter("*)(uid=*"),
+            "(&(objectClass=person)(uid=\\2a\\29\\28uid=\\2a))"
+        );
+        assert_eq!(
+            ldap.user_filter("a\\b\0"),
+            "(&(objectClass=person)(uid=a\\5cb\\00))"
+        );
+    }
+
+    #[test]
+    fn empty_passwords_never_reach_the_directory() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let ldap = Ldap::new(LdapConfig {
+            url: format!("ldap://{}", listener.local_addr().unwrap()),
+            ..Default::default()
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let data = test_data(dir.path());
+
+        let outcome = ldap.authenticate(&data, "alice", "");
+        assert!(matches!(outcome, Outcome::Failure));
+        assert!(listener.accept().is_err());
+    }
+
+    #[test]
+    fn never_takes_over_local_users() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = test_data(dir.path());
+        let local = User {
+            name: "bob".to_string(),
+            ..Default::default()
+        };
+        data.db.save_user("bob", &local).unwrap();
+        let directory_user = |name: &str, dn: &str| DirectoryUser {
+            dn: dn.to_string(),
+            name: name.to_string(),
+            groups: vec![],
+        };
+
+        let taken = directory_user("bob", "uid=bob,dc=example,dc=org");
+        assert!(directory_user_record(&data, taken, Role::Admin).is_err());
+
+        let alice = directory_user("alice", "uid=alice,dc=example,dc=org");
+        let user = directory_user_record(&data, alice, Role::Admin).unwrap();
+        assert_eq!(user.ldap_dn.as_deref(), Some("uid=alice,dc=example,dc=org"));
+        // found again by the dn, with the role of the latest login
+        let again = directory_user("alice", "UID=alice,dc=example,dc=org");
+        let user = directory_user_record(&data, again, Role::Viewer).unwrap();
+        assert_eq!(user.name, "alice");
+        assert_eq!(user.role, Role::Viewer);
+        assert_eq!(data.db.users().unwrap().len(), 2);
+    }
+}
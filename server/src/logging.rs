@@ -0,0 +1,192 @@
+use crate::config::{LogConfig, LogFormat};
+use crate::metrics;
+use crate::session::random_token;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::BlockingError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{web, Error};
+use std::cell::RefCell;
+use std::future::{ready, Future, Ready};
+use std::io::IsTerminal;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tracing::{error, info, info_span, Instrument, Span};
+use tracing_subscriber::EnvFilter;
+
+pub const HEADER_NAME: &str = "x-request-id";
+
+thread_local! {
+    // set while the future of a request is polled, see `WithRequestId`
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+// id of the request handled on this thread, passed on to the backend
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.with(|id| id.borrow().clone())
+}
+
+fn with_request_id<R>(id: Option<String>, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<String>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            let previous = self.0.take();
+            REQUEST_ID.with(|id| *id.borrow_mut() = previous);
+        }
+    }
+
+    let _restore = Restore(REQUEST_ID.with(|current| current.replace(id)));
+    f()
+}
+
+// `web::block` keeping the request id and span, for backend calls on the
+// blocking thread
+pub async fn block<F, R>(f: F) -> Result<R, BlockingError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let id = request_id();
+    let span = Span::current();
+    web::block(move || span.in_scope(|| with_request_id(id, f))).await
+}
+
+// polls the future of a request with its id set for the thread
+struct WithRequestId<F> {
+    id: String,
+    future: Pin<Box<F>>,
+}
+
+impl<F: Future> Future for WithRequestId<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let id = Some(self.id.clone());
+        with_request_id(id, || self.future.as_mut().poll(cx))
+    }
+}
+
+// Events go to stdout, as text (colored on a terminal) or one json object per line. Log records of
+// the libraries are passed on, too. The level was checked with the config.
+pub fn init(config: &LogConfig) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(&config.level))
+        .with_ansi(std::io::stdout().is_terminal());
+    match config.format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_current_span(true)
+            .with_span_list(false)
+            .init(),
+    }
+}
+
+// Gives every request an id and a span carrying it, so everything logged
+// while handling it, including the calls of the wrapper, can be found by the
+// id. The id is sent back in the `X-Request-Id` header and handed to the
+// backend, which logs it with what it ran. Also counts the
+// requests for the metrics.
+pub struct RequestLog;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestLog
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestLogMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestLogMiddleware { service }))
+    }
+}
+
+pub struct RequestLogMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestLogMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let id = random_token(12);
+        let span = info_span!(
+            "request",
+            request_id = %id,
+            method = %req.method(),
+            path = %req.path()
+        );
+        let start = Instant::now();
+        let future = span.in_scope(|| with_request_id(Some(id.clone()), || self.service.call(req)));
+
+        let request_id = id.clone();
+        let future = async move {
+            let elapsed = || start.elapsed().as_millis() as u64;
+            match future.await {
+                Ok(mut response) => {
+                    metrics::record_request(&response, start.elapsed());
+                    info!(
+                        status = response.status().as_u16(),
+                        duration_ms = elapsed(),
+                        "Request finished"
+                    );
+                    if let Ok(value) = HeaderValue::from_str(&id) {
+                        response
+                            .headers_mut()
+                            .insert(HeaderName::from_static(HEADER_NAME), value);
+                    }
+                    Ok(response)
+                }
+                Err(e) => {
+                    error!(duration_ms = elapsed(), "Request failed: {}", e);
+                    Err(e)
+                }
+            }
+        }
+        .instrument(span);
+        Box::pin(WithRequestId {
+            id: request_id,
+            future: Box::pin(future),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_id_is_restored() {
+        assert_eq!(request_id(), None);
+        let inner = with_request_id(Some("outer".to_string()), || {
+            with_request_id(Some("inner".to_string()), request_id)
+        });
+        assert_eq!(inner.as_deref(), Some("inner"));
+        assert_eq!(request_id(), None);
+    }
+
+    #[actix_rt::test]
+    async fn blocking_calls_keep_the_request_id() {
+        let future = WithRequestId {
+            id: "abc".to_string(),
+            future: Box::pin(async { block(request_id).await.unwrap() }),
+        };
+        assert_eq!(future.await.as_deref(), Some("abc"));
+        assert_eq!(request_id(), None);
+    }
+}
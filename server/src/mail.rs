@@ -0,0 +1,328 @@
+use crate::config::{SmtpConfig, SmtpSecurity};
+use crate::session::client_ip;
+use crate::tokens::authorized_user;
+use crate::{current_wg_config, logging, AppData};
+use actix_identity::Identity;
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use lettre::message::header::ContentType;
+use lettre::message::{Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use qrcode::{Color, QrCode};
+use shared::TokenScope;
+use std::time::Duration;
+use tracing::{error, info};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+// pixels per module of the qr code and the blank modules around it
+const QR_SCALE: usize = 8;
+const QR_BORDER: usize = 4;
+
+// Sends the configs of the peers to their users through the smtp relay.
+pub struct Mailer {
+    config: SmtpConfig,
+    from: Mailbox,
+    transport: SmtpTransport,
+}
+
+impl Mailer {
+    pub fn new(config: SmtpConfig) -> Result<Self, String> {
+        let from = config.from.parse().map_err(|e| format!("{}", e))?;
+        let builder = match config.security {
+            SmtpSecurity::Tls => SmtpTransport::relay(&config.host),
+            SmtpSecurity::Starttls => SmtpTransport::starttls_relay(&config.host),
+            SmtpSecurity::None => Ok(SmtpTransport::builder_dangerous(&config.host)),
+        }
+        .map_err(|e| e.to_string())?;
+        let mut builder = builder.port(config.port()).timeout(Some(TIMEOUT));
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+        Ok(Self {
+            transport: builder.build(),
+            config,
+            from,
+        })
+    }
+
+    // the config as wg.conf and its qr code as wg.png, for the wireguard apps
+    pub fn send_peer_config(
+        &self,
+        to: Mailbox,
+        peer: &shared::wg_conf::Peer,
+        interface: &str,
+        peer_config: &str,
+    ) -> Result<(), String> {
+        let fill = |template: &str| {
+            template
+                .replace("{name}", &peer.name)
+                .replace("{address}", &peer.allowed_ips.to_string())
+                .replace("{interface}", interface)
+        };
+        let png = qr_png(peer_config)?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(fill(&self.config.subject))
+            .multipart(
+                MultiPart::mixed()
+                    .singlepart(SinglePart::plain(fill(&self.config.body)))
+                    .singlepart(
+                        Attachment::new("wg.conf".to_string())
+                            .body(peer_config.to_string(), ContentType::TEXT_PLAIN),
+                    )
+                    .singlepart(
+                        Attachment::new("wg.png".to_string())
+                            .body(png, ContentType::parse("image/png").unwrap()),
+                    ),
+            )
+            .map_err(|e| e.to_string())?;
+        self.transport.send(&message).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+// black modules on white, scaled up so phones can scan it from a screen
+fn qr_png(content: &str) -> Result<Vec<u8>, String> {
+    let code = QrCode::new(content).map_err(|e| e.to_string())?;
+    let modules = code.width();
+    let colors = code.to_colors();
+    let size = (modules + 2 * QR_BORDER) * QR_SCALE;
+    let mut pixels = vec![255u8; size * size];
+    for (i, color) in colors.iter().enumerate() {
+        if *color != Color::Dark {
+            continue;
+        }
+        let x = (i % modules + QR_BORDER) * QR_SCALE;
+        let y = (i / modules + QR_BORDER) * QR_SCALE;
+        for row in y..y + QR_SCALE {
+            pixels[row * size + x..row * size + x + QR_SCALE].fill(0);
+        }
+    }
+
+    let mut png = vec![];
+    let mut encoder = png::Encoder::new(&mut png, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&pixels))
+        .map_err(|e| e.to_string())?;
+    Ok(png)
+}
+
+// ---- Apis ----
+
+// The config holds the private key of the peer, so sending it is as much as
+// downloading it.
+#[post("/send_peer_config")]
+async fn send_peer_config(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    request_data: web::Json<shared::Request>,
+) -> impl Responder {
+    let username = match authorized_user(&req, &id, &data, TokenScope::DownloadConfigs) {
+        Some(username) => username,
+        None => return HttpResponse::Forbidden().body(""),
+    };
+    if data.mailer.is_none() {
+        return HttpResponse::NotFound().finish();
+    }
+    let (public_key, email) = match request_data.0 {
+        shared::Request::SendPeerConfig { public_key, email } => (public_key, email),
+        _ => return HttpResponse::Ok().json(shared::Response::Failure),
+    };
+    let to = match email.trim().parse::<Mailbox>() {
+        Ok(to) => to,
+        Err(_) => return HttpResponse::Ok().json(shared::Response::Failure),
+    };
+    let wg_config = current_wg_config(&data);
+    let peer = match wg_config.peer(&public_key) {
+        // the client keeps the private key of some peers, there is nothing to send
+        Some(peer) if !peer.private_key.is_empty() => peer.clone(),
+        _ => return HttpResponse::Ok().json(shared::Response::Failure),
+    };
+
+    let peer_config = wg_config.peer_config(&peer);
+    let recipient = to.email.to_string();
+    let send_data = data.clone();
+    let send_peer = peer.clone();
+    let sent = logging::block(move || {
+        let mailer = send_data.mailer.as_ref().unwrap();
+        mailer.send_peer_config(to, &send_peer, &send_data.wg.interface, &peer_config)
+    })
+    .await;
+    match sent {
+        Ok(Ok(())) => {
+            info!("Sent the config of peer {} to {}", peer.name, recipient);
+            data.audit.change(
+                &username,
+                "peer.send",
+                &peer.public_key,
+                None,
+                Some(&recipient),
+                &client_ip(&req),
+            );
+            HttpResponse::Ok().json(shared::Response::Success)
+        }
+        Ok(Err(e)) => {
+            error!("Could not send the config of peer {}: {}", peer.name, e);
+            HttpResponse::Ok().json(shared::Response::Failure)
+        }
+        Err(e) => {
+            error!("Could not send the config of peer {}: {}", peer.name, e);
+            HttpResponse::Ok().json(shared::Response::Failure)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    struct Mail {
+        commands: Vec<String>,
+        data: String,
+    }
+
+    // A relay that takes one mail and answers RCPT with `rcpt_reply`.
+    fn smtp_sink(rcpt_reply: &'static str) -> (u16, mpsc::Receiver<Mail>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, mails) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut mail = Mail {
+                commands: vec![],
+                data: String::new(),
+            };
+            stream.write_all(b"220 sink\r\n").unwrap();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 {
+                    break;
+                }
+                let command = line.trim_end().to_string();
+                let verb = command.split([' ', ':']).next().unwrap().to_uppercase();
+                mail.commands.push(command);
+                let reply = match verb.as_str() {
+                    "EHLO" => "250 sink",
+                    "RCPT" => rcpt_reply,
+                    "DATA" => {
+                        stream.write_all(b"354 go on\r\n").unwrap();
+                        loop {
+                            let mut line = String::new();
+                            reader.read_line(&mut line).unwrap();
+                            if line == ".\r\n" {
+                                break;
+                            }
+                            mail.data.push_str(&line);
+                        }
+                        "250 queued"
+                    }
+                    "QUIT" => {
+                        stream.write_all(b"221 bye\r\n").unwrap();
+                        break;
+                    }
+                    _ => "250 ok",
+                };
+                stream
+                    .write_all(format!("{}\r\n", reply).as_bytes())
+                    .unwrap();
+            }
+            let _ = sender.send(mail);
+        });
+        (port, mails)
+    }
+
+    fn mailer(port: u16) -> Mailer {
+        Mailer::new(SmtpConfig {
+            host: "127.0.0.1".to_string(),
+            port: Some(port),
+            security: SmtpSecurity::None,
+            from: "VPN <vpn@example.com>".to_string(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn peer() -> shared::wg_conf::Peer {
+        shared::wg_conf::Peer {
+            name: "laptop".to_string(),
+            allowed_ips: "10.0.0.2/32".parse().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn sends_the_config_and_qr_code() {
+        let (port, mails) = smtp_sink("250 ok");
+        let to = "alice@example.com".parse().unwrap();
+        mailer(port)
+            .send_peer_config(to, &peer(), "wg0", "[Interface]\nPrivateKey = secret\n")
+            .unwrap();
+
+        let mail = mails.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(mail
+            .commands
+            .contains(&"MAIL FROM:<vpn@example.com>".to_string()));
+        assert!(mail
+            .commands
+            .contains(&"RCPT TO:<alice@example.com>".to_string()));
+        assert!(mail
+            .data
+            .contains("Subject: WireGuard config for laptop\r\n"));
+        assert!(mail
+            .data
+            .contains("config for wg0, your address is 10.0.0.2/32."));
+        assert!(mail.data.contains("filename=\"wg.conf\""));
+        assert!(mail.data.contains("PrivateKey = secret"));
+        assert!(mail.data.contains("filename=\"wg.png\""));
+        assert!(mail.data.contains("Content-Type: image/png"));
+    }
+
+    #[test]
+    fn reports_refused_recipients() {
+        let (port, _mails) = smtp_sink("550 no such user");
+        let to = "bob@example.com".parse().unwrap();
+        let sent = mailer(port).send_peer_config(to, &peer(), "wg0", "[Interface]\n");
+        assert!(sent.unwrap_err().contains("no such user"));
+    }
+
+    #[test]
+    fn qr_codes_are_valid_pngs() {
+        let png = qr_png("[Interface]\nPrivateKey = secret\n").unwrap();
+        assert_eq!(
+            png[..8],
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n']
+        );
+        // the first chunk is the header with the size
+        assert_eq!(&png[12..16], b"IHDR");
+        let width = u32::from_be_bytes(png[16..20].try_into().unwrap());
+        let height = u32::from_be_bytes(png[20..24].try_into().unwrap());
+        let modules = QrCode::new("[Interface]\nPrivateKey = secret\n")
+            .unwrap()
+            .width();
+        assert_eq!(width as usize, (modules + 2 * QR_BORDER) * QR_SCALE);
+        assert_eq!(height, width);
+
+        let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        assert_eq!((info.width, info.height), (width, height));
+        assert_eq!(info.color_type, png::ColorType::Grayscale);
+        let pixel = |x: usize, y: usize| pixels[y * width as usize + x];
+        // the white border, then the dark corner of the top left finder pattern
+        let start = QR_BORDER * QR_SCALE;
+        assert_eq!(pixel(start - 1, start - 1), 255);
+        assert_eq!(pixel(start, start), 0);
+        assert_eq!(pixel(start + QR_SCALE - 1, start + QR_SCALE - 1), 0);
+        assert!(pixels.iter().all(|p| *p == 0 || *p == 255));
+    }
+}
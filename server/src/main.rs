@@ -1,6 +1,6 @@
 use actix_files::{Files, NamedFile};
-use actix_identity::{CookieIdentityPolicy, Identity, IdentityService};
-use actix_web::{cookie::SameSite, get, post, web, App, HttpResponse, HttpServer, Responder};
+use actix_identity::{Identity, IdentityService};
+use actix_web::{delete, get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
 use bcrypt::{hash, verify, DEFAULT_COST};
 use lazy_static::*;
 use regex::bytes::Regex;
@@ -9,18 +9,59 @@ use std::io::Write;
 use std::net::SocketAddrV4;
 use std::process::Command;
 use std::str;
-use tempfile::NamedTempFile;
+
+mod audit;
+mod auth;
+mod config;
+mod csrf;
+mod db;
+mod events;
+mod groups;
+mod health;
+mod invites;
+mod kek;
+mod ldap;
+mod logging;
+mod mail;
+mod metrics;
+mod oidc;
+mod quota;
+mod session;
+mod share;
+mod throttle;
+mod tls;
+mod tokens;
+mod totp;
+mod traffic;
+mod webhooks;
+mod wg;
+
+use audit::AuditLog;
+use auth::AuthProvider;
+use config::{Config, ListenAddr};
+use csrf::Csrf;
+use db::Db;
+use events::Events;
+use invites::Invites;
+use kek::Kek;
+use logging::RequestLog;
+use mail::Mailer;
+use metrics::HttpMetrics;
+use oidc::Oidc;
+use quota::Quotas;
+use session::{client_ip, SessionKeys, Sessions};
+use share::ShareLinks;
+use shared::TokenScope;
+use throttle::LoginThrottle;
+use tokens::{authorized_user, ApiTokens};
+use totp::{PendingLogins, Totp};
+use tracing::{error, info};
+use traffic::TrafficStats;
+use webhooks::{PeerInfo, Webhooks};
+use wg::WireGuard;
 
 lazy_static! {
     static ref DEFAULT_INTERFACE_REG: Regex = Regex::new("default.*dev (\\w*).*").unwrap();
-    static ref WG_INTERFACE_NAME_REG: Regex = Regex::new("interface: (\\w*).*").unwrap();
-    static ref WG_INTERFACE_NAME: String = wireguard_interface_name().unwrap_or_else(|| {
-        println!("Wireguard Interface not found");
-        std::process::exit(1)
-    });
-    static ref INTERFACE_ADDRESS: std::net::Ipv4Addr =
-        wireguard_interface_link((*WG_INTERFACE_NAME).clone()).unwrap();
-    static ref DEFAULT_LINK: String = default_device().unwrap();
 }
 
 fn default_device() -> Option<String> {
@@ -41,24 +82,6 @@ fn default_device() -> Option<String> {
     .ok()
 }
 
-fn wireguard_interface_name() -> Option<String> {
-    if let Some(cap) = WG_INTERFACE_NAME_REG.captures_iter(&wg_show()).next() {
-        match cap.get(1).map(|x| x.as_bytes().to_vec()) {
-            Some(name) => match String::from_utf8(name) {
-                Ok(s) => Some(s),
-                Err(_) => None,
-            },
-            None => None,
-        }
-    } else {
-        None
-    }
-}
-
-fn wireguard_interface_link(name: String) -> Result<std::net::Ipv4Addr, std::io::Error> {
-    get_iface_ip(name)
-}
-
 fn get_iface_ip(name: String) -> Result<std::net::Ipv4Addr, std::io::Error> {
     let ifaces = get_if_addrs::get_if_addrs()?;
     let err = Err(std::io::Error::new(
@@ -81,20 +104,28 @@ fn get_iface_ip(name: String) -> Result<std::net::Ipv4Addr, std::io::Error> {
 }
 
 fn get_user_by_name(username: String, app_data: &web::Data<AppData>) -> Option<User> {
-    if let Ok(users) = app_data.db.all::<User>() {
-        for (_, user) in users {
-            if user.name == username {
-                return Some(user);
-            }
+    match app_data.db.user_by_name(&username) {
+        Ok(user) => user,
+        Err(e) => {
+            error!("Could not load user {}: {}", username, e);
+            None
         }
     }
-    None
+}
+
+fn save_user(
+    app_data: &web::Data<AppData>,
+    old_name: &str,
+    user: &User,
+) -> Result<(), rusqlite::Error> {
+    app_data.db.save_user(old_name, user)
 }
 
 // ---- Apis ("/api/*") ----
 
 #[post("/login")]
 async fn login_request(
+    req: HttpRequest,
     data: web::Data<AppData>,
     login_data: web::Json<shared::Request>,
     id: Identity,
@@ -105,61 +136,162 @@ async fn login_request(
         _ => return web::Json(shared::Response::LoginFailure),
     };
 
-    // search for user with matching username
-    let user = match get_user_by_name(username, &data) {
+    // limit the attempts before doing any expensive work
+    let ip = client_ip(&req);
+    if let Err(retry_after) = data.throttle.attempt(&ip, &username, &data.audit) {
+        return web::Json(shared::Response::TooManyAttempts { retry_after });
+    }
+
+    // check the password with the local store or the directory
+    let user = match auth::authenticate(&data, &username, &password).await {
         Some(user) => user,
-        _ => return web::Json(shared::Response::LoginFailure),
+        None => {
+            data.audit.record("", "login.failure", &username, &ip);
+            return web::Json(shared::Response::LoginFailure);
+        }
     };
 
-    // check the password
-    if let Ok(result) = verify(&password, &user.hashed_pass) {
-        if result {
-            id.remember(user.name.to_owned());
-            return web::Json(shared::Response::LoginSuccess { session: user.name });
-        }
+    // the session only starts once the second factor checks out, the
+    // attempts are only reset then, too
+    if Totp::enabled(&user.totp) {
+        let token = data.pending_logins.start(&user.name);
+        return web::Json(shared::Response::TotpRequired { token });
     }
-
-    web::Json(shared::Response::LoginFailure)
+    data.throttle.success(&ip, &username);
+    data.sessions.login(&id, &req, &user.name);
+    data.audit.record(&user.name, "login", &user.name, &ip);
+    web::Json(shared::Response::LoginSuccess { session: user.name })
 }
 
 #[post("/logout")]
-async fn logout_request(id: Identity) -> impl Responder {
-    id.forget();
+async fn logout_request(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+) -> impl Responder {
+    if let Some(username) = data.sessions.user(&id) {
+        data.audit
+            .record(&username, "logout", &username, &client_ip(&req));
+    }
+    data.sessions.logout(&id);
     web::Json(shared::Response::Logout)
 }
 
+#[get("/sessions")]
+async fn list_sessions(id: Identity, data: web::Data<AppData>) -> impl Responder {
+    if let Some(username) = data.sessions.user(&id) {
+        let sessions = data.sessions.list(&id, &username);
+        HttpResponse::Ok().json(shared::Response::Sessions { sessions })
+    } else {
+        HttpResponse::Forbidden().body("")
+    }
+}
+
+#[post("/revoke_session")]
+async fn revoke_session(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    request_data: web::Json<shared::Request>,
+) -> impl Responder {
+    if let Some(username) = data.sessions.user(&id) {
+        match request_data.0 {
+            shared::Request::RevokeSession { id: handle } => {
+                if !data.sessions.revoke_by_handle(&username, &handle) {
+                    return web::Json(shared::Response::Failure);
+                }
+                data.audit
+                    .record(&username, "session.revoke", &handle, &client_ip(&req));
+                // the list may now be empty if the current session was revoked
+                let sessions = data.sessions.list(&id, &username);
+                web::Json(shared::Response::Sessions { sessions })
+            }
+            _ => web::Json(shared::Response::Failure),
+        }
+    } else {
+        web::Json(shared::Response::Failure)
+    }
+}
+
 #[get("/session")]
-async fn session_request(id: Identity) -> impl Responder {
-    if let Some(name) = id.identity() {
+async fn session_request(id: Identity, data: web::Data<AppData>) -> impl Responder {
+    if let Some(name) = data.sessions.user(&id) {
         web::Json(shared::Response::LoginSuccess { session: name })
     } else {
         web::Json(shared::Response::LoginFailure)
     }
 }
 
-fn wg_show() -> Vec<u8> {
-    Command::new("./wg_wrapper.bin")
-        .arg("show")
-        .output()
-        .unwrap()
-        .stdout
-}
-
 fn current_wg_config(data: &web::Data<AppData>) -> shared::wg_conf::WireGuardConf {
-    let output = Command::new("./wg_wrapper.bin")
-        .args(&["showconf", &(*WG_INTERFACE_NAME)])
-        .output()
-        .unwrap()
-        .stdout;
-
-    let config = str::from_utf8(&output).unwrap().to_string();
+    let config = data.wg.showconf();
     let mut wg_config = shared::wg_conf::WireGuardConf::from(config);
 
-    wg_config.interface.dns = *INTERFACE_ADDRESS;
-    if let Ok(ppkeys) = data.db.all::<PubPrivKey>() {
+    wg_config.interface.dns = data.interface_address;
+    let quotas = data
+        .quotas
+        .states(&data.wg.interface, session::now())
+        .unwrap_or_else(|e| {
+            error!("Could not read the quotas: {}", e);
+            vec![]
+        });
+    let (_, resets) = data.quotas.period(session::now());
+    // disabled peers are not on the interface, they are listed after the others
+    for state in quotas.iter().filter(|q| q.disabled || q.user_disabled) {
+        if wg_config
+            .peers
+            .iter()
+            .all(|p| p.public_key != state.public_key)
+        {
+            if let Ok(allowed_ips) = state.allowed_ips.parse() {
+                wg_config.peers.push(shared::wg_conf::Peer {
+                    public_key: state.public_key.clone(),
+                    allowed_ips,
+                    disabled: true,
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    for peer in &mut wg_config.peers {
+        if let Some(state) = quotas.iter().find(|q| q.public_key == peer.public_key) {
+            peer.quota = state.limit.map(|limit| shared::wg_conf::Quota {
+                limit,
+                used: state.used,
+                warn_percent: data.quotas.warn_percent(),
+                resets,
+            });
+        }
+    }
+
+    match data.db.peer_routes(&data.wg.interface) {
+        Ok(mut routes) => {
+            for peer in &mut wg_config.peers {
+                peer.routes = routes.remove(&peer.public_key);
+            }
+        }
+        Err(e) => error!("Could not read the routes of the peers: {}", e),
+    }
+    match data.db.peer_tags(&data.wg.interface) {
+        Ok(mut tags) => {
+            for peer in &mut wg_config.peers {
+                peer.tags = tags.remove(&peer.public_key).unwrap_or_default();
+            }
+        }
+        Err(e) => error!("Could not read the tags of the peers: {}", e),
+    }
+    match data.db.peer_expiries(&data.wg.interface) {
+        Ok(mut expiries) => {
+            for peer in &mut wg_config.peers {
+                peer.expires = expiries.remove(&peer.public_key);
+            }
+        }
+        Err(e) => error!("Could not read the expiries of the peers: {}", e),
+    }
+
+    if let Ok(ppkeys) = data.db.peers(&data.wg.interface) {
         for peer in &mut wg_config.peers {
             if let Some(ppk) = ppkeys
-                .values()
+                .iter()
                 .filter(|ppk| ppk.public_key == peer.public_key)
                 .collect::<Vec<_>>()
                 .first()
@@ -171,34 +303,36 @@ fn current_wg_config(data: &web::Data<AppData>) -> shared::wg_conf::WireGuardCon
     }
 
     wg_config.interface.address.set_ip(data.ip);
+    wg_config.interface.endpoint = data.config.wireguard.endpoint.as_ref().map(|host| {
+        let port = wg_config.interface.address.port();
+        format!("{}:{}", config::url_host(host), port)
+    });
     wg_config.interface.private_key = "(hidden)".to_string();
 
     wg_config
 }
 
-fn wg_add_peer(peer: &shared::wg_conf::Peer) -> Result<(), std::io::Error> {
-    let mut file = NamedTempFile::new()?;
-    writeln!(file, "{}", peer.to_string())?;
-    let path = file.path();
-    let _c = Command::new("./wg_wrapper.bin")
-        .args(&["add", &(*WG_INTERFACE_NAME), path.to_str().unwrap()])
-        .spawn()
-        .unwrap()
-        .wait();
-    Ok(())
-}
-
-fn wg_remove_peer(peer: &shared::wg_conf::Peer) {
-    let _c = Command::new("./wg_wrapper.bin")
-        .args(&["remove", &(*WG_INTERFACE_NAME), &peer.public_key])
-        .spawn()
-        .unwrap()
-        .wait();
+// first address of the tunnel subnet that is neither the interface nor taken by a peer
+// The addresses of the subnet no peer on the interface has, the database
+// leaves out those of its peers when it picks one.
+fn free_addresses<'a>(
+    data: &'a web::Data<AppData>,
+    wg_config: &'a shared::wg_conf::WireGuardConf,
+) -> impl Iterator<Item = ipnet::Ipv4Net> + 'a {
+    data.config
+        .wireguard
+        .subnet
+        .hosts()
+        .filter(move |ip| {
+            *ip != data.interface_address
+                && !wg_config.peers.iter().any(|p| p.allowed_ips.addr() == *ip)
+        })
+        .map(|ip| ipnet::Ipv4Net::new(ip, 32).unwrap())
 }
 
 #[get("/config")]
-async fn show_config(id: Identity, data: web::Data<AppData>) -> impl Responder {
-    if id.identity().is_some() {
+async fn show_config(req: HttpRequest, id: Identity, data: web::Data<AppData>) -> impl Responder {
+    if authorized_user(&req, &id, &data, TokenScope::ReadConfig).is_some() {
         let wg_config = current_wg_config(&data);
         HttpResponse::Ok().json(shared::Response::WireGuardConf { config: wg_config })
     } else {
@@ -206,80 +340,250 @@ async fn show_config(id: Identity, data: web::Data<AppData>) -> impl Responder {
     }
 }
 
-#[get("/new_peer")]
-async fn new_peer(id: Identity, data: web::Data<AppData>) -> impl Responder {
-    if id.identity().is_some() {
-        // get current config
-        let mut wg_config = current_wg_config(&data);
-        // get last peers allowed ip
-        let allowed_ips = if !wg_config.peers.is_empty() {
-            let addr = wg_config.peers.last().unwrap().allowed_ips.addr();
-            let o = addr.octets();
-            ipnet::Ipv4Net::new(std::net::Ipv4Addr::new(o[0], o[1], o[2], o[3] + 1), 32).unwrap()
-        } else {
-            ipnet::Ipv4Net::new(std::net::Ipv4Addr::new(10, 200, 100, 2), 32).unwrap()
+// The body with the tags is optional, scripts made before there were groups
+// don't send one.
+#[post("/new_peer")]
+async fn new_peer(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    request_data: Option<web::Json<shared::Request>>,
+) -> impl Responder {
+    if let Some(username) = authorized_user(&req, &id, &data, TokenScope::ManagePeers) {
+        let tags = match request_data.map(|r| r.into_inner()) {
+            None => vec![],
+            Some(shared::Request::NewPeer { tags }) => tags,
+            Some(_) => return HttpResponse::Ok().json(shared::Response::Failure),
         };
-        // generate private key
-        let output = Command::new("wg").arg("genkey").output().unwrap().stdout;
+        let (mut peer, tags) = match (generated_peer(&data), groups::clean_tags(tags)) {
+            (Some(peer), Some(tags)) => (peer, tags),
+            _ => return HttpResponse::Ok().json(shared::Response::Failure),
+        };
+        peer.tags = tags;
+        return add_peer(&data, &req, &username, peer).await;
+    }
+    HttpResponse::Forbidden().body("")
+}
 
-        let mut private_key = str::from_utf8(&output).unwrap().to_string();
-        private_key.pop(); // remove linefeed
+// a peer with a new key pair from the backend, None if it could not make one
+fn generated_peer(data: &AppData) -> Option<shared::wg_conf::Peer> {
+    let (private_key, public_key) = match data.wg.key_pair() {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("Could not generate a key pair: {}", e);
+            return None;
+        }
+    };
+    let mut peer = shared::wg_conf::Peer::new();
+    peer.private_key = private_key;
+    peer.public_key = public_key;
+    Some(peer)
+}
 
-        let mut new_peer = shared::wg_conf::Peer::new();
-        new_peer.allowed_ips = allowed_ips;
-        // generate public key
-        new_peer.set_private_key(&private_key);
+// The key pair was made by the client, only the public key is sent and
+// stored. The config is put together by the client with its private key.
+#[post("/new_peer_with_key")]
+async fn new_peer_with_key(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    request_data: web::Json<shared::Request>,
+) -> impl Responder {
+    if let Some(username) = authorized_user(&req, &id, &data, TokenScope::ManagePeers) {
+        let (public_key, tags) = match request_data.0 {
+            shared::Request::NewPeerWithKey { public_key, tags } => {
+                (public_key.trim().to_string(), groups::clean_tags(tags))
+            }
+            _ => return HttpResponse::Ok().json(shared::Response::Failure),
+        };
+        let tags = match tags {
+            Some(tags) if is_wg_key(&public_key) => tags,
+            _ => return HttpResponse::Ok().json(shared::Response::Failure),
+        };
 
-        new_peer.name = format!("Peer {}", wg_config.peers.len() + 1);
+        let mut peer = shared::wg_conf::Peer::new();
+        peer.public_key = public_key;
+        peer.tags = tags;
 
-        match data.db.save_with_id(
-            &PubPrivKey {
-                private_key: new_peer.private_key.clone(),
-                public_key: new_peer.public_key.clone(),
-                name: new_peer.name.clone(),
-            },
-            &new_peer.allowed_ips.to_string(),
-        ) {
-            Ok(_) => {}
-            Err(e) => println!("Could not save PubPrivKey {}", e),
-        }
+        return add_peer(&data, &req, &username, peer).await;
+    }
+    HttpResponse::Forbidden().body("")
+}
 
-        new_peer.endpoint = SocketAddrV4::new(data.ip, wg_config.interface.address.port());
+// base64 of 32 bytes, like `wg` prints keys
+fn is_wg_key(key: &str) -> bool {
+    key.len() == 44 && base64::decode(key).map(|k| k.len() == 32).unwrap_or(false)
+}
 
-        match wg_add_peer(&new_peer) {
-            Ok(_) => {}
-            Err(e) => println!("Ehh: {}", e),
+async fn add_peer(
+    data: &web::Data<AppData>,
+    req: &HttpRequest,
+    username: &str,
+    peer: shared::wg_conf::Peer,
+) -> HttpResponse {
+    match create_peer(data, req, username, peer).await {
+        Some((mut wg_config, peer)) => {
+            wg_config.peers.push(peer);
+            HttpResponse::Ok().json(shared::Response::WireGuardConf { config: wg_config })
         }
+        None => HttpResponse::Ok().json(shared::Response::Failure),
+    }
+}
 
-        wg_config.peers.push(new_peer);
+// Gives the peer an address and a name if it has none and the defaults of its
+// groups, the peer is only stored if the kernel took it. Returns the config
+// from before and the peer.
+async fn create_peer(
+    data: &web::Data<AppData>,
+    req: &HttpRequest,
+    username: &str,
+    mut peer: shared::wg_conf::Peer,
+) -> Option<(shared::wg_conf::WireGuardConf, shared::wg_conf::Peer)> {
+    // get current config
+    let wg_config = current_wg_config(data);
+    if wg_config
+        .peers
+        .iter()
+        .any(|p| p.public_key == peer.public_key)
+    {
+        return None;
+    }
+    if peer.name.is_empty() {
+        peer.name = format!("Peer {}", wg_config.peers.len() + 1);
+    }
+
+    peer.endpoint = SocketAddrV4::new(data.ip, wg_config.interface.address.port());
+    let quota = groups::apply_defaults(data, &mut peer);
 
-        return HttpResponse::Ok().json(shared::Response::WireGuardConf { config: wg_config });
+    let key = PubPrivKey {
+        private_key: peer.private_key.clone(),
+        public_key: peer.public_key.clone(),
+        name: peer.name.clone(),
+    };
+    // the database stays locked while the backend runs, not on a worker
+    let add_data = data.clone();
+    let taken = wg_config.clone();
+    let added = logging::block(move || {
+        let added = add_data.db.add_peer(
+            &add_data.wg.interface,
+            free_addresses(&add_data, &taken),
+            &key,
+            |address| {
+                peer.allowed_ips = address;
+                add_data.wg.add_peer(&peer)
+            },
+        );
+        (added, peer)
+    })
+    .await;
+    let peer = match added {
+        Ok((Ok(Some(_)), peer)) => peer,
+        Ok((Ok(None), _)) => {
+            error!("Could not add peer: no free address left");
+            return None;
+        }
+        Ok((Err(e), _)) => {
+            error!("Could not add peer: {}", e);
+            return None;
+        }
+        Err(e) => {
+            error!("Could not add peer: {}", e);
+            return None;
+        }
+    };
+    if let Err(e) = data
+        .db
+        .set_peer_routes(&peer.public_key, peer.routes.as_deref())
+    {
+        error!("Could not save the routes of peer {}: {}", peer.name, e);
     }
-    HttpResponse::Forbidden().body("")
+    if let Err(e) = data.db.set_peer_tags(&peer.public_key, &peer.tags) {
+        error!("Could not save the tags of peer {}: {}", peer.name, e);
+    }
+    if quota.is_some() {
+        if let Err(e) = data.quotas.set_limit(&peer.public_key, quota) {
+            error!("Could not set the quota of peer {}: {}", peer.name, e);
+        }
+    }
+    data.audit.change(
+        username,
+        "peer.add",
+        &peer.public_key,
+        None,
+        Some(&peer_summary(&peer)),
+        &client_ip(req),
+    );
+    // the new peer is the last one on the interface, before the disabled
+    // peers that are listed after them
+    let index = wg_config.peers.iter().filter(|p| !p.disabled).count();
+    data.events.send(&shared::Event::PeerAdded {
+        index,
+        peer: peer.clone(),
+    });
+    data.webhooks.notify(
+        "peer.created",
+        &data.wg.interface,
+        PeerInfo {
+            public_key: &peer.public_key,
+            name: &peer.name,
+            allowed_ips: &peer.allowed_ips.to_string(),
+        },
+    );
+
+    Some((wg_config, peer))
+}
+
+// how a peer shows up in the audit log
+fn peer_summary(peer: &shared::wg_conf::Peer) -> String {
+    format!("{} ({})", peer.name, peer.allowed_ips)
 }
 
 #[post("/update_peer_name")]
 async fn update_peer_name(
+    req: HttpRequest,
     id: Identity,
     data: web::Data<AppData>,
     request_data: web::Json<shared::Request>,
 ) -> impl Responder {
-    if id.identity().is_some() {
+    if let Some(username) = authorized_user(&req, &id, &data, TokenScope::ManagePeers) {
         match request_data.0 {
-            shared::Request::UpdatePeerName { index, name } => {
+            shared::Request::UpdatePeerName { public_key, name } => {
                 let mut wg_config = current_wg_config(&data);
 
-                let peer = &mut wg_config.peers[index];
-                peer.name = name;
+                let peer = match wg_config
+                    .peers
+                    .iter_mut()
+                    .find(|p| p.public_key == public_key)
+                {
+                    Some(peer) => peer,
+                    None => return web::Json(shared::Response::Failure),
+                };
+                let old_name = std::mem::replace(&mut peer.name, name);
 
-                let _ = data.db.save_with_id(
+                if let Err(e) = data.db.save_peer(
+                    &data.wg.interface,
+                    &peer.allowed_ips.to_string(),
                     &PubPrivKey {
                         private_key: peer.private_key.clone(),
                         public_key: peer.public_key.clone(),
                         name: peer.name.clone(),
                     },
-                    &peer.allowed_ips.to_string(),
+                ) {
+                    error!("Could not save peer: {}", e);
+                    return web::Json(shared::Response::Failure);
+                }
+                data.audit.change(
+                    &username,
+                    "peer.rename",
+                    &peer.public_key,
+                    Some(&old_name),
+                    Some(&peer.name),
+                    &client_ip(&req),
                 );
+                data.events.send(&shared::Event::PeerRenamed {
+                    public_key: peer.public_key.clone(),
+                    name: peer.name.clone(),
+                });
                 web::Json(shared::Response::Success)
             }
             _ => web::Json(shared::Response::Failure),
@@ -289,13 +593,63 @@ async fn update_peer_name(
     }
 }
 
+// Sets the monthly quota of the peer, a peer that was disabled for its quota
+// is enabled again right away when there is room now.
+#[post("/set_peer_quota")]
+async fn set_peer_quota(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    request_data: web::Json<shared::Request>,
+) -> impl Responder {
+    let username = match authorized_user(&req, &id, &data, TokenScope::ManagePeers) {
+        Some(username) => username,
+        None => return HttpResponse::Forbidden().body(""),
+    };
+    let (public_key, limit) = match request_data.0 {
+        shared::Request::SetPeerQuota { public_key, limit } => (public_key, limit),
+        _ => return HttpResponse::Ok().json(shared::Response::Failure),
+    };
+    // the usage is counted by the traffic sampler
+    if limit.is_some() && data.config.traffic.interval_secs == 0 {
+        return HttpResponse::Ok().json(shared::Response::Failure);
+    }
+    let wg_config = current_wg_config(&data);
+    let peer = match wg_config.peer(&public_key) {
+        Some(peer) => peer,
+        None => return HttpResponse::Ok().json(shared::Response::Failure),
+    };
+
+    if let Err(e) = data.quotas.set_limit(&peer.public_key, limit) {
+        error!("Could not save peer: {}", e);
+        return HttpResponse::Ok().json(shared::Response::Failure);
+    }
+    let bytes = |quota: Option<u64>| quota.map(|q| q.to_string());
+    data.audit.change(
+        &username,
+        "peer.quota",
+        &peer.public_key,
+        bytes(peer.quota.as_ref().map(|q| q.limit)).as_deref(),
+        bytes(limit).as_deref(),
+        &client_ip(&req),
+    );
+    data.events.send(&shared::Event::ConfigChanged);
+
+    let enforce_data = data.clone();
+    let _ = logging::block(move || enforce_data.quotas.enforce(&enforce_data)).await;
+    HttpResponse::Ok().json(shared::Response::WireGuardConf {
+        config: current_wg_config(&data),
+    })
+}
+
 #[post("/update_user")]
 async fn update_user(
+    req: HttpRequest,
     id: Identity,
     data: web::Data<AppData>,
     request_data: web::Json<shared::Request>,
 ) -> impl Responder {
-    if let Some(username) = id.identity() {
+    if let Some(username) = data.sessions.user(&id) {
         let (name, old_password, new_password, password_confirmation) = match request_data.0 {
             shared::Request::UpdateUser {
                 name,
@@ -314,16 +668,51 @@ async fn update_user(
             return web::Json(shared::Response::Failure);
         }
 
-        let user = match get_user_by_name(username, &data) {
+        let user = match get_user_by_name(username.clone(), &data) {
             Some(user) => user,
             _ => return web::Json(shared::Response::Failure),
         };
 
-        if verify(&old_password, &user.hashed_pass).is_ok() && new_password == password_confirmation
-        {
+        if name != username && get_user_by_name(name.clone(), &data).is_some() {
+            return web::Json(shared::Response::Failure);
+        }
+
+        if new_password != password_confirmation {
+            return web::Json(shared::Response::Failure);
+        }
+
+        let ip = client_ip(&req);
+        if let Err(retry_after) = data.throttle.attempt(&ip, &username, &data.audit) {
+            return web::Json(shared::Response::TooManyAttempts { retry_after });
+        }
+
+        if verify(&old_password, &user.hashed_pass).unwrap_or(false) {
+            data.throttle.success(&ip, &username);
             match hash(&new_password, DEFAULT_COST) {
                 Ok(hashed_pass) => {
-                    let _ = data.db.save_with_id(&User { name, hashed_pass }, "user");
+                    let user = User {
+                        name,
+                        hashed_pass,
+                        ..user
+                    };
+                    if let Err(e) = save_user(&data, &username, &user) {
+                        error!("Could not save user: {}", e);
+                        return web::Json(shared::Response::Failure);
+                    }
+                    // only once the new password is stored, the other sessions stay otherwise
+                    data.sessions.revoke_others(&id, &user.name);
+                    if user.name != username {
+                        data.audit.change(
+                            &user.name,
+                            "user.rename",
+                            &user.name,
+                            Some(&username),
+                            Some(&user.name),
+                            &ip,
+                        );
+                    }
+                    data.audit
+                        .record(&user.name, "user.password", &user.name, &ip);
                     return web::Json(shared::Response::Success);
                 }
                 Err(_) => return web::Json(shared::Response::Failure),
@@ -336,37 +725,63 @@ async fn update_user(
     }
 }
 
-#[get("/download_peer/{index}")]
+// The keys are base64, a `/` in them would split a path segment.
+#[derive(Deserialize)]
+struct PeerKey {
+    public_key: String,
+}
+
+#[get("/download_peer")]
 async fn download_peer_file(
+    req: HttpRequest,
     id: Identity,
     data: web::Data<AppData>,
-    index: web::Path<usize>,
+    peer_key: web::Query<PeerKey>,
 ) -> Result<NamedFile, std::io::Error> {
-    if id.identity().is_some() {
+    if authorized_user(&req, &id, &data, TokenScope::DownloadConfigs).is_some() {
         let wg_config = current_wg_config(&data);
-        let peer = &wg_config.peers[index.into_inner()];
+        let peer = match wg_config.peer(&peer_key.public_key) {
+            Some(peer) => peer,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "no such peer",
+                ))
+            }
+        };
+        // empty if it could not be decrypted or the client keeps it, a config
+        // without it is useless
+        if peer.private_key.is_empty() {
+            return Err(std::io::Error::other("private key not available"));
+        }
         let mut tmp = tempfile::tempfile().unwrap();
         let _res = write!(tmp, "{}", wg_config.peer_config(peer));
         Ok(NamedFile::from_file(tmp, "wg.conf")?)
     } else {
-        Err(std::io::Error::new(std::io::ErrorKind::Other, "No Session"))
+        Err(std::io::Error::other("No Session"))
     }
 }
 
-#[get("/remove_peer/{index}")]
+#[delete("/remove_peer")]
 async fn remove_peer(
+    req: HttpRequest,
     id: Identity,
     data: web::Data<AppData>,
-    index: web::Path<usize>,
+    peer_key: web::Query<PeerKey>,
 ) -> impl Responder {
-    if id.identity().is_some() {
+    if let Some(username) = authorized_user(&req, &id, &data, TokenScope::ManagePeers) {
         let mut wg_config = current_wg_config(&data);
-        let peer = &wg_config.peers.remove(index.into_inner());
-        wg_remove_peer(peer);
-
-        match data.db.delete(&peer.allowed_ips.to_string()) {
-            Ok(_) => {}
-            Err(e) => println!("Could not delete peer: {}", e),
+        let index = match wg_config
+            .peers
+            .iter()
+            .position(|p| p.public_key == peer_key.public_key)
+        {
+            Some(index) => index,
+            None => return HttpResponse::NotFound().body(""),
+        };
+        let peer = &wg_config.peers.remove(index);
+        if !delete_peer(&data, &req, &username, peer).await {
+            return HttpResponse::Ok().json(shared::Response::Failure);
         }
 
         HttpResponse::Ok().json(shared::Response::WireGuardConf { config: wg_config })
@@ -375,8 +790,57 @@ async fn remove_peer(
     }
 }
 
-async fn index() -> impl Responder {
-    NamedFile::open("./client/index.html")
+// removes the peer from the interface and the database, false if that failed
+async fn delete_peer(
+    data: &web::Data<AppData>,
+    req: &HttpRequest,
+    username: &str,
+    peer: &shared::wg_conf::Peer,
+) -> bool {
+    let remove_data = data.clone();
+    let removed = peer.clone();
+    let result = logging::block(move || {
+        remove_data
+            .db
+            .remove_peer(&removed.public_key, || remove_data.wg.remove_peer(&removed))
+    })
+    .await;
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            error!("Could not remove peer: {}", e);
+            return false;
+        }
+        Err(e) => {
+            error!("Could not remove peer: {}", e);
+            return false;
+        }
+    }
+    data.audit.change(
+        username,
+        "peer.remove",
+        &peer.public_key,
+        Some(&peer_summary(peer)),
+        None,
+        &client_ip(req),
+    );
+    data.events.send(&shared::Event::PeerRemoved {
+        public_key: peer.public_key.clone(),
+    });
+    data.webhooks.notify(
+        "peer.removed",
+        &data.wg.interface,
+        PeerInfo {
+            public_key: &peer.public_key,
+            name: &peer.name,
+            allowed_ips: &peer.allowed_ips.to_string(),
+        },
+    );
+    true
+}
+
+async fn index(data: web::Data<AppData>) -> impl Responder {
+    NamedFile::open(data.config.static_dir.join("index.html"))
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -386,73 +850,615 @@ struct PubPrivKey {
     name: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+    #[default]
+    Admin,
+    // can look at and download peers, but not change them
+    Viewer,
+}
+
+impl Role {
+    fn allows(&self, scope: TokenScope) -> bool {
+        match self {
+            Role::Admin => true,
+            Role::Viewer => !matches!(scope, TokenScope::ManagePeers | TokenScope::ReadAudit),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
 struct User {
     name: String,
     hashed_pass: String,
+    #[serde(default)]
+    role: Role,
+    // set for users created by a single sign-on login, they have no password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    oidc_subject: Option<String>,
+    // set for users from the ldap directory, they have no password either
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ldap_dn: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    totp: Option<Totp>,
 }
 
 struct AppData {
     ip: std::net::Ipv4Addr,
-    db: jfs::Store,
+    interface_address: std::net::Ipv4Addr,
+    db: Db,
+    sessions: Sessions,
+    pending_logins: PendingLogins,
+    throttle: LoginThrottle,
+    enroll_throttle: LoginThrottle,
+    tokens: ApiTokens,
+    oidc: Option<Oidc>,
+    auth: Vec<Box<dyn AuthProvider>>,
+    audit: AuditLog,
+    http_metrics: HttpMetrics,
+    traffic: TrafficStats,
+    quotas: Quotas,
+    events: Events,
+    webhooks: Webhooks,
+    mailer: Option<Mailer>,
+    shares: ShareLinks,
+    invites: Invites,
+    wg: WireGuard,
+    config: Config,
+}
+
+// AppData on a new database in `data_dir` for interface wg0, nothing is run
+// until a test calls the backend, `wg_wrapper.bin` in `data_dir`.
+#[cfg(test)]
+fn test_data(data_dir: &std::path::Path) -> web::Data<AppData> {
+    let mut config = Config {
+        data_dir: data_dir.to_path_buf(),
+        ..Default::default()
+    };
+    config.wireguard.interface = Some("wg0".to_string());
+    config.wireguard.wrapper = data_dir.join("wg_wrapper.bin");
+    let db = Db::open(data_dir, None).unwrap();
+    db.add_interface("wg0").unwrap();
+    web::Data::new(AppData {
+        ip: std::net::Ipv4Addr::new(192, 0, 2, 1),
+        interface_address: std::net::Ipv4Addr::new(10, 200, 100, 1),
+        sessions: Sessions::new(db.clone(), config.session.clone()),
+        pending_logins: PendingLogins::default(),
+        throttle: LoginThrottle::new(config.login.clone()),
+        enroll_throttle: LoginThrottle::for_enrollment(config.login.clone()),
+        tokens: ApiTokens::new(db.clone()),
+        oidc: None,
+        auth: auth::providers(&config),
+        audit: AuditLog::new(db.clone()),
+        http_metrics: HttpMetrics::default(),
+        traffic: TrafficStats::new(db.clone(), config.traffic.clone()),
+        quotas: Quotas::new(db.clone(), config.quota.clone()),
+        events: Events::default(),
+        webhooks: Webhooks::new(db.clone(), vec![]),
+        mailer: None,
+        shares: ShareLinks::new(db.clone()),
+        invites: Invites::new(db.clone()),
+        wg: WireGuard::new(&config.wireguard).unwrap(),
+        db,
+        config,
+    })
 }
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1)
+    });
+    logging::init(&config.log);
+
+    if std::env::args().any(|arg| arg == "--rotate-session-key") {
+        SessionKeys::rotate(&config.data_dir, config.session.key_grace_secs)?;
+        info!(
+            "Rotated the session key, restart the server to use it. \
+             Sessions signed with the old key stay valid for {} seconds.",
+            config.session.key_grace_secs
+        );
+        return Ok(());
+    }
+
+    let kek = kek::load(&config.encryption).unwrap_or_else(|e| {
+        error!("Invalid encryption key: {}", e);
+        std::process::exit(1)
+    });
+    let open_db = |kek| {
+        Db::open(&config.data_dir, kek).unwrap_or_else(|e| {
+            error!("Could not open the database: {}", e);
+            std::process::exit(1)
+        })
+    };
+
+    if let Some(path) = arg_value("--rotate-encryption-key") {
+        let db = open_db(kek);
+        let new_kek = Kek::load_or_generate(std::path::Path::new(&path)).unwrap_or_else(|e| {
+            error!("Invalid encryption key: {}", e);
+            std::process::exit(1)
+        });
+        match db.rotate_kek(&new_kek) {
+            Ok(count) => info!(
+                "Encrypted {} private keys with key {}. Set encryption.key_file to {} \
+                 and restart the server, the old key is no longer needed.",
+                count, new_kek.id, path
+            ),
+            Err(e) => {
+                error!("Could not rotate the encryption key: {}", e);
+                std::process::exit(1)
+            }
+        }
+        return Ok(());
+    }
+
     // let's print the interface in case it is not there we exit!
-    println!("WG Interface: {}", *(WG_INTERFACE_NAME));
+    let wg = WireGuard::new(&config.wireguard).unwrap_or_else(|| {
+        error!("Wireguard Interface not found");
+        std::process::exit(1)
+    });
+    info!("WG Interface: {}", wg.interface);
 
-    let ip: std::net::Ipv4Addr = get_iface_ip((*DEFAULT_LINK).clone())?;
-    let db = jfs::Store::new_with_cfg(
-        "data",
-        jfs::Config {
-            single: true,
-            ..Default::default()
-        },
-    )
-    .unwrap();
+    let interface_address = get_iface_ip(wg.interface.clone())?;
+    if !config.wireguard.subnet.contains(&interface_address) {
+        error!(
+            "Invalid configuration: wireguard.subnet: {} does not contain the address {} of {}",
+            config.wireguard.subnet, interface_address, wg.interface
+        );
+        std::process::exit(1);
+    }
+
+    let default_link = default_device().unwrap_or_else(|| {
+        error!("No default route found");
+        std::process::exit(1)
+    });
+    let ip: std::net::Ipv4Addr = get_iface_ip(default_link)?;
+    let db = open_db(kek);
+    if let Err(e) = db
+        .add_interface(&wg.interface)
+        .map_err(db::DbError::from)
+        .and_then(|_| db.import_json(&config.data_dir, &wg.interface))
+    {
+        error!("Could not open the database: {}", e);
+        std::process::exit(1);
+    }
 
     // use this part to create a user or use the example in the readme
-    // if let Ok(user) = db.get::<User>("user") {
+    // if let Ok(Some(user)) = db.user_by_name("admin") {
     //    println!("{:?}", user);
     // } else {
     //    let name = "admin".to_string();
     //    match hash("admin", DEFAULT_COST) {
     //        Ok(hashed_pass) => {
-    //            let _res = db.save_with_id(&User { name, hashed_pass }, "user");
+    //            let _res = db.save_user(&name, &User { name: name.clone(), hashed_pass, ..Default::default() });
     //            ()
     //        }
     //        Err(e) => println!("Could not hash pass {}", e),
     //    }
     // }
 
-    HttpServer::new(move || {
+    let session_keys =
+        SessionKeys::load_or_create(&config.data_dir, config.session.key_grace_secs)?;
+    let sessions = Sessions::new(db.clone(), config.session.clone());
+    let tokens = ApiTokens::new(db.clone());
+    let audit = AuditLog::new(db.clone());
+    let traffic = TrafficStats::new(db.clone(), config.traffic.clone());
+    let quotas = Quotas::new(db.clone(), config.quota.clone());
+    let webhooks = Webhooks::new(db.clone(), config.webhooks.clone());
+    let shares = ShareLinks::new(db.clone());
+    let invites = Invites::new(db.clone());
+    let mailer = config.smtp.clone().map(|smtp| {
+        Mailer::new(smtp).unwrap_or_else(|e| {
+            error!("Invalid configuration: smtp: {}", e);
+            std::process::exit(1)
+        })
+    });
+
+    let listen = config.listen.clone();
+    let tls = match &config.tls {
+        Some(tls_config) => {
+            let resolver = tls::CertResolver::new(&tls_config.cert, &tls_config.key)
+                .unwrap_or_else(|e| {
+                    error!("Invalid TLS certificate: {}", e);
+                    std::process::exit(1)
+                });
+            tls::reload_on_sighup(resolver.clone());
+            Some((tls::server_config(resolver), tls_config.redirect))
+        }
+        None => None,
+    };
+    let app_data = web::Data::new(AppData {
+        ip,
+        interface_address,
+        db,
+        sessions,
+        pending_logins: PendingLogins::default(),
+        throttle: LoginThrottle::new(config.login.clone()),
+        enroll_throttle: LoginThrottle::for_enrollment(config.login.clone()),
+        tokens,
+        oidc: config.oidc.clone().map(Oidc::new),
+        auth: auth::providers(&config),
+        audit,
+        http_metrics: HttpMetrics::default(),
+        traffic,
+        quotas,
+        events: Events::default(),
+        webhooks,
+        mailer,
+        shares,
+        invites,
+        wg,
+        config,
+    });
+    let metrics_data = app_data.clone();
+    traffic::start_sampler(app_data.clone());
+    events::start_status(app_data.clone());
+    webhooks::start(app_data.clone());
+
+    let mut server = HttpServer::new(move || {
+        let config = &app_data.config;
+        // with a listener of its own, the metrics are only served there
+        let serve_metrics = config.metrics.token.is_some() && config.metrics.listen.is_none();
         App::new()
-            .app_data(web::Data::new(AppData { ip, db: db.clone() }))
-            .wrap(IdentityService::new(
-                CookieIdentityPolicy::new(&[0; 32])
-                    .name("auth-cookie")
-                    .same_site(SameSite::Strict)
-                    .secure(false),
-            ))
+            .app_data(app_data.clone())
+            .wrap(IdentityService::new(session_keys.policy(config)))
+            .wrap(Csrf::new(config))
+            .wrap(RequestLog)
+            .configure(|cfg| {
+                if serve_metrics {
+                    cfg.service(metrics::metrics);
+                }
+            })
+            .service(health::healthz)
+            .service(health::readyz)
             .service(
                 web::scope("/api")
                     .service(login_request)
                     .service(logout_request)
                     .service(new_peer)
+                    .service(new_peer_with_key)
                     .service(update_peer_name)
+                    .service(set_peer_quota)
                     .service(download_peer_file)
+                    .service(mail::send_peer_config)
+                    .service(share::share_peer)
+                    .service(share::open_share)
+                    .service(invites::list_invites)
+                    .service(invites::create_invite)
+                    .service(invites::revoke_invite)
+                    .service(invites::enroll)
                     .service(remove_peer)
+                    .service(groups::set_peer_tags)
+                    .service(groups::bulk_peers)
+                    .service(groups::download_peers)
                     .service(update_user)
                     .service(session_request)
+                    .service(list_sessions)
+                    .service(revoke_session)
+                    .service(totp::login_totp_request)
+                    .service(totp::totp_status)
+                    .service(totp::totp_enroll)
+                    .service(totp::totp_confirm)
+                    .service(totp::totp_disable)
+                    .service(tokens::list_tokens)
+                    .service(tokens::create_token)
+                    .service(tokens::revoke_token)
+                    .service(oidc::login_options)
+                    .service(oidc::oidc_login)
+                    .service(oidc::oidc_callback)
+                    .service(audit::list_audit)
+                    .service(audit::export_audit)
+                    .service(traffic::show_traffic)
+                    .service(events::events)
+                    .service(webhooks::list_deliveries)
                     .service(show_config)
                     .default_service(web::route().to(HttpResponse::NotFound)),
             )
-            .service(Files::new("/public", "./client/public"))
-            .service(Files::new("/pkg", "./client/pkg"))
+            .service(Files::new("/public", config.static_dir.join("public")))
+            .service(Files::new("/pkg", config.static_dir.join("pkg")))
             .default_service(web::route().to(index))
-    })
-    .bind("127.0.0.1:8000")?
-    .run()
-    .await
+    });
+
+    let mut https_port = None;
+    for addr in listen {
+        server = match (addr, &tls) {
+            (ListenAddr::Tcp(addr), Some((tls_config, _))) => {
+                info!("Listening on https://{}", addr);
+                https_port.get_or_insert(addr.port());
+                server.bind_rustls(addr, tls_config.clone())?
+            }
+            (ListenAddr::Tcp(addr), None) => {
+                info!("Listening on http://{}", addr);
+                server.bind(addr)?
+            }
+            (ListenAddr::Unix(path), _) => {
+                info!("Listening on unix:{}", path.display());
+                remove_stale_socket(&path)?;
+                server.bind_uds(path)?
+            }
+        };
+    }
+
+    if let (Some((_, Some(redirect))), Some(https_port)) = (tls, https_port) {
+        // checked by the config validation
+        let host = metrics_data
+            .config
+            .tls
+            .as_ref()
+            .and_then(|t| t.redirect_host(&metrics_data.config.wireguard))
+            .unwrap_or_default()
+            .to_string();
+        info!("Redirecting http://{} to https://{}", redirect, host);
+        let redirect_server = HttpServer::new(move || {
+            let host = host.clone();
+            App::new().default_service(web::route().to(move |req: HttpRequest| {
+                let host = host.clone();
+                async move { tls::redirect_to_https(req, &host, https_port) }
+            }))
+        })
+        .bind(redirect)?
+        .run();
+        actix_rt::spawn(redirect_server);
+    }
+
+    if let Some(listen) = metrics_data.config.metrics.listen {
+        info!("Serving metrics on http://{}/metrics", listen);
+        let metrics_server = HttpServer::new(move || {
+            App::new()
+                .app_data(metrics_data.clone())
+                .service(metrics::metrics)
+        })
+        .workers(1)
+        .bind(listen)?
+        .run();
+        actix_rt::spawn(metrics_server);
+    }
+
+    server.run().await
+}
+
+// value following `name` on the command line
+fn arg_value(name: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != name).nth(1)
+}
+
+// a socket left behind by a previous run would make the bind fail
+fn remove_stale_socket(path: &std::path::Path) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{self, TestRequest};
+    use std::os::unix::fs::PermissionsExt;
+
+    // base64 like real keys, with the characters that need escaping in urls
+    const FIRST: &str = "1st/peer+key=";
+    const SECOND: &str = "2nd/peer+key=";
+
+    // A wrapper listing two peers on the interface and an admin with a token
+    // to manage them, returns the token.
+    fn setup(dir: &std::path::Path) -> (web::Data<AppData>, String) {
+        let wrapper = dir.join("wg_wrapper.bin");
+        let showconf = format!(
+            "[Interface]\nListenPort = 51820\n\n\
+             [Peer]\nPublicKey = {}\nAllowedIPs = 10.200.100.2/32\n\n\
+             [Peer]\nPublicKey = {}\nAllowedIPs = 10.200.100.3/32\n",
+            FIRST, SECOND
+        );
+        std::fs::write(
+            &wrapper,
+            format!(
+                "#!/bin/sh\n[ \"$1\" = showconf ] && printf '{}'\nexit 0\n",
+                showconf
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let data = test_data(dir);
+        for (public_key, allowed_ips) in [(FIRST, "10.200.100.2/32"), (SECOND, "10.200.100.3/32")] {
+            let key = PubPrivKey {
+                private_key: "private".to_string(),
+                public_key: public_key.to_string(),
+                name: format!("peer {}", &public_key[..3]),
+            };
+            data.db.save_peer("wg0", allowed_ips, &key).unwrap();
+        }
+        let admin = User {
+            name: "admin".to_string(),
+            ..Default::default()
+        };
+        data.db.save_user("admin", &admin).unwrap();
+        let secret = data
+            .tokens
+            .create(
+                "admin",
+                "test".to_string(),
+                vec![TokenScope::ManagePeers],
+                None,
+            )
+            .unwrap();
+        (data, format!("Bearer {}", secret))
+    }
+
+    fn names(data: &AppData) -> Vec<(String, String)> {
+        let mut names = data
+            .db
+            .peers("wg0")
+            .unwrap()
+            .into_iter()
+            .map(|p| (p.public_key, p.name))
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[actix_rt::test]
+    async fn peers_are_picked_by_public_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (data, token) = setup(dir.path());
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .service(update_peer_name)
+                .service(remove_peer),
+        )
+        .await;
+
+        let rename = TestRequest::post()
+            .uri("/update_peer_name")
+            .insert_header((header::AUTHORIZATION, token.clone()))
+            .set_json(shared::Request::UpdatePeerName {
+                public_key: SECOND.to_string(),
+                name: "laptop".to_string(),
+            })
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, rename).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(
+            names(&data),
+            [
+                (FIRST.to_string(), "peer 1st".to_string()),
+                (SECOND.to_string(), "laptop".to_string())
+            ]
+        );
+
+        let remove = TestRequest::delete()
+            .uri("/remove_peer?public_key=1st%2Fpeer%2Bkey%3D")
+            .insert_header((header::AUTHORIZATION, token.clone()))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, remove).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(names(&data), [(SECOND.to_string(), "laptop".to_string())]);
+
+        let unknown = TestRequest::delete()
+            .uri("/remove_peer?public_key=unknown")
+            .insert_header((header::AUTHORIZATION, token))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, unknown).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(names(&data).len(), 1);
+    }
+
+    async fn post<B: actix_web::body::MessageBody>(
+        app: &impl actix_web::dev::Service<
+            actix_http::Request,
+            Response = actix_web::dev::ServiceResponse<B>,
+            Error = actix_web::Error,
+        >,
+        uri: &str,
+        cookie: &actix_web::cookie::Cookie<'static>,
+        request: shared::Request,
+    ) -> shared::Response {
+        let req = TestRequest::post()
+            .uri(uri)
+            .cookie(cookie.clone())
+            .set_json(request)
+            .to_request();
+        test::call_and_read_body_json(app, req).await
+    }
+
+    #[actix_rt::test]
+    async fn sessions_are_revoked_by_their_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = test_data(dir.path());
+        for name in ["alice", "bob"] {
+            let user = User {
+                name: name.to_string(),
+                hashed_pass: hash("secret", 4).unwrap(),
+                ..Default::default()
+            };
+            data.db.save_user(name, &user).unwrap();
+        }
+        let keys = SessionKeys::load_or_create(dir.path(), 0).unwrap();
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityService::new(keys.policy(&data.config)))
+                .app_data(data.clone())
+                .service(login_request)
+                .service(session_request)
+                .service(list_sessions)
+                .service(revoke_session)
+                .service(update_user),
+        )
+        .await;
+
+        let mut cookies = vec![];
+        for name in ["alice", "alice", "bob"] {
+            let req = TestRequest::post()
+                .uri("/login")
+                .set_json(shared::Request::Login {
+                    username: name.to_string(),
+                    password: "secret".to_string(),
+                })
+                .to_request();
+            let response = test::call_service(&app, req).await;
+            cookies.push(response.response().cookies().next().unwrap().into_owned());
+        }
+        let logged_in = |cookie: &actix_web::cookie::Cookie<'static>| {
+            let req = TestRequest::get()
+                .uri("/session")
+                .cookie(cookie.clone())
+                .to_request();
+            let app = &app;
+            async move {
+                match test::call_and_read_body_json(app, req).await {
+                    shared::Response::LoginSuccess { session } => Some(session),
+                    _ => None,
+                }
+            }
+        };
+
+        // bob can't end a session of alice, even with its handle
+        let req = TestRequest::get()
+            .uri("/sessions")
+            .cookie(cookies[0].clone())
+            .to_request();
+        let handle = match test::call_and_read_body_json(&app, req).await {
+            shared::Response::Sessions { sessions } => sessions[0].id.clone(),
+            _ => panic!("no sessions"),
+        };
+        let revoke = shared::Request::RevokeSession { id: handle };
+        assert!(matches!(
+            post(&app, "/revoke_session", &cookies[2], revoke).await,
+            shared::Response::Failure
+        ));
+        assert_eq!(logged_in(&cookies[0]).await.as_deref(), Some("alice"));
+        assert_eq!(logged_in(&cookies[1]).await.as_deref(), Some("alice"));
+
+        // a new password ends the other sessions of alice only
+        let update = shared::Request::UpdateUser {
+            name: "alice".to_string(),
+            old_password: "secret".to_string(),
+            new_password: "new secret".to_string(),
+            password_confirmation: "new secret".to_string(),
+        };
+        assert!(matches!(
+            post(&app, "/update_user", &cookies[0], update).await,
+            shared::Response::Success
+        ));
+        assert_eq!(logged_in(&cookies[0]).await.as_deref(), Some("alice"));
+        assert_eq!(logged_in(&cookies[1]).await, None);
+        assert_eq!(logged_in(&cookies[2]).await.as_deref(), Some("bob"));
+    }
 }
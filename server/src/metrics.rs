@@ -0,0 +1,346 @@
+use crate::csrf::equal;
+use crate::logging;
+use crate::session::now;
+use crate::tokens::bearer_token;
+use crate::{current_wg_config, AppData};
+use actix_web::dev::ServiceResponse;
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::error;
+
+// upper bounds of the request duration histogram, in seconds
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    // requests per bucket, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+// Request counts and durations, by method and route pattern so ids in the
+// path don't make up new series.
+#[derive(Default)]
+pub struct HttpMetrics {
+    requests: Mutex<BTreeMap<(String, String, u16), u64>>,
+    durations: Mutex<BTreeMap<(String, String), Histogram>>,
+}
+
+impl HttpMetrics {
+    pub fn observe(&self, method: &str, route: &str, status: u16, duration: Duration) {
+        let key = (method.to_string(), route.to_string());
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((key.0.clone(), key.1.clone(), status))
+            .or_insert(0) += 1;
+
+        let secs = duration.as_secs_f64();
+        let mut durations = self.durations.lock().unwrap();
+        let histogram = durations.entry(key).or_default();
+        if let Some(i) = BUCKETS.iter().position(|bound| secs <= *bound) {
+            histogram.buckets[i] += 1;
+        }
+        histogram.count += 1;
+        histogram.sum += secs;
+    }
+
+    fn render(&self, out: &mut String) {
+        header(
+            out,
+            "http_requests_total",
+            "counter",
+            "HTTP requests handled",
+        );
+        for ((method, route, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                escape(method),
+                escape(route),
+                status,
+                count
+            );
+        }
+
+        header(
+            out,
+            "http_request_duration_seconds",
+            "histogram",
+            "Time to handle HTTP requests",
+        );
+        for ((method, route), histogram) in self.durations.lock().unwrap().iter() {
+            let labels = format!("method=\"{}\",route=\"{}\"", escape(method), escape(route));
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_sum{{{}}} {}",
+                labels, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+    }
+}
+
+// by the route pattern, requests no route matched are counted together
+pub fn record_request<B>(response: &ServiceResponse<B>, duration: Duration) {
+    let request = response.request();
+    if let Some(data) = request.app_data::<web::Data<AppData>>() {
+        let route = request
+            .match_pattern()
+            .unwrap_or_else(|| "unmatched".to_string());
+        data.http_metrics.observe(
+            request.method().as_str(),
+            &route,
+            response.status().as_u16(),
+            duration,
+        );
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// label values are quoted, backslash, quote and newline have to be escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn render(data: &web::Data<AppData>) -> String {
+    let mut out = String::new();
+    let interface = escape(&data.wg.interface);
+    let wg_config = current_wg_config(data);
+    let names = wg_config
+        .peers
+        .iter()
+        .map(|p| (p.public_key.as_str(), p.name.as_str()))
+        .collect::<HashMap<_, _>>();
+    let stats = data.wg.dump();
+    let now = now();
+
+    let peer_labels = |public_key: &str| {
+        format!(
+            "interface=\"{}\",public_key=\"{}\",name=\"{}\"",
+            interface,
+            escape(public_key),
+            escape(names.get(public_key).copied().unwrap_or_default())
+        )
+    };
+
+    header(
+        &mut out,
+        "wireguard_peer_receive_bytes_total",
+        "counter",
+        "Bytes received from the peer",
+    );
+    for peer in &stats {
+        let _ = writeln!(
+            out,
+            "wireguard_peer_receive_bytes_total{{{}}} {}",
+            peer_labels(&peer.public_key),
+            peer.rx_bytes
+        );
+    }
+    header(
+        &mut out,
+        "wireguard_peer_transmit_bytes_total",
+        "counter",
+        "Bytes sent to the peer",
+    );
+    for peer in &stats {
+        let _ = writeln!(
+            out,
+            "wireguard_peer_transmit_bytes_total{{{}}} {}",
+            peer_labels(&peer.public_key),
+            peer.tx_bytes
+        );
+    }
+    header(
+        &mut out,
+        "wireguard_peer_last_handshake_age_seconds",
+        "gauge",
+        "Seconds since the last handshake with the peer, +Inf if there was none",
+    );
+    for peer in &stats {
+        let age = match peer.latest_handshake {
+            0 => "+Inf".to_string(),
+            time => now.saturating_sub(time).to_string(),
+        };
+        let _ = writeln!(
+            out,
+            "wireguard_peer_last_handshake_age_seconds{{{}}} {}",
+            peer_labels(&peer.public_key),
+            age
+        );
+    }
+
+    header(
+        &mut out,
+        "wireguard_peers",
+        "gauge",
+        "Peers configured on the interface",
+    );
+    let _ = writeln!(
+        out,
+        "wireguard_peers{{interface=\"{}\"}} {}",
+        interface,
+        wg_config.peers.iter().filter(|p| !p.disabled).count()
+    );
+
+    // the interface takes one address of the subnet
+    let subnet = data.config.wireguard.subnet;
+    let size = subnet.hosts().count().saturating_sub(1);
+    let used = wg_config
+        .peers
+        .iter()
+        .filter(|p| subnet.contains(&p.allowed_ips.addr()))
+        .count();
+    header(
+        &mut out,
+        "wireguard_ipam_addresses",
+        "gauge",
+        "Addresses of the subnet that can be given to peers",
+    );
+    let _ = writeln!(
+        out,
+        "wireguard_ipam_addresses{{interface=\"{}\",subnet=\"{}\"}} {}",
+        interface, subnet, size
+    );
+    header(
+        &mut out,
+        "wireguard_ipam_used_addresses",
+        "gauge",
+        "Addresses of the subnet taken by peers",
+    );
+    let _ = writeln!(
+        out,
+        "wireguard_ipam_used_addresses{{interface=\"{}\",subnet=\"{}\"}} {}",
+        interface, subnet, used
+    );
+
+    header(
+        &mut out,
+        "wireguard_backend_failures_total",
+        "counter",
+        "Calls of the wrapper or wg that failed, by command",
+    );
+    for (command, count) in data.wg.failures() {
+        let _ = writeln!(
+            out,
+            "wireguard_backend_failures_total{{command=\"{}\"}} {}",
+            escape(&command),
+            count
+        );
+    }
+
+    data.http_metrics.render(&mut out);
+    out
+}
+
+// ---- Apis ----
+
+// Served on the metrics listener, and on the normal ones if only a token is
+// configured.
+#[get("/metrics")]
+async fn metrics(req: HttpRequest, data: web::Data<AppData>) -> HttpResponse {
+    if let Some(token) = &data.config.metrics.token {
+        match bearer_token(req.headers()) {
+            Some(sent) if equal(sent, token.trim()) => {}
+            _ => return HttpResponse::Unauthorized().finish(),
+        }
+    }
+
+    let render_data = data.clone();
+    match logging::block(move || render(&render_data)).await {
+        Ok(body) => HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(body),
+        Err(e) => {
+            error!("Could not render metrics: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_cumulative_buckets() {
+        let http = HttpMetrics::default();
+        http.observe("GET", "/api/config", 200, Duration::from_millis(3));
+        http.observe("GET", "/api/config", 200, Duration::from_millis(200));
+        http.observe("GET", "/api/config", 403, Duration::from_secs(60));
+        let mut out = String::new();
+        http.render(&mut out);
+
+        let labels = "method=\"GET\",route=\"/api/config\"";
+        for line in [
+            format!("http_requests_total{{{},status=\"200\"}} 2", labels),
+            format!("http_requests_total{{{},status=\"403\"}} 1", labels),
+            format!(
+                "http_request_duration_seconds_bucket{{{},le=\"0.005\"}} 1",
+                labels
+            ),
+            format!(
+                "http_request_duration_seconds_bucket{{{},le=\"0.1\"}} 1",
+                labels
+            ),
+            format!(
+                "http_request_duration_seconds_bucket{{{},le=\"0.25\"}} 2",
+                labels
+            ),
+            format!(
+                "http_request_duration_seconds_bucket{{{},le=\"10\"}} 2",
+                labels
+            ),
+            format!(
+                "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} 3",
+                labels
+            ),
+            format!("http_request_duration_seconds_count{{{}}} 3", labels),
+        ] {
+            assert!(
+                out.lines().any(|l| l == line),
+                "{} missing in\n{}",
+                line,
+                out
+            );
+        }
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+}
@@ -0,0 +1,614 @@
+use crate::config::OidcConfig;
+use crate::csrf::equal;
+use crate::session::{client_ip, now, random_token};
+use crate::{get_user_by_name, save_user, AppData, Role, User};
+use actix_identity::Identity;
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::{get, http::header, web, HttpRequest, HttpResponse, Responder};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+const PENDING_SECS: u64 = 10 * 60;
+// binds the state to the browser that started the login
+const STATE_COOKIE: &str = "oidc-state";
+const STATE_COOKIE_PATH: &str = "/api/oidc";
+// tolerated clock difference to the identity provider
+const CLOCK_SKEW_SECS: u64 = 60;
+
+#[derive(Deserialize, Debug, Clone)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+// A login that was sent to the identity provider, keyed by its `state`.
+struct PendingAuth {
+    verifier: String,
+    nonce: String,
+    expires: u64,
+}
+
+// What the identity provider told us about the user.
+struct OidcIdentity {
+    subject: String,
+    name: String,
+    role: Role,
+}
+
+// OpenID Connect authorization code flow with PKCE.
+//
+// The id token is taken from the token endpoint response, which we fetch
+// ourselves from the configured issuer over tls, so its signature is not
+// checked (OpenID Connect Core 3.1.3.7). Issuer, audience, expiry and nonce
+// are.
+pub struct Oidc {
+    config: OidcConfig,
+    // fetched on the first login, so the server starts while the provider is down
+    discovery: Mutex<Option<Discovery>>,
+    pending: Mutex<HashMap<String, PendingAuth>>,
+    agent: ureq::Agent,
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig) -> Self {
+        Self {
+            config,
+            discovery: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build(),
+        }
+    }
+
+    fn discovery(&self) -> Result<Discovery, String> {
+        if let Some(discovery) = self.discovery.lock().unwrap().as_ref() {
+            return Ok(discovery.clone());
+        }
+
+        let url = format!(
+            "{}/.well-known/openid-configuration",
+            self.config.issuer.trim_end_matches('/')
+        );
+        let discovery: Discovery = self
+            .agent
+            .get(&url)
+            .call()
+            .map_err(|e| format!("{}: {}", url, e))?
+            .into_json()
+            .map_err(|e| format!("{}: {}", url, e))?;
+        if discovery.issuer.trim_end_matches('/') != self.config.issuer.trim_end_matches('/') {
+            return Err(format!(
+                "{}: issuer is '{}', expected '{}'",
+                url, discovery.issuer, self.config.issuer
+            ));
+        }
+
+        *self.discovery.lock().unwrap() = Some(discovery.clone());
+        Ok(discovery)
+    }
+
+    // where to send the browser to log in, and the state it has to come back with
+    fn authorization_url(&self) -> Result<(String, String), String> {
+        let discovery = self.discovery()?;
+        let state = random_token(24);
+        let nonce = random_token(24);
+        let verifier = random_token(48);
+        let challenge =
+            base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD);
+
+        let mut url = url::Url::parse(&discovery.authorization_endpoint)
+            .map_err(|e| format!("{}: {}", discovery.authorization_endpoint, e))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.config.client_id)
+            .append_pair("redirect_uri", &self.config.redirect_url)
+            .append_pair("scope", &self.config.scopes.join(" "))
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256");
+
+        let mut pending = self.pending.lock().unwrap();
+        let now = now();
+        pending.retain(|_, p| p.expires > now);
+        pending.insert(
+            state.clone(),
+            PendingAuth {
+                verifier,
+                nonce,
+                expires: now + PENDING_SECS,
+            },
+        );
+        Ok((url.to_string(), state))
+    }
+
+    // exchanges the code from the callback and checks the id token
+    fn finish(&self, code: &str, state: &str) -> Result<OidcIdentity, String> {
+        let pending = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|p| p.expires > now())
+            .ok_or("unknown or expired state")?;
+        let discovery = self.discovery()?;
+
+        let mut request = self.agent.post(&discovery.token_endpoint);
+        if let Some(secret) = &self.config.client_secret {
+            let credentials = format!(
+                "{}:{}",
+                form_encode(&self.config.client_id),
+                form_encode(secret)
+            );
+            request = request.set(
+                "Authorization",
+                &format!("Basic {}", base64::encode(credentials)),
+            );
+        }
+        let response: TokenResponse = request
+            .send_form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_url),
+                ("client_id", &self.config.client_id),
+                ("code_verifier", &pending.verifier),
+            ])
+            .map_err(|e| match e {
+                ureq::Error::Status(status, response) => format!(
+                    "token endpoint answered {}: {}",
+                    status,
+                    response.into_string().unwrap_or_default()
+                ),
+                e => format!("token endpoint: {}", e),
+            })?
+            .into_json()
+            .map_err(|e| format!("token endpoint: {}", e))?;
+
+        let claims = id_token_claims(&response.id_token)?;
+        self.check_claims(&claims, &discovery, &pending.nonce)?;
+
+        let subject = claim_str(&claims, "sub").ok_or("id token has no sub")?;
+        let name = claim_str(&claims, &self.config.username_claim)
+            .filter(|n| !n.is_empty())
+            .ok_or_else(|| format!("id token has no {}", self.config.username_claim))?;
+        let role = self.role(&claims).ok_or_else(|| {
+            format!(
+                "{} has none of the configured values in {}",
+                name, self.config.role_claim
+            )
+        })?;
+
+        Ok(OidcIdentity {
+            subject,
+            name,
+            role,
+        })
+    }
+
+    fn check_claims(
+        &self,
+        claims: &Value,
+        discovery: &Discovery,
+        nonce: &str,
+    ) -> Result<(), String> {
+        if claim_str(claims, "iss").as_deref() != Some(discovery.issuer.as_str()) {
+            return Err("id token issuer does not match".to_string());
+        }
+
+        let audience = claim_values(claims, "aud");
+        if !audience.contains(&self.config.client_id) {
+            return Err("id token is not meant for this client".to_string());
+        }
+        if audience.len() > 1
+            && claim_str(claims, "azp").as_deref() != Some(self.config.client_id.as_str())
+        {
+            return Err("id token has several audiences but a different azp".to_string());
+        }
+
+        match claims.get("exp").and_then(Value::as_u64) {
+            Some(exp) if exp + CLOCK_SKEW_SECS > now() => {}
+            _ => return Err("id token is expired".to_string()),
+        }
+
+        if claim_str(claims, "nonce").as_deref() != Some(nonce) {
+            return Err("id token nonce does not match".to_string());
+        }
+        Ok(())
+    }
+
+    fn role(&self, claims: &Value) -> Option<Role> {
+        let roles = &self.config.roles;
+        if roles.admin.is_empty() && roles.viewer.is_empty() {
+            return Some(Role::Viewer);
+        }
+
+        let values = claim_values(claims, &self.config.role_claim);
+        if values.iter().any(|v| roles.admin.contains(v)) {
+            Some(Role::Admin)
+        } else if values.iter().any(|v| roles.viewer.contains(v)) {
+            Some(Role::Viewer)
+        } else {
+            None
+        }
+    }
+}
+
+fn id_token_claims(id_token: &str) -> Result<Value, String> {
+    let payload = id_token.split('.').nth(1).ok_or("id token is not a jwt")?;
+    let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+        .map_err(|e| format!("id token: {}", e))?;
+    serde_json::from_slice(&payload).map_err(|e| format!("id token: {}", e))
+}
+
+fn claim_str(claims: &Value, claim: &str) -> Option<String> {
+    claims.get(claim)?.as_str().map(str::to_string)
+}
+
+// claims like `aud` or `groups` can be a single string or a list of them
+fn claim_values(claims: &Value, claim: &str) -> Vec<String> {
+    match claims.get(claim) {
+        Some(Value::String(value)) => vec![value.clone()],
+        Some(Value::Array(values)) => values
+            .iter()
+            .filter_map(|v| v.as_str().map(str::to_string))
+            .collect(),
+        _ => vec![],
+    }
+}
+
+// client credentials are form encoded before going into basic auth (RFC 6749 2.3.1)
+fn form_encode(value: &str) -> String {
+    url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
+// Finds the user created by an earlier login of this identity or creates it.
+// A local user with the same name is never taken over.
+fn sso_user(data: &web::Data<AppData>, identity: OidcIdentity) -> Result<User, String> {
+    let existing = data
+        .db
+        .users()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|u| u.oidc_subject.as_deref() == Some(identity.subject.as_str()));
+
+    let user = match existing {
+        Some(user) => User {
+            role: identity.role,
+            ..user
+        },
+        None => {
+            if get_user_by_name(identity.name.clone(), data).is_some() {
+                return Err(format!(
+                    "the name {} is taken by another user",
+                    identity.name
+                ));
+            }
+            info!("Creating user {} on first single sign-on", identity.name);
+            User {
+                name: identity.name,
+                role: identity.role,
+                oidc_subject: Some(identity.subject),
+                ..Default::default()
+            }
+        }
+    };
+
+    save_user(data, &user.name, &user).map_err(|e| format!("could not save user: {}", e))?;
+    Ok(user)
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::Found()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+// ---- Apis ----
+
+#[get("/login_options")]
+async fn login_options(data: web::Data<AppData>) -> impl Responder {
+    web::Json(shared::Response::LoginOptions {
+        sso: data.oidc.is_some(),
+    })
+}
+
+#[get("/oidc/login")]
+async fn oidc_login(data: web::Data<AppData>) -> HttpResponse {
+    if data.oidc.is_none() {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let oidc_data = data.clone();
+    let url = web::block(move || oidc_data.oidc.as_ref().unwrap().authorization_url()).await;
+    match url {
+        Ok(Ok((url, state))) => {
+            // lax, the provider sends the browser back with a top level navigation
+            let cookie = Cookie::build(STATE_COOKIE, state)
+                .path(STATE_COOKIE_PATH)
+                .http_only(true)
+                .secure(data.config.secure_cookies())
+                .same_site(SameSite::Lax)
+                .max_age(time::Duration::seconds(PENDING_SECS as i64))
+                .finish();
+            let mut response = redirect(&url);
+            let _ = response.add_cookie(&cookie);
+            response
+        }
+        Ok(Err(e)) => {
+            warn!("Single sign-on failed: {}", e);
+            HttpResponse::BadGateway().body("The identity provider is not available")
+        }
+        Err(e) => {
+            error!("Single sign-on failed: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Callback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+#[get("/oidc/callback")]
+async fn oidc_callback(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    query: web::Query<Callback>,
+) -> HttpResponse {
+    if data.oidc.is_none() {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let (code, state) = match query.into_inner() {
+        Callback {
+            code: Some(code),
+            state: Some(state),
+            ..
+        } => (code, state),
+        Callback { error, .. } => {
+            warn!(
+                "Single sign-on failed: identity provider answered {}",
+                error.unwrap_or_default()
+            );
+            return redirect("/");
+        }
+    };
+
+    // the state has to come back to the browser it was handed to, otherwise
+    // anybody could log a victim into their own account with a callback link
+    let mut response = redirect("/");
+    let _ = response.add_removal_cookie(
+        &Cookie::build(STATE_COOKIE, "")
+            .path(STATE_COOKIE_PATH)
+            .finish(),
+    );
+    match req.cookie(STATE_COOKIE) {
+        Some(cookie) if equal(cookie.value(), &state) => {}
+        _ => {
+            warn!("Single sign-on failed: state does not belong to this browser");
+            return response;
+        }
+    }
+
+    let oidc_data = data.clone();
+    let identity = web::block(move || oidc_data.oidc.as_ref().unwrap().finish(&code, &state)).await;
+    let user = match identity {
+        Ok(Ok(identity)) => sso_user(&data, identity),
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(e.to_string()),
+    };
+
+    match user {
+        Ok(user) => {
+            data.sessions.login(&id, &req, &user.name);
+            data.audit
+                .record(&user.name, "login.sso", &user.name, &client_ip(&req));
+        }
+        Err(e) => warn!("Single sign-on failed: {}", e),
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RoleMapping;
+    use serde_json::json;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    // Answers discovery and token requests like a provider would, with the
+    // claims the test put into `claims`. Token request bodies end up in `forms`.
+    struct MockProvider {
+        issuer: String,
+        claims: Arc<Mutex<Value>>,
+        forms: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockProvider {
+        fn start() -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let issuer = format!("http://{}", listener.local_addr().unwrap());
+            let claims = Arc::new(Mutex::new(json!({})));
+            let forms = Arc::new(Mutex::new(vec![]));
+
+            let (thread_issuer, thread_claims, thread_forms) =
+                (issuer.clone(), claims.clone(), forms.clone());
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).unwrap();
+                    let mut length = 0;
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line.trim().is_empty() {
+                            break;
+                        }
+                        if let Some((name, value)) = line.split_once(':') {
+                            if name.eq_ignore_ascii_case("content-length") {
+                                length = value.trim().parse().unwrap();
+                            }
+                        }
+                    }
+                    let mut body = vec![0; length];
+                    reader.read_exact(&mut body).unwrap();
+
+                    let response = if request_line.contains("/.well-known/openid-configuration") {
+                        json!({
+                            "issuer": thread_issuer,
+                            "authorization_endpoint": format!("{}/auth", thread_issuer),
+                            "token_endpoint": format!("{}/token", thread_issuer),
+                        })
+                    } else {
+                        thread_forms
+                            .lock()
+                            .unwrap()
+                            .push(String::from_utf8(body).unwrap());
+                        let claims = thread_claims.lock().unwrap().to_string();
+                        let id_token = format!(
+                            "e30.{}.c2ln",
+                            base64::encode_config(claims, base64::URL_SAFE_NO_PAD)
+                        );
+                        json!({ "id_token": id_token })
+                    }
+                    .to_string();
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        response.len(),
+                        response
+                    );
+                }
+            });
+            Self {
+                issuer,
+                claims,
+                forms,
+            }
+        }
+
+        fn oidc(&self, roles: RoleMapping) -> Oidc {
+            Oidc::new(OidcConfig {
+                issuer: self.issuer.clone(),
+                client_id: "wireguard".to_string(),
+                redirect_url: "https://vpn.example.com/api/oidc/callback".to_string(),
+                roles,
+                ..Default::default()
+            })
+        }
+
+        // sends the browser to the provider and returns the state and nonce it got
+        fn start_login(&self, oidc: &Oidc) -> (String, String, String) {
+            let (url, state) = oidc.authorization_url().unwrap();
+            let url = url::Url::parse(&url).unwrap();
+            assert!(url.as_str().starts_with(&format!("{}/auth?", self.issuer)));
+            let param = |name: &str| {
+                url.query_pairs()
+                    .find(|(k, _)| k == name)
+                    .map(|(_, v)| v.to_string())
+                    .unwrap()
+            };
+            assert_eq!(param("state"), state);
+            (state, param("nonce"), param("code_challenge"))
+        }
+
+        fn set_claims(&self, nonce: &str, extra: Value) {
+            let mut claims = json!({
+                "iss": self.issuer,
+                "aud": "wireguard",
+                "sub": "1234",
+                "preferred_username": "alice",
+                "exp": now() + 300,
+                "nonce": nonce,
+            });
+            for (key, value) in extra.as_object().unwrap() {
+                claims[key] = value.clone();
+            }
+            *self.claims.lock().unwrap() = claims;
+        }
+    }
+
+    #[test]
+    fn logs_in_with_pkce() {
+        let provider = MockProvider::start();
+        let oidc = provider.oidc(RoleMapping::default());
+        let (state, nonce, challenge) = provider.start_login(&oidc);
+        provider.set_claims(&nonce, json!({}));
+
+        let identity = oidc.finish("the-code", &state).unwrap();
+        assert_eq!(identity.subject, "1234");
+        assert_eq!(identity.name, "alice");
+        // without a mapping nobody becomes an admin
+        assert_eq!(identity.role, Role::Viewer);
+
+        let form = provider.forms.lock().unwrap()[0].clone();
+        let form = url::form_urlencoded::parse(form.as_bytes()).collect::<HashMap<_, _>>();
+        assert_eq!(form["code"], "the-code");
+        let verifier = form["code_verifier"].to_string();
+        assert_eq!(
+            base64::encode_config(Sha256::digest(verifier.as_bytes()), base64::URL_SAFE_NO_PAD),
+            challenge
+        );
+
+        // a state works only once
+        assert!(oidc.finish("the-code", &state).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_id_tokens() {
+        let provider = MockProvider::start();
+        let oidc = provider.oidc(RoleMapping::default());
+        let cases = vec![
+            json!({ "nonce": "other" }),
+            json!({ "aud": "someone-else" }),
+            json!({ "aud": ["wireguard", "other"], "azp": "other" }),
+            json!({ "iss": "https://evil.example.org" }),
+            json!({ "exp": now() - CLOCK_SKEW_SECS - 1 }),
+        ];
+        for extra in cases {
+            let (state, nonce, _) = provider.start_login(&oidc);
+            provider.set_claims(&nonce, extra.clone());
+            assert!(oidc.finish("code", &state).is_err(), "{}", extra);
+        }
+        assert!(oidc.finish("code", "unknown").is_err());
+    }
+
+    #[test]
+    fn maps_roles() {
+        let provider = MockProvider::start();
+        let oidc = provider.oidc(RoleMapping {
+            admin: vec!["vpn-admins".to_string()],
+            viewer: vec!["vpn-users".to_string()],
+        });
+        let cases = vec![
+            (json!(["vpn-users", "vpn-admins"]), Some(Role::Admin)),
+            (json!("vpn-users"), Some(Role::Viewer)),
+            (json!(["staff"]), None),
+        ];
+        for (groups, role) in cases {
+            let (state, nonce, _) = provider.start_login(&oidc);
+            provider.set_claims(&nonce, json!({ "groups": groups }));
+            assert_eq!(oidc.finish("code", &state).ok().map(|i| i.role), role);
+        }
+    }
+}
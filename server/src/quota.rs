@@ -0,0 +1,641 @@
+use crate::config::QuotaConfig;
+use crate::db::Db;
+use crate::session::now;
+use crate::webhooks::PeerInfo;
+use crate::wg::WireGuard;
+use crate::AppData;
+use rusqlite::params;
+use std::sync::Mutex;
+use tracing::{error, info, warn};
+
+const DAY: u64 = 24 * 60 * 60;
+// actor of the changes made by the server in the audit log
+const ACTOR: &str = "quota";
+
+// A peer with a quota or one that is disabled, as stored.
+pub struct QuotaState {
+    pub public_key: String,
+    pub allowed_ips: String,
+    pub name: String,
+    pub limit: Option<u64>,
+    pub used: u64,
+    warned: Option<u64>,
+    pub disabled: bool,
+    // disabled by a user, it stays off the interface whatever the quota says
+    pub user_disabled: bool,
+}
+
+// Monthly data quotas of the peers. The usage is summed up from the daily
+// traffic since the last reset day. A peer that used up its quota is taken
+// off the interface and added again once the month starts over or the quota
+// is raised.
+pub struct Quotas {
+    db: Db,
+    config: QuotaConfig,
+    // the sampler and the api both enforce
+    enforcing: Mutex<()>,
+}
+
+impl Quotas {
+    pub fn new(db: Db, config: QuotaConfig) -> Self {
+        Self {
+            db,
+            config,
+            enforcing: Mutex::new(()),
+        }
+    }
+
+    pub fn warn_percent(&self) -> u64 {
+        self.config.warn_percent
+    }
+
+    // start of the running month and of the next one
+    pub fn period(&self, time: u64) -> (u64, u64) {
+        let (year, month, day) = civil_from_days((time / DAY) as i64);
+        let (year, month) = match (day >= self.reset_day(year, month), month) {
+            (true, _) => (year, month),
+            (false, 1) => (year - 1, 12),
+            (false, _) => (year, month - 1),
+        };
+        let (next_year, next_month) = next_month(year, month);
+        (
+            days_from_civil(year, month, self.reset_day(year, month)) as u64 * DAY,
+            days_from_civil(next_year, next_month, self.reset_day(next_year, next_month)) as u64
+                * DAY,
+        )
+    }
+
+    // months shorter than the reset day start over on their last day
+    fn reset_day(&self, year: i64, month: u32) -> u32 {
+        self.config.reset_day.min(days_in_month(year, month))
+    }
+
+    pub fn set_limit(&self, public_key: &str, limit: Option<u64>) -> rusqlite::Result<()> {
+        self.db.conn().execute(
+            "UPDATE peers SET quota_bytes = ?2 WHERE public_key = ?1",
+            params![public_key, limit],
+        )?;
+        Ok(())
+    }
+
+    pub fn states(&self, interface: &str, time: u64) -> rusqlite::Result<Vec<QuotaState>> {
+        let (start, _) = self.period(time);
+        let conn = self.db.conn();
+        let mut statement = conn.prepare(
+            "SELECT p.public_key, p.allowed_ips, p.name, p.quota_bytes, p.quota_warned,
+                    p.quota_disabled, p.user_disabled,
+                    (SELECT COALESCE(SUM(t.rx + t.tx), 0) FROM traffic t
+                     WHERE t.public_key = p.public_key AND t.resolution = ?2 AND t.time >= ?3)
+             FROM peers p JOIN interfaces i ON i.id = p.interface_id
+             WHERE i.name = ?1
+               AND (p.quota_bytes IS NOT NULL OR p.quota_disabled != 0 OR p.user_disabled != 0)",
+        )?;
+        let states = statement
+            .query_map(params![interface, DAY, start], |row| {
+                Ok(QuotaState {
+                    public_key: row.get(0)?,
+                    allowed_ips: row.get(1)?,
+                    name: row.get(2)?,
+                    limit: row.get(3)?,
+                    warned: row.get(4)?,
+                    disabled: row.get(5)?,
+                    user_disabled: row.get(6)?,
+                    used: row.get(7)?,
+                })
+            })?
+            .collect::<Result<_, _>>();
+        states
+    }
+
+    // Warns about, disables and enables the peers as their usage says.
+    pub fn enforce(&self, data: &AppData) {
+        self.enforce_at(data, now())
+    }
+
+    fn enforce_at(&self, data: &AppData, time: u64) {
+        let wg = &data.wg;
+        let _enforcing = self.enforcing.lock().unwrap();
+        let (start, _) = self.period(time);
+        let states = match self.states(&wg.interface, time) {
+            Ok(states) => states,
+            Err(e) => {
+                error!("Could not read the quotas: {}", e);
+                return;
+            }
+        };
+
+        for state in states {
+            let used_up = state.limit.map(|l| state.used >= l).unwrap_or(false);
+            if used_up != state.disabled {
+                if let Err(e) = self.set_disabled(wg, &state, used_up) {
+                    error!("Could not update peer {}: {}", state.public_key, e);
+                    continue;
+                }
+                let action = if used_up {
+                    "peer.disable"
+                } else {
+                    "peer.enable"
+                };
+                data.audit.change(
+                    ACTOR,
+                    action,
+                    &state.public_key,
+                    None,
+                    Some(&usage(&state)),
+                    "",
+                );
+                // disabled peers move to the end of the list
+                data.events.send(&shared::Event::ConfigChanged);
+                // a peer disabled by a user was off the interface already
+                if state.user_disabled {
+                    continue;
+                }
+                data.webhooks.notify(
+                    if used_up {
+                        "peer.disabled"
+                    } else {
+                        "peer.enabled"
+                    },
+                    &wg.interface,
+                    PeerInfo {
+                        public_key: &state.public_key,
+                        name: &state.name,
+                        allowed_ips: &state.allowed_ips,
+                    },
+                );
+            }
+
+            let limit = match state.limit {
+                Some(limit) => limit,
+                None => continue,
+            };
+            let warn_at = limit as u128 * self.config.warn_percent as u128 / 100;
+            if !used_up && state.used as u128 >= warn_at && state.warned != Some(start) {
+                warn!("Peer {} used {} of its quota", state.name, usage(&state));
+                data.audit.change(
+                    ACTOR,
+                    "peer.quota_warning",
+                    &state.public_key,
+                    None,
+                    Some(&usage(&state)),
+                    "",
+                );
+                if let Err(e) = self.db.conn().execute(
+                    "UPDATE peers SET quota_warned = ?2 WHERE public_key = ?1",
+                    params![state.public_key, start],
+                ) {
+                    error!("Could not update peer {}: {}", state.public_key, e);
+                }
+            }
+        }
+    }
+
+    // Takes the peer off the interface or puts it back, for a user. A peer
+    // disabled by its quota stays off. None if it was like that already,
+    // otherwise whether the interface changed.
+    pub fn set_user_disabled(
+        &self,
+        wg: &WireGuard,
+        peer: &shared::wg_conf::Peer,
+        disabled: bool,
+    ) -> Result<Option<bool>, String> {
+        let _enforcing = self.enforcing.lock().unwrap();
+        let (quota_disabled, user_disabled) = self
+            .db
+            .conn()
+            .query_row(
+                "SELECT quota_disabled, user_disabled FROM peers WHERE public_key = ?1",
+                [&peer.public_key],
+                |row| Ok((row.get::<_, bool>(0)?, row.get::<_, bool>(1)?)),
+            )
+            .map_err(|e| e.to_string())?;
+        if user_disabled == disabled {
+            return Ok(None);
+        }
+        let wg_peer = shared::wg_conf::Peer {
+            public_key: peer.public_key.clone(),
+            allowed_ips: peer.allowed_ips,
+            ..Default::default()
+        };
+        if !quota_disabled {
+            if disabled {
+                wg.remove_peer(&wg_peer).map_err(|e| e.to_string())?;
+            } else {
+                wg.add_peer(&wg_peer).map_err(|e| e.to_string())?;
+            }
+        }
+        self.db
+            .conn()
+            .execute(
+                "UPDATE peers SET user_disabled = ?2 WHERE public_key = ?1",
+                params![peer.public_key, disabled],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(Some(!quota_disabled))
+    }
+
+    // the interface is changed first, a failed update is done again next time
+    fn set_disabled(
+        &self,
+        wg: &WireGuard,
+        state: &QuotaState,
+        disabled: bool,
+    ) -> Result<(), String> {
+        let peer = shared::wg_conf::Peer {
+            public_key: state.public_key.clone(),
+            allowed_ips: state.allowed_ips.parse().map_err(|e| format!("{}", e))?,
+            ..Default::default()
+        };
+        if state.user_disabled {
+            // only the flag changes
+        } else if disabled {
+            wg.remove_peer(&peer).map_err(|e| e.to_string())?;
+            warn!("Disabled peer {}, its quota is used up", state.name);
+        } else {
+            wg.add_peer(&peer).map_err(|e| e.to_string())?;
+            info!("Enabled peer {} again", state.name);
+        }
+        self.db
+            .conn()
+            .execute(
+                "UPDATE peers SET quota_disabled = ?2 WHERE public_key = ?1",
+                params![state.public_key, disabled],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+fn usage(state: &QuotaState) -> String {
+    match state.limit {
+        Some(limit) => format!("{} of {} bytes", state.used, limit),
+        None => format!("{} bytes, no quota", state.used),
+    }
+}
+
+// Days since 1970-01-01 to year, month and day and back, in the proleptic
+// Gregorian calendar (http://howardhinnant.github.io/date_algorithms.html).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn next_month(year: i64, month: u32) -> (i64, u32) {
+    if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    }
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    let (next_year, next_month) = next_month(year, month);
+    (days_from_civil(next_year, next_month, 1) - days_from_civil(year, month, 1)) as u32
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let month = month as i64;
+    let doy = (153 * if month > 2 { month - 3 } else { month + 9 } + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditQuery;
+    use crate::{test_data, PubPrivKey};
+    use actix_web::web;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    const KEY: &str = "quota/peer+key=";
+    const NOON: u64 = 12 * 60 * 60;
+
+    fn with_reset_day(reset_day: u32) -> (Quotas, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let config = QuotaConfig {
+            reset_day,
+            ..Default::default()
+        };
+        (
+            Quotas::new(Db::open(dir.path(), None).unwrap(), config),
+            dir,
+        )
+    }
+
+    fn time(year: i64, month: u32, day: u32) -> u64 {
+        days_from_civil(year, month, day) as u64 * DAY
+    }
+
+    #[test]
+    fn converts_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        // 2100 is not a leap year, 2000 was
+        assert_eq!(civil_from_days(47_540), (2100, 2, 28));
+        assert_eq!(civil_from_days(47_541), (2100, 3, 1));
+        for days in -800_000..800_000 {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn counts_days_of_months() {
+        assert_eq!(days_in_month(2023, 1), 31);
+        assert_eq!(days_in_month(2023, 2), 28);
+        assert_eq!(days_in_month(2024, 2), 29);
+        assert_eq!(days_in_month(2000, 2), 29);
+        assert_eq!(days_in_month(2100, 2), 28);
+        assert_eq!(days_in_month(2023, 4), 30);
+        assert_eq!(days_in_month(2023, 12), 31);
+    }
+
+    #[test]
+    fn periods_roll_over_months_and_years() {
+        let (quotas, _dir) = with_reset_day(1);
+        let noon = 12 * 60 * 60;
+        assert_eq!(
+            quotas.period(time(2023, 5, 17) + noon),
+            (time(2023, 5, 1), time(2023, 6, 1))
+        );
+        assert_eq!(
+            quotas.period(time(2023, 12, 31) + noon),
+            (time(2023, 12, 1), time(2024, 1, 1))
+        );
+        // midnight already belongs to the new month
+        assert_eq!(
+            quotas.period(time(2024, 1, 1)),
+            (time(2024, 1, 1), time(2024, 2, 1))
+        );
+
+        let (quotas, _dir) = with_reset_day(15);
+        assert_eq!(
+            quotas.period(time(2024, 1, 14) + noon),
+            (time(2023, 12, 15), time(2024, 1, 15))
+        );
+        assert_eq!(
+            quotas.period(time(2023, 12, 15)),
+            (time(2023, 12, 15), time(2024, 1, 15))
+        );
+    }
+
+    #[test]
+    fn late_reset_days_use_the_end_of_short_months() {
+        let (quotas, _dir) = with_reset_day(31);
+        assert_eq!(
+            quotas.period(time(2023, 2, 27)),
+            (time(2023, 1, 31), time(2023, 2, 28))
+        );
+        assert_eq!(
+            quotas.period(time(2023, 2, 28)),
+            (time(2023, 2, 28), time(2023, 3, 31))
+        );
+        assert_eq!(
+            quotas.period(time(2023, 4, 30)),
+            (time(2023, 4, 30), time(2023, 5, 31))
+        );
+        assert_eq!(
+            quotas.period(time(2023, 12, 31)),
+            (time(2023, 12, 31), time(2024, 1, 31))
+        );
+
+        let (quotas, _dir) = with_reset_day(29);
+        // leap years, 2100 isn't one
+        assert_eq!(
+            quotas.period(time(2024, 2, 29)),
+            (time(2024, 2, 29), time(2024, 3, 29))
+        );
+        assert_eq!(
+            quotas.period(time(2100, 2, 28)),
+            (time(2100, 2, 28), time(2100, 3, 29))
+        );
+        assert_eq!(
+            quotas.period(time(2100, 2, 27)),
+            (time(2100, 1, 29), time(2100, 2, 28))
+        );
+
+        let (quotas, _dir) = with_reset_day(30);
+        assert_eq!(
+            quotas.period(time(2024, 3, 1)),
+            (time(2024, 2, 29), time(2024, 3, 30))
+        );
+        // every moment is in exactly one period
+        let mut end = quotas.period(time(2023, 1, 1)).1;
+        for _ in 0..48 {
+            let (start, next) = quotas.period(end);
+            assert_eq!(start, end);
+            assert!(next > start);
+            end = next;
+        }
+    }
+
+    // A wrapper that writes the peers it adds and removes to `calls`, and a
+    // peer with a quota of 1000 bytes.
+    fn setup(dir: &Path) -> web::Data<AppData> {
+        let wrapper = dir.join("wg_wrapper.bin");
+        std::fs::write(
+            &wrapper,
+            format!(
+                "#!/bin/sh
+case \"$1\" in
+\
+                 add) sed -n 's/^PublicKey = /add /p' \"$3\" >> {calls} ;;
+\
+                 remove) echo \"remove $3\" >> {calls} ;;
+\
+                 esac
+exit 0
+",
+                calls = dir.join("calls").display()
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let data = test_data(dir);
+        let key = PubPrivKey {
+            private_key: "private".to_string(),
+            public_key: KEY.to_string(),
+            name: "peer".to_string(),
+        };
+        data.db.save_peer("wg0", "10.200.100.2/32", &key).unwrap();
+        data.quotas.set_limit(KEY, Some(1000)).unwrap();
+        data
+    }
+
+    fn calls(dir: &Path) -> Vec<String> {
+        let calls = std::fs::read_to_string(dir.join("calls")).unwrap_or_default();
+        let _ = std::fs::remove_file(dir.join("calls"));
+        calls.lines().map(|l| l.to_string()).collect()
+    }
+
+    fn add_traffic(data: &AppData, time: u64, bytes: u64) {
+        data.db
+            .conn()
+            .execute(
+                "INSERT INTO traffic (public_key, resolution, time, rx, tx) VALUES (?1, ?2, ?3, ?4, 0)
+                 ON CONFLICT (public_key, resolution, time) DO UPDATE SET rx = rx + ?4",
+                params![KEY, DAY, time / DAY * DAY, bytes],
+            )
+            .unwrap();
+    }
+
+    // quota_disabled, user_disabled and quota_warned of the peer
+    fn flags(data: &AppData) -> (bool, bool, Option<u64>) {
+        data.db
+            .conn()
+            .query_row(
+                "SELECT quota_disabled, user_disabled, quota_warned FROM peers
+                 WHERE public_key = ?1",
+                [KEY],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .unwrap()
+    }
+
+    fn actions(data: &AppData) -> Vec<String> {
+        let mut actions = data
+            .audit
+            .entries(&AuditQuery::default(), -1)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.action)
+            .collect::<Vec<_>>();
+        // newest first
+        actions.reverse();
+        actions
+    }
+
+    #[test]
+    fn disables_at_the_limit_until_the_quota_allows_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = setup(dir.path());
+        let now = time(2024, 5, 10) + NOON;
+        let add = format!("add {}", KEY);
+        let remove = format!("remove {}", KEY);
+
+        add_traffic(&data, now, 999);
+        data.quotas.enforce_at(&data, now);
+        assert!(!flags(&data).0);
+        add_traffic(&data, now, 1);
+        data.quotas.enforce_at(&data, now);
+        assert!(flags(&data).0);
+        assert_eq!(calls(dir.path()), [remove.as_str()]);
+        data.quotas.enforce_at(&data, now);
+        assert!(calls(dir.path()).is_empty());
+
+        // a raised quota
+        data.quotas.set_limit(KEY, Some(2000)).unwrap();
+        data.quotas.enforce_at(&data, now);
+        assert!(!flags(&data).0);
+        assert_eq!(calls(dir.path()), [add.as_str()]);
+
+        // and the next month
+        data.quotas.set_limit(KEY, Some(1000)).unwrap();
+        data.quotas.enforce_at(&data, now);
+        assert_eq!(calls(dir.path()), [remove.as_str()]);
+        data.quotas.enforce_at(&data, time(2024, 6, 1));
+        assert!(!flags(&data).0);
+        assert_eq!(calls(dir.path()), [add.as_str()]);
+
+        let changes = actions(&data)
+            .into_iter()
+            .filter(|a| a != "peer.quota_warning")
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            ["peer.disable", "peer.enable", "peer.disable", "peer.enable"]
+        );
+    }
+
+    #[test]
+    fn warns_once_per_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = setup(dir.path());
+        let now = time(2024, 5, 10) + NOON;
+
+        add_traffic(&data, now, 799);
+        data.quotas.enforce_at(&data, now);
+        assert_eq!(flags(&data).2, None);
+        add_traffic(&data, now, 1);
+        data.quotas.enforce_at(&data, now);
+        data.quotas.enforce_at(&data, now + 60);
+        assert_eq!(flags(&data).2, Some(time(2024, 5, 1)));
+        assert_eq!(actions(&data), ["peer.quota_warning"]);
+
+        // the usage of the last month doesn't count in the next one
+        let next = time(2024, 6, 3);
+        data.quotas.enforce_at(&data, next);
+        assert_eq!(actions(&data).len(), 1);
+        add_traffic(&data, next, 800);
+        data.quotas.enforce_at(&data, next);
+        assert_eq!(flags(&data).2, Some(time(2024, 6, 1)));
+        assert_eq!(actions(&data), ["peer.quota_warning", "peer.quota_warning"]);
+        assert!(calls(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn peers_disabled_by_users_stay_off() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = setup(dir.path());
+        let now = time(2024, 5, 10) + NOON;
+        let peer = shared::wg_conf::Peer {
+            public_key: KEY.to_string(),
+            allowed_ips: "10.200.100.2/32".parse().unwrap(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            data.quotas.set_user_disabled(&data.wg, &peer, true),
+            Ok(Some(true))
+        );
+        assert_eq!(calls(dir.path()), [format!("remove {}", KEY)]);
+        assert_eq!(
+            data.quotas.set_user_disabled(&data.wg, &peer, true),
+            Ok(None)
+        );
+
+        // the quota only changes the flag, the peer is off the interface already
+        add_traffic(&data, now, 1000);
+        data.quotas.enforce_at(&data, now);
+        assert_eq!(flags(&data), (true, true, None));
+        assert!(calls(dir.path()).is_empty());
+
+        // enabled by the user, it stays off until the quota allows it
+        assert_eq!(
+            data.quotas.set_user_disabled(&data.wg, &peer, false),
+            Ok(Some(false))
+        );
+        assert!(calls(dir.path()).is_empty());
+        data.quotas.enforce_at(&data, time(2024, 6, 1));
+        assert_eq!(flags(&data), (false, false, None));
+        assert_eq!(calls(dir.path()), [format!("add {}", KEY)]);
+
+        // a new month doesn't put back a peer the user disabled
+        assert_eq!(
+            data.quotas.set_user_disabled(&data.wg, &peer, true),
+            Ok(Some(true))
+        );
+        add_traffic(&data, time(2024, 6, 2), 1000);
+        data.quotas.enforce_at(&data, time(2024, 6, 2));
+        data.quotas.enforce_at(&data, time(2024, 7, 1));
+        assert_eq!(flags(&data), (false, true, None));
+        assert_eq!(calls(dir.path()), [format!("remove {}", KEY)]);
+    }
+}
@@ -0,0 +1,612 @@
+use crate::config::{Config, SessionConfig};
+use crate::db::Db;
+use actix_identity::{CookieIdentityPolicy, Identity, IdentityPolicy};
+use actix_web::cookie::time::Duration;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{http::header, Error, HttpRequest};
+use rand::RngCore;
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::future::{ready, Ready};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, info, warn};
+
+const KEY_FILE: &str = "session_keys.json";
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+pub fn random_token(len: usize) -> String {
+    let mut bytes = vec![0; len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
+}
+
+// ---- Signing keys ----
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RetiredKey {
+    key: String,
+    retired_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SessionKeys {
+    current: String,
+    #[serde(default)]
+    retired: Vec<RetiredKey>,
+}
+
+impl SessionKeys {
+    // Reads the key file from the data dir, creating it with a fresh key on
+    // first run. Retired keys past the grace period are dropped.
+    pub fn load_or_create(data_dir: &Path, grace_secs: u64) -> Result<Self, std::io::Error> {
+        let path = data_dir.join(KEY_FILE);
+        if !path.exists() {
+            let keys = Self {
+                current: random_token(64),
+                retired: vec![],
+            };
+            keys.save(&path)?;
+            info!("Generated new session key in {}", path.display());
+            return Ok(keys);
+        }
+
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        if mode & 0o077 != 0 {
+            warn!(
+                "{} is accessible by other users (mode {:o}), run chmod 600 on it",
+                path.display(),
+                mode & 0o777
+            );
+        }
+
+        let content = std::fs::read_to_string(&path)?;
+        let mut keys: Self = serde_json::from_str(&content)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        let keys_valid = std::iter::once(&keys.current)
+            .chain(keys.retired.iter().map(|k| &k.key))
+            .all(|k| {
+                base64::decode_config(k, base64::URL_SAFE_NO_PAD)
+                    .map(|k| k.len() >= 32)
+                    .unwrap_or(false)
+            });
+        if !keys_valid {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{} contains a key shorter than 32 bytes", path.display()),
+            ));
+        }
+
+        let count = keys.retired.len();
+        let now = now();
+        keys.retired
+            .retain(|k| k.retired_at.saturating_add(grace_secs) > now);
+        if keys.retired.len() != count {
+            keys.save(&path)?;
+        }
+        Ok(keys)
+    }
+
+    // Replaces the current key, the old one stays valid for the grace period.
+    pub fn rotate(data_dir: &Path, grace_secs: u64) -> Result<(), std::io::Error> {
+        let mut keys = Self::load_or_create(data_dir, grace_secs)?;
+        let old = std::mem::replace(&mut keys.current, random_token(64));
+        keys.retired.insert(
+            0,
+            RetiredKey {
+                key: old,
+                retired_at: now(),
+            },
+        );
+        keys.save(&data_dir.join(KEY_FILE))
+    }
+
+    fn save(&self, path: &Path) -> Result<(), std::io::Error> {
+        let json = serde_json::to_string_pretty(self)?;
+        let tmp = path.with_extension("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(tmp, path)
+    }
+
+    pub fn policy(&self, config: &Config) -> KeyRingPolicy {
+        let policy = |key: &str| {
+            let key = base64::decode_config(key, base64::URL_SAFE_NO_PAD).unwrap_or_default();
+            CookieIdentityPolicy::new(&key)
+                .name(config.cookie.name.clone())
+                .same_site(config.cookie.same_site())
+                .secure(config.secure_cookies())
+                .login_deadline(Duration::seconds(config.session.lifetime_secs as i64))
+                .visit_deadline(Duration::seconds(config.session.idle_timeout_secs as i64))
+        };
+
+        KeyRingPolicy {
+            current: policy(&self.current),
+            retired: self.retired.iter().map(|k| policy(&k.key)).collect(),
+        }
+    }
+}
+
+// Cookie policy that issues cookies with the current key but still accepts
+// cookies signed with retired keys, re-issuing them with the current one.
+pub struct KeyRingPolicy {
+    current: CookieIdentityPolicy,
+    retired: Vec<CookieIdentityPolicy>,
+}
+
+impl IdentityPolicy for KeyRingPolicy {
+    type Future = Ready<Result<Option<String>, Error>>;
+    type ResponseFuture = Ready<Result<(), Error>>;
+
+    fn from_request(&self, req: &mut ServiceRequest) -> Self::Future {
+        for policy in std::iter::once(&self.current).chain(&self.retired) {
+            // the cookie policy never has to wait, its futures are always ready
+            match policy.from_request(req).into_inner() {
+                Ok(None) => continue,
+                result => return ready(result),
+            }
+        }
+        ready(Ok(None))
+    }
+
+    fn to_response<B>(
+        &self,
+        identity: Option<String>,
+        changed: bool,
+        response: &mut ServiceResponse<B>,
+    ) -> Self::ResponseFuture {
+        ready(
+            self.current
+                .to_response(identity, changed, response)
+                .into_inner(),
+        )
+    }
+}
+
+// ---- Server side sessions ----
+
+// don't write the database on every request just to bump `last_seen`
+const LAST_SEEN_RESOLUTION_SECS: u64 = 60;
+
+// The cookie only carries a random session id, the session itself lives
+// in the database so it can be listed and revoked from the server.
+pub struct Sessions {
+    db: Db,
+    config: SessionConfig,
+}
+
+impl Sessions {
+    pub fn new(db: Db, config: SessionConfig) -> Self {
+        Self { db, config }
+    }
+
+    pub fn login(&self, id: &Identity, req: &HttpRequest, user: &str) {
+        self.purge_expired();
+        let session_id = random_token(32);
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|ua| ua.to_str().ok())
+            .unwrap_or_default();
+        let result = self.db.conn().execute(
+            "INSERT INTO sessions (id, user_id, created, last_seen, handle, ip, user_agent)
+             SELECT ?1, id, ?2, ?2, ?3, ?4, ?5 FROM users WHERE name = ?6",
+            params![
+                session_id,
+                now(),
+                random_token(12),
+                client_ip(req),
+                user_agent,
+                user
+            ],
+        );
+        match result {
+            Ok(1) => id.remember(session_id),
+            Ok(_) => error!("Could not save session: unknown user {}", user),
+            Err(e) => error!("Could not save session: {}", e),
+        }
+    }
+
+    pub fn logout(&self, id: &Identity) {
+        if let Some(session_id) = id.identity() {
+            self.revoke(&session_id);
+        }
+        id.forget();
+    }
+
+    // name of the logged in user, if the session is known and not expired
+    pub fn user(&self, id: &Identity) -> Option<String> {
+        let session_id = id.identity()?;
+        let (user, created, last_seen): (String, u64, u64) = self
+            .db
+            .conn()
+            .query_row(
+                "SELECT u.name, s.created, s.last_seen FROM sessions s
+                 JOIN users u ON u.id = s.user_id WHERE s.id = ?1",
+                [&session_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .ok()?;
+        if self.expired(created, last_seen) {
+            self.revoke(&session_id);
+            return None;
+        }
+
+        let now = now();
+        if last_seen + LAST_SEEN_RESOLUTION_SECS <= now {
+            let _ = self.db.conn().execute(
+                "UPDATE sessions SET last_seen = ?1 WHERE id = ?2",
+                params![now, session_id],
+            );
+        }
+        Some(user)
+    }
+
+    pub fn list(&self, id: &Identity, user: &str) -> Vec<shared::SessionInfo> {
+        let current = id.identity().unwrap_or_default();
+        let conn = self.db.conn();
+        let mut statement = match conn.prepare(
+            "SELECT s.id, s.handle, s.created, s.last_seen, s.ip, s.user_agent FROM sessions s
+             JOIN users u ON u.id = s.user_id WHERE u.name = ?1 ORDER BY s.last_seen DESC",
+        ) {
+            Ok(statement) => statement,
+            Err(_) => return vec![],
+        };
+        let sessions = statement.query_map([user], |row| {
+            Ok(shared::SessionInfo {
+                current: row.get::<_, String>(0)? == current,
+                id: row.get(1)?,
+                created: row.get(2)?,
+                last_seen: row.get(3)?,
+                ip: row.get(4)?,
+                user_agent: row.get(5)?,
+            })
+        });
+        match sessions {
+            Ok(sessions) => sessions
+                .filter_map(Result::ok)
+                .filter(|s| !self.expired(s.created, s.last_seen))
+                .collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    // revoke one of the sessions of `user` by its public handle
+    pub fn revoke_by_handle(&self, user: &str, handle: &str) -> bool {
+        self.db
+            .conn()
+            .execute(
+                "DELETE FROM sessions WHERE handle = ?1
+                 AND user_id = (SELECT id FROM users WHERE name = ?2)",
+                params![handle, user],
+            )
+            .map(|deleted| deleted > 0)
+            .unwrap_or(false)
+    }
+
+    // ends every session of `user` except the one making the request
+    pub fn revoke_others(&self, id: &Identity, user: &str) {
+        let current = id.identity().unwrap_or_default();
+        let _ = self.db.conn().execute(
+            "DELETE FROM sessions WHERE id != ?1
+             AND user_id = (SELECT id FROM users WHERE name = ?2)",
+            params![current, user],
+        );
+    }
+
+    pub fn revoke(&self, session_id: &str) {
+        let _ = self
+            .db
+            .conn()
+            .execute("DELETE FROM sessions WHERE id = ?1", [session_id]);
+    }
+
+    fn expired(&self, created: u64, last_seen: u64) -> bool {
+        let now = now();
+        created.saturating_add(self.config.lifetime_secs) < now
+            || last_seen.saturating_add(self.config.idle_timeout_secs) < now
+    }
+
+    fn purge_expired(&self) {
+        let now = now();
+        let _ = self.db.conn().execute(
+            "DELETE FROM sessions WHERE created + ?1 < ?3 OR last_seen + ?2 < ?3",
+            params![
+                self.config.lifetime_secs,
+                self.config.idle_timeout_secs,
+                now
+            ],
+        );
+    }
+}
+
+// Address of the client, unix socket connections come from a local reverse
+// proxy so we go by its forwarding headers there.
+pub fn client_ip(req: &HttpRequest) -> String {
+    match req.peer_addr() {
+        Some(addr) => addr.ip().to_string(),
+        None => req
+            .connection_info()
+            .realip_remote_addr()
+            .unwrap_or("unknown")
+            .to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::User;
+    use actix_http::Request;
+    use actix_identity::IdentityService;
+    use actix_web::body::MessageBody;
+    use actix_web::cookie::Cookie;
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::test::{self, TestRequest};
+    use actix_web::{get, web, App, HttpResponse};
+
+    const GRACE: u64 = 60;
+
+    fn cookie<B>(response: &ServiceResponse<B>) -> Cookie<'static> {
+        response.response().cookies().next().unwrap().into_owned()
+    }
+
+    #[get("/remember")]
+    async fn remember(id: Identity) -> HttpResponse {
+        id.remember("session".to_string());
+        HttpResponse::Ok().finish()
+    }
+
+    #[get("/identity")]
+    async fn identity(id: Identity) -> HttpResponse {
+        HttpResponse::Ok().body(id.identity().unwrap_or_default())
+    }
+
+    // the identity a cookie made with `signed_by` has for a server with `keys`
+    async fn identity_with(signed_by: &SessionKeys, keys: &SessionKeys) -> String {
+        let config = Config::default();
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityService::new(signed_by.policy(&config)))
+                .service(remember),
+        )
+        .await;
+        let response =
+            test::call_service(&app, TestRequest::get().uri("/remember").to_request()).await;
+        let cookie = cookie(&response);
+
+        let app = test::init_service(
+            App::new()
+                .wrap(IdentityService::new(keys.policy(&config)))
+                .service(identity),
+        )
+        .await;
+        let req = TestRequest::get()
+            .uri("/identity")
+            .cookie(cookie)
+            .to_request();
+        String::from_utf8(
+            test::read_body(test::call_service(&app, req).await)
+                .await
+                .to_vec(),
+        )
+        .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn rotated_keys_verify_during_the_grace_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = SessionKeys::load_or_create(dir.path(), GRACE).unwrap();
+        let path = dir.path().join(KEY_FILE);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let loaded = SessionKeys::load_or_create(dir.path(), GRACE).unwrap();
+        assert_eq!(loaded.current, keys.current);
+
+        SessionKeys::rotate(dir.path(), GRACE).unwrap();
+        let mut rotated = SessionKeys::load_or_create(dir.path(), GRACE).unwrap();
+        assert_ne!(rotated.current, keys.current);
+        assert_eq!(rotated.retired.len(), 1);
+        assert_eq!(rotated.retired[0].key, keys.current);
+        assert_eq!(identity_with(&keys, &rotated).await, "session");
+
+        // past the grace period the old key is dropped from the file
+        rotated.retired[0].retired_at = now() - GRACE - 1;
+        rotated.save(&path).unwrap();
+        let expired = SessionKeys::load_or_create(dir.path(), GRACE).unwrap();
+        assert!(expired.retired.is_empty());
+        let stored = SessionKeys::load_or_create(dir.path(), u64::MAX).unwrap();
+        assert!(stored.retired.is_empty());
+        assert_eq!(identity_with(&keys, &expired).await, "");
+        assert_eq!(identity_with(&expired, &expired).await, "session");
+    }
+
+    #[get("/login/{user}")]
+    async fn login(
+        req: HttpRequest,
+        id: Identity,
+        sessions: web::Data<Sessions>,
+        user: web::Path<String>,
+    ) -> HttpResponse {
+        sessions.login(&id, &req, &user);
+        HttpResponse::Ok().finish()
+    }
+
+    #[get("/user")]
+    async fn current_user(id: Identity, sessions: web::Data<Sessions>) -> HttpResponse {
+        HttpResponse::Ok().body(sessions.user(&id).unwrap_or_default())
+    }
+
+    #[get("/revoke_others")]
+    async fn revoke_others(id: Identity, sessions: web::Data<Sessions>) -> HttpResponse {
+        sessions.revoke_others(&id, "user");
+        HttpResponse::Ok().finish()
+    }
+
+    struct Setup {
+        db: Db,
+        sessions: web::Data<Sessions>,
+        dir: tempfile::TempDir,
+    }
+
+    fn setup() -> Setup {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), None).unwrap();
+        for name in ["user", "other"] {
+            let user = User {
+                name: name.to_string(),
+                ..Default::default()
+            };
+            db.save_user(name, &user).unwrap();
+        }
+        let config = SessionConfig {
+            lifetime_secs: 3600,
+            idle_timeout_secs: 600,
+            key_grace_secs: GRACE,
+        };
+        let sessions = web::Data::new(Sessions::new(db.clone(), config));
+        Setup { db, sessions, dir }
+    }
+
+    async fn app(
+        setup: &Setup,
+    ) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error> {
+        let keys = SessionKeys::load_or_create(setup.dir.path(), GRACE).unwrap();
+        test::init_service(
+            App::new()
+                .wrap(IdentityService::new(keys.policy(&Config::default())))
+                .app_data(setup.sessions.clone())
+                .service(login)
+                .service(current_user)
+                .service(revoke_others),
+        )
+        .await
+    }
+
+    async fn get<B>(
+        app: &impl Service<Request, Response = ServiceResponse<B>, Error = Error>,
+        uri: &str,
+        cookie: Option<Cookie<'static>>,
+    ) -> ServiceResponse<B> {
+        let mut req = TestRequest::get().uri(uri);
+        if let Some(cookie) = cookie {
+            req = req.cookie(cookie);
+        }
+        test::call_service(app, req.to_request()).await
+    }
+
+    async fn user_of<B: MessageBody>(
+        app: &impl Service<Request, Response = ServiceResponse<B>, Error = Error>,
+        cookie: &Cookie<'static>,
+    ) -> String {
+        let response = get(app, "/user", Some(cookie.clone())).await;
+        String::from_utf8(test::read_body(response).await.to_vec()).unwrap()
+    }
+
+    fn set(db: &Db, column: &str, value: u64) {
+        db.conn()
+            .execute(&format!("UPDATE sessions SET {} = ?1", column), [value])
+            .unwrap();
+    }
+
+    fn count(db: &Db) -> u32 {
+        db.conn()
+            .query_row("SELECT COUNT(*) FROM sessions", [], |row| row.get(0))
+            .unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn sessions_expire() {
+        let setup = setup();
+        let app = app(&setup).await;
+
+        let session = cookie(&get(&app, "/login/user", None).await);
+        assert_eq!(user_of(&app, &session).await, "user");
+        set(&setup.db, "created", now() - 3601);
+        assert_eq!(user_of(&app, &session).await, "");
+        assert_eq!(count(&setup.db), 0);
+
+        let session = cookie(&get(&app, "/login/user", None).await);
+        set(&setup.db, "last_seen", now() - 601);
+        assert_eq!(user_of(&app, &session).await, "");
+        assert_eq!(count(&setup.db), 0);
+    }
+
+    #[actix_rt::test]
+    async fn last_seen_is_written_once_a_minute() {
+        let setup = setup();
+        let app = app(&setup).await;
+        let session = cookie(&get(&app, "/login/user", None).await);
+        let last_seen = |db: &Db| -> u64 {
+            db.conn()
+                .query_row("SELECT last_seen FROM sessions", [], |row| row.get(0))
+                .unwrap()
+        };
+
+        let recent = now() - LAST_SEEN_RESOLUTION_SECS / 2;
+        set(&setup.db, "last_seen", recent);
+        assert_eq!(user_of(&app, &session).await, "user");
+        assert_eq!(last_seen(&setup.db), recent);
+
+        set(&setup.db, "last_seen", now() - LAST_SEEN_RESOLUTION_SECS);
+        assert_eq!(user_of(&app, &session).await, "user");
+        assert!(last_seen(&setup.db) >= now() - 1);
+    }
+
+    #[actix_rt::test]
+    async fn sessions_are_revoked() {
+        let setup = setup();
+        let app = app(&setup).await;
+        let first = cookie(&get(&app, "/login/user", None).await);
+        let second = cookie(&get(&app, "/login/user", None).await);
+        let third = cookie(&get(&app, "/login/user", None).await);
+        let other = cookie(&get(&app, "/login/other", None).await);
+
+        let handle: String = setup
+            .db
+            .conn()
+            .query_row(
+                "SELECT s.handle FROM sessions s JOIN users u ON u.id = s.user_id
+                 WHERE u.name = 'user' LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        // only the owner can revoke a session by its handle
+        assert!(!setup.sessions.revoke_by_handle("other", &handle));
+        assert_eq!(count(&setup.db), 4);
+        assert!(setup.sessions.revoke_by_handle("user", &handle));
+        assert!(!setup.sessions.revoke_by_handle("user", &handle));
+        assert_eq!(count(&setup.db), 3);
+
+        let mut live = vec![];
+        for session in [first, second, third] {
+            if !user_of(&app, &session).await.is_empty() {
+                live.push(session);
+            }
+        }
+        assert_eq!(live.len(), 2);
+        let (keep, revoked) = (live[0].clone(), live[1].clone());
+        get(&app, "/revoke_others", Some(keep.clone())).await;
+        assert_eq!(user_of(&app, &keep).await, "user");
+        assert_eq!(user_of(&app, &revoked).await, "");
+        assert_eq!(user_of(&app, &other).await, "other");
+        assert_eq!(count(&setup.db), 2);
+    }
+}
@@ -0,0 +1,209 @@
+use crate::db::Db;
+use crate::session::{client_ip, now, random_token};
+use crate::tokens::{authorized_user, hash};
+use crate::totp::qr_code;
+use crate::{current_wg_config, AppData};
+use actix_identity::Identity;
+use actix_web::{post, web, HttpRequest, HttpResponse, Responder};
+use rusqlite::params;
+use shared::TokenScope;
+use tracing::{error, info};
+
+// links can be made for a week at most
+const MAX_HOURS: u64 = 7 * 24;
+
+// Links that show the config of a peer once to whoever has them, without a
+// login. Only the sha256 of the token is stored, the link itself is shown
+// once to the user that made it.
+pub struct ShareLinks {
+    db: Db,
+}
+
+impl ShareLinks {
+    pub fn new(db: Db) -> Self {
+        Self { db }
+    }
+
+    // returns the token, expired links are removed on the way
+    fn create(&self, public_key: &str, user: &str, expires: u64) -> rusqlite::Result<String> {
+        let token = random_token(32);
+        let time = now();
+        let conn = self.db.conn();
+        conn.execute("DELETE FROM share_links WHERE expires <= ?1", [time])?;
+        conn.execute(
+            "INSERT INTO share_links (hash, public_key, created_by, created, expires)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![hash(&token), public_key, user, time, expires],
+        )?;
+        Ok(token)
+    }
+
+    // the public key of the peer, the link is gone afterwards
+    fn redeem(&self, token: &str) -> rusqlite::Result<Option<String>> {
+        let key = hash(token);
+        let conn = self.db.conn();
+        let public_key = conn
+            .query_row(
+                "SELECT public_key FROM share_links WHERE hash = ?1 AND expires > ?2",
+                params![key, now()],
+                |row| row.get(0),
+            )
+            .ok();
+        conn.execute("DELETE FROM share_links WHERE hash = ?1", [key])?;
+        Ok(public_key)
+    }
+}
+
+// ---- Apis ----
+
+// Anybody with the link gets the private key, so making one needs the same
+// rights as downloading the config.
+#[post("/share_peer")]
+async fn share_peer(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    request_data: web::Json<shared::Request>,
+) -> impl Responder {
+    let username = match authorized_user(&req, &id, &data, TokenScope::DownloadConfigs) {
+        Some(username) => username,
+        None => return HttpResponse::Forbidden().body(""),
+    };
+    let (public_key, hours) = match request_data.0 {
+        shared::Request::SharePeer { public_key, hours } if (1..=MAX_HOURS).contains(&hours) => {
+            (public_key, hours)
+        }
+        _ => return HttpResponse::Ok().json(shared::Response::Failure),
+    };
+    let wg_config = current_wg_config(&data);
+    let peer = match wg_config.peer(&public_key) {
+        // the client keeps the private key of some peers, there is nothing to share
+        Some(peer) if !peer.private_key.is_empty() => peer,
+        _ => return HttpResponse::Ok().json(shared::Response::Failure),
+    };
+
+    let expires = now() + hours * 60 * 60;
+    match data.shares.create(&peer.public_key, &username, expires) {
+        Ok(token) => {
+            data.audit.change(
+                &username,
+                "peer.share",
+                &peer.public_key,
+                None,
+                Some(&format!("valid for {} hours", hours)),
+                &client_ip(&req),
+            );
+            HttpResponse::Ok().json(shared::Response::ShareLink {
+                public_key: peer.public_key.clone(),
+                token,
+                expires,
+            })
+        }
+        Err(e) => {
+            error!("Could not create a share link: {}", e);
+            HttpResponse::Ok().json(shared::Response::Failure)
+        }
+    }
+}
+
+// A post, so link previews of chat apps don't use up the link.
+#[post("/share/{token}")]
+async fn open_share(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    token: web::Path<String>,
+) -> impl Responder {
+    let public_key = match data.shares.redeem(&token) {
+        Ok(Some(public_key)) => public_key,
+        Ok(None) => return HttpResponse::Ok().json(shared::Response::Failure),
+        Err(e) => {
+            error!("Could not open a share link: {}", e);
+            return HttpResponse::Ok().json(shared::Response::Failure);
+        }
+    };
+    let wg_config = current_wg_config(&data);
+    let peer = match wg_config
+        .peers
+        .iter()
+        .find(|p| p.public_key == public_key && !p.private_key.is_empty())
+    {
+        Some(peer) => peer,
+        None => return HttpResponse::Ok().json(shared::Response::Failure),
+    };
+
+    let config = wg_config.peer_config(peer);
+    let qr_code = match qr_code(&config) {
+        Some(qr_code) => qr_code,
+        None => return HttpResponse::Ok().json(shared::Response::Failure),
+    };
+    info!("Share link of peer {} was opened", peer.name);
+    data.audit
+        .record("", "peer.share_open", &peer.public_key, &client_ip(&req));
+    HttpResponse::Ok().json(shared::Response::SharedConfig {
+        name: peer.name.clone(),
+        config,
+        qr_code,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (ShareLinks, Db, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), None).unwrap();
+        (ShareLinks::new(db.clone()), db, dir)
+    }
+
+    fn stored(db: &Db) -> Vec<String> {
+        let conn = db.conn();
+        let mut statement = conn.prepare("SELECT hash FROM share_links").unwrap();
+        let hashes = statement
+            .query_map([], |row| row.get(0))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        hashes
+    }
+
+    #[test]
+    fn opens_only_once() {
+        let (shares, _db, _dir) = setup();
+        let token = shares.create("peer", "admin", now() + 3600).unwrap();
+        assert_eq!(shares.redeem(&token).unwrap().as_deref(), Some("peer"));
+        assert_eq!(shares.redeem(&token).unwrap(), None);
+        assert_eq!(shares.redeem("unknown").unwrap(), None);
+    }
+
+    #[test]
+    fn expired_links_are_refused() {
+        let (shares, db, _dir) = setup();
+        let token = shares.create("peer", "admin", now()).unwrap();
+        assert_eq!(shares.redeem(&token).unwrap(), None);
+        assert!(stored(&db).is_empty());
+
+        // and removed when the next one is made
+        shares.create("peer", "admin", now()).unwrap();
+        shares.create("peer", "admin", now() + 3600).unwrap();
+        assert_eq!(stored(&db).len(), 1);
+    }
+
+    #[test]
+    fn stores_only_the_hash() {
+        let (shares, db, _dir) = setup();
+        let token = shares.create("peer", "admin", now() + 3600).unwrap();
+        assert_eq!(stored(&db), [hash(&token)]);
+        assert_ne!(hash(&token), token);
+    }
+
+    #[test]
+    fn removing_the_peer_removes_its_links() {
+        let (shares, db, _dir) = setup();
+        let token = shares.create("peer", "admin", now() + 3600).unwrap();
+        let other = shares.create("other", "admin", now() + 3600).unwrap();
+        db.remove_peer("peer", || Ok(())).unwrap();
+        assert_eq!(shares.redeem(&token).unwrap(), None);
+        assert_eq!(shares.redeem(&other).unwrap().as_deref(), Some("other"));
+    }
+}
@@ -0,0 +1,302 @@
+use crate::audit::AuditLog;
+use crate::config::LoginConfig;
+use crate::session::now;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::warn;
+
+#[derive(Default)]
+struct Attempts {
+    // failed attempts since the last success or lockout
+    failures: u32,
+    last_attempt: u64,
+    locked_until: u64,
+}
+
+// Limits password (and code) checks per username and per client address.
+//
+// Every attempt counts as failed until `success` is called, so concurrent
+// requests can't slip past the limit while bcrypt is still running. After a
+// failure the next attempt has to wait `backoff_secs`, doubled with every
+// further failure, and after `max_failures` the key is locked for
+// `lockout_secs`.
+pub struct LoginThrottle {
+    config: LoginConfig,
+    // what is throttled, lockouts are audited as `<kind>.lockout`
+    kind: &'static str,
+    attempts: Mutex<HashMap<String, Attempts>>,
+}
+
+impl LoginThrottle {
+    pub fn new(config: LoginConfig) -> Self {
+        Self::with_kind(config, "login")
+    }
+
+    // Invite codes have their own instance, so enrollments neither reset
+    // nor add to the failed logins of an address.
+    pub fn for_enrollment(config: LoginConfig) -> Self {
+        Self::with_kind(config, "enroll")
+    }
+
+    fn with_kind(config: LoginConfig, kind: &'static str) -> Self {
+        Self {
+            config,
+            kind,
+            attempts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Registers an attempt, or returns how many seconds the client has to wait.
+    pub fn attempt(&self, ip: &str, user: &str, audit: &AuditLog) -> Result<(), u64> {
+        self.attempt_at(ip, user, audit, now())
+    }
+
+    // For a second step of an attempt that was already registered (and waited
+    // for), only fails while the user or address is locked. Use `failure` if
+    // the step fails.
+    pub fn check(&self, ip: &str, user: &str) -> Result<(), u64> {
+        let mut attempts = self.attempts.lock().unwrap();
+        let now = now();
+        self.purge(&mut attempts, now);
+        let locked = self
+            .keys(ip, user)
+            .iter()
+            .filter_map(|(key, _)| attempts.get(key))
+            .map(|a| a.locked_until.saturating_sub(now))
+            .max()
+            .unwrap_or(0);
+        match locked {
+            0 => Ok(()),
+            wait => Err(wait),
+        }
+    }
+
+    pub fn failure(&self, ip: &str, user: &str, audit: &AuditLog) {
+        let mut attempts = self.attempts.lock().unwrap();
+        self.count(&mut attempts, ip, user, audit, now());
+    }
+
+    fn attempt_at(&self, ip: &str, user: &str, audit: &AuditLog, now: u64) -> Result<(), u64> {
+        let mut attempts = self.attempts.lock().unwrap();
+        self.purge(&mut attempts, now);
+
+        let wait = self.current_wait(&attempts, ip, user, now);
+        if wait > 0 {
+            return Err(wait);
+        }
+        self.count(&mut attempts, ip, user, audit, now);
+        Ok(())
+    }
+
+    fn keys(&self, ip: &str, user: &str) -> [(String, u32); 2] {
+        [
+            (format!("user:{}", user), self.config.max_failures),
+            (format!("ip:{}", ip), self.config.max_failures_per_ip),
+        ]
+    }
+
+    fn current_wait(
+        &self,
+        attempts: &HashMap<String, Attempts>,
+        ip: &str,
+        user: &str,
+        now: u64,
+    ) -> u64 {
+        self.keys(ip, user)
+            .iter()
+            .filter_map(|(key, _)| attempts.get(key).map(|a| self.wait(a, now)))
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn count(
+        &self,
+        attempts: &mut HashMap<String, Attempts>,
+        ip: &str,
+        user: &str,
+        audit: &AuditLog,
+        now: u64,
+    ) {
+        for (key, max_failures) in self.keys(ip, user).iter() {
+            let entry = attempts.entry(key.clone()).or_default();
+            entry.failures += 1;
+            entry.last_attempt = now;
+            if entry.failures >= *max_failures {
+                entry.failures = 0;
+                entry.locked_until = now + self.config.lockout_secs;
+                warn!(
+                    "Locked {} after too many failed {} attempts",
+                    key, self.kind
+                );
+                audit.record("", &format!("{}.lockout", self.kind), key, ip);
+            }
+        }
+    }
+
+    // Resets the failures of the user, but only takes back the one attempt
+    // from the address, else a valid login of one account would clear the
+    // guesses against all others.
+    pub fn success(&self, ip: &str, user: &str) {
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.remove(&format!("user:{}", user));
+        let key = format!("ip:{}", ip);
+        if let Some(entry) = attempts.get_mut(&key) {
+            entry.failures = entry.failures.saturating_sub(1);
+            if entry.failures == 0 && entry.locked_until == 0 {
+                attempts.remove(&key);
+            }
+        }
+    }
+
+    fn wait(&self, attempts: &Attempts, now: u64) -> u64 {
+        if attempts.locked_until > now {
+            return attempts.locked_until - now;
+        }
+        if attempts.failures == 0 {
+            return 0;
+        }
+        let backoff = self
+            .config
+            .backoff_secs
+            .saturating_mul(1u64.checked_shl(attempts.failures - 1).unwrap_or(u64::MAX))
+            .min(self.config.max_backoff_secs);
+        (attempts.last_attempt + backoff).saturating_sub(now)
+    }
+
+    // failures are forgotten after a lockout period without attempts
+    fn purge(&self, attempts: &mut HashMap<String, Attempts>, now: u64) {
+        let lockout_secs = self.config.lockout_secs;
+        attempts.retain(|_, a| {
+            a.locked_until > now || a.last_attempt.saturating_add(lockout_secs) > now
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::AuditQuery;
+    use crate::db::Db;
+
+    const T: u64 = 1_000_000;
+
+    fn setup() -> (LoginThrottle, AuditLog, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let audit = AuditLog::new(Db::open(dir.path(), None).unwrap());
+        (LoginThrottle::new(LoginConfig::default()), audit, dir)
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let (throttle, audit, _dir) = setup();
+        assert_eq!(throttle.attempt_at("ip", "user", &audit, T), Ok(()));
+        assert_eq!(throttle.attempt_at("ip", "user", &audit, T), Err(1));
+        assert_eq!(throttle.attempt_at("ip", "user", &audit, T + 1), Ok(()));
+        assert_eq!(throttle.attempt_at("ip", "user", &audit, T + 2), Err(1));
+        assert_eq!(throttle.attempt_at("ip", "user", &audit, T + 3), Ok(()));
+        assert_eq!(throttle.attempt_at("ip", "user", &audit, T + 3), Err(4));
+
+        let attempts = Attempts {
+            failures: 30,
+            last_attempt: T,
+            locked_until: 0,
+        };
+        assert_eq!(throttle.wait(&attempts, T), 60);
+        let attempts = Attempts {
+            failures: 200,
+            ..attempts
+        };
+        assert_eq!(throttle.wait(&attempts, T + 10), 50);
+    }
+
+    #[test]
+    fn locks_after_max_failures() {
+        let (throttle, audit, _dir) = setup();
+        let mut time = T;
+        for _ in 0..5 {
+            time += 60;
+            assert_eq!(throttle.attempt_at("ip", "user", &audit, time), Ok(()));
+        }
+        assert_eq!(
+            throttle.attempt_at("ip", "user", &audit, time + 60),
+            Err(15 * 60 - 60)
+        );
+        // other users from the same address only wait for the backoff
+        assert_eq!(
+            throttle.attempt_at("ip", "other", &audit, time + 60),
+            Ok(())
+        );
+        // and the lock is over after the lockout
+        assert_eq!(
+            throttle.attempt_at("ip2", "user", &audit, time + 15 * 60),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn success_resets_the_failures() {
+        let (throttle, audit, _dir) = setup();
+        assert_eq!(throttle.attempt("ip", "user", &audit), Ok(()));
+        assert!(throttle.attempt("ip", "user", &audit).is_err());
+        throttle.success("ip", "user");
+        assert_eq!(throttle.attempt("ip", "user", &audit), Ok(()));
+    }
+
+    #[test]
+    fn success_keeps_the_failures_of_other_users() {
+        let (throttle, audit, _dir) = setup();
+        let mut time = T;
+        for _ in 0..3 {
+            time += 60;
+            assert_eq!(throttle.attempt_at("ip", "victim", &audit, time), Ok(()));
+        }
+        time += 60;
+        assert_eq!(throttle.attempt_at("ip", "attacker", &audit, time), Ok(()));
+        throttle.success("ip", "attacker");
+
+        let attempts = throttle.attempts.lock().unwrap();
+        assert_eq!(attempts["ip:ip"].failures, 3);
+        assert_eq!(attempts["user:victim"].failures, 3);
+        assert!(!attempts.contains_key("user:attacker"));
+    }
+
+    #[test]
+    fn second_steps_only_count_failures() {
+        let (throttle, audit, _dir) = setup();
+        // the password step
+        assert_eq!(throttle.attempt("ip", "user", &audit), Ok(()));
+        assert_eq!(throttle.check("ip", "user"), Ok(()));
+        assert_eq!(throttle.check("ip", "user"), Ok(()));
+        for _ in 0..3 {
+            throttle.failure("ip", "user", &audit);
+            assert_eq!(throttle.check("ip", "user"), Ok(()));
+        }
+        throttle.failure("ip", "user", &audit);
+        assert!(throttle.check("ip", "user").is_err());
+    }
+
+    #[test]
+    fn enrollments_are_counted_apart_from_logins() {
+        let (login, audit, _dir) = setup();
+        let enroll = LoginThrottle::for_enrollment(LoginConfig::default());
+        assert_eq!(login.attempt_at("ip", "user", &audit, T), Ok(()));
+        let mut time = T;
+        for _ in 0..5 {
+            time += 60;
+            assert_eq!(enroll.attempt_at("ip", "enroll@ip", &audit, time), Ok(()));
+        }
+        assert!(enroll.attempt_at("ip", "enroll@ip", &audit, time).is_err());
+        let actions = audit
+            .entries(&AuditQuery::default(), -1)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.action)
+            .collect::<Vec<_>>();
+        assert_eq!(actions, ["enroll.lockout"]);
+
+        // a valid code leaves the failed logins of the address alone
+        enroll.success("ip", "enroll@ip");
+        assert_eq!(login.attempt_at("ip", "user", &audit, T), Err(1));
+        assert_eq!(login.attempt_at("ip", "other", &audit, T), Err(1));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::config::url_host;
+use actix_web::{http::header, HttpRequest, HttpResponse};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{any_supported_type, CertifiedKey};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tracing::{error, info};
+
+// Hands out the current certificate, swapped in place by `reload` so
+// running listeners pick it up on the next handshake.
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn new(cert_path: &Path, key_path: &Path) -> Result<Arc<Self>, Error> {
+        let current = load_certified_key(cert_path, key_path)?;
+        Ok(Arc::new(Self {
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+            current: RwLock::new(Arc::new(current)),
+        }))
+    }
+
+    pub fn reload(&self) -> Result<(), Error> {
+        let certified_key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = Arc::new(certified_key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+pub fn server_config(resolver: Arc<CertResolver>) -> ServerConfig {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver)
+}
+
+pub fn reload_on_sighup(resolver: Arc<CertResolver>) {
+    use actix_rt::signal::unix::{signal, SignalKind};
+
+    actix_rt::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                error!(
+                    "Could not listen for SIGHUP, certificate reload disabled: {}",
+                    e
+                );
+                return;
+            }
+        };
+
+        while hangup.recv().await.is_some() {
+            match resolver.reload() {
+                Ok(_) => info!("Reloaded TLS certificate"),
+                Err(e) => error!(
+                    "Could not reload TLS certificate, keeping the old one: {}",
+                    e
+                ),
+            }
+        }
+    });
+}
+
+// Used by the plain http listener, `https_port` is where the tls listener
+// runs. The host is the configured one, the Host header is up to the client
+// and only the path and query are taken from the request.
+pub fn redirect_to_https(req: HttpRequest, host: &str, https_port: u16) -> HttpResponse {
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .filter(|p| p.starts_with('/'))
+        .unwrap_or("/");
+    let host = url_host(host);
+    let location = match https_port {
+        443 => format!("https://{}{}", host, path),
+        port => format!("https://{}:{}{}", host, port, path),
+    };
+
+    HttpResponse::MovedPermanently()
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, Error> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(open(cert_path)?))?;
+    if certs.is_empty() {
+        return Err(invalid(cert_path, "no certificate found"));
+    }
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(open(key_path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid(key_path, "no private key found"))?;
+    let key = any_supported_type(&key).map_err(|_| invalid(key_path, "unsupported key type"))?;
+
+    Ok(CertifiedKey::new(
+        certs.into_iter().map(Certificate).collect(),
+        key,
+    ))
+}
+
+fn open(path: &Path) -> Result<File, Error> {
+    File::open(path).map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+}
+
+fn invalid(path: &Path, msg: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), msg),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn location(req: HttpRequest, port: u16) -> String {
+        location_on(req, "vpn.example.com", port)
+    }
+
+    fn location_on(req: HttpRequest, host: &str, port: u16) -> String {
+        let response = redirect_to_https(req, host, port);
+        assert_eq!(response.status(), 301);
+        response
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn keeps_path_and_query() {
+        let req = TestRequest::with_uri("/api/config?x=1").to_http_request();
+        assert_eq!(
+            location(req, 8443),
+            "https://vpn.example.com:8443/api/config?x=1"
+        );
+        let req = TestRequest::with_uri("/").to_http_request();
+        assert_eq!(location(req, 443), "https://vpn.example.com/");
+    }
+
+    #[test]
+    fn brackets_ipv6_hosts() {
+        let req = TestRequest::with_uri("/login").to_http_request();
+        assert_eq!(
+            location_on(req, "2001:db8::1", 8443),
+            "https://[2001:db8::1]:8443/login"
+        );
+        let req = TestRequest::with_uri("/").to_http_request();
+        assert_eq!(
+            location_on(req, "2001:db8::1", 443),
+            "https://[2001:db8::1]/"
+        );
+    }
+
+    #[test]
+    fn ignores_the_host_header() {
+        let req = TestRequest::with_uri("/login")
+            .insert_header((header::HOST, "evil.example.org"))
+            .to_http_request();
+        assert_eq!(location(req, 443), "https://vpn.example.com/login");
+        // absolute form, the authority of the request line isn't used either
+        let req = TestRequest::with_uri("http://evil.example.org/login").to_http_request();
+        assert_eq!(location(req, 443), "https://vpn.example.com/login");
+    }
+}
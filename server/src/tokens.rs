@@ -0,0 +1,345 @@
+use crate::db::Db;
+use crate::session::{client_ip, now, random_token};
+use crate::{get_user_by_name, AppData};
+use actix_identity::Identity;
+use actix_web::http::header::{HeaderMap, AUTHORIZATION};
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use rusqlite::params;
+use sha2::{Digest, Sha256};
+use shared::TokenScope;
+use tracing::error;
+
+const TOKEN_PREFIX: &str = "wgw_";
+const MAX_NAME_LEN: usize = 64;
+// same as for sessions, don't write the database on every request
+const LAST_USED_RESOLUTION_SECS: u64 = 60;
+
+// Tokens for scripts, stored by the sha256 of the secret so the database
+// alone is not enough to use them.
+pub struct ApiTokens {
+    db: Db,
+}
+
+impl ApiTokens {
+    pub fn new(db: Db) -> Self {
+        Self { db }
+    }
+
+    // returns the secret, it is not stored and can't be shown again
+    pub fn create(
+        &self,
+        user: &str,
+        name: String,
+        scopes: Vec<TokenScope>,
+        expires: Option<u64>,
+    ) -> rusqlite::Result<String> {
+        let secret = format!("{}{}", TOKEN_PREFIX, random_token(32));
+        let inserted = self.db.conn().execute(
+            "INSERT INTO api_tokens (hash, id, user_id, name, scopes, created, expires)
+             SELECT ?1, ?2, id, ?3, ?4, ?5, ?6 FROM users WHERE name = ?7",
+            params![
+                hash(&secret),
+                random_token(12),
+                name,
+                serde_json::to_string(&scopes).unwrap(),
+                now(),
+                expires,
+                user
+            ],
+        )?;
+        // nothing is inserted for an unknown user
+        if inserted != 1 {
+            return Err(rusqlite::Error::QueryReturnedNoRows);
+        }
+        Ok(secret)
+    }
+
+    fn list(&self, user: &str) -> Vec<shared::ApiTokenInfo> {
+        let conn = self.db.conn();
+        let mut statement = match conn.prepare(
+            "SELECT t.id, t.name, t.scopes, t.created, t.expires, t.last_used FROM api_tokens t
+             JOIN users u ON u.id = t.user_id WHERE u.name = ?1 ORDER BY t.created",
+        ) {
+            Ok(statement) => statement,
+            Err(_) => return vec![],
+        };
+        let tokens = statement.query_map([user], |row| {
+            Ok(shared::ApiTokenInfo {
+                id: row.get(0)?,
+                name: row.get(1)?,
+                scopes: scopes(row.get(2)?),
+                created: row.get(3)?,
+                expires: row.get(4)?,
+                last_used: row.get(5)?,
+            })
+        });
+        match tokens {
+            Ok(tokens) => tokens.filter_map(Result::ok).collect(),
+            Err(_) => vec![],
+        }
+    }
+
+    fn revoke(&self, user: &str, id: &str) -> bool {
+        self.db
+            .conn()
+            .execute(
+                "DELETE FROM api_tokens WHERE id = ?1
+                 AND user_id = (SELECT id FROM users WHERE name = ?2)",
+                params![id, user],
+            )
+            .map(|deleted| deleted > 0)
+            .unwrap_or(false)
+    }
+
+    // owner of the token, if it is valid and has the scope
+    fn user(&self, secret: &str, scope: TokenScope) -> Option<String> {
+        let key = hash(secret);
+        let (user, token_scopes, last_used) = self.lookup(&key)?;
+        if !token_scopes.contains(&scope) {
+            return None;
+        }
+
+        let now = now();
+        if last_used.unwrap_or(0) + LAST_USED_RESOLUTION_SECS <= now {
+            let _ = self.db.conn().execute(
+                "UPDATE api_tokens SET last_used = ?1 WHERE hash = ?2",
+                params![now, key],
+            );
+        }
+        Some(user)
+    }
+
+    // whether the token exists and hasn't expired, whatever its scopes
+    pub fn valid(&self, secret: &str) -> bool {
+        self.lookup(&hash(secret)).is_some()
+    }
+
+    fn lookup(&self, key: &str) -> Option<(String, Vec<TokenScope>, Option<u64>)> {
+        let (user, token_scopes, expires, last_used): (String, String, Option<u64>, Option<u64>) =
+            self.db
+                .conn()
+                .query_row(
+                    "SELECT u.name, t.scopes, t.expires, t.last_used FROM api_tokens t
+                     JOIN users u ON u.id = t.user_id WHERE t.hash = ?1",
+                    [key],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                )
+                .ok()?;
+        if expires.map(|e| e <= now()).unwrap_or(false) {
+            return None;
+        }
+        Some((user, scopes(token_scopes), last_used))
+    }
+}
+
+// scopes unknown to this version are dropped
+fn scopes(json: String) -> Vec<TokenScope> {
+    serde_json::from_str::<Vec<serde_json::Value>>(&json)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|scope| serde_json::from_value(scope).ok())
+        .collect()
+}
+
+pub fn hash(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+// User behind a request, either from an api token with the given scope or
+// from the session cookie. A request carrying a token is never checked
+// against the session. Either way the role of the user has to allow the scope.
+pub fn authorized_user(
+    req: &HttpRequest,
+    id: &Identity,
+    data: &web::Data<AppData>,
+    scope: TokenScope,
+) -> Option<String> {
+    let name = match bearer_token(req.headers()) {
+        Some(secret) => data.tokens.user(secret, scope)?,
+        None => data.sessions.user(id)?,
+    };
+    let user = get_user_by_name(name, data)?;
+    if user.role.allows(scope) {
+        Some(user.name)
+    } else {
+        None
+    }
+}
+
+// ---- Apis, managing tokens needs a session ----
+
+#[get("/tokens")]
+async fn list_tokens(id: Identity, data: web::Data<AppData>) -> impl Responder {
+    match data.sessions.user(&id) {
+        Some(user) => HttpResponse::Ok().json(shared::Response::ApiTokens {
+            tokens: data.tokens.list(&user),
+        }),
+        None => HttpResponse::Forbidden().body(""),
+    }
+}
+
+#[post("/tokens")]
+async fn create_token(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    request_data: web::Json<shared::Request>,
+) -> impl Responder {
+    let user = match data.sessions.user(&id) {
+        Some(user) => user,
+        None => return web::Json(shared::Response::Failure),
+    };
+    let (name, scopes, expires_in_days) = match request_data.0 {
+        shared::Request::CreateApiToken {
+            name,
+            scopes,
+            expires_in_days,
+        } => (name.trim().to_string(), scopes, expires_in_days),
+        _ => return web::Json(shared::Response::Failure),
+    };
+
+    let scopes = TokenScope::ALL
+        .iter()
+        .copied()
+        .filter(|s| scopes.contains(s))
+        .collect::<Vec<_>>();
+    if name.is_empty()
+        || name.len() > MAX_NAME_LEN
+        || scopes.is_empty()
+        || expires_in_days == Some(0)
+    {
+        return web::Json(shared::Response::Failure);
+    }
+    let expires = match expires_in_days {
+        Some(days) => match days.checked_mul(24 * 60 * 60) {
+            Some(secs) => Some(now().saturating_add(secs)),
+            None => return web::Json(shared::Response::Failure),
+        },
+        None => None,
+    };
+
+    let labels = scopes
+        .iter()
+        .map(|s| s.label())
+        .collect::<Vec<_>>()
+        .join(", ");
+    match data.tokens.create(&user, name.clone(), scopes, expires) {
+        Ok(token) => {
+            data.audit.change(
+                &user,
+                "token.create",
+                &name,
+                None,
+                Some(&labels),
+                &client_ip(&req),
+            );
+            web::Json(shared::Response::ApiTokenCreated {
+                token,
+                tokens: data.tokens.list(&user),
+            })
+        }
+        Err(e) => {
+            error!("Could not save api token: {}", e);
+            web::Json(shared::Response::Failure)
+        }
+    }
+}
+
+#[post("/revoke_token")]
+async fn revoke_token(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    request_data: web::Json<shared::Request>,
+) -> impl Responder {
+    let user = match data.sessions.user(&id) {
+        Some(user) => user,
+        None => return web::Json(shared::Response::Failure),
+    };
+    match request_data.0 {
+        shared::Request::RevokeApiToken { id: token_id } => {
+            if !data.tokens.revoke(&user, &token_id) {
+                return web::Json(shared::Response::Failure);
+            }
+            data.audit
+                .record(&user, "token.revoke", &token_id, &client_ip(&req));
+            web::Json(shared::Response::ApiTokens {
+                tokens: data.tokens.list(&user),
+            })
+        }
+        _ => web::Json(shared::Response::Failure),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::User;
+
+    fn setup() -> (ApiTokens, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), None).unwrap();
+        let user = User {
+            name: "alice".to_string(),
+            ..Default::default()
+        };
+        db.save_user("alice", &user).unwrap();
+        (ApiTokens::new(db), dir)
+    }
+
+    #[test]
+    fn unknown_users_get_no_token() {
+        let (tokens, _dir) = setup();
+        let created = tokens.create("bob", "ci".to_string(), vec![TokenScope::ReadConfig], None);
+        assert!(created.is_err());
+        assert!(tokens.list("bob").is_empty());
+    }
+
+    #[test]
+    fn tokens_need_the_scope_and_expire() {
+        let (tokens, _dir) = setup();
+        let secret = tokens
+            .create(
+                "alice",
+                "ci".to_string(),
+                vec![TokenScope::ReadConfig],
+                None,
+            )
+            .unwrap();
+        assert!(secret.starts_with(TOKEN_PREFIX));
+        assert_eq!(
+            tokens.user(&secret, TokenScope::ReadConfig).as_deref(),
+            Some("alice")
+        );
+        assert_eq!(tokens.user(&secret, TokenScope::ManagePeers), None);
+        assert!(tokens.valid(&secret));
+        assert!(!tokens.valid("wgw_unknown"));
+
+        let expired = tokens
+            .create(
+                "alice",
+                "old".to_string(),
+                vec![TokenScope::ReadConfig],
+                Some(now() - 1),
+            )
+            .unwrap();
+        assert!(!tokens.valid(&expired));
+        assert_eq!(tokens.user(&expired, TokenScope::ReadConfig), None);
+
+        let id = tokens.list("alice")[0].id.clone();
+        assert!(tokens.revoke("alice", &id));
+        assert!(!tokens.valid(&secret));
+    }
+}
@@ -0,0 +1,474 @@
+use crate::session::{client_ip, now, random_token};
+use crate::{get_user_by_name, save_user, AppData, User};
+use actix_identity::Identity;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use qrcode::{render::svg, QrCode};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::error;
+
+// RFC 6238 defaults, the only parameters authenticator apps reliably support
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+const ISSUER: &str = "Wireguard";
+
+const RECOVERY_CODES: usize = 10;
+const PENDING_LOGIN_SECS: u64 = 5 * 60;
+const PENDING_LOGIN_ATTEMPTS: u32 = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Totp {
+    // base32 encoded shared secret
+    secret: String,
+    // set once the user proved their app produces matching codes
+    confirmed: bool,
+    // time step of the last accepted code, codes can't be used twice
+    #[serde(default)]
+    last_step: u64,
+    // sha256 of the unused recovery codes
+    #[serde(default)]
+    recovery_codes: Vec<String>,
+}
+
+impl Totp {
+    fn new() -> Self {
+        let secret: Vec<u8> = (0..20).map(|_| rand::random::<u8>()).collect();
+        Self {
+            secret: base32::encode(base32::Alphabet::RFC4648 { padding: false }, &secret),
+            confirmed: false,
+            last_step: 0,
+            recovery_codes: vec![],
+        }
+    }
+
+    pub fn enabled(totp: &Option<Totp>) -> bool {
+        totp.as_ref().map(|t| t.confirmed).unwrap_or(false)
+    }
+
+    // accepts the codes of the previous, current and next step to allow for clock drift
+    fn matching_step(&self, code: &str) -> Option<u64> {
+        let secret = base32::decode(base32::Alphabet::RFC4648 { padding: false }, &self.secret)?;
+        let code = match code.trim().parse::<u32>() {
+            Ok(code) if code < 10u32.pow(DIGITS) => code,
+            _ => return None,
+        };
+
+        let current = now() / STEP_SECS;
+        (current.saturating_sub(1)..=current + 1)
+            .find(|step| *step > self.last_step && code_at(&secret, *step) == code)
+    }
+
+    fn check_code(&mut self, code: &str) -> bool {
+        match self.matching_step(code) {
+            Some(step) => {
+                self.last_step = step;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Either a current code from the app or one of the recovery codes. What
+    // it used up still has to be consumed in the database, see `consume`.
+    fn verify(&self, code: &str) -> Option<UsedCode> {
+        if let Some(step) = self.matching_step(code) {
+            return Some(UsedCode::Step(step));
+        }
+        let hashed = hash_recovery_code(code);
+        if self.recovery_codes.contains(&hashed) {
+            return Some(UsedCode::RecoveryCode(hashed));
+        }
+        None
+    }
+
+    fn new_recovery_codes(&mut self) -> Vec<String> {
+        let codes = (0..RECOVERY_CODES)
+            .map(|_| {
+                let bytes: Vec<u8> = (0..5).map(|_| rand::random::<u8>()).collect();
+                let code = base32::encode(base32::Alphabet::RFC4648 { padding: false }, &bytes)
+                    .to_lowercase();
+                format!("{}-{}", &code[..4], &code[4..])
+            })
+            .collect::<Vec<_>>();
+        self.recovery_codes = codes.iter().map(|c| hash_recovery_code(c)).collect();
+        codes
+    }
+
+    fn provisioning_uri(&self, user: &str) -> String {
+        format!(
+            "otpauth://totp/{issuer}:{user}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits={digits}&period={period}",
+            issuer = ISSUER,
+            user = utf8_percent_encode(user, NON_ALPHANUMERIC),
+            secret = self.secret,
+            digits = DIGITS,
+            period = STEP_SECS,
+        )
+    }
+}
+
+enum UsedCode {
+    Step(u64),
+    // sha256 of the code
+    RecoveryCode(String),
+}
+
+// Marks the code as used, false if a concurrent login got there first.
+fn consume(data: &AppData, user: &str, used: &UsedCode) -> rusqlite::Result<bool> {
+    match used {
+        UsedCode::Step(step) => data.db.consume_totp_step(user, *step),
+        UsedCode::RecoveryCode(hashed) => data.db.consume_recovery_code(user, hashed),
+    }
+}
+
+// HOTP value (RFC 4226) for the given counter
+fn code_at(secret: &[u8], step: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("hmac accepts any key length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    binary % 10u32.pow(DIGITS)
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    let normalized = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+    Sha256::digest(normalized.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn qr_code(content: &str) -> Option<String> {
+    let svg = QrCode::new(content)
+        .ok()?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+    Some(format!(
+        "data:image/svg+xml;base64,{}",
+        base64::encode(svg.as_bytes())
+    ))
+}
+
+// ---- Second login step ----
+
+struct PendingLogin {
+    user: String,
+    expires: u64,
+    attempts: u32,
+}
+
+// Users who got their password right and still owe us a code, keyed by a
+// token the client sends back together with the code.
+#[derive(Default)]
+pub struct PendingLogins(Mutex<HashMap<String, PendingLogin>>);
+
+impl PendingLogins {
+    pub fn start(&self, user: &str) -> String {
+        let mut pending = self.0.lock().unwrap();
+        let now = now();
+        pending.retain(|_, p| p.expires > now);
+
+        let token = random_token(32);
+        pending.insert(
+            token.clone(),
+            PendingLogin {
+                user: user.to_string(),
+                expires: now + PENDING_LOGIN_SECS,
+                attempts: 0,
+            },
+        );
+        token
+    }
+
+    fn user(&self, token: &str) -> Option<String> {
+        let mut pending = self.0.lock().unwrap();
+        let login = pending.get_mut(token)?;
+        login.attempts += 1;
+        if login.expires <= now() || login.attempts > PENDING_LOGIN_ATTEMPTS {
+            pending.remove(token);
+            return None;
+        }
+        Some(login.user.clone())
+    }
+
+    fn finish(&self, token: &str) {
+        self.0.lock().unwrap().remove(token);
+    }
+}
+
+#[post("/login_totp")]
+async fn login_totp_request(
+    req: HttpRequest,
+    data: web::Data<AppData>,
+    request_data: web::Json<shared::Request>,
+    id: Identity,
+) -> impl Responder {
+    let (token, code) = match request_data.0 {
+        shared::Request::LoginTotp { token, code } => (token, code),
+        _ => return web::Json(shared::Response::LoginFailure),
+    };
+
+    let name = match data.pending_logins.user(&token) {
+        Some(name) => name,
+        None => return web::Json(shared::Response::LoginFailure),
+    };
+
+    // codes are throttled together with the passwords, the password step
+    // already counted (and waited for) this attempt, only a wrong code adds
+    // another failure
+    let ip = client_ip(&req);
+    if let Err(retry_after) = data.throttle.check(&ip, &name) {
+        return web::Json(shared::Response::TooManyAttempts { retry_after });
+    }
+
+    let user = match get_user_by_name(name, &data) {
+        Some(user) => user,
+        None => return web::Json(shared::Response::LoginFailure),
+    };
+
+    // the step or recovery code is used up before letting the user in, of
+    // two requests with the same code only one gets through
+    let consumed = match user.totp.as_ref().and_then(|t| t.verify(&code)) {
+        Some(used) => consume(&data, &user.name, &used),
+        None => Ok(false),
+    };
+    match consumed {
+        Ok(true) => {}
+        Ok(false) => {
+            data.throttle.failure(&ip, &user.name, &data.audit);
+            data.audit.record("", "login.failure", &user.name, &ip);
+            return web::Json(shared::Response::LoginFailure);
+        }
+        Err(e) => {
+            error!("Could not use up the code of {}: {}", user.name, e);
+            return web::Json(shared::Response::LoginFailure);
+        }
+    }
+    data.pending_logins.finish(&token);
+    data.throttle.success(&ip, &user.name);
+    data.sessions.login(&id, &req, &user.name);
+    data.audit.record(&user.name, "login", &user.name, &ip);
+    web::Json(shared::Response::LoginSuccess { session: user.name })
+}
+
+// ---- Enrollment ----
+
+fn current_user(id: &Identity, data: &web::Data<AppData>) -> Option<User> {
+    data.sessions
+        .user(id)
+        .and_then(|name| get_user_by_name(name, data))
+}
+
+#[get("/totp")]
+async fn totp_status(id: Identity, data: web::Data<AppData>) -> impl Responder {
+    match current_user(&id, &data) {
+        Some(user) => HttpResponse::Ok().json(shared::Response::TotpStatus {
+            enabled: Totp::enabled(&user.totp),
+        }),
+        None => HttpResponse::Forbidden().body(""),
+    }
+}
+
+#[post("/totp/enroll")]
+async fn totp_enroll(req: HttpRequest, id: Identity, data: web::Data<AppData>) -> impl Responder {
+    // single sign-on users get their second factor from the identity provider
+    let mut user = match current_user(&id, &data) {
+        Some(user) if !Totp::enabled(&user.totp) && user.oidc_subject.is_none() => user,
+        _ => return web::Json(shared::Response::Failure),
+    };
+
+    let totp = Totp::new();
+    let qr_code = match qr_code(&totp.provisioning_uri(&user.name)) {
+        Some(qr_code) => qr_code,
+        None => return web::Json(shared::Response::Failure),
+    };
+    let secret = totp.secret.clone();
+    user.totp = Some(totp);
+
+    match save_user(&data, &user.name, &user) {
+        Ok(_) => {
+            data.audit
+                .record(&user.name, "totp.enroll", &user.name, &client_ip(&req));
+            web::Json(shared::Response::TotpEnrollment { secret, qr_code })
+        }
+        Err(_) => web::Json(shared::Response::Failure),
+    }
+}
+
+#[post("/totp/confirm")]
+async fn totp_confirm(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    request_data: web::Json<shared::Request>,
+) -> impl Responder {
+    let code = match request_data.0 {
+        shared::Request::TotpConfirm { code } => code,
+        _ => return web::Json(shared::Response::Failure),
+    };
+    let mut user = match current_user(&id, &data) {
+        Some(user) => user,
+        None => return web::Json(shared::Response::Failure),
+    };
+
+    let totp = match user.totp.as_mut() {
+        Some(totp) if !totp.confirmed => totp,
+        _ => return web::Json(shared::Response::Failure),
+    };
+    let ip = client_ip(&req);
+    if let Err(retry_after) = data.throttle.attempt(&ip, &user.name, &data.audit) {
+        return web::Json(shared::Response::TooManyAttempts { retry_after });
+    }
+    if !totp.check_code(&code) {
+        return web::Json(shared::Response::Failure);
+    }
+    data.throttle.success(&ip, &user.name);
+    totp.confirmed = true;
+    let codes = totp.new_recovery_codes();
+
+    match save_user(&data, &user.name, &user) {
+        Ok(_) => {
+            data.audit
+                .record(&user.name, "totp.enable", &user.name, &ip);
+            web::Json(shared::Response::RecoveryCodes { codes })
+        }
+        Err(_) => web::Json(shared::Response::Failure),
+    }
+}
+
+#[post("/totp/disable")]
+async fn totp_disable(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    request_data: web::Json<shared::Request>,
+) -> impl Responder {
+    let password = match request_data.0 {
+        shared::Request::TotpDisable { password } => password,
+        _ => return web::Json(shared::Response::Failure),
+    };
+    let mut user = match current_user(&id, &data) {
+        Some(user) => user,
+        None => return web::Json(shared::Response::Failure),
+    };
+
+    let ip = client_ip(&req);
+    if let Err(retry_after) = data.throttle.attempt(&ip, &user.name, &data.audit) {
+        return web::Json(shared::Response::TooManyAttempts { retry_after });
+    }
+    if crate::auth::authenticate(&data, &user.name, &password)
+        .await
+        .is_none()
+    {
+        return web::Json(shared::Response::Failure);
+    }
+    data.throttle.success(&ip, &user.name);
+
+    user.totp = None;
+    match save_user(&data, &user.name, &user) {
+        Ok(_) => {
+            data.audit
+                .record(&user.name, "totp.disable", &user.name, &ip);
+            web::Json(shared::Response::TotpStatus { enabled: false })
+        }
+        Err(_) => web::Json(shared::Response::Failure),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::Db;
+
+    // RFC 6238 appendix B, SHA1 with the ascii secret, last six of the eight digits
+    #[test]
+    fn matches_the_rfc_vectors() {
+        let secret = b"12345678901234567890";
+        let vectors = [
+            (59, 287082),
+            (1111111109, 81804),
+            (1111111111, 50471),
+            (1234567890, 5924),
+            (2000000000, 279037),
+            (20000000000, 353130),
+        ];
+        for (time, code) in vectors.iter() {
+            assert_eq!(code_at(secret, time / STEP_SECS), *code, "time {}", time);
+        }
+    }
+
+    #[test]
+    fn accepts_a_code_only_once() {
+        let mut totp = Totp::new();
+        let secret =
+            base32::decode(base32::Alphabet::RFC4648 { padding: false }, &totp.secret).unwrap();
+        let code = format!("{:06}", code_at(&secret, now() / STEP_SECS));
+        assert!(totp.check_code(&code));
+        assert!(!totp.check_code(&code));
+        assert!(!totp.check_code("not a code"));
+    }
+
+    fn save(totp: Totp) -> (Db, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), None).unwrap();
+        let user = User {
+            name: "user".to_string(),
+            totp: Some(totp),
+            ..Default::default()
+        };
+        db.save_user("user", &user).unwrap();
+        (db, dir)
+    }
+
+    fn stored(db: &Db) -> Totp {
+        db.user_by_name("user").unwrap().unwrap().totp.unwrap()
+    }
+
+    #[test]
+    fn steps_are_consumed_once() {
+        let (db, _dir) = save(Totp::new());
+        assert!(db.consume_totp_step("user", 100).unwrap());
+        assert!(!db.consume_totp_step("user", 100).unwrap());
+        assert!(!db.consume_totp_step("user", 99).unwrap());
+        assert!(db.consume_totp_step("user", 101).unwrap());
+        assert_eq!(stored(&db).last_step, 101);
+        assert!(!db.consume_totp_step("nobody", 200).unwrap());
+    }
+
+    #[test]
+    fn recovery_codes_are_single_use() {
+        let mut totp = Totp::new();
+        let codes = totp.new_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+        let (db, _dir) = save(totp);
+
+        // case and separators don't matter
+        let used = stored(&db).verify(&codes[0].to_uppercase().replace('-', " "));
+        let hashed = match used {
+            Some(UsedCode::RecoveryCode(hashed)) => hashed,
+            _ => panic!("not accepted as recovery code"),
+        };
+        // a second login with the same code loses
+        assert!(db.consume_recovery_code("user", &hashed).unwrap());
+        assert!(!db.consume_recovery_code("user", &hashed).unwrap());
+        assert!(stored(&db).verify(&codes[0]).is_none());
+        assert!(stored(&db).verify(&codes[1]).is_some());
+        assert_eq!(stored(&db).recovery_codes.len(), RECOVERY_CODES - 1);
+    }
+}
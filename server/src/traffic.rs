@@ -0,0 +1,384 @@
+use crate::config::TrafficConfig;
+use crate::db::Db;
+use crate::invites;
+use crate::session::now;
+use crate::tokens::authorized_user;
+use crate::wg::PeerStats;
+use crate::AppData;
+use actix_identity::Identity;
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use rusqlite::params;
+use shared::{TokenScope, TrafficPoint, TrafficRange, TrafficSeries};
+use std::collections::HashSet;
+use std::time::Duration;
+use tracing::{debug, error};
+
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+// Transfer of the peers over time. The counters of the interface are read
+// every `interval_secs` and the difference to the last reading is added to
+// buckets of that interval, of an hour and of a day. Each resolution is kept
+// for its own retention.
+pub struct TrafficStats {
+    db: Db,
+    config: TrafficConfig,
+}
+
+impl TrafficStats {
+    pub fn new(db: Db, config: TrafficConfig) -> Self {
+        Self { db, config }
+    }
+
+    // bucket length and how long the buckets are kept
+    fn resolutions(&self) -> Vec<(u64, u64)> {
+        let raw = self.config.raw_retention_hours * HOUR;
+        let hourly = self.config.hourly_retention_days * DAY;
+        let daily = self.config.daily_retention_days * DAY;
+        if self.config.interval_secs == HOUR {
+            vec![(HOUR, raw.max(hourly)), (DAY, daily)]
+        } else {
+            vec![
+                (self.config.interval_secs, raw),
+                (HOUR, hourly),
+                (DAY, daily),
+            ]
+        }
+    }
+
+    pub fn record(&self, stats: &[PeerStats], time: u64) -> rusqlite::Result<()> {
+        let mut conn = self.db.conn();
+        let tx = conn.transaction()?;
+        for peer in stats {
+            let last = tx
+                .query_row(
+                    "SELECT rx, tx FROM traffic_counters WHERE public_key = ?1",
+                    [&peer.public_key],
+                    |row| Ok((row.get::<_, u64>(0)?, row.get::<_, u64>(1)?)),
+                )
+                .ok();
+            tx.execute(
+                "INSERT INTO traffic_counters (public_key, rx, tx) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (public_key) DO UPDATE SET rx = ?2, tx = ?3",
+                params![peer.public_key, peer.rx_bytes, peer.tx_bytes],
+            )?;
+
+            // the first reading only sets the counters, what came before is unknown
+            let (last_rx, last_tx) = match last {
+                Some(last) => last,
+                None => continue,
+            };
+            let rx = delta(last_rx, peer.rx_bytes);
+            let tx_bytes = delta(last_tx, peer.tx_bytes);
+            if rx == 0 && tx_bytes == 0 {
+                continue;
+            }
+            for (resolution, _) in self.resolutions().iter() {
+                tx.execute(
+                    "INSERT INTO traffic (public_key, resolution, time, rx, tx)
+                     VALUES (?1, ?2, ?3, ?4, ?5)
+                     ON CONFLICT (public_key, resolution, time)
+                     DO UPDATE SET rx = rx + ?4, tx = tx + ?5",
+                    params![
+                        peer.public_key,
+                        resolution,
+                        time - time % resolution,
+                        rx,
+                        tx_bytes
+                    ],
+                )?;
+            }
+        }
+
+        for (resolution, retention) in self.resolutions().iter() {
+            tx.execute(
+                "DELETE FROM traffic WHERE resolution = ?1 AND time < ?2",
+                params![resolution, time.saturating_sub(*retention)],
+            )?;
+        }
+        tx.commit()
+    }
+
+    // the buckets of the range ending now, with their start and length
+    pub fn series(
+        &self,
+        range: TrafficRange,
+        time: u64,
+    ) -> rusqlite::Result<(u64, u64, Vec<TrafficSeries>)> {
+        let step = match range {
+            TrafficRange::Day => self.config.interval_secs,
+            TrafficRange::Week => HOUR,
+            TrafficRange::Month => DAY,
+        };
+        let end = time - time % step + step;
+        let start = end - range.secs();
+
+        let conn = self.db.conn();
+        let mut statement = conn.prepare(
+            "SELECT public_key, time, rx, tx FROM traffic
+             WHERE resolution = ?1 AND time >= ?2
+             ORDER BY public_key, time",
+        )?;
+        let rows = statement.query_map(params![step, start], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                TrafficPoint {
+                    time: row.get(1)?,
+                    rx: row.get(2)?,
+                    tx: row.get(3)?,
+                },
+            ))
+        })?;
+
+        let mut series: Vec<TrafficSeries> = vec![];
+        for row in rows {
+            let (public_key, point) = row?;
+            match series.last_mut() {
+                Some(last) if last.public_key == public_key => last.points.push(point),
+                _ => series.push(TrafficSeries {
+                    public_key,
+                    points: vec![point],
+                }),
+            }
+        }
+        Ok((start, step, series))
+    }
+}
+
+// counters start over when the interface or the peer is set up again
+fn delta(last: u64, current: u64) -> u64 {
+    if current >= last {
+        current - last
+    } else {
+        current
+    }
+}
+
+fn sample(data: &AppData) {
+    let peers = match data.db.peers(&data.wg.interface) {
+        Ok(peers) => peers
+            .into_iter()
+            .map(|p| p.public_key)
+            .collect::<HashSet<_>>(),
+        Err(e) => {
+            error!("Could not sample traffic: {}", e);
+            return;
+        }
+    };
+    // peers added outside of the web interface are not tracked
+    let stats = data
+        .wg
+        .dump()
+        .into_iter()
+        .filter(|s| peers.contains(&s.public_key))
+        .collect::<Vec<_>>();
+    match data.traffic.record(&stats, now()) {
+        Ok(_) => debug!("Sampled traffic of {} peers", stats.len()),
+        Err(e) => error!("Could not sample traffic: {}", e),
+    }
+    data.quotas.enforce(data);
+    invites::remove_expired_peers(data);
+}
+
+pub fn start_sampler(data: web::Data<AppData>) {
+    let interval_secs = data.config.traffic.interval_secs;
+    if interval_secs == 0 {
+        return;
+    }
+
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(Duration::from_secs(interval_secs));
+        loop {
+            interval.tick().await;
+            let data = data.clone();
+            if let Err(e) = web::block(move || sample(&data)).await {
+                error!("Could not sample traffic: {}", e);
+            }
+        }
+    });
+}
+
+// ---- Apis ----
+
+#[get("/traffic/{range}")]
+async fn show_traffic(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+    range: web::Path<TrafficRange>,
+) -> impl Responder {
+    if authorized_user(&req, &id, &data, TokenScope::ReadConfig).is_none() {
+        return HttpResponse::Forbidden().body("");
+    }
+    // nothing is recorded with sampling turned off
+    if data.config.traffic.interval_secs == 0 {
+        return HttpResponse::NotFound().finish();
+    }
+
+    let range = range.into_inner();
+    match data.traffic.series(range, now()) {
+        Ok((start, step, series)) => HttpResponse::Ok().json(shared::Response::Traffic {
+            range,
+            start,
+            step,
+            series,
+        }),
+        Err(e) => {
+            error!("Could not read traffic: {}", e);
+            HttpResponse::Ok().json(shared::Response::Failure)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a day boundary
+    const T: u64 = 19_000 * DAY;
+
+    fn setup(config: TrafficConfig) -> (TrafficStats, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Db::open(dir.path(), None).unwrap();
+        (TrafficStats::new(db, config), dir)
+    }
+
+    fn stats(public_key: &str, rx_bytes: u64, tx_bytes: u64) -> PeerStats {
+        PeerStats {
+            public_key: public_key.to_string(),
+            latest_handshake: 0,
+            rx_bytes,
+            tx_bytes,
+        }
+    }
+
+    // (time, rx, tx) of the buckets of one resolution
+    fn buckets(traffic: &TrafficStats, resolution: u64) -> Vec<(u64, u64, u64)> {
+        let conn = traffic.db.conn();
+        let mut statement = conn
+            .prepare("SELECT time, rx, tx FROM traffic WHERE resolution = ?1 ORDER BY time")
+            .unwrap();
+        let buckets = statement
+            .query_map([resolution], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        buckets
+    }
+
+    #[test]
+    fn counters_can_start_over() {
+        assert_eq!(delta(100, 150), 50);
+        assert_eq!(delta(100, 100), 0);
+        assert_eq!(delta(100, 30), 30);
+    }
+
+    #[test]
+    fn adds_the_differences_to_every_resolution() {
+        let (traffic, _dir) = setup(TrafficConfig::default());
+        // the first reading only sets the counters
+        traffic.record(&[stats("a", 100, 50)], T).unwrap();
+        assert!(buckets(&traffic, 300).is_empty());
+
+        traffic.record(&[stats("a", 150, 80)], T + 300).unwrap();
+        traffic.record(&[stats("a", 200, 80)], T + 600).unwrap();
+        // the interface was set up again
+        traffic.record(&[stats("a", 10, 5)], T + HOUR).unwrap();
+
+        assert_eq!(
+            buckets(&traffic, 300),
+            [(T + 300, 50, 30), (T + 600, 50, 0), (T + HOUR, 10, 5)]
+        );
+        assert_eq!(buckets(&traffic, HOUR), [(T, 100, 30), (T + HOUR, 10, 5)]);
+        assert_eq!(buckets(&traffic, DAY), [(T, 110, 35)]);
+    }
+
+    #[test]
+    fn removes_buckets_after_their_retention() {
+        let (traffic, _dir) = setup(TrafficConfig::default());
+        traffic.record(&[stats("a", 0, 0)], T).unwrap();
+        traffic.record(&[stats("a", 10, 10)], T + 300).unwrap();
+        traffic.record(&[stats("a", 20, 20)], T + HOUR).unwrap();
+
+        // raw samples are kept for 48 hours
+        traffic
+            .record(&[stats("a", 30, 30)], T + 49 * HOUR)
+            .unwrap();
+        assert_eq!(
+            buckets(&traffic, 300),
+            [(T + HOUR, 10, 10), (T + 49 * HOUR, 10, 10)]
+        );
+        assert_eq!(buckets(&traffic, HOUR).len(), 3);
+
+        // hourly sums for 35 days
+        traffic.record(&[stats("a", 30, 30)], T + 40 * DAY).unwrap();
+        assert!(buckets(&traffic, 300).is_empty());
+        assert!(buckets(&traffic, HOUR).is_empty());
+        assert_eq!(buckets(&traffic, DAY), [(T, 20, 20), (T + 2 * DAY, 10, 10)]);
+    }
+
+    #[test]
+    fn hourly_sampling_has_no_raw_resolution() {
+        let config = TrafficConfig {
+            interval_secs: HOUR,
+            ..Default::default()
+        };
+        let (traffic, _dir) = setup(config.clone());
+        assert_eq!(traffic.resolutions(), [(HOUR, 35 * DAY), (DAY, 400 * DAY)]);
+
+        let (longer_raw, _dir) = setup(TrafficConfig {
+            raw_retention_hours: 40 * 24,
+            ..config
+        });
+        assert_eq!(
+            longer_raw.resolutions(),
+            [(HOUR, 40 * DAY), (DAY, 400 * DAY)]
+        );
+
+        traffic.record(&[stats("a", 0, 0)], T).unwrap();
+        traffic.record(&[stats("a", 10, 10)], T + HOUR).unwrap();
+        assert_eq!(buckets(&traffic, HOUR), [(T + HOUR, 10, 10)]);
+        assert_eq!(buckets(&traffic, DAY), [(T, 10, 10)]);
+    }
+
+    #[test]
+    fn series_are_grouped_by_peer() {
+        let (traffic, _dir) = setup(TrafficConfig::default());
+        traffic
+            .record(&[stats("b", 0, 0), stats("a", 0, 0)], T)
+            .unwrap();
+        traffic
+            .record(&[stats("b", 5, 5), stats("a", 10, 10)], T + 300)
+            .unwrap();
+        traffic.record(&[stats("a", 30, 10)], T + 600).unwrap();
+
+        let (start, step, series) = traffic.series(TrafficRange::Day, T + 610).unwrap();
+        assert_eq!(step, 300);
+        assert_eq!(start, T + 900 - DAY);
+        assert_eq!(series.len(), 2);
+        assert_eq!(series[0].public_key, "a");
+        let times = series[0].points.iter().map(|p| p.time).collect::<Vec<_>>();
+        assert_eq!(times, [T + 300, T + 600]);
+        assert_eq!(series[1].public_key, "b");
+        assert_eq!(series[1].points.len(), 1);
+
+        let (start, step, series) = traffic.series(TrafficRange::Week, T + 610).unwrap();
+        assert_eq!(step, HOUR);
+        assert_eq!(start, T + HOUR - 7 * DAY);
+        assert_eq!(series[0].points.len(), 1);
+        assert_eq!(series[0].points[0].time, T);
+        assert_eq!(series[0].points[0].rx, 30);
+
+        let (start, step, _) = traffic.series(TrafficRange::Month, T + 610).unwrap();
+        assert_eq!(step, DAY);
+        assert_eq!(start, T + DAY - 30 * DAY);
+
+        // older buckets are out of the range
+        let (_, _, series) = traffic.series(TrafficRange::Day, T + DAY + 310).unwrap();
+        assert_eq!(series.len(), 1);
+        let times = series[0].points.iter().map(|p| p.time).collect::<Vec<_>>();
+        assert_eq!(times, [T + 600]);
+    }
+}
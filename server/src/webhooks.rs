@@ -0,0 +1,538 @@
+use crate::config::WebhookConfig;
+use crate::db::Db;
+use crate::session::{now, random_token};
+use crate::tokens::authorized_user;
+use crate::{current_wg_config, AppData};
+use actix_identity::Identity;
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use hmac::{Hmac, Mac};
+use rusqlite::{params, Row};
+use serde::Serialize;
+use sha2::Sha256;
+use shared::{TokenScope, WebhookDelivery};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+pub const EVENTS: &[&str] = &[
+    "peer.created",
+    "peer.removed",
+    "peer.disabled",
+    "peer.enabled",
+    "peer.offline",
+    "peer.online",
+    "peer.expired",
+];
+
+// wait before the attempts after the first one, then it is given up
+const RETRY_DELAYS: [u64; 5] = [10, 60, 5 * 60, 30 * 60, 2 * 60 * 60];
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(5);
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+// a peer without a handshake for this long is offline, they renew every two minutes
+const OFFLINE_SECS: u64 = 3 * 60;
+const KEEP_SECS: u64 = 30 * 24 * 60 * 60;
+const LIST_LIMIT: i64 = 200;
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    id: &'a str,
+    event: &'a str,
+    time: u64,
+    interface: &'a str,
+    peer: PeerInfo<'a>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+pub struct PeerInfo<'a> {
+    pub public_key: &'a str,
+    pub name: &'a str,
+    pub allowed_ips: &'a str,
+}
+
+struct Due {
+    id: String,
+    url: String,
+    event: String,
+    payload: String,
+    attempts: u32,
+}
+
+// Sends peer events as signed JSON to the configured urls. Deliveries are
+// queued in the database, so retries survive a restart, and kept there for
+// a while as the delivery log.
+pub struct Webhooks {
+    db: Db,
+    targets: Vec<WebhookConfig>,
+    agent: ureq::Agent,
+    // online state of the peers from the last look, None before the first
+    online: Mutex<Option<HashMap<String, bool>>>,
+}
+
+impl Webhooks {
+    pub fn new(db: Db, targets: Vec<WebhookConfig>) -> Self {
+        Self {
+            db,
+            targets,
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .build(),
+            online: Mutex::new(None),
+        }
+    }
+
+    fn wanted(&self, event: &str) -> bool {
+        self.targets.iter().any(|t| wants(t, event))
+    }
+
+    // queues the event for every url that wants it
+    pub fn notify(&self, event: &str, interface: &str, peer: PeerInfo) {
+        let time = now();
+        for target in self.targets.iter().filter(|t| wants(t, event)) {
+            let id = random_token(16);
+            let payload = serde_json::to_string(&Payload {
+                id: &id,
+                event,
+                time,
+                interface,
+                peer,
+            })
+            .unwrap();
+            if let Err(e) = self.db.conn().execute(
+                "INSERT INTO webhook_deliveries (id, url, event, payload, created, next_attempt)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?5)",
+                params![id, target.url, event, payload, time],
+            ) {
+                error!(
+                    "Could not queue webhook {} for {}: {}",
+                    event, target.url, e
+                );
+            }
+        }
+    }
+
+    // sends what is due and forgets old deliveries
+    fn deliver_due(&self) -> rusqlite::Result<()> {
+        let time = now();
+        let due = {
+            let conn = self.db.conn();
+            conn.execute(
+                "DELETE FROM webhook_deliveries WHERE next_attempt IS NULL AND created < ?1",
+                [time.saturating_sub(KEEP_SECS)],
+            )?;
+            let mut statement = conn.prepare(
+                "SELECT id, url, event, payload, attempts FROM webhook_deliveries
+                 WHERE next_attempt <= ?1 ORDER BY next_attempt LIMIT 20",
+            )?;
+            let due = statement
+                .query_map([time], |row| {
+                    Ok(Due {
+                        id: row.get(0)?,
+                        url: row.get(1)?,
+                        event: row.get(2)?,
+                        payload: row.get(3)?,
+                        attempts: row.get(4)?,
+                    })
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            due
+        };
+
+        for delivery in due {
+            let (status, result) = match self.targets.iter().find(|t| t.url == delivery.url) {
+                Some(target) => self.send(target, &delivery),
+                None => (None, Err("the url is no longer configured".to_string())),
+            };
+            let attempts = delivery.attempts + 1;
+            match result {
+                Ok(()) => {
+                    self.db.conn().execute(
+                        "UPDATE webhook_deliveries SET attempts = ?2, next_attempt = NULL,
+                         delivered = ?3, last_status = ?4, last_error = NULL WHERE id = ?1",
+                        params![delivery.id, attempts, now(), status],
+                    )?;
+                }
+                Err(e) => {
+                    let next_attempt = RETRY_DELAYS
+                        .get(delivery.attempts as usize)
+                        .filter(|_| self.targets.iter().any(|t| t.url == delivery.url))
+                        .map(|delay| now() + delay);
+                    match next_attempt {
+                        Some(_) => warn!(
+                            "Webhook {} to {} failed, retrying: {}",
+                            delivery.event, delivery.url, e
+                        ),
+                        None => error!(
+                            "Webhook {} to {} failed {} times, giving up: {}",
+                            delivery.event, delivery.url, attempts, e
+                        ),
+                    }
+                    self.db.conn().execute(
+                        "UPDATE webhook_deliveries SET attempts = ?2, next_attempt = ?3,
+                         last_status = ?4, last_error = ?5 WHERE id = ?1",
+                        params![delivery.id, attempts, next_attempt, status, e],
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // the status code, if there was an answer, and whether it was a success
+    fn send(&self, target: &WebhookConfig, delivery: &Due) -> (Option<u16>, Result<(), String>) {
+        let timestamp = now().to_string();
+        let signature = sign(&target.secret, &timestamp, &delivery.payload);
+        let response = self
+            .agent
+            .post(&target.url)
+            .set("Content-Type", "application/json")
+            .set("X-Webhook-Id", &delivery.id)
+            .set("X-Webhook-Event", &delivery.event)
+            .set("X-Webhook-Timestamp", &timestamp)
+            .set("X-Webhook-Signature", &format!("sha256={}", signature))
+            .send_string(&delivery.payload);
+        match response {
+            Ok(response) => (Some(response.status()), Ok(())),
+            Err(ureq::Error::Status(status, response)) => {
+                let body = response.into_string().unwrap_or_default();
+                let body = body.chars().take(200).collect::<String>();
+                (Some(status), Err(format!("answered {}: {}", status, body)))
+            }
+            Err(e) => (None, Err(e.to_string())),
+        }
+    }
+
+    // newest first
+    pub fn deliveries(&self, limit: i64) -> rusqlite::Result<Vec<WebhookDelivery>> {
+        let conn = self.db.conn();
+        let mut statement = conn.prepare(
+            "SELECT id, url, event, created, attempts, next_attempt, delivered, last_status,
+                    last_error
+             FROM webhook_deliveries ORDER BY created DESC, rowid DESC LIMIT ?1",
+        )?;
+        let deliveries = statement
+            .query_map([limit], delivery_from_row)?
+            .collect::<Result<_, _>>();
+        deliveries
+    }
+}
+
+fn wants(target: &WebhookConfig, event: &str) -> bool {
+    target.events.is_empty() || target.events.iter().any(|e| e == event)
+}
+
+fn delivery_from_row(row: &Row) -> rusqlite::Result<WebhookDelivery> {
+    Ok(WebhookDelivery {
+        id: row.get(0)?,
+        url: row.get(1)?,
+        event: row.get(2)?,
+        created: row.get(3)?,
+        attempts: row.get(4)?,
+        next_attempt: row.get(5)?,
+        delivered: row.get(6)?,
+        last_status: row.get(7)?,
+        last_error: row.get(8)?,
+    })
+}
+
+// hex of the HMAC-SHA256 of `<timestamp>.<payload>`
+fn sign(secret: &str, timestamp: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Compares the handshakes with the last look and sends peers that came
+// online or went offline. The first look only remembers them.
+fn watch(data: &web::Data<AppData>) {
+    let webhooks = &data.webhooks;
+    if !webhooks.wanted("peer.online") && !webhooks.wanted("peer.offline") {
+        return;
+    }
+
+    let time = now();
+    let handshakes = data
+        .wg
+        .dump()
+        .into_iter()
+        .map(|s| (s.public_key, s.latest_handshake))
+        .collect::<HashMap<_, _>>();
+    let config = current_wg_config(data);
+    let mut online = webhooks.online.lock().unwrap();
+    let last = online.take();
+    let mut current = HashMap::new();
+    for peer in config.peers.iter().filter(|p| !p.disabled) {
+        let handshake = handshakes.get(&peer.public_key).copied().unwrap_or(0);
+        let is_online = handshake != 0 && time.saturating_sub(handshake) < OFFLINE_SECS;
+        current.insert(peer.public_key.clone(), is_online);
+
+        let was_online = match last.as_ref().and_then(|l| l.get(&peer.public_key)) {
+            Some(was_online) => *was_online,
+            None => continue,
+        };
+        if was_online != is_online {
+            let event = if is_online {
+                "peer.online"
+            } else {
+                "peer.offline"
+            };
+            info!("Peer {} is {}", peer.name, &event[5..]);
+            webhooks.notify(
+                event,
+                &data.wg.interface,
+                PeerInfo {
+                    public_key: &peer.public_key,
+                    name: &peer.name,
+                    allowed_ips: &peer.allowed_ips.to_string(),
+                },
+            );
+        }
+    }
+    *online = Some(current);
+}
+
+pub fn start(data: web::Data<AppData>) {
+    if data.config.webhooks.is_empty() {
+        return;
+    }
+
+    let delivery_data = data.clone();
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(DELIVERY_INTERVAL);
+        loop {
+            interval.tick().await;
+            let data = delivery_data.clone();
+            match web::block(move || data.webhooks.deliver_due()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Could not deliver webhooks: {}", e),
+                Err(e) => error!("Could not deliver webhooks: {}", e),
+            }
+        }
+    });
+
+    actix_rt::spawn(async move {
+        let mut interval = actix_rt::time::interval(WATCH_INTERVAL);
+        loop {
+            interval.tick().await;
+            let data = data.clone();
+            if let Err(e) = web::block(move || watch(&data)).await {
+                error!("Could not watch the peers: {}", e);
+            }
+        }
+    });
+}
+
+// ---- Apis ----
+
+#[get("/webhooks/deliveries")]
+async fn list_deliveries(
+    req: HttpRequest,
+    id: Identity,
+    data: web::Data<AppData>,
+) -> impl Responder {
+    if authorized_user(&req, &id, &data, TokenScope::ReadAudit).is_none() {
+        return HttpResponse::Forbidden().body("");
+    }
+    match data.webhooks.deliveries(LIST_LIMIT) {
+        Ok(deliveries) => {
+            HttpResponse::Ok().json(shared::Response::WebhookDeliveries { deliveries })
+        }
+        Err(e) => {
+            error!("Could not read webhook deliveries: {}", e);
+            HttpResponse::Ok().json(shared::Response::Failure)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    const SECRET: &str = "0123456789abcdef";
+
+    struct Request {
+        headers: HashMap<String, String>,
+        body: String,
+    }
+
+    // Answers every request with the next of `statuses` (the last one once
+    // they run out) and hands the requests to the test.
+    fn receiver(statuses: Vec<u16>) -> (String, mpsc::Receiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, requests) = mpsc::channel();
+        std::thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = HashMap::new();
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    match line.trim_end().split_once(": ") {
+                        Some((name, value)) => {
+                            headers.insert(name.to_lowercase(), value.to_string());
+                        }
+                        None => break,
+                    }
+                }
+                let length = headers["content-length"].parse().unwrap();
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let _ = sender.send(Request {
+                    headers,
+                    body: String::from_utf8(body).unwrap(),
+                });
+
+                let status = statuses[i.min(statuses.len() - 1)];
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: 2\r\nConnection: close\r\n\r\nno",
+                    status
+                );
+            }
+        });
+        (url, requests)
+    }
+
+    fn webhooks(url: &str, events: Vec<String>) -> (Webhooks, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let target = WebhookConfig {
+            url: url.to_string(),
+            secret: SECRET.to_string(),
+            events,
+        };
+        (
+            Webhooks::new(Db::open(dir.path(), None).unwrap(), vec![target]),
+            dir,
+        )
+    }
+
+    fn notify(webhooks: &Webhooks, event: &str) {
+        let peer = PeerInfo {
+            public_key: "key",
+            name: "laptop",
+            allowed_ips: "10.0.0.2/32",
+        };
+        webhooks.notify(event, "wg0", peer);
+    }
+
+    // pretends the retry is due now
+    fn make_due(webhooks: &Webhooks) {
+        webhooks
+            .db
+            .conn()
+            .execute(
+                "UPDATE webhook_deliveries SET next_attempt = 0 WHERE next_attempt IS NOT NULL",
+                [],
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn signs_timestamp_and_payload() {
+        assert_eq!(
+            sign(SECRET, "1700000000", r#"{"event":"peer.created"}"#),
+            "e7ad263086f2f99f4b29bf6a7a8c8594f1a1cd590ac88981b9df571136a961e6"
+        );
+    }
+
+    #[test]
+    fn delivers_signed_payloads() {
+        let (url, requests) = receiver(vec![204]);
+        let (webhooks, _dir) = webhooks(&url, vec!["peer.created".to_string()]);
+        notify(&webhooks, "peer.removed");
+        notify(&webhooks, "peer.created");
+        webhooks.deliver_due().unwrap();
+
+        let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        let header = |name: &str| request.headers[name].clone();
+        assert_eq!(header("x-webhook-event"), "peer.created");
+        assert_eq!(
+            header("x-webhook-signature"),
+            format!(
+                "sha256={}",
+                sign(SECRET, &header("x-webhook-timestamp"), &request.body)
+            )
+        );
+        let payload: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(payload["id"], header("x-webhook-id"));
+        assert_eq!(payload["event"], "peer.created");
+        assert_eq!(payload["peer"]["name"], "laptop");
+        // only the wanted event was queued
+        assert!(requests.recv_timeout(Duration::from_millis(200)).is_err());
+
+        let deliveries = webhooks.deliveries(10).unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].last_status, Some(204));
+        assert!(deliveries[0].delivered.is_some());
+        assert_eq!(deliveries[0].next_attempt, None);
+    }
+
+    #[test]
+    fn retries_with_backoff_until_delivered() {
+        let (url, requests) = receiver(vec![500, 503, 200]);
+        let (webhooks, _dir) = webhooks(&url, vec![]);
+        notify(&webhooks, "peer.expired");
+
+        for (attempt, status) in [(1, 500), (2, 503)] {
+            let start = now();
+            webhooks.deliver_due().unwrap();
+            requests.recv_timeout(Duration::from_secs(5)).unwrap();
+            let delivery = &webhooks.deliveries(1).unwrap()[0];
+            assert_eq!(delivery.attempts, attempt);
+            assert_eq!(delivery.last_status, Some(status));
+            let delay = RETRY_DELAYS[attempt as usize - 1];
+            let next_attempt = delivery.next_attempt.unwrap();
+            assert!((start + delay..=now() + delay).contains(&next_attempt));
+
+            // not due yet
+            webhooks.deliver_due().unwrap();
+            assert!(requests.recv_timeout(Duration::from_millis(200)).is_err());
+            make_due(&webhooks);
+        }
+
+        webhooks.deliver_due().unwrap();
+        let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        // the same delivery every time
+        let delivery = &webhooks.deliveries(1).unwrap()[0];
+        assert_eq!(request.headers["x-webhook-id"], delivery.id);
+        assert_eq!(delivery.attempts, 3);
+        assert!(delivery.delivered.is_some());
+        assert_eq!(delivery.next_attempt, None);
+        assert_eq!(delivery.last_error, None);
+    }
+
+    #[test]
+    fn gives_up_after_the_last_retry() {
+        let (url, requests) = receiver(vec![500]);
+        let (webhooks, _dir) = webhooks(&url, vec![]);
+        notify(&webhooks, "peer.created");
+
+        for _ in 0..=RETRY_DELAYS.len() {
+            webhooks.deliver_due().unwrap();
+            requests.recv_timeout(Duration::from_secs(5)).unwrap();
+            make_due(&webhooks);
+        }
+        let delivery = &webhooks.deliveries(1).unwrap()[0];
+        assert_eq!(delivery.attempts as usize, RETRY_DELAYS.len() + 1);
+        assert_eq!(delivery.next_attempt, None);
+        assert_eq!(delivery.delivered, None);
+        assert!(delivery.last_error.as_deref().unwrap().contains("500"));
+
+        webhooks.deliver_due().unwrap();
+        assert!(requests.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+}
@@ -0,0 +1,384 @@
+use crate::config::{Backend, WireGuardConfig};
+use crate::logging;
+use lazy_static::*;
+use regex::bytes::Regex;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::PathBuf;
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::NamedTempFile;
+use tracing::{debug, error, warn};
+
+// the id of the request a call belongs to, logged by the wrapper
+const REQUEST_ID_ENV: &str = "WG_WRAPPER_REQUEST_ID";
+// Calls take milliseconds, one that hangs is killed. Peers are added and
+// removed while the database is locked.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref WG_INTERFACE_NAME_REG: Regex = Regex::new("interface: (\\w*).*").unwrap();
+}
+
+// Talks to the kernel either through the setuid wrapper or by calling `wg` directly.
+pub struct WireGuard {
+    backend: Backend,
+    binary: PathBuf,
+    pub interface: String,
+    // failed calls by command, for the metrics
+    failures: Mutex<HashMap<String, u64>>,
+}
+
+// Counters of a peer from `wg show <interface> dump`.
+pub struct PeerStats {
+    pub public_key: String,
+    // unix time, 0 if there never was one
+    pub latest_handshake: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+impl WireGuard {
+    pub fn new(config: &WireGuardConfig) -> Option<Self> {
+        let binary = match config.backend {
+            Backend::Wrapper => config.wrapper.clone(),
+            Backend::Wg => config.wg.clone(),
+        };
+        let mut wg = Self {
+            backend: config.backend,
+            binary,
+            interface: String::new(),
+            failures: Mutex::new(HashMap::new()),
+        };
+
+        wg.interface = match &config.interface {
+            Some(interface) => interface.clone(),
+            None => wg.detect_interface()?,
+        };
+        Some(wg)
+    }
+
+    fn detect_interface(&self) -> Option<String> {
+        let output = self.show();
+        let cap = WG_INTERFACE_NAME_REG.captures_iter(&output).next()?;
+        String::from_utf8(cap.get(1)?.as_bytes().to_vec()).ok()
+    }
+
+    pub fn show(&self) -> Vec<u8> {
+        self.run(&["show"]).unwrap_or_default()
+    }
+
+    pub fn showconf(&self) -> String {
+        let output = self.run(&["showconf", &self.interface]).unwrap_or_default();
+        String::from_utf8_lossy(&output).to_string()
+    }
+
+    fn dump_args(&self) -> Vec<&str> {
+        match self.backend {
+            Backend::Wrapper => vec!["dump", &self.interface],
+            Backend::Wg => vec!["show", &self.interface, "dump"],
+        }
+    }
+
+    // transfer and handshake of every peer, empty if the call failed
+    pub fn dump(&self) -> Vec<PeerStats> {
+        let output = self.run(&self.dump_args()).unwrap_or_default();
+        // the first line is the interface, then one tab separated line per peer:
+        // public key, preshared key, endpoint, allowed ips, handshake, rx, tx, keepalive
+        String::from_utf8_lossy(&output)
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let fields = line.split('\t').collect::<Vec<_>>();
+                if fields.len() < 7 {
+                    return None;
+                }
+                Some(PeerStats {
+                    public_key: fields[0].to_string(),
+                    latest_handshake: fields[4].parse().ok()?,
+                    rx_bytes: fields[5].parse().ok()?,
+                    tx_bytes: fields[6].parse().ok()?,
+                })
+            })
+            .collect()
+    }
+
+    // the interface can be listed through the backend
+    pub fn check_interface(&self) -> Result<(), String> {
+        let output = self.run(&self.dump_args()).map_err(|e| e.to_string())?;
+        if output.is_empty() {
+            return Err(format!(
+                "{} printed nothing for {}",
+                self.binary.display(),
+                self.interface
+            ));
+        }
+        Ok(())
+    }
+
+    // The wrapper only works as a setuid root binary, `wg` has to be
+    // executable. Whether the server may use `wg` is up to its capabilities,
+    // which the interface check covers.
+    pub fn check_binary(&self) -> Result<(), String> {
+        let meta = std::fs::metadata(&self.binary)
+            .map_err(|e| format!("{}: {}", self.binary.display(), e))?;
+        self.check_mode(meta.is_file(), meta.permissions().mode(), meta.uid())
+    }
+
+    fn check_mode(&self, is_file: bool, mode: u32, uid: u32) -> Result<(), String> {
+        if !is_file || mode & 0o111 == 0 {
+            return Err(format!("{} is not executable", self.binary.display()));
+        }
+        if self.backend == Backend::Wrapper {
+            if uid != 0 {
+                return Err(format!("{} is not owned by root", self.binary.display()));
+            }
+            if mode & 0o4000 == 0 {
+                return Err(format!("{} has no setuid bit", self.binary.display()));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn failures(&self) -> Vec<(String, u64)> {
+        let mut failures = self
+            .failures
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(command, count)| (command.clone(), *count))
+            .collect::<Vec<_>>();
+        failures.sort();
+        failures
+    }
+
+    // a new private key and its public key, from `wg genkey` and `wg pubkey`
+    pub fn key_pair(&self) -> Result<(String, String), std::io::Error> {
+        let private_key = self.run(&["genkey"])?;
+        let private_key = String::from_utf8_lossy(&private_key).trim().to_string();
+        let public_key = self.run_with_input(&["pubkey"], Some(private_key.as_bytes()))?;
+        let public_key = String::from_utf8_lossy(&public_key).trim().to_string();
+        if private_key.is_empty() || public_key.is_empty() {
+            return Err(std::io::Error::other(format!(
+                "{} printed no key",
+                self.binary.display()
+            )));
+        }
+        Ok((private_key, public_key))
+    }
+
+    pub fn add_peer(&self, peer: &shared::wg_conf::Peer) -> Result<(), std::io::Error> {
+        let mut file = NamedTempFile::new()?;
+        writeln!(file, "{}", peer.to_string())?;
+        let path = file.path().to_str().unwrap();
+        let args = match self.backend {
+            Backend::Wrapper => ["add", &self.interface, path],
+            Backend::Wg => ["addconf", &self.interface, path],
+        };
+        self.run(&args).map(|_| ())
+    }
+
+    pub fn remove_peer(&self, peer: &shared::wg_conf::Peer) -> Result<(), std::io::Error> {
+        match self.backend {
+            Backend::Wrapper => self.run(&["remove", &self.interface, &peer.public_key]),
+            Backend::Wg => self.run(&["set", &self.interface, "peer", &peer.public_key, "remove"]),
+        }
+        .map(|_| ())
+    }
+
+    // Runs the binary and returns what it printed. Its stderr and exit code
+    // end up in the log of the request that caused the call.
+    fn run(&self, args: &[&str]) -> Result<Vec<u8>, std::io::Error> {
+        self.run_with_input(args, None)
+    }
+
+    // `input` is written to the stdin of the binary, it is never logged
+    fn run_with_input(
+        &self,
+        args: &[&str],
+        input: Option<&[u8]>,
+    ) -> Result<Vec<u8>, std::io::Error> {
+        let start = Instant::now();
+        let mut command = Command::new(&self.binary);
+        if let Some(id) = logging::request_id() {
+            command.env(REQUEST_ID_ENV, id);
+        }
+        let output = command
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                if let Some(input) = input {
+                    child.stdin.take().unwrap().write_all(input)?;
+                }
+                wait_with_timeout(child, TIMEOUT)
+            })
+            .map_err(|e| {
+                error!(binary = %self.binary.display(), ?args, "Could not run: {}", e);
+                self.count_failure(args);
+                e
+            })?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let stderr = stderr.trim();
+        debug!(
+            binary = %self.binary.display(),
+            ?args,
+            status = output.status.code(),
+            duration_ms = start.elapsed().as_millis() as u64,
+            "Ran backend"
+        );
+
+        if !output.status.success() {
+            error!(
+                binary = %self.binary.display(),
+                ?args,
+                status = output.status.code(),
+                stderr,
+                "Backend failed"
+            );
+            self.count_failure(args);
+            return Err(std::io::Error::other(format!(
+                "{} exited with {}",
+                self.binary.display(),
+                output.status
+            )));
+        }
+        if !stderr.is_empty() {
+            warn!(binary = %self.binary.display(), ?args, stderr, "Backend wrote to stderr");
+        }
+        Ok(output.stdout)
+    }
+
+    fn count_failure(&self, args: &[&str]) {
+        let command = args.first().copied().unwrap_or_default().to_string();
+        *self.failures.lock().unwrap().entry(command).or_insert(0) += 1;
+    }
+}
+
+// `Child::wait_with_output`, but kills the child when it takes longer than `timeout`
+fn wait_with_timeout(mut child: Child, timeout: Duration) -> Result<Output, std::io::Error> {
+    drop(child.stdin.take());
+    fn read_all(mut pipe: impl Read + Send + 'static) -> JoinHandle<std::io::Result<Vec<u8>>> {
+        thread::spawn(move || {
+            let mut bytes = vec![];
+            pipe.read_to_end(&mut bytes).map(|_| bytes)
+        })
+    }
+    let stdout = child.stdout.take().map(read_all);
+    let stderr = child.stderr.take().map(read_all);
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("no answer after {} seconds", timeout.as_secs_f32()),
+            ));
+        }
+        thread::sleep(Duration::from_millis(5));
+    };
+    let output = |reader: Option<JoinHandle<_>>| match reader {
+        Some(reader) => reader.join().unwrap(),
+        None => Ok(vec![]),
+    };
+    Ok(Output {
+        status,
+        stdout: output(stdout)?,
+        stderr: output(stderr)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wireguard(backend: Backend, binary: PathBuf) -> WireGuard {
+        WireGuard::new(&WireGuardConfig {
+            backend,
+            wrapper: binary.clone(),
+            wg: binary,
+            interface: Some("wg0".to_string()),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn wg_has_to_be_executable() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("wg");
+        let wg = wireguard(Backend::Wg, binary.clone());
+        assert!(wg.check_binary().is_err());
+
+        std::fs::write(&binary, "").unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(
+            wg.check_binary(),
+            Err(format!("{} is not executable", binary.display()))
+        );
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(wg.check_binary(), Ok(()));
+
+        let wg = wireguard(Backend::Wg, dir.path().to_path_buf());
+        assert!(wg.check_binary().is_err());
+    }
+
+    #[test]
+    fn the_wrapper_has_to_be_setuid_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("wg_wrapper.bin");
+        std::fs::write(&binary, "").unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let wrapper = wireguard(Backend::Wrapper, binary.clone());
+        // no setuid bit, whoever owns the file
+        assert!(wrapper.check_binary().is_err());
+
+        assert_eq!(wrapper.check_mode(true, 0o4755, 0), Ok(()));
+        assert_eq!(
+            wrapper.check_mode(true, 0o755, 0),
+            Err(format!("{} has no setuid bit", binary.display()))
+        );
+        assert_eq!(
+            wrapper.check_mode(true, 0o4755, 1000),
+            Err(format!("{} is not owned by root", binary.display()))
+        );
+        assert!(wrapper.check_mode(true, 0o4644, 0).is_err());
+        assert!(wrapper.check_mode(false, 0o4755, 0).is_err());
+        // wg only needs the exec bit
+        let wg = wireguard(Backend::Wg, binary);
+        assert_eq!(wg.check_mode(true, 0o755, 1000), Ok(()));
+    }
+
+    #[test]
+    fn hanging_calls_are_killed() {
+        let spawn = |command: &mut Command| {
+            command
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap()
+        };
+        let child = spawn(Command::new("sh").args(["-c", "echo out; echo err >&2"]));
+        let output = wait_with_timeout(child, Duration::from_secs(10)).unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+
+        let start = Instant::now();
+        let child = spawn(Command::new("sleep").arg("10"));
+        let e = wait_with_timeout(child, Duration::from_millis(100)).unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
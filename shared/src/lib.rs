@@ -1,6 +1,11 @@
 use serde::{Deserialize, Serialize};
 pub mod wg_conf;
 
+// how many peers `/api/download_peers` zips at once, their keys are in the url
+pub const MAX_DOWNLOAD_PEERS: usize = 100;
+
+// Peers are picked by public key, their position in the list moves when
+// some are removed or disabled.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
     Login {
@@ -8,26 +13,347 @@ pub enum Request {
         password: String,
     },
     PeerDownload {
-        index: usize,
+        public_key: String,
     },
     UpdatePeerName {
-        index: usize,
+        public_key: String,
         name: String,
     },
+    // bytes per month, None removes the quota
+    SetPeerQuota {
+        public_key: String,
+        limit: Option<u64>,
+    },
+    // mails the config and its qr code to the user of the peer
+    SendPeerConfig {
+        public_key: String,
+        email: String,
+    },
+    // a link that shows the config once, for `hours`
+    SharePeer {
+        public_key: String,
+        hours: u64,
+    },
+    // the interface has to be the one of the server, None for it;
+    // `peer_hours` is how long the enrolled peers live, None for no limit
+    CreateInvite {
+        #[serde(default)]
+        interface: Option<String>,
+        routes: Option<String>,
+        quota: Option<u64>,
+        uses: u32,
+        hours: u64,
+        #[serde(default)]
+        peer_hours: Option<u64>,
+    },
+    RevokeInvite {
+        id: String,
+    },
+    // redeems an invite code for a new peer named `name`, without a login
+    Enroll {
+        code: String,
+        name: String,
+    },
+    // the defaults of the groups among `tags` are applied to the new peer
+    NewPeer {
+        tags: Vec<String>,
+    },
+    NewPeerWithKey {
+        public_key: String,
+        #[serde(default)]
+        tags: Vec<String>,
+    },
+    SetPeerTags {
+        public_key: String,
+        tags: Vec<String>,
+    },
+    BulkPeers {
+        public_keys: Vec<String>,
+        action: BulkAction,
+    },
     UpdateUser {
         name: String,
         old_password: String,
         new_password: String,
         password_confirmation: String,
     },
+    RevokeSession {
+        id: String,
+    },
+    LoginTotp {
+        token: String,
+        code: String,
+    },
+    TotpConfirm {
+        code: String,
+    },
+    TotpDisable {
+        password: String,
+    },
+    CreateApiToken {
+        name: String,
+        scopes: Vec<TokenScope>,
+        expires_in_days: Option<u64>,
+    },
+    RevokeApiToken {
+        id: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum BulkAction {
+    Disable,
+    Enable,
+    Remove,
+    // AllowedIPs template like the one of invites, None for the server's address
+    SetRoutes(Option<String>),
+    AddTag(String),
+    RemoveTag(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: String,
+    pub created: u64,
+    pub last_seen: u64,
+    pub ip: String,
+    pub user_agent: String,
+    pub current: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenScope {
+    ReadConfig,
+    ManagePeers,
+    DownloadConfigs,
+    ReadAudit,
+}
+
+impl TokenScope {
+    pub const ALL: [TokenScope; 4] = [
+        TokenScope::ReadConfig,
+        TokenScope::ManagePeers,
+        TokenScope::DownloadConfigs,
+        TokenScope::ReadAudit,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            TokenScope::ReadConfig => "Read config",
+            TokenScope::ManagePeers => "Manage peers",
+            TokenScope::DownloadConfigs => "Download configs",
+            TokenScope::ReadAudit => "Read audit log",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiTokenInfo {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<TokenScope>,
+    pub created: u64,
+    pub expires: Option<u64>,
+    pub last_used: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub time: u64,
+    // user that triggered the action, empty if nobody is logged in
+    pub actor: String,
+    pub action: String,
+    pub target: String,
+    // the changed value, if the action changed one
+    #[serde(default)]
+    pub before: Option<String>,
+    #[serde(default)]
+    pub after: Option<String>,
+    pub ip: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TrafficRange {
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl TrafficRange {
+    pub const ALL: [TrafficRange; 3] = [TrafficRange::Day, TrafficRange::Week, TrafficRange::Month];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            TrafficRange::Day => "24h",
+            TrafficRange::Week => "7d",
+            TrafficRange::Month => "30d",
+        }
+    }
+
+    pub fn secs(&self) -> u64 {
+        let day = 24 * 60 * 60;
+        match self {
+            TrafficRange::Day => day,
+            TrafficRange::Week => 7 * day,
+            TrafficRange::Month => 30 * day,
+        }
+    }
+
+    pub fn path(&self) -> &'static str {
+        match self {
+            TrafficRange::Day => "day",
+            TrafficRange::Week => "week",
+            TrafficRange::Month => "month",
+        }
+    }
+}
+
+// Bytes in the bucket starting at `time`, as seen by the server: rx came
+// from the peer, tx went to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrafficPoint {
+    pub time: u64,
+    pub rx: u64,
+    pub tx: u64,
+}
+
+// buckets without traffic are left out
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrafficSeries {
+    pub public_key: String,
+    pub points: Vec<TrafficPoint>,
+}
+
+// A code that lets people add a peer for themselves, the peers get the
+// routes, quota and lifetime of the invite.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InviteInfo {
+    pub id: String,
+    pub interface: String,
+    // AllowedIPs template, `{subnet}` and `{server}` are filled in
+    pub routes: Option<String>,
+    pub quota: Option<u64>,
+    // the enrolled peers are removed after that many hours
+    #[serde(default)]
+    pub peer_hours: Option<u64>,
+    pub uses_left: u32,
+    pub created_by: String,
+    pub created: u64,
+    pub expires: u64,
+}
+
+// One event sent to one webhook url, with how it went.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub url: String,
+    pub event: String,
+    pub created: u64,
+    pub attempts: u32,
+    // None once it was delivered or given up on
+    pub next_attempt: Option<u64>,
+    pub delivered: Option<u64>,
+    pub last_status: Option<u16>,
+    pub last_error: Option<String>,
+}
+
+// what the interface knows about a peer right now
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerStatus {
+    pub public_key: String,
+    // unix time, 0 if there never was one
+    pub latest_handshake: u64,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+// Pushed to the open pages over `/api/events`, peers are found by their
+// public key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Event {
+    // `index` is where the peer is in the config now
+    PeerAdded { index: usize, peer: wg_conf::Peer },
+    PeerRenamed { public_key: String, name: String },
+    PeerRemoved { public_key: String },
+    // anything else, the config has to be loaded again
+    ConfigChanged,
+    Status { peers: Vec<PeerStatus> },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Response {
-    LoginSuccess { session: String },
+    LoginSuccess {
+        session: String,
+    },
     LoginFailure,
+    LoginOptions {
+        sso: bool,
+    },
+    TotpRequired {
+        token: String,
+    },
+    TooManyAttempts {
+        retry_after: u64,
+    },
     Logout,
-    WireGuardConf { config: wg_conf::WireGuardConf },
+    WireGuardConf {
+        config: wg_conf::WireGuardConf,
+    },
+    Sessions {
+        sessions: Vec<SessionInfo>,
+    },
+    TotpStatus {
+        enabled: bool,
+    },
+    TotpEnrollment {
+        secret: String,
+        qr_code: String,
+    },
+    RecoveryCodes {
+        codes: Vec<String>,
+    },
+    ApiTokens {
+        tokens: Vec<ApiTokenInfo>,
+    },
+    ApiTokenCreated {
+        token: String,
+        tokens: Vec<ApiTokenInfo>,
+    },
+    AuditLog {
+        entries: Vec<AuditEntry>,
+    },
+    WebhookDeliveries {
+        deliveries: Vec<WebhookDelivery>,
+    },
+    Traffic {
+        range: TrafficRange,
+        // time of the first bucket and the length of a bucket
+        start: u64,
+        step: u64,
+        series: Vec<TrafficSeries>,
+    },
+    // the token goes into `/share/<token>`, it can't be shown again
+    ShareLink {
+        public_key: String,
+        token: String,
+        expires: u64,
+    },
+    Invites {
+        invites: Vec<InviteInfo>,
+    },
+    // the code can't be shown again
+    InviteCreated {
+        code: String,
+        invites: Vec<InviteInfo>,
+    },
+    // what a share link or an enrollment shows, the qr code is a data url
+    SharedConfig {
+        name: String,
+        config: String,
+        qr_code: String,
+    },
     Success,
     Failure,
 }
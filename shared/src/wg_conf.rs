@@ -13,6 +13,9 @@ pub struct Interface {
     pub private_key: String,
     pub public_key: String,
     pub dns: Ipv4Addr,
+    // host:port peers connect to, falls back to `address`
+    #[serde(default)]
+    pub endpoint: Option<String>,
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -23,6 +26,7 @@ impl Interface {
             private_key: "".to_string(),
             public_key: "".to_string(),
             dns: Ipv4Addr::new(0, 0, 0, 0),
+            endpoint: None,
         }
     }
 
@@ -40,6 +44,7 @@ impl Interface {
             private_key: "".to_string(),
             public_key: "".to_string(),
             dns: Ipv4Addr::new(0, 0, 0, 0),
+            endpoint: None,
         }
     }
 
@@ -55,6 +60,31 @@ pub struct Peer {
     pub endpoint: SocketAddrV4,
     pub allowed_ips: Ipv4Net,
     pub name: String,
+    #[serde(default)]
+    pub quota: Option<Quota>,
+    // taken off the interface, it is kept to be added again later
+    #[serde(default)]
+    pub disabled: bool,
+    // AllowedIPs of the peer's own config, the address of the server if None
+    #[serde(default)]
+    pub routes: Option<String>,
+    // groups like "office" or "iot", for filtering and the defaults of new peers
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // when the peer is removed, for peers enrolled with an invite that limits it
+    #[serde(default)]
+    pub expires: Option<u64>,
+}
+
+// Monthly data limit of a peer, in bytes received and sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quota {
+    pub limit: u64,
+    pub used: u64,
+    // the used share at which the server warns, in percent
+    pub warn_percent: u64,
+    // unix time the usage starts over
+    pub resets: u64,
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -79,6 +109,11 @@ impl Peer {
             endpoint: SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 8080),
             allowed_ips: Ipv4Net::new(Ipv4Addr::new(0, 0, 0, 0), 16).unwrap(),
             name: "".to_string(),
+            quota: None,
+            disabled: false,
+            routes: None,
+            tags: vec![],
+            expires: None,
         }
     }
 
@@ -103,6 +138,11 @@ impl Peer {
             endpoint: SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 8080),
             allowed_ips: Ipv4Net::new(Ipv4Addr::new(0, 0, 0, 0), 16).unwrap(),
             name: "".to_string(),
+            quota: None,
+            disabled: false,
+            routes: None,
+            tags: vec![],
+            expires: None,
         }
     }
 }
@@ -133,6 +173,10 @@ impl WireGuardConf {
         }
     }
 
+    pub fn peer(&self, public_key: &str) -> Option<&Peer> {
+        self.peers.iter().find(|p| p.public_key == public_key)
+    }
+
     pub fn peer_config(&self, peer: &Peer) -> String {
         let mut peer_conf = "[Interface]\n".to_string();
         // ListenPort
@@ -146,11 +190,17 @@ impl WireGuardConf {
         // PublicKey
         peer_conf.push_str(&format!("PublicKey = {}\n", self.interface.public_key));
         // AllowedIPs
-        peer_conf.push_str(&format!("AllowedIPs = {}/32\n", self.interface.dns));
+        match &peer.routes {
+            Some(routes) => peer_conf.push_str(&format!("AllowedIPs = {}\n", routes)),
+            None => peer_conf.push_str(&format!("AllowedIPs = {}/32\n", self.interface.dns)),
+        }
         // Endpoint
         peer_conf.push_str(&format!(
             "Endpoint = {}\n",
-            self.interface.address.to_string()
+            self.interface
+                .endpoint
+                .clone()
+                .unwrap_or_else(|| self.interface.address.to_string())
         ));
         peer_conf
     }
@@ -1,58 +1,86 @@
-use nix::unistd::{setuid, Uid};
+use nix::unistd::{getuid, setuid, Uid};
 use std::env;
-use std::process::Command;
-use std::str;
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::process::{exit, Command, Stdio};
+
+const WG: &str = "/usr/bin/wg";
+// set by the server, the id of the request a call belongs to
+const REQUEST_ID_ENV: &str = "WG_WRAPPER_REQUEST_ID";
+const SYSLOG: &str = "/dev/log";
 
 fn main() {
+    let caller = getuid();
     let _res = setuid(Uid::from_raw(0));
 
     let args = env::args_os()
         .map(|a| a.into_string().unwrap())
         .collect::<Vec<_>>();
+    let args = args.iter().map(String::as_str).collect::<Vec<_>>();
+    // argv can be empty when started with execve
+    let args = args.get(1..).unwrap_or_default();
 
-    match args[1].as_str() {
-        "show" => {
-            run_show();
-        }
-        "showconf" => {
-            run_showconf(&args[2]);
-        }
-        "add" => {
-            run_add_conf(&args[2], &args[3]);
-        }
-        "remove" => {
-            run_remove_peer(&args[2], &args[3]);
+    // stdout, stderr and the exit code of wg are passed on, the server logs them
+    let wg_args = match *args {
+        ["show"] => vec!["show"],
+        ["genkey"] => vec!["genkey"],
+        // the private key comes on stdin
+        ["pubkey"] => vec!["pubkey"],
+        ["showconf", iface] => vec!["showconf", iface],
+        ["dump", iface] => vec!["show", iface, "dump"],
+        ["add", iface, path] => vec!["addconf", iface, path],
+        ["remove", iface, key] => vec!["set", iface, "peer", key, "remove"],
+        _ => {
+            eprintln!(
+                "usage: wg_wrapper show | genkey | pubkey | showconf <iface> | dump <iface> \
+                 | add <iface> <file> | remove <iface> <public key>"
+            );
+            exit(2);
         }
-        _ => {}
-    }
-}
-
-fn run_show() {
-    let output = Command::new("/usr/bin/wg")
-        .args(&["show"])
-        .output()
-        .unwrap();
-    println!("{}", str::from_utf8(&output.stdout).unwrap());
+    };
+    log(caller, &wg_args);
+    exit(run_wg(&wg_args));
 }
 
-fn run_showconf(iface: &str) {
-    let output = Command::new("/usr/bin/wg")
-        .args(&["showconf", iface])
-        .output()
-        .unwrap();
-    println!("{}", str::from_utf8(&output.stdout).unwrap());
-}
-
-fn run_add_conf(iface: &str, path: &str) {
-    let _output = Command::new("/usr/bin/wg")
-        .args(&["addconf", iface, path])
-        .output()
-        .unwrap();
+// What is run as root for whom goes to the system log, with the request id
+// of the server to find the request that caused it.
+fn log(caller: Uid, args: &[&str]) {
+    let request_id = env::var(REQUEST_ID_ENV)
+        .ok()
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= 64
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .unwrap_or_else(|| "-".to_string());
+    // facility authpriv, severity info
+    let message = format!(
+        "<86>wg_wrapper[{}]: request_id={} uid={} wg {}",
+        std::process::id(),
+        request_id,
+        caller,
+        args.join(" ")
+    )
+    // arguments come from whoever runs the wrapper, they can't add lines
+    .replace(|c: char| c.is_control(), "?");
+    if let Ok(socket) = UnixDatagram::unbound() {
+        let _ = socket.send_to(message.as_bytes(), SYSLOG);
+    }
 }
 
-fn run_remove_peer(iface: &str, key: &str) {
-    let _output = Command::new("/usr/bin/wg")
-        .args(&["set", iface, "peer", &key, "remove"])
-        .output()
-        .unwrap();
+fn run_wg(args: &[&str]) -> i32 {
+    match Command::new(WG).args(args).stdin(Stdio::inherit()).output() {
+        Ok(output) => {
+            let _ = std::io::stdout().write_all(&output.stdout);
+            let _ = std::io::stderr().write_all(&output.stderr);
+            // killed by a signal
+            output.status.code().unwrap_or(1)
+        }
+        Err(e) => {
+            eprintln!("could not run {}: {}", WG, e);
+            1
+        }
+    }
 }